@@ -0,0 +1,5 @@
+-- Add down migration script here
+ALTER TABLE accounts DROP CONSTRAINT IF EXISTS fk_assignment_strategy;
+ALTER TABLE accounts DROP COLUMN IF EXISTS assignment_strategy_id;
+
+DROP TABLE IF EXISTS assignment_strategies;
@@ -0,0 +1,18 @@
+-- Add up migration script here
+CREATE TABLE IF NOT EXISTS assignment_strategies (
+        assignment_strategy_id SERIAL PRIMARY KEY,
+        assignment_strategy_name TEXT NOT NULL UNIQUE
+);
+
+INSERT INTO assignment_strategies (assignment_strategy_name)
+VALUES
+('decision_tree'),
+('round_robin_territory'),
+('least_loaded'),
+('logistic_regression');
+
+ALTER TABLE accounts
+        ADD COLUMN IF NOT EXISTS assignment_strategy_id INTEGER NOT NULL DEFAULT 1,
+        ADD CONSTRAINT fk_assignment_strategy
+            FOREIGN KEY(assignment_strategy_id)
+                REFERENCES assignment_strategies(assignment_strategy_id);
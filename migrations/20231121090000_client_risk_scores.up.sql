@@ -0,0 +1,12 @@
+-- Add up migration script here
+CREATE TABLE IF NOT EXISTS client_risk_scores (
+        client_risk_score_id SERIAL PRIMARY KEY,
+        client_id INTEGER NOT NULL UNIQUE,
+        -- Probability of no new consult within horizon_days
+        risk_score REAL NOT NULL,
+        horizon_days INTEGER NOT NULL,
+        scored_at TIMESTAMPTZ NOT NULL DEFAULT NOW(),
+        CONSTRAINT fk_client
+            FOREIGN KEY(client_id)
+                REFERENCES clients(id) ON DELETE CASCADE
+);
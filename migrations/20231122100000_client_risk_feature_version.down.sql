@@ -0,0 +1,2 @@
+-- Add down migration script here
+ALTER TABLE client_risk_scores DROP COLUMN IF EXISTS feature_version;
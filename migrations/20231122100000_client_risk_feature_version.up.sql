@@ -0,0 +1,3 @@
+-- Add up migration script here
+ALTER TABLE client_risk_scores
+        ADD COLUMN IF NOT EXISTS feature_version INTEGER NOT NULL DEFAULT 1;
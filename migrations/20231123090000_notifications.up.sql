@@ -0,0 +1,16 @@
+-- Add up migration script here
+CREATE TABLE IF NOT EXISTS notifications (
+        notification_id SERIAL PRIMARY KEY,
+        user_id INTEGER NOT NULL,
+        -- What changed. Same entity_type_id values as the subscription arrays on users
+        entity_type_id INTEGER NOT NULL,
+        entity_id INTEGER NOT NULL,
+        msg TEXT NOT NULL,
+        read_at TIMESTAMPTZ DEFAULT NULL,
+        created_at TIMESTAMPTZ NOT NULL DEFAULT NOW(),
+        CONSTRAINT fk_user
+            FOREIGN KEY(user_id)
+                REFERENCES users(id) ON DELETE CASCADE
+);
+
+CREATE INDEX IF NOT EXISTS notifications_unread_idx ON notifications (user_id) WHERE read_at IS NULL;
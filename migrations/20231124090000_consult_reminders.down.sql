@@ -0,0 +1,3 @@
+-- Add down migration script here
+DROP INDEX IF EXISTS consults_reminder_due_idx;
+ALTER TABLE consults DROP COLUMN IF EXISTS reminder_sent_at;
@@ -0,0 +1,4 @@
+-- Add up migration script here
+ALTER TABLE consults ADD COLUMN IF NOT EXISTS reminder_sent_at TIMESTAMPTZ DEFAULT NULL;
+
+CREATE INDEX IF NOT EXISTS consults_reminder_due_idx ON consults (consult_start) WHERE reminder_sent_at IS NULL;
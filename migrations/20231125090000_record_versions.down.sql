@@ -0,0 +1,4 @@
+-- Add down migration script here
+ALTER TABLE clients ALTER COLUMN updated_at DROP NOT NULL;
+ALTER TABLE consults ALTER COLUMN updated_at DROP NOT NULL;
+ALTER TABLE consults ALTER COLUMN updated_at SET DEFAULT NULL;
@@ -0,0 +1,8 @@
+-- Add up migration script here
+-- updated_at doubles as the row version for optimistic concurrency on edit forms, so it can't be NULL.
+UPDATE consults SET updated_at = created_at WHERE updated_at IS NULL;
+UPDATE clients SET updated_at = COALESCE(created_at, NOW()) WHERE updated_at IS NULL;
+
+ALTER TABLE consults ALTER COLUMN updated_at SET DEFAULT NOW();
+ALTER TABLE consults ALTER COLUMN updated_at SET NOT NULL;
+ALTER TABLE clients ALTER COLUMN updated_at SET NOT NULL;
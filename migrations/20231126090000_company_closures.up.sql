@@ -0,0 +1,7 @@
+-- Add up migration script here
+CREATE TABLE IF NOT EXISTS company_closures (
+    closure_id SERIAL PRIMARY KEY,
+    closure_date DATE NOT NULL UNIQUE,
+    closure_name TEXT NOT NULL,
+    created_at TIMESTAMPTZ NOT NULL DEFAULT NOW()
+);
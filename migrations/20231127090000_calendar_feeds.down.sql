@@ -0,0 +1,5 @@
+-- Add down migration script here
+DROP TABLE IF EXISTS calendar_feeds;
+
+ALTER TABLE consults DROP COLUMN IF EXISTS cancelled_at;
+ALTER TABLE consults DROP COLUMN IF EXISTS ics_sequence;
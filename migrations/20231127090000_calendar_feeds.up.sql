@@ -0,0 +1,20 @@
+-- Add up migration script here
+ALTER TABLE consults ADD COLUMN IF NOT EXISTS ics_sequence INTEGER NOT NULL DEFAULT 0;
+ALTER TABLE consults ADD COLUMN IF NOT EXISTS cancelled_at TIMESTAMPTZ DEFAULT NULL;
+
+CREATE TABLE IF NOT EXISTS calendar_feeds (
+    feed_id SERIAL PRIMARY KEY,
+    token TEXT NOT NULL UNIQUE,
+    user_id INTEGER NOT NULL,
+    -- 1 user (their subscriptions), 4 consultant, 5 location
+    entity_type_id INTEGER NOT NULL,
+    entity_id INTEGER NOT NULL,
+    created_at TIMESTAMPTZ NOT NULL DEFAULT NOW(),
+    revoked_at TIMESTAMPTZ DEFAULT NULL,
+    CONSTRAINT fk_user
+        FOREIGN KEY(user_id)
+            REFERENCES users(id)
+);
+
+CREATE UNIQUE INDEX IF NOT EXISTS calendar_feeds_active_idx
+    ON calendar_feeds (user_id, entity_type_id, entity_id) WHERE revoked_at IS NULL;
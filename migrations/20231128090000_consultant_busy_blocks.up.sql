@@ -0,0 +1,19 @@
+-- Add up migration script here
+-- Events imported from consultants' outside calendars (.ics), one row per occurrence
+CREATE TABLE IF NOT EXISTS consultant_busy_blocks (
+    block_id SERIAL PRIMARY KEY,
+    consultant_id INTEGER NOT NULL,
+    -- File the events came from. Re-importing a source replaces its rows.
+    source TEXT NOT NULL,
+    uid TEXT NOT NULL,
+    summary TEXT DEFAULT NULL,
+    busy_start TIMESTAMPTZ NOT NULL,
+    busy_end TIMESTAMPTZ NOT NULL,
+    created_at TIMESTAMPTZ NOT NULL DEFAULT NOW(),
+    CONSTRAINT fk_consultant
+        FOREIGN KEY(consultant_id)
+            REFERENCES consultants(id) ON DELETE CASCADE
+);
+
+CREATE INDEX IF NOT EXISTS consultant_busy_blocks_start_idx
+    ON consultant_busy_blocks (consultant_id, busy_start);
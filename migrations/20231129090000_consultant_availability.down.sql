@@ -0,0 +1,3 @@
+-- Add down migration script here
+DROP TABLE IF EXISTS consultant_time_off;
+DROP TABLE IF EXISTS consultant_working_hours;
@@ -0,0 +1,33 @@
+-- Add up migration script here
+-- Weekly hours in local (consult_offset) time, one span per weekday. 0 is Sunday.
+CREATE TABLE IF NOT EXISTS consultant_working_hours (
+    hours_id SERIAL PRIMARY KEY,
+    consultant_id INTEGER NOT NULL,
+    weekday SMALLINT NOT NULL CHECK (weekday BETWEEN 0 AND 6),
+    start_time TIME NOT NULL,
+    end_time TIME NOT NULL CHECK (end_time > start_time),
+    CONSTRAINT fk_consultant
+        FOREIGN KEY(consultant_id)
+            REFERENCES consultants(id) ON DELETE CASCADE,
+    UNIQUE (consultant_id, weekday)
+);
+
+-- Whole local days off. Only approved rows block scheduling.
+CREATE TABLE IF NOT EXISTS consultant_time_off (
+    time_off_id SERIAL PRIMARY KEY,
+    consultant_id INTEGER NOT NULL,
+    first_day DATE NOT NULL,
+    last_day DATE NOT NULL CHECK (last_day >= first_day),
+    reason TEXT DEFAULT NULL,
+    status TEXT NOT NULL DEFAULT 'pending' CHECK (status IN ('pending', 'approved', 'denied')),
+    requested_by INTEGER DEFAULT NULL,
+    reviewed_by INTEGER DEFAULT NULL,
+    reviewed_at TIMESTAMPTZ DEFAULT NULL,
+    created_at TIMESTAMPTZ NOT NULL DEFAULT NOW(),
+    CONSTRAINT fk_consultant
+        FOREIGN KEY(consultant_id)
+            REFERENCES consultants(id) ON DELETE CASCADE
+);
+
+CREATE INDEX IF NOT EXISTS consultant_time_off_days_idx
+    ON consultant_time_off (consultant_id, first_day, last_day);
@@ -0,0 +1,4 @@
+-- Add down migration script here
+DROP INDEX IF EXISTS consults_pending_idx;
+DROP TABLE IF EXISTS booking_links;
+ALTER TABLE consults DROP COLUMN IF EXISTS booking_status;
@@ -0,0 +1,24 @@
+-- Add up migration script here
+-- Self-booked consults start out pending until staff confirm them. Declined ones are also cancelled.
+ALTER TABLE consults ADD COLUMN IF NOT EXISTS booking_status TEXT NOT NULL DEFAULT 'confirmed'
+    CHECK (booking_status IN ('pending', 'confirmed', 'declined'));
+
+-- Public booking pages. Anyone with the token can request a consult for the client or with the consultant.
+CREATE TABLE IF NOT EXISTS booking_links (
+    link_id SERIAL PRIMARY KEY,
+    token TEXT NOT NULL UNIQUE,
+    -- 4 consultant, 7 client
+    entity_type_id INTEGER NOT NULL,
+    entity_id INTEGER NOT NULL,
+    created_by INTEGER DEFAULT NULL,
+    created_at TIMESTAMPTZ NOT NULL DEFAULT NOW(),
+    revoked_at TIMESTAMPTZ DEFAULT NULL,
+    CONSTRAINT fk_user
+        FOREIGN KEY(created_by)
+            REFERENCES users(id)
+);
+
+CREATE UNIQUE INDEX IF NOT EXISTS booking_links_active_idx
+    ON booking_links (entity_type_id, entity_id) WHERE revoked_at IS NULL;
+
+CREATE INDEX IF NOT EXISTS consults_pending_idx ON consults (consult_start) WHERE booking_status = 'pending';
@@ -0,0 +1,292 @@
+use chrono::{DateTime, Duration, NaiveDate, Utc};
+use serde::{Deserialize, Serialize};
+use sqlx::{FromRow, Pool, Postgres};
+
+use crate::{hbs_helpers::preview, linfa::{consult_offset, meeting_time::day_bounds}};
+
+use super::pdf::{PdfText, TextStyle};
+
+// A consultant's consults for a day or a week, laid out to take on the road: when, where to go, who to
+// call. The HTML page and the PDF are built from the same days so they always agree.
+
+// Longest range one agenda covers
+pub const MAX_AGENDA_DAYS: i64 = 31;
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize, FromRow)]
+pub struct AgendaConsultant {
+    pub id: i32,
+    pub slug: String,
+    pub consultant_name: String,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize, FromRow)]
+pub struct AgendaConsult {
+    pub id: i32,
+    pub slug: String,
+    pub consult_start: DateTime<Utc>,
+    pub consult_end: Option<DateTime<Utc>>,
+    pub consult_purpose_name: Option<String>,
+    pub booking_status: String,
+    pub notes: Option<String>,
+    pub client_name: Option<String>,
+    pub client_primary_phone: String,
+    pub client_mobile_phone: Option<String>,
+    pub client_email: String,
+    pub location_name: String,
+    pub location_address_one: String,
+    pub location_address_two: Option<String>,
+    pub location_city: String,
+    pub location_state: String,
+    pub location_zip: String,
+    pub location_phone: Option<String>,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct AgendaEntry {
+    pub slug: String,
+    pub start_time: String,
+    pub end_time: Option<String>,
+    pub purpose: Option<String>,
+    pub pending: bool,
+    pub client_name: String,
+    pub phones: String,
+    pub client_email: String,
+    pub location_name: String,
+    pub address: Vec<String>,
+    pub location_phone: Option<String>,
+    pub notes: Option<String>,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct AgendaDay {
+    pub date: NaiveDate,
+    pub label: String,
+    pub consults: Vec<AgendaEntry>,
+}
+
+pub async fn agenda_consultant(db: &Pool<Postgres>, slug: &str) -> Result<Option<AgendaConsultant>, sqlx::Error> {
+    sqlx::query_as::<_, AgendaConsultant>(
+        "SELECT id, slug, consultant_f_name || ' ' || consultant_l_name AS consultant_name
+            FROM consultants WHERE slug = $1",
+    )
+    .bind(slug)
+    .fetch_optional(db)
+    .await
+}
+
+// Cancelled and declined consults are left off, pending requests stay so the slot isn't a surprise
+pub async fn agenda_consults(
+    db: &Pool<Postgres>,
+    consultant_id: i32,
+    first_day: NaiveDate,
+    last_day: NaiveDate,
+) -> Result<Vec<AgendaConsult>, sqlx::Error> {
+    let (range_start, _) = day_bounds(first_day);
+    let (_, range_end) = day_bounds(last_day);
+    sqlx::query_as::<_, AgendaConsult>(
+        "SELECT consults.id, consults.slug, consult_start, consult_end, consult_purpose_name, booking_status,
+                consults.notes, client_primary_phone, client_mobile_phone, client_email,
+                COALESCE(client_company_name, client_f_name || ' ' || client_l_name) AS client_name,
+                location_name, location_address_one, location_address_two, location_city, location_state,
+                location_zip, location_phone
+            FROM consults
+            INNER JOIN clients ON clients.id = consults.client_id
+            INNER JOIN locations ON locations.id = consults.location_id
+            LEFT JOIN consult_purposes ON consult_purposes.consult_purpose_id = consults.consult_purpose_id
+            WHERE consults.consultant_id = $1 AND cancelled_at IS NULL
+                AND consult_start >= $2 AND consult_start < $3
+            ORDER BY consult_start",
+    )
+    .bind(consultant_id)
+    .bind(range_start)
+    .bind(range_end)
+    .fetch_all(db)
+    .await
+}
+
+// Defaults to today. Parse failures and oversized ranges come back as the message to show.
+pub fn agenda_range(
+    start: Option<&str>,
+    end: Option<&str>,
+    today: NaiveDate,
+) -> Result<(NaiveDate, NaiveDate), String> {
+    let parse = |date: Option<&str>, default: NaiveDate| match date.filter(|date| !date.is_empty()) {
+        Some(date) => NaiveDate::parse_from_str(date, "%Y-%m-%d").map_err(|_| format!("{} isn't a date", date)),
+        None => Ok(default),
+    };
+    let first_day = parse(start, today)?;
+    let last_day = parse(end, first_day)?;
+    if last_day < first_day {
+        return Err("The agenda has to end on or after its first day".to_owned());
+    }
+    if last_day - first_day >= Duration::days(MAX_AGENDA_DAYS) {
+        return Err(format!("An agenda covers at most {} days", MAX_AGENDA_DAYS));
+    }
+    Ok((first_day, last_day))
+}
+
+pub fn agenda_title(consultant_name: &str, first_day: NaiveDate, last_day: NaiveDate) -> String {
+    if first_day == last_day {
+        format!("{}: {}", consultant_name, first_day.format("%A, %B %-d %Y"))
+    } else {
+        format!(
+            "{}: {} - {}",
+            consultant_name,
+            first_day.format("%a %b %-d"),
+            last_day.format("%a %b %-d %Y")
+        )
+    }
+}
+
+fn entry(consult: AgendaConsult) -> AgendaEntry {
+    let local_time = |time: DateTime<Utc>| time.with_timezone(&consult_offset()).format("%H:%M").to_string();
+    let phones = std::iter::once(consult.client_primary_phone)
+        .chain(consult.client_mobile_phone.map(|mobile| format!("{} (mobile)", mobile)))
+        .filter(|phone| !phone.trim().is_empty())
+        .collect::<Vec<String>>()
+        .join(", ");
+    let address = [
+        Some(consult.location_address_one),
+        consult.location_address_two,
+        Some(format!("{}, {} {}", consult.location_city, consult.location_state.trim(), consult.location_zip)),
+    ]
+    .into_iter()
+    .flatten()
+    .filter(|line| !line.trim().is_empty())
+    .collect();
+    AgendaEntry {
+        slug: consult.slug,
+        start_time: local_time(consult.consult_start),
+        end_time: consult.consult_end.map(local_time),
+        purpose: consult.consult_purpose_name,
+        pending: consult.booking_status == "pending",
+        client_name: consult.client_name.unwrap_or_else(|| "Unnamed client".to_owned()),
+        phones,
+        client_email: consult.client_email,
+        location_name: consult.location_name,
+        address,
+        location_phone: consult.location_phone.filter(|phone| !phone.trim().is_empty()),
+        notes: consult.notes.filter(|notes| !notes.trim().is_empty()),
+    }
+}
+
+// Every day in the range gets a heading, so a free day reads as free rather than missing
+pub fn agenda_days(consults: Vec<AgendaConsult>, first_day: NaiveDate, last_day: NaiveDate) -> Vec<AgendaDay> {
+    let mut days = first_day
+        .iter_days()
+        .take_while(|date| *date <= last_day)
+        .map(|date| AgendaDay {
+            date,
+            label: date.format("%A, %B %-d").to_string(),
+            consults: vec![],
+        })
+        .collect::<Vec<AgendaDay>>();
+    for consult in consults {
+        let date = consult.consult_start.with_timezone(&consult_offset()).date_naive();
+        if let Some(day) = days.iter_mut().find(|day| day.date == date) {
+            day.consults.push(entry(consult));
+        }
+    }
+    days
+}
+
+pub fn agenda_pdf(title: &str, days: &[AgendaDay]) -> Vec<u8> {
+    let mut pdf = PdfText::new();
+    pdf.line(TextStyle::Title, title, 0.0);
+    for day in days {
+        pdf.gap(8.0);
+        pdf.line(TextStyle::Heading, &day.label, 0.0);
+        if day.consults.is_empty() {
+            pdf.line(TextStyle::Detail, "No consults", 12.0);
+        }
+        for consult in &day.consults {
+            let time = match &consult.end_time {
+                Some(end_time) => format!("{} - {}", consult.start_time, end_time),
+                None => consult.start_time.clone(),
+            };
+            let mut heading = format!("{}  {}", time, consult.client_name);
+            if let Some(purpose) = &consult.purpose {
+                heading.push_str(&format!(" - {}", purpose));
+            }
+            if consult.pending {
+                heading.push_str(" (pending)");
+            }
+            pdf.gap(4.0);
+            pdf.line(TextStyle::Body, &heading, 12.0);
+            pdf.line(TextStyle::Detail, &format!("{}, {}", consult.phones, consult.client_email), 24.0);
+            pdf.line(TextStyle::Detail, &consult.location_name, 24.0);
+            for line in &consult.address {
+                pdf.line(TextStyle::Detail, line, 24.0);
+            }
+            if let Some(phone) = &consult.location_phone {
+                pdf.line(TextStyle::Detail, &format!("Location phone: {}", phone), 24.0);
+            }
+            if let Some(notes) = &consult.notes {
+                pdf.line(TextStyle::Detail, &format!("Notes: {}", preview(notes)), 24.0);
+            }
+        }
+    }
+    pdf.finish()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::TimeZone;
+
+    fn date(d: u32) -> NaiveDate {
+        NaiveDate::from_ymd_opt(2023, 11, d).unwrap()
+    }
+
+    fn consult(id: i32, start: DateTime<Utc>) -> AgendaConsult {
+        AgendaConsult {
+            id,
+            slug: format!("consult-{}", id),
+            consult_start: start,
+            consult_end: Some(start + Duration::hours(1)),
+            consult_purpose_name: Some("Audit".to_string()),
+            booking_status: "confirmed".to_string(),
+            notes: Some(String::new()),
+            client_name: None,
+            client_primary_phone: "555-0100".to_string(),
+            client_mobile_phone: Some("555-0101".to_string()),
+            client_email: "client@example.com".to_string(),
+            location_name: "Office".to_string(),
+            location_address_one: "1 Main St".to_string(),
+            location_address_two: None,
+            location_city: "Austin".to_string(),
+            location_state: "TX".to_string(),
+            location_zip: "78701".to_string(),
+            location_phone: None,
+        }
+    }
+
+    #[test]
+    fn range_defaults_to_today_and_is_capped() {
+        assert_eq!(agenda_range(None, None, date(14)), Ok((date(14), date(14))));
+        assert_eq!(agenda_range(Some("2023-11-13"), Some(""), date(14)), Ok((date(13), date(13))));
+        assert_eq!(agenda_range(Some("2023-11-13"), Some("2023-11-19"), date(14)), Ok((date(13), date(19))));
+        assert!(agenda_range(Some("2023-11-19"), Some("2023-11-13"), date(14)).is_err());
+        assert!(agenda_range(Some("2023-11-01"), Some("2023-12-02"), date(14)).is_err());
+        assert!(agenda_range(Some("next week"), None, date(14)).is_err());
+    }
+
+    #[test]
+    fn consults_land_on_their_local_day() {
+        // 23:30 local on the 13th is already the 14th in UTC
+        let late = consult_offset().with_ymd_and_hms(2023, 11, 13, 23, 30, 0).unwrap().with_timezone(&Utc);
+        let morning = consult_offset().with_ymd_and_hms(2023, 11, 14, 9, 0, 0).unwrap().with_timezone(&Utc);
+        let days = agenda_days(vec![consult(1, late), consult(2, morning)], date(13), date(15));
+        assert_eq!(days.len(), 3);
+        assert_eq!(days[0].label, "Monday, November 13");
+        assert_eq!(days[0].consults[0].start_time, "23:30");
+        assert_eq!(days[1].consults[0].slug, "consult-2");
+        assert!(days[2].consults.is_empty());
+
+        let entry = &days[1].consults[0];
+        assert_eq!(entry.client_name, "Unnamed client");
+        assert_eq!(entry.phones, "555-0100, 555-0101 (mobile)");
+        assert_eq!(entry.address, vec!["1 Main St", "Austin, TX 78701"]);
+        assert_eq!(entry.notes, None);
+    }
+}
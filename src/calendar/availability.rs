@@ -0,0 +1,403 @@
+use std::collections::{BTreeMap, HashMap};
+
+use chrono::{DateTime, Datelike, Duration, NaiveDate, NaiveTime, Utc};
+use serde::{Deserialize, Serialize};
+use sqlx::{FromRow, Pool, Postgres, QueryBuilder};
+
+use crate::linfa::{consult_offset, meeting_time::day_bounds};
+
+// When consultants can be booked: weekly working hours and approved time off. Hours are local
+// (consult_offset) wall-clock times, one span per weekday. A consultant with no hours saved is treated as
+// working whenever the office is open, so hours only narrow things once someone fills them in. Time off
+// covers whole local days and only counts once an admin approves it.
+
+pub const PENDING: &str = "pending";
+pub const APPROVED: &str = "approved";
+pub const DENIED: &str = "denied";
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize, FromRow)]
+pub struct WorkingHours {
+    pub consultant_id: i32,
+    // 0 is Sunday, like Weekday::num_days_from_sunday
+    pub weekday: i16,
+    pub start_time: NaiveTime,
+    pub end_time: NaiveTime,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize, FromRow)]
+pub struct TimeOff {
+    pub time_off_id: i32,
+    pub consultant_id: i32,
+    pub consultant_name: String,
+    pub first_day: NaiveDate,
+    pub last_day: NaiveDate,
+    pub reason: Option<String>,
+    pub status: String,
+    pub created_at: DateTime<Utc>,
+    pub reviewed_at: Option<DateTime<Utc>>,
+}
+
+impl TimeOff {
+    pub fn covers(&self, date: NaiveDate) -> bool {
+        self.first_day <= date && date <= self.last_day
+    }
+
+    pub fn overlaps(&self, start: DateTime<Utc>, end: DateTime<Utc>) -> bool {
+        let (off_start, _) = day_bounds(self.first_day);
+        let (_, off_end) = day_bounds(self.last_day);
+        off_start < end && start < off_end
+    }
+}
+
+pub fn weekday_name(weekday: i16) -> &'static str {
+    match weekday {
+        0 => "Sunday",
+        1 => "Monday",
+        2 => "Tuesday",
+        3 => "Wednesday",
+        4 => "Thursday",
+        5 => "Friday",
+        _ => "Saturday",
+    }
+}
+
+// Working hours and approved time off for the consultants involved, loaded once per check
+#[derive(Debug, Clone, Default)]
+pub struct Availability {
+    pub hours: Vec<WorkingHours>,
+    pub time_off: Vec<TimeOff>,
+}
+
+impl Availability {
+    // Why the consultant can't take [start, end), if they can't
+    pub fn unavailable_reason(&self, consultant_id: i32, start: DateTime<Utc>, end: DateTime<Utc>) -> Option<String> {
+        if let Some(off) = self
+            .time_off
+            .iter()
+            .find(|off| off.consultant_id == consultant_id && off.status == APPROVED && off.overlaps(start, end))
+        {
+            return Some(format!(
+                "Consultant is off {} - {}",
+                off.first_day.format("%b %-d"),
+                off.last_day.format("%b %-d")
+            ));
+        }
+        let own_hours = self.hours.iter().filter(|hours| hours.consultant_id == consultant_id).collect::<Vec<_>>();
+        if own_hours.is_empty() {
+            return None;
+        }
+        let local_start = start.with_timezone(&consult_offset());
+        let local_end = end.with_timezone(&consult_offset());
+        let weekday = local_start.weekday().num_days_from_sunday() as i16;
+        let within = own_hours.iter().any(|hours| {
+            hours.weekday == weekday
+                && local_start.time() >= hours.start_time
+                && local_end.date_naive() == local_start.date_naive()
+                && local_end.time() <= hours.end_time
+        });
+        if within {
+            None
+        } else {
+            Some(match own_hours.iter().find(|hours| hours.weekday == weekday) {
+                Some(hours) => format!(
+                    "Consultant works {} - {} on {}s",
+                    hours.start_time.format("%H:%M"),
+                    hours.end_time.format("%H:%M"),
+                    weekday_name(weekday)
+                ),
+                None => format!("Consultant doesn't work {}s", weekday_name(weekday)),
+            })
+        }
+    }
+
+    pub fn is_available(&self, consultant_id: i32, start: DateTime<Utc>, end: DateTime<Utc>) -> bool {
+        self.unavailable_reason(consultant_id, start, end).is_none()
+    }
+
+    // What the calendar shows on each day: who's off, and the filtered consultant's hours
+    pub fn day_notes(&self, start: NaiveDate, end: NaiveDate, consultant_id: Option<i32>) -> BTreeMap<NaiveDate, Vec<String>> {
+        let mut notes: BTreeMap<NaiveDate, Vec<String>> = BTreeMap::new();
+        let mut day = start;
+        while day < end {
+            let mut day_notes = vec![];
+            if let Some(consultant_id) = consultant_id {
+                let own_hours = self.hours.iter().filter(|hours| hours.consultant_id == consultant_id).collect::<Vec<_>>();
+                if !own_hours.is_empty() {
+                    let weekday = day.weekday().num_days_from_sunday() as i16;
+                    day_notes.push(match own_hours.iter().find(|hours| hours.weekday == weekday) {
+                        Some(hours) => format!("Working {} - {}", hours.start_time.format("%H:%M"), hours.end_time.format("%H:%M")),
+                        None => "Not working".to_owned(),
+                    });
+                }
+            }
+            for off in self.time_off.iter().filter(|off| off.status == APPROVED && off.covers(day)) {
+                if consultant_id.is_none_or(|id| id == off.consultant_id) {
+                    day_notes.push(format!("{} off", off.consultant_name));
+                }
+            }
+            if !day_notes.is_empty() {
+                notes.insert(day, day_notes);
+            }
+            day += Duration::days(1);
+        }
+        notes
+    }
+}
+
+// One row per weekday on the form, start_{weekday} and end_{weekday}. Blank rows are days off.
+pub fn week_from_form(form: &HashMap<String, String>) -> Result<Vec<(i16, NaiveTime, NaiveTime)>, String> {
+    let field = |name: String| form.get(&name).map(|value| value.trim()).filter(|value| !value.is_empty());
+    let mut week = vec![];
+    for weekday in 0..7i16 {
+        let (start, end) = match (field(format!("start_{}", weekday)), field(format!("end_{}", weekday))) {
+            (None, None) => continue,
+            (Some(start), Some(end)) => (start, end),
+            _ => return Err(format!("{} needs both a start and an end", weekday_name(weekday))),
+        };
+        let parse = |value: &str| {
+            NaiveTime::parse_from_str(value, "%H:%M").map_err(|_| format!("{} has an invalid time", weekday_name(weekday)))
+        };
+        let (start, end) = (parse(start)?, parse(end)?);
+        if end <= start {
+            return Err(format!("{} has to end after it starts", weekday_name(weekday)));
+        }
+        week.push((weekday, start, end));
+    }
+    Ok(week)
+}
+
+const TIME_OFF_SELECT: &str = "SELECT time_off_id, consultant_time_off.consultant_id,
+        consultant_f_name || ' ' || consultant_l_name AS consultant_name,
+        first_day, last_day, reason, status, consultant_time_off.created_at, reviewed_at
+    FROM consultant_time_off
+    INNER JOIN consultants ON consultants.id = consultant_time_off.consultant_id";
+
+// Everyone's hours plus approved time off touching [start, end)
+pub async fn load_availability(
+    db: &Pool<Postgres>,
+    start: DateTime<Utc>,
+    end: DateTime<Utc>,
+) -> Result<Availability, sqlx::Error> {
+    let hours = sqlx::query_as::<_, WorkingHours>(
+        "SELECT consultant_id, weekday, start_time, end_time FROM consultant_working_hours ORDER BY consultant_id, weekday",
+    )
+    .fetch_all(db)
+    .await?;
+    // Padded a day each side since time off is in local days
+    let time_off = sqlx::query_as::<_, TimeOff>(&format!(
+        "{} WHERE status = $1 AND first_day <= $3 AND last_day >= $2 ORDER BY first_day",
+        TIME_OFF_SELECT
+    ))
+    .bind(APPROVED)
+    .bind(start.date_naive() - Duration::days(1))
+    .bind(end.date_naive() + Duration::days(1))
+    .fetch_all(db)
+    .await?;
+    Ok(Availability { hours, time_off })
+}
+
+// Notes for each calendar day in [start, end). The calendar still renders if they can't be loaded.
+pub async fn availability_in_range(
+    db: &Pool<Postgres>,
+    start: NaiveDate,
+    end: NaiveDate,
+    consultant_id: Option<i32>,
+) -> BTreeMap<NaiveDate, Vec<String>> {
+    match load_availability(db, day_bounds(start).0, day_bounds(end).0).await {
+        Ok(availability) => availability.day_notes(start, end, consultant_id),
+        Err(err) => {
+            println!("Error loading consultant availability: {}", err);
+            BTreeMap::new()
+        }
+    }
+}
+
+pub async fn working_hours_for(db: &Pool<Postgres>, consultant_id: i32) -> Result<Vec<WorkingHours>, sqlx::Error> {
+    sqlx::query_as::<_, WorkingHours>(
+        "SELECT consultant_id, weekday, start_time, end_time FROM consultant_working_hours
+            WHERE consultant_id = $1 ORDER BY weekday",
+    )
+    .bind(consultant_id)
+    .fetch_all(db)
+    .await
+}
+
+// Replaces the whole week. Days left out are days off.
+pub async fn set_working_hours(
+    db: &Pool<Postgres>,
+    consultant_id: i32,
+    week: &[(i16, NaiveTime, NaiveTime)],
+) -> Result<(), sqlx::Error> {
+    let mut tx = db.begin().await?;
+    sqlx::query("DELETE FROM consultant_working_hours WHERE consultant_id = $1")
+        .bind(consultant_id)
+        .execute(&mut *tx)
+        .await?;
+    if !week.is_empty() {
+        let mut query =
+            QueryBuilder::<Postgres>::new("INSERT INTO consultant_working_hours (consultant_id, weekday, start_time, end_time) ");
+        query.push_values(week, |mut row, (weekday, start_time, end_time)| {
+            row.push_bind(consultant_id).push_bind(*weekday).push_bind(*start_time).push_bind(*end_time);
+        });
+        query.build().execute(&mut *tx).await?;
+    }
+    tx.commit().await
+}
+
+pub async fn request_time_off(
+    db: &Pool<Postgres>,
+    consultant_id: i32,
+    first_day: NaiveDate,
+    last_day: NaiveDate,
+    reason: &str,
+    requested_by: Option<i32>,
+) -> Result<(), sqlx::Error> {
+    sqlx::query(
+        "INSERT INTO consultant_time_off (consultant_id, first_day, last_day, reason, requested_by)
+            VALUES ($1, $2, $3, NULLIF($4, ''), $5)",
+    )
+    .bind(consultant_id)
+    .bind(first_day)
+    .bind(last_day)
+    .bind(reason)
+    .bind(requested_by)
+    .execute(db)
+    .await
+    .map(|_| ())
+}
+
+// A consultant's requests that haven't ended yet
+pub async fn time_off_for(db: &Pool<Postgres>, consultant_id: i32, from: NaiveDate) -> Result<Vec<TimeOff>, sqlx::Error> {
+    sqlx::query_as::<_, TimeOff>(&format!(
+        "{} WHERE consultant_time_off.consultant_id = $1 AND last_day >= $2 ORDER BY first_day",
+        TIME_OFF_SELECT
+    ))
+    .bind(consultant_id)
+    .bind(from)
+    .fetch_all(db)
+    .await
+}
+
+// Pending requests first, then what's been decided for upcoming days
+pub async fn time_off_to_review(db: &Pool<Postgres>, from: NaiveDate) -> Result<Vec<TimeOff>, sqlx::Error> {
+    sqlx::query_as::<_, TimeOff>(&format!(
+        "{} WHERE status = $1 OR last_day >= $2 ORDER BY status = $1 DESC, first_day",
+        TIME_OFF_SELECT
+    ))
+    .bind(PENDING)
+    .bind(from)
+    .fetch_all(db)
+    .await
+}
+
+// Only pending requests can be decided, returns the consultant whose request it was
+pub async fn review_time_off(
+    db: &Pool<Postgres>,
+    time_off_id: i32,
+    approve: bool,
+    reviewed_by: i32,
+) -> Result<i32, sqlx::Error> {
+    sqlx::query_scalar::<_, i32>(
+        "UPDATE consultant_time_off SET status = $1, reviewed_by = $2, reviewed_at = NOW()
+            WHERE time_off_id = $3 AND status = $4
+            RETURNING consultant_id",
+    )
+    .bind(if approve { APPROVED } else { DENIED })
+    .bind(reviewed_by)
+    .bind(time_off_id)
+    .bind(PENDING)
+    .fetch_optional(db)
+    .await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::TimeZone;
+
+    fn local(y: i32, m: u32, d: u32, h: u32, min: u32) -> DateTime<Utc> {
+        consult_offset().with_ymd_and_hms(y, m, d, h, min, 0).unwrap().with_timezone(&Utc)
+    }
+
+    fn time(h: u32, m: u32) -> NaiveTime {
+        NaiveTime::from_hms_opt(h, m, 0).unwrap()
+    }
+
+    fn availability() -> Availability {
+        Availability {
+            hours: vec![
+                // Consultant 1 works Monday and Wednesday mornings
+                WorkingHours { consultant_id: 1, weekday: 1, start_time: time(8, 0), end_time: time(12, 0) },
+                WorkingHours { consultant_id: 1, weekday: 3, start_time: time(8, 0), end_time: time(12, 0) },
+            ],
+            time_off: vec![TimeOff {
+                time_off_id: 1,
+                consultant_id: 2,
+                consultant_name: "Pat Doe".to_string(),
+                first_day: NaiveDate::from_ymd_opt(2023, 11, 22).unwrap(),
+                last_day: NaiveDate::from_ymd_opt(2023, 11, 24).unwrap(),
+                reason: None,
+                status: APPROVED.to_string(),
+                created_at: local(2023, 11, 1, 9, 0),
+                reviewed_at: None,
+            }],
+        }
+    }
+
+    #[test]
+    fn hours_narrow_when_set() {
+        let availability = availability();
+        // Monday November 20th 2023
+        assert!(availability.is_available(1, local(2023, 11, 20, 9, 0), local(2023, 11, 20, 10, 0)));
+        assert_eq!(
+            availability.unavailable_reason(1, local(2023, 11, 20, 11, 30), local(2023, 11, 20, 12, 30)).as_deref(),
+            Some("Consultant works 08:00 - 12:00 on Mondays")
+        );
+        assert_eq!(
+            availability.unavailable_reason(1, local(2023, 11, 21, 9, 0), local(2023, 11, 21, 10, 0)).as_deref(),
+            Some("Consultant doesn't work Tuesdays")
+        );
+        // No hours saved, no restriction
+        assert!(availability.is_available(3, local(2023, 11, 21, 16, 0), local(2023, 11, 21, 17, 0)));
+    }
+
+    #[test]
+    fn week_form_skips_blank_days() {
+        let form = HashMap::from([
+            ("start_1".to_string(), "08:00".to_string()),
+            ("end_1".to_string(), "16:30".to_string()),
+            ("start_2".to_string(), " ".to_string()),
+            ("end_2".to_string(), "".to_string()),
+        ]);
+        assert_eq!(week_from_form(&form), Ok(vec![(1, time(8, 0), time(16, 30))]));
+        let form = HashMap::from([("start_5".to_string(), "09:00".to_string())]);
+        assert_eq!(week_from_form(&form), Err("Friday needs both a start and an end".to_string()));
+        let form = HashMap::from([("start_0".to_string(), "12:00".to_string()), ("end_0".to_string(), "09:00".to_string())]);
+        assert_eq!(week_from_form(&form), Err("Sunday has to end after it starts".to_string()));
+    }
+
+    #[test]
+    fn approved_time_off_blocks_whole_local_days() {
+        let mut availability = availability();
+        assert!(!availability.is_available(2, local(2023, 11, 24, 23, 0), local(2023, 11, 24, 23, 30)));
+        assert!(availability.is_available(2, local(2023, 11, 25, 0, 0), local(2023, 11, 25, 1, 0)));
+        let notes = availability.day_notes(
+            NaiveDate::from_ymd_opt(2023, 11, 19).unwrap(),
+            NaiveDate::from_ymd_opt(2023, 11, 26).unwrap(),
+            None,
+        );
+        assert_eq!(notes.len(), 3);
+        assert_eq!(notes[&NaiveDate::from_ymd_opt(2023, 11, 22).unwrap()], vec!["Pat Doe off".to_string()]);
+        let notes = availability.day_notes(
+            NaiveDate::from_ymd_opt(2023, 11, 20).unwrap(),
+            NaiveDate::from_ymd_opt(2023, 11, 22).unwrap(),
+            Some(1),
+        );
+        assert_eq!(notes[&NaiveDate::from_ymd_opt(2023, 11, 20).unwrap()], vec!["Working 08:00 - 12:00".to_string()]);
+        assert_eq!(notes[&NaiveDate::from_ymd_opt(2023, 11, 21).unwrap()], vec!["Not working".to_string()]);
+
+        // Pending requests don't count yet
+        availability.time_off[0].status = PENDING.to_string();
+        assert!(availability.is_available(2, local(2023, 11, 23, 9, 0), local(2023, 11, 23, 10, 0)));
+    }
+}
@@ -0,0 +1,363 @@
+use chrono::{DateTime, Duration, NaiveDate, Utc};
+use deadpool_redis::Pool as RedisPool;
+use serde::{Deserialize, Serialize};
+use sqlx::{FromRow, Pool, Postgres};
+use uuid::Uuid;
+
+use crate::linfa::{
+    consult_offset,
+    meeting_time::{day_bounds, load_busy_blocks, within_business_hours, BusyBlock},
+};
+use crate::redis_mod::typed::{self, RedisModel};
+
+use super::{
+    availability::{load_availability, Availability},
+    holidays::holidays_in_range,
+};
+
+// Self-service booking: a tokenized link per client or per consultant opens a public page where the client
+// picks a purpose, a location and an open slot. What they pick is saved as a pending consult that holds the
+// slot until staff confirm or decline it. On a consultant link the client first confirms from a link
+// emailed to the address on file, so knowing a client's email isn't enough to book as them.
+
+// Entity types a booking link can be for
+pub const CONSULTANT_LINK: i32 = 4;
+pub const CLIENT_LINK: i32 = 7;
+// Self-booked consults are an hour, on the hour
+pub const BOOKING_MINUTES: i64 = 60;
+// How far ahead the page lets clients book
+pub const BOOKING_DAYS_AHEAD: i64 = 60;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum BookingStatus {
+    Pending,
+    Confirmed,
+    Declined,
+}
+
+impl BookingStatus {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            BookingStatus::Pending => "pending",
+            BookingStatus::Confirmed => "confirmed",
+            BookingStatus::Declined => "declined",
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize, FromRow)]
+pub struct BookingLink {
+    pub link_id: i32,
+    pub token: String,
+    pub entity_type_id: i32,
+    pub entity_id: i32,
+    pub link_name: String,
+    pub created_at: DateTime<Utc>,
+}
+
+const LINK_SELECT: &str = "SELECT link_id, token, entity_type_id, entity_id, created_at,
+        COALESCE(CASE entity_type_id
+            WHEN 4 THEN (SELECT consultant_f_name || ' ' || consultant_l_name FROM consultants WHERE consultants.id = entity_id)
+            ELSE (SELECT COALESCE(client_company_name, client_f_name || ' ' || client_l_name) FROM clients WHERE clients.id = entity_id)
+        END, 'Consults') AS link_name
+    FROM booking_links";
+
+pub fn is_link_type(entity_type_id: i32) -> bool {
+    matches!(entity_type_id, CONSULTANT_LINK | CLIENT_LINK)
+}
+
+pub async fn link_by_token(db: &Pool<Postgres>, token: &str) -> Result<Option<BookingLink>, sqlx::Error> {
+    sqlx::query_as::<_, BookingLink>(&format!("{} WHERE token = $1 AND revoked_at IS NULL", LINK_SELECT))
+        .bind(token)
+        .fetch_optional(db)
+        .await
+}
+
+pub async fn booking_links(db: &Pool<Postgres>) -> Result<Vec<BookingLink>, sqlx::Error> {
+    sqlx::query_as::<_, BookingLink>(&format!(
+        "{} WHERE revoked_at IS NULL ORDER BY entity_type_id, created_at",
+        LINK_SELECT
+    ))
+    .fetch_all(db)
+    .await
+}
+
+// One live link per client or consultant, asking again keeps the existing URL.
+pub async fn create_link(
+    db: &Pool<Postgres>,
+    entity_type_id: i32,
+    entity_id: i32,
+    created_by: i32,
+) -> Result<(), sqlx::Error> {
+    sqlx::query(
+        "INSERT INTO booking_links (token, entity_type_id, entity_id, created_by) VALUES ($1, $2, $3, $4)
+            ON CONFLICT (entity_type_id, entity_id) WHERE revoked_at IS NULL DO NOTHING",
+    )
+    .bind(Uuid::new_v4().simple().to_string())
+    .bind(entity_type_id)
+    .bind(entity_id)
+    .bind(created_by)
+    .execute(db)
+    .await
+    .map(|_| ())
+}
+
+pub async fn revoke_link(db: &Pool<Postgres>, link_id: i32) -> Result<u64, sqlx::Error> {
+    sqlx::query("UPDATE booking_links SET revoked_at = NOW() WHERE link_id = $1 AND revoked_at IS NULL")
+        .bind(link_id)
+        .execute(db)
+        .await
+        .map(|result| result.rows_affected())
+}
+
+// Consultant links are shared with many clients, who say who they are by the email we have on file
+pub async fn client_id_by_email(db: &Pool<Postgres>, email: &str) -> Result<Option<i32>, sqlx::Error> {
+    sqlx::query_scalar::<_, i32>("SELECT id FROM clients WHERE LOWER(client_email) = LOWER($1) ORDER BY id LIMIT 1")
+        .bind(email.trim())
+        .fetch_optional(db)
+        .await
+}
+
+// What the booking form asked for. Re-checked against open slots whenever it's placed.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct BookingRequest {
+    pub link_token: String,
+    pub client_id: i32,
+    pub consult_purpose_id: i32,
+    pub location_id: i32,
+    pub date: NaiveDate,
+    pub start_time: String,
+    pub num_attendees: i32,
+    pub notes: String,
+}
+
+// Held while the client confirms it, keyed by the token in the emailed link
+impl RedisModel for BookingRequest {
+    const NAMESPACE: &'static str = "booking_request";
+    const VERSION: u32 = 1;
+}
+
+pub const CONFIRM_WITHIN_SECS: usize = 60 * 60;
+
+// Returns the confirmation token
+pub async fn hold_request(r_pool: &RedisPool, request: &BookingRequest) -> Result<String, String> {
+    let mut con = r_pool.get().await.map_err(|e| format!("Error in Redis {}", e))?;
+    let token = Uuid::new_v4().simple().to_string();
+    typed::set_ex(&mut con, &token, request, CONFIRM_WITHIN_SECS)
+        .await
+        .map_err(|e| format!("Error in Redis {}", e))?;
+    Ok(token)
+}
+
+// Each confirmation link works once. None when it expired or was already used.
+pub async fn take_request(r_pool: &RedisPool, token: &str) -> Result<Option<BookingRequest>, String> {
+    let mut con = r_pool.get().await.map_err(|e| format!("Error in Redis {}", e))?;
+    let request: Option<BookingRequest> = typed::get(&mut con, token).await.map_err(|e| format!("Error in Redis {}", e))?;
+    // Only whoever wins the DEL places it
+    let deleted = typed::del::<BookingRequest, _>(&mut con, token)
+        .await
+        .map_err(|e| format!("Error in Redis {}", e))?;
+    Ok(request.filter(|_| deleted))
+}
+
+pub async fn client_email(db: &Pool<Postgres>, client_id: i32) -> Result<String, sqlx::Error> {
+    sqlx::query_scalar::<_, String>("SELECT client_email FROM clients WHERE id = $1")
+        .bind(client_id)
+        .fetch_one(db)
+        .await
+}
+
+pub async fn consultant_ids(db: &Pool<Postgres>) -> Result<Vec<i32>, sqlx::Error> {
+    sqlx::query_scalar::<_, i32>("SELECT id FROM consultants ORDER BY id")
+        .fetch_all(db)
+        .await
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct OpenSlot {
+    // Local wall-clock times, what the client picks from
+    pub start_time: String,
+    pub end_time: String,
+    // Who could take it, in the order given
+    pub consultant_ids: Vec<i32>,
+}
+
+// Hourly slots on `date` within business hours, after `now`, that at least one of `consultant_ids` is
+// working, not off and not otherwise booked for.
+pub fn open_slots(
+    date: NaiveDate,
+    now: DateTime<Utc>,
+    consultant_ids: &[i32],
+    availability: &Availability,
+    busy: &[BusyBlock],
+) -> Vec<OpenSlot> {
+    let (day_start, _) = day_bounds(date);
+    (0..24)
+        .filter_map(|hour| {
+            let start = day_start + Duration::hours(hour);
+            let end = start + Duration::minutes(BOOKING_MINUTES);
+            if start <= now || !within_business_hours(start, end) {
+                return None;
+            }
+            let free = consultant_ids
+                .iter()
+                .copied()
+                .filter(|id| availability.is_available(*id, start, end))
+                .filter(|id| !busy.iter().any(|b| b.consultant_id == *id && b.overlaps(start, end)))
+                .collect::<Vec<i32>>();
+            if free.is_empty() {
+                return None;
+            }
+            Some(OpenSlot {
+                start_time: start.with_timezone(&consult_offset()).format("%H:%M").to_string(),
+                end_time: end.with_timezone(&consult_offset()).format("%H:%M").to_string(),
+                consultant_ids: free,
+            })
+        })
+        .collect()
+}
+
+// Nothing is open on holidays, company closures, or outside the booking window
+pub async fn slots_for(
+    db: &Pool<Postgres>,
+    date: NaiveDate,
+    now: DateTime<Utc>,
+    consultant_ids: &[i32],
+) -> Result<Vec<OpenSlot>, String> {
+    let today = now.with_timezone(&consult_offset()).date_naive();
+    if date < today || date > today + Duration::days(BOOKING_DAYS_AHEAD) {
+        return Ok(vec![]);
+    }
+    if !holidays_in_range(db, date, date + Duration::days(1)).await.is_empty() {
+        return Ok(vec![]);
+    }
+    let (day_start, day_end) = day_bounds(date);
+    let availability = load_availability(db, day_start, day_end)
+        .await
+        .map_err(|e| format!("Error in DB {}", e))?;
+    let busy = load_busy_blocks(db, date).await?;
+    Ok(open_slots(date, now, consultant_ids, &availability, &busy))
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, FromRow)]
+pub struct PendingConsult {
+    pub id: i32,
+    pub slug: String,
+    pub consult_start: DateTime<Utc>,
+    pub consult_end: Option<DateTime<Utc>>,
+    pub client_name: Option<String>,
+    pub client_email: String,
+    pub consultant_name: Option<String>,
+    pub location_name: String,
+    pub notes: Option<String>,
+}
+
+// Upcoming requests waiting on staff, soonest first
+pub async fn pending_consults(db: &Pool<Postgres>) -> Result<Vec<PendingConsult>, sqlx::Error> {
+    sqlx::query_as::<_, PendingConsult>(
+        "SELECT consults.id, consults.slug, consult_start, consult_end, notes, client_email, location_name,
+                consultant_f_name || ' ' || consultant_l_name AS consultant_name,
+                COALESCE(client_company_name, client_f_name || ' ' || client_l_name) AS client_name
+            FROM consults
+            INNER JOIN clients ON clients.id = consults.client_id
+            INNER JOIN locations ON locations.id = consults.location_id
+            LEFT JOIN consultants ON consultants.id = consults.consultant_id
+            WHERE booking_status = $1 AND cancelled_at IS NULL AND consult_start > NOW()
+            ORDER BY consult_start",
+    )
+    .bind(BookingStatus::Pending.as_str())
+    .fetch_all(db)
+    .await
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, FromRow)]
+pub struct DecidedBooking {
+    pub id: i32,
+    pub client_id: i32,
+    pub location_id: i32,
+    pub consultant_id: Option<i32>,
+}
+
+// Only pending requests can be decided. Declining also cancels, which frees the slot everywhere that
+// already skips cancelled consults.
+pub async fn decide_booking(
+    db: &Pool<Postgres>,
+    consult_id: i32,
+    decision: BookingStatus,
+) -> Result<Option<DecidedBooking>, sqlx::Error> {
+    sqlx::query_as::<_, DecidedBooking>(
+        "UPDATE consults
+            SET booking_status = $1,
+                cancelled_at = CASE WHEN $1 = $2 THEN NOW() ELSE cancelled_at END,
+                updated_at = NOW()
+            WHERE id = $3 AND booking_status = $4 AND cancelled_at IS NULL
+            RETURNING id, client_id, location_id, consultant_id",
+    )
+    .bind(decision.as_str())
+    .bind(BookingStatus::Declined.as_str())
+    .bind(consult_id)
+    .bind(BookingStatus::Pending.as_str())
+    .fetch_optional(db)
+    .await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::calendar::availability::{TimeOff, WorkingHours, APPROVED};
+    use chrono::{NaiveTime, TimeZone};
+
+    fn date() -> NaiveDate {
+        // A Tuesday
+        NaiveDate::from_ymd_opt(2023, 11, 14).unwrap()
+    }
+
+    fn local(h: u32) -> DateTime<Utc> {
+        consult_offset().with_ymd_and_hms(2023, 11, 14, h, 0, 0).unwrap().with_timezone(&Utc)
+    }
+
+    fn starts(slots: &[OpenSlot]) -> Vec<&str> {
+        slots.iter().map(|slot| slot.start_time.as_str()).collect()
+    }
+
+    #[test]
+    fn slots_follow_business_hours_and_skip_the_past() {
+        let slots = open_slots(date(), local(0), &[1], &Availability::default(), &[]);
+        assert_eq!(starts(&slots), vec!["08:00", "09:00", "10:00", "11:00", "12:00", "13:00", "14:00", "15:00", "16:00", "17:00"]);
+        assert_eq!(slots[0].end_time, "09:00");
+        let slots = open_slots(date(), local(15), &[1], &Availability::default(), &[]);
+        assert_eq!(starts(&slots), vec!["16:00", "17:00"]);
+    }
+
+    #[test]
+    fn slots_need_a_free_consultant() {
+        let availability = Availability {
+            hours: vec![WorkingHours {
+                consultant_id: 1,
+                weekday: 2,
+                start_time: NaiveTime::from_hms_opt(9, 0, 0).unwrap(),
+                end_time: NaiveTime::from_hms_opt(12, 0, 0).unwrap(),
+            }],
+            time_off: vec![TimeOff {
+                time_off_id: 1,
+                consultant_id: 2,
+                consultant_name: "Pat Doe".to_string(),
+                first_day: date(),
+                last_day: date(),
+                reason: None,
+                status: APPROVED.to_string(),
+                created_at: local(0),
+                reviewed_at: None,
+            }],
+        };
+        let busy = vec![BusyBlock { consultant_id: 1, busy_start: local(10), busy_end: local(11) }];
+        let slots = open_slots(date(), local(0), &[1, 2], &availability, &busy);
+        assert_eq!(starts(&slots), vec!["09:00", "11:00"]);
+        assert_eq!(slots[0].consultant_ids, vec![1]);
+        // Consultant 3 has no hours set, so is free all day
+        let slots = open_slots(date(), local(0), &[1, 3], &availability, &busy);
+        assert_eq!(slots[2].start_time, "10:00");
+        assert_eq!(slots[2].consultant_ids, vec![3]);
+        assert_eq!(slots[1].consultant_ids, vec![1, 3]);
+    }
+}
@@ -0,0 +1,453 @@
+use chrono::{DateTime, Duration, Utc};
+use ics::components::Property;
+use ics::parameters;
+use ics::properties::{
+    Attendee, Categories, Description, DtEnd, DtStart, LastModified, Location, Method, Organizer, Sequence, Status,
+    Summary,
+};
+use ics::{escape_text, Event, ICalendar};
+use serde::{Deserialize, Serialize};
+use sqlx::{FromRow, Pool, Postgres, QueryBuilder};
+use uuid::Uuid;
+
+use crate::linfa::consult_offset;
+
+use super::booking::BookingStatus;
+
+// iCalendar output for consults: single-consult .ics downloads and tokenized feeds calendar apps poll.
+// A consult's UID comes from its slug so every export and feed refers to the same event, and
+// ics_sequence is bumped on each reschedule or cancellation so clients replace their copy instead of
+// keeping a stale one. Cancelled consults stay in feeds with STATUS:CANCELLED so subscribers drop them,
+// and booking requests still waiting on the consultant go out as STATUS:TENTATIVE.
+
+pub const PRODID: &str = "-//Consults//Consult Calendar 1.0//EN";
+pub const UID_DOMAIN: &str = "domain.tld";
+// Used when a consult has no consultant yet
+pub const ORGANIZER_EMAIL: &str = "nobody@domain.tld";
+// Feeds include this much history so just-finished consults don't vanish from phones
+const FEED_PAST_DAYS: i64 = 30;
+
+// Entity types a feed can follow. A user feed follows everything the user subscribes to.
+pub const USER_FEED: i32 = 1;
+pub const CONSULTANT_FEED: i32 = 4;
+pub const LOCATION_FEED: i32 = 5;
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize, FromRow)]
+pub struct IcsConsult {
+    pub id: i32,
+    pub slug: String,
+    pub consult_purpose_id: i32,
+    pub consult_start: DateTime<Utc>,
+    pub consult_end: Option<DateTime<Utc>>,
+    pub notes: Option<String>,
+    pub ics_sequence: i32,
+    pub cancelled_at: Option<DateTime<Utc>>,
+    pub booking_status: String,
+    pub updated_at: DateTime<Utc>,
+    pub client_name: Option<String>,
+    pub client_email: String,
+    pub consultant_name: Option<String>,
+    pub consultant_email: Option<String>,
+    pub location_name: String,
+    pub location_address_one: String,
+    pub location_city: String,
+    pub location_state: String,
+    pub location_zip: String,
+}
+
+const ICS_CONSULT_SELECT: &str = "SELECT consults.id, consults.slug, consult_purpose_id, consult_start, consult_end,
+        consults.notes, ics_sequence, cancelled_at, booking_status, consults.updated_at,
+        COALESCE(client_company_name, client_f_name || ' ' || client_l_name) AS client_name, client_email,
+        consultant_f_name || ' ' || consultant_l_name AS consultant_name, users.email AS consultant_email,
+        location_name, location_address_one, location_city, location_state, location_zip
+    FROM consults
+    INNER JOIN clients ON clients.id = consults.client_id
+    INNER JOIN locations ON locations.id = consults.location_id
+    LEFT JOIN consultants ON consultants.id = consults.consultant_id
+    LEFT JOIN users ON users.id = consultants.user_id";
+
+pub fn ics_datetime(date: &DateTime<Utc>) -> String {
+    date.format("%Y%m%dT%H%M%SZ").to_string()
+}
+
+pub fn consult_uid(slug: &str) -> String {
+    format!("consult-{}@{}", slug, UID_DOMAIN)
+}
+
+pub fn category_from_purpose(id: i32) -> &'static str {
+    match id {
+        1 => "INTRODUCTION",
+        2 => "WALKTHROUGH/INIT",
+        3 => "CONTINUED",
+        4 => "FINAL SERVICE",
+        5 => "AUDIT",
+        _ => "CONSULT",
+    }
+}
+
+// Parameter values with separators have to be quoted
+fn param_value(value: &str) -> String {
+    if value.contains([',', ';', ':']) {
+        format!("\"{}\"", value.replace('"', "'"))
+    } else {
+        value.to_owned()
+    }
+}
+
+fn attendee(email: &str, name: Option<&str>, role: &'static str) -> Attendee<'static> {
+    let mut attendee = Attendee::new(format!("mailto:{}", email));
+    attendee.append(parameters!("ROLE" => role; "PARTSTAT" => "NEEDS-ACTION"));
+    if let Some(name) = name {
+        attendee.append(parameters!("CN" => param_value(name)));
+    }
+    attendee
+}
+
+impl IcsConsult {
+    pub fn consult_end_or_default(&self) -> DateTime<Utc> {
+        self.consult_end.unwrap_or(self.consult_start + Duration::hours(1))
+    }
+
+    pub fn summary(&self) -> String {
+        format!(
+            "{} consult: {}",
+            category_from_purpose(self.consult_purpose_id),
+            self.client_name.as_deref().unwrap_or("Client")
+        )
+    }
+
+    pub fn location(&self) -> String {
+        format!(
+            "{}, {}, {}, {} {}",
+            self.location_name, self.location_address_one, self.location_city, self.location_state.trim(), self.location_zip
+        )
+    }
+}
+
+pub fn consult_event(consult: &IcsConsult, now: DateTime<Utc>) -> Event<'static> {
+    let mut event = Event::new(consult_uid(&consult.slug), ics_datetime(&now));
+    event.push(DtStart::new(ics_datetime(&consult.consult_start)));
+    event.push(DtEnd::new(ics_datetime(&consult.consult_end_or_default())));
+    event.push(Sequence::new(consult.ics_sequence.to_string()));
+    event.push(LastModified::new(ics_datetime(&consult.updated_at)));
+    event.push(if consult.cancelled_at.is_some() {
+        Status::cancelled()
+    } else if consult.booking_status == BookingStatus::Pending.as_str() {
+        Status::tentative()
+    } else {
+        Status::confirmed()
+    });
+    event.push(Summary::new(escape_text(consult.summary())));
+    event.push(Location::new(escape_text(consult.location())));
+    event.push(Categories::new(category_from_purpose(consult.consult_purpose_id)));
+    if let Some(notes) = consult.notes.as_deref().filter(|notes| !notes.is_empty()) {
+        event.push(Description::new(escape_text(notes.to_owned())));
+    }
+
+    // Sent on the consultant's behalf, replies still reach them
+    let organizer = match &consult.consultant_email {
+        Some(email) => {
+            let mut organizer = Organizer::new(format!("mailto:{}", email));
+            organizer.append(parameters!("SENT-BY" => format!("\"mailto:{}\"", ORGANIZER_EMAIL)));
+            if let Some(name) = &consult.consultant_name {
+                organizer.append(parameters!("CN" => param_value(name)));
+            }
+            organizer
+        }
+        None => Organizer::new(format!("mailto:{}", ORGANIZER_EMAIL)),
+    };
+    event.push(organizer);
+    event.push(attendee(&consult.client_email, consult.client_name.as_deref(), "REQ-PARTICIPANT"));
+    if let Some(email) = &consult.consultant_email {
+        event.push(attendee(email, consult.consultant_name.as_deref(), "CHAIR"));
+    }
+    event
+}
+
+// METHOD:PUBLISH, these are read-only copies rather than invitations.
+pub fn consult_calendar(consults: &[IcsConsult], name: &str, now: DateTime<Utc>) -> ICalendar<'static> {
+    let mut calendar = ICalendar::new("2.0", PRODID);
+    calendar.push(Method::new("PUBLISH"));
+    calendar.push(Property::new("X-WR-CALNAME", escape_text(name.to_owned())));
+    // How often subscribers should poll
+    calendar.push(Property::new("X-PUBLISHED-TTL", "PT1H"));
+    for consult in consults {
+        calendar.add_event(consult_event(consult, now));
+    }
+    calendar
+}
+
+// iTIP methods for the invitation emails
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub enum ItipMethod {
+    Request,
+    Cancel,
+}
+
+impl ItipMethod {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            ItipMethod::Request => "REQUEST",
+            ItipMethod::Cancel => "CANCEL",
+        }
+    }
+}
+
+// METHOD:REQUEST invites, re-sent with the bumped SEQUENCE on reschedule. METHOD:CANCEL withdraws the
+// invite, the event already carries STATUS:CANCELLED by then.
+pub fn itip_calendar(consult: &IcsConsult, method: ItipMethod, now: DateTime<Utc>) -> ICalendar<'static> {
+    let mut calendar = ICalendar::new("2.0", PRODID);
+    calendar.push(Method::new(method.as_str()));
+    calendar.add_event(consult_event(consult, now));
+    calendar
+}
+
+// Everyone on the event: the client contact and the consultant, when there is one
+pub fn invite_recipients(consult: &IcsConsult) -> Vec<&str> {
+    let mut recipients = vec![consult.client_email.as_str()];
+    if let Some(email) = consult.consultant_email.as_deref().filter(|email| *email != consult.client_email) {
+        recipients.push(email);
+    }
+    recipients
+}
+
+pub fn invite_subject(consult: &IcsConsult, method: ItipMethod) -> String {
+    match method {
+        ItipMethod::Cancel => format!("Cancelled: {}", consult.summary()),
+        ItipMethod::Request if consult.ics_sequence > 0 => format!("Updated: {}", consult.summary()),
+        ItipMethod::Request => format!("Invitation: {}", consult.summary()),
+    }
+}
+
+// Plain text part for mail clients that don't show invites
+pub fn invite_text(consult: &IcsConsult, method: ItipMethod) -> String {
+    let start = consult.consult_start.with_timezone(&consult_offset());
+    let end = consult.consult_end_or_default().with_timezone(&consult_offset());
+    let when = format!("{} - {}", start.format("%A, %B %-d %Y %-I:%M %p"), end.format("%-I:%M %p"));
+    match method {
+        ItipMethod::Cancel => format!("{} on {} has been cancelled.", consult.summary(), when),
+        ItipMethod::Request => format!("{}\nWhen: {}\nWhere: {}", consult.summary(), when, consult.location()),
+    }
+}
+
+pub async fn ics_consult_by_slug(db: &Pool<Postgres>, slug: &str) -> Result<IcsConsult, sqlx::Error> {
+    sqlx::query_as::<_, IcsConsult>(&format!("{} WHERE consults.slug = $1", ICS_CONSULT_SELECT))
+        .bind(slug)
+        .fetch_one(db)
+        .await
+}
+
+pub async fn ics_consult_by_id(db: &Pool<Postgres>, consult_id: i32) -> Result<IcsConsult, sqlx::Error> {
+    sqlx::query_as::<_, IcsConsult>(&format!("{} WHERE consults.id = $1", ICS_CONSULT_SELECT))
+        .bind(consult_id)
+        .fetch_one(db)
+        .await
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize, FromRow)]
+pub struct CalendarFeed {
+    pub feed_id: i32,
+    pub token: String,
+    pub user_id: i32,
+    pub entity_type_id: i32,
+    pub entity_id: i32,
+    pub feed_name: String,
+    pub created_at: DateTime<Utc>,
+}
+
+const FEED_SELECT: &str = "SELECT feed_id, token, user_id, entity_type_id, entity_id, created_at,
+        COALESCE(CASE entity_type_id
+            WHEN 4 THEN (SELECT consultant_f_name || ' ' || consultant_l_name FROM consultants WHERE consultants.id = entity_id)
+            WHEN 5 THEN (SELECT location_name FROM locations WHERE locations.id = entity_id)
+            ELSE (SELECT username || '''s consults' FROM users WHERE users.id = entity_id)
+        END, 'Consults') AS feed_name
+    FROM calendar_feeds";
+
+pub fn is_feed_type(entity_type_id: i32) -> bool {
+    matches!(entity_type_id, USER_FEED | CONSULTANT_FEED | LOCATION_FEED)
+}
+
+pub async fn feed_by_token(db: &Pool<Postgres>, token: &str) -> Result<Option<CalendarFeed>, sqlx::Error> {
+    sqlx::query_as::<_, CalendarFeed>(&format!("{} WHERE token = $1 AND revoked_at IS NULL", FEED_SELECT))
+        .bind(token)
+        .fetch_optional(db)
+        .await
+}
+
+pub async fn user_feeds(db: &Pool<Postgres>, user_id: i32) -> Result<Vec<CalendarFeed>, sqlx::Error> {
+    sqlx::query_as::<_, CalendarFeed>(&format!(
+        "{} WHERE user_id = $1 AND revoked_at IS NULL ORDER BY created_at",
+        FEED_SELECT
+    ))
+    .bind(user_id)
+    .fetch_all(db)
+    .await
+}
+
+// A user has at most one live feed per entity, asking again returns the same URL.
+pub async fn create_feed(
+    db: &Pool<Postgres>,
+    user_id: i32,
+    entity_type_id: i32,
+    entity_id: i32,
+) -> Result<(), sqlx::Error> {
+    sqlx::query(
+        "INSERT INTO calendar_feeds (token, user_id, entity_type_id, entity_id) VALUES ($1, $2, $3, $4)
+            ON CONFLICT (user_id, entity_type_id, entity_id) WHERE revoked_at IS NULL DO NOTHING",
+    )
+    .bind(Uuid::new_v4().simple().to_string())
+    .bind(user_id)
+    .bind(entity_type_id)
+    .bind(entity_id)
+    .execute(db)
+    .await
+    .map(|_| ())
+}
+
+// Scoped to the owner. The old URL stops working, a new feed gets a fresh token.
+pub async fn revoke_feed(db: &Pool<Postgres>, feed_id: i32, user_id: i32) -> Result<u64, sqlx::Error> {
+    sqlx::query("UPDATE calendar_feeds SET revoked_at = NOW() WHERE feed_id = $1 AND user_id = $2 AND revoked_at IS NULL")
+        .bind(feed_id)
+        .bind(user_id)
+        .execute(db)
+        .await
+        .map(|result| result.rows_affected())
+}
+
+pub async fn feed_consults(
+    db: &Pool<Postgres>,
+    feed: &CalendarFeed,
+    now: DateTime<Utc>,
+) -> Result<Vec<IcsConsult>, sqlx::Error> {
+    let mut query = QueryBuilder::<Postgres>::new(ICS_CONSULT_SELECT);
+    query.push(" WHERE consult_start >= ");
+    query.push_bind(now - Duration::days(FEED_PAST_DAYS));
+    match feed.entity_type_id {
+        CONSULTANT_FEED => {
+            query.push(" AND consults.consultant_id = ").push_bind(feed.entity_id);
+        }
+        LOCATION_FEED => {
+            query.push(" AND consults.location_id = ").push_bind(feed.entity_id);
+        }
+        _ => {
+            // The user's own consults as a consultant, plus anything they subscribe to
+            query.push(" AND (consultants.user_id = ").push_bind(feed.entity_id);
+            query.push(
+                " OR EXISTS (SELECT 1 FROM users subscriber WHERE subscriber.id = ",
+            );
+            query.push_bind(feed.entity_id);
+            query.push(
+                " AND (consults.id = ANY(subscriber.consult_subs)
+                    OR consults.client_id = ANY(subscriber.client_subs)
+                    OR consults.consultant_id = ANY(subscriber.consultant_subs)
+                    OR consults.location_id = ANY(subscriber.location_subs))))",
+            );
+        }
+    }
+    query.push(" ORDER BY consult_start");
+    query.build_query_as::<IcsConsult>().fetch_all(db).await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::TimeZone;
+
+    fn consult() -> IcsConsult {
+        let start = Utc.with_ymd_and_hms(2023, 11, 28, 15, 0, 0).unwrap();
+        IcsConsult {
+            id: 4,
+            slug: "0b7c".to_string(),
+            consult_purpose_id: 2,
+            consult_start: start,
+            consult_end: None,
+            notes: Some("Bring plans; two copies".to_string()),
+            ics_sequence: 2,
+            cancelled_at: None,
+            booking_status: "confirmed".to_string(),
+            updated_at: start - Duration::days(1),
+            client_name: Some("Acme, Inc".to_string()),
+            client_email: "ops@acme.test".to_string(),
+            consultant_name: Some("Pat Doe".to_string()),
+            consultant_email: Some("pat@domain.tld".to_string()),
+            location_name: "HQ".to_string(),
+            location_address_one: "1 Main St".to_string(),
+            location_city: "Austin".to_string(),
+            location_state: "TX".to_string(),
+            location_zip: "78701".to_string(),
+        }
+    }
+
+    // Undoes line folding so long lines can be matched whole
+    fn unfold(ics: String) -> String {
+        ics.replace("\r\n ", "")
+    }
+
+    #[test]
+    fn events_keep_uid_and_carry_sequence() {
+        let now = Utc.with_ymd_and_hms(2023, 11, 26, 9, 0, 0).unwrap();
+        let ics = unfold(consult_event(&consult(), now).to_string());
+        assert!(ics.contains("UID:consult-0b7c@domain.tld\r\n"));
+        assert!(ics.contains("DTSTART:20231128T150000Z\r\n"));
+        // No end saved means an hour long
+        assert!(ics.contains("DTEND:20231128T160000Z\r\n"));
+        assert!(ics.contains("SEQUENCE:2\r\n"));
+        assert!(ics.contains("STATUS:CONFIRMED\r\n"));
+        assert!(ics.contains("LOCATION:HQ\\, 1 Main St\\, Austin\\, TX 78701\r\n"));
+        assert!(ics.contains("DESCRIPTION:Bring plans\\; two copies\r\n"));
+        assert!(ics.contains("CN=\"Acme, Inc\""));
+        assert!(ics.contains("ROLE=REQ-PARTICIPANT:mailto:ops@acme.test\r\n"));
+    }
+
+    #[test]
+    fn cancelled_consults_stay_in_the_calendar() {
+        let now = Utc.with_ymd_and_hms(2023, 11, 26, 9, 0, 0).unwrap();
+        let mut cancelled = consult();
+        cancelled.cancelled_at = Some(now);
+        cancelled.ics_sequence = 3;
+        let ics = unfold(consult_calendar(&[consult(), cancelled], "Pat's consults", now).to_string());
+        assert!(ics.contains("METHOD:PUBLISH\r\n"));
+        assert_eq!(ics.matches("BEGIN:VEVENT").count(), 2);
+        assert!(ics.contains("STATUS:CANCELLED\r\n"));
+        assert!(ics.contains("SEQUENCE:3\r\n"));
+    }
+
+    #[test]
+    fn pending_requests_are_tentative() {
+        let now = Utc.with_ymd_and_hms(2023, 11, 26, 9, 0, 0).unwrap();
+        let mut pending = consult();
+        pending.booking_status = BookingStatus::Pending.as_str().to_string();
+        let ics = unfold(consult_event(&pending, now).to_string());
+        assert!(ics.contains("STATUS:TENTATIVE\r\n"));
+        assert!(!ics.contains("STATUS:CONFIRMED"));
+    }
+
+    #[test]
+    fn invites_follow_the_consult_lifecycle() {
+        let now = Utc.with_ymd_and_hms(2023, 11, 26, 9, 0, 0).unwrap();
+        let mut invite = consult();
+        invite.ics_sequence = 0;
+        let ics = unfold(itip_calendar(&invite, ItipMethod::Request, now).to_string());
+        assert!(ics.contains("METHOD:REQUEST\r\n"));
+        assert!(!ics.contains("X-WR-CALNAME"));
+        assert!(ics.contains("ORGANIZER;CN=Pat Doe;SENT-BY=\"mailto:nobody@domain.tld\":mailto:pat@domain.tld\r\n"));
+        assert_eq!(invite_subject(&invite, ItipMethod::Request), "Invitation: WALKTHROUGH/INIT consult: Acme, Inc");
+        assert_eq!(invite_recipients(&invite), vec!["ops@acme.test", "pat@domain.tld"]);
+        // 15:00Z is 9am at the consult offset
+        assert!(invite_text(&invite, ItipMethod::Request).contains("When: Tuesday, November 28 2023 9:00 AM - 10:00 AM"));
+
+        let mut cancelled = consult();
+        cancelled.cancelled_at = Some(now);
+        let ics = unfold(itip_calendar(&cancelled, ItipMethod::Cancel, now).to_string());
+        assert!(ics.contains("METHOD:CANCEL\r\n"));
+        assert!(ics.contains("STATUS:CANCELLED\r\n"));
+        assert!(invite_subject(&cancelled, ItipMethod::Cancel).starts_with("Cancelled: "));
+        assert!(invite_subject(&consult(), ItipMethod::Request).starts_with("Updated: "));
+    }
+
+    #[test]
+    fn only_known_feed_types() {
+        assert!(is_feed_type(USER_FEED) && is_feed_type(CONSULTANT_FEED) && is_feed_type(LOCATION_FEED));
+        assert!(!is_feed_type(7));
+    }
+}
@@ -0,0 +1,226 @@
+use std::collections::BTreeMap;
+
+use chrono::{Datelike, Duration, Months, NaiveDate, Weekday};
+use serde::{Deserialize, Serialize};
+use sqlx::{FromRow, Pool, Postgres};
+
+// US federal holidays computed for any year, plus company closures from the company_closures table.
+// A fixed-date holiday that falls on a weekend is also listed on the weekday it's observed
+// (Saturday -> Friday, Sunday -> Monday), which can land in the previous or next year.
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Holiday {
+    pub date: NaiveDate,
+    pub name: String,
+    // True for company closures, false for federal holidays
+    pub closure: bool,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize, FromRow)]
+pub struct CompanyClosure {
+    pub closure_id: i32,
+    pub closure_date: NaiveDate,
+    pub closure_name: String,
+}
+
+pub fn days_in_month(year: i32, month: u32) -> u32 {
+    NaiveDate::from_ymd_opt(year, month, 1)
+        .and_then(|first| first.checked_add_months(Months::new(1)).map(|next| (next - first).num_days() as u32))
+        .unwrap_or(0)
+}
+
+fn nth_weekday(year: i32, month: u32, weekday: Weekday, n: u8) -> NaiveDate {
+    NaiveDate::from_weekday_of_month_opt(year, month, weekday, n).unwrap()
+}
+
+fn last_weekday(year: i32, month: u32, weekday: Weekday) -> NaiveDate {
+    let last = NaiveDate::from_ymd_opt(year, month, days_in_month(year, month)).unwrap();
+    let back = (7 + last.weekday().num_days_from_monday() - weekday.num_days_from_monday()) % 7;
+    last - Duration::days(back as i64)
+}
+
+// The weekday a fixed-date holiday is taken off when it falls on a weekend
+pub fn observed(date: NaiveDate) -> NaiveDate {
+    match date.weekday() {
+        Weekday::Sat => date - Duration::days(1),
+        Weekday::Sun => date + Duration::days(1),
+        _ => date,
+    }
+}
+
+fn federal(date: NaiveDate, name: &str) -> Holiday {
+    Holiday { date, name: name.to_owned(), closure: false }
+}
+
+pub fn federal_holidays(year: i32) -> Vec<Holiday> {
+    let fixed = |month: u32, day: u32| NaiveDate::from_ymd_opt(year, month, day).unwrap();
+    let mut fixed_dates = vec![(fixed(1, 1), "New Year's Day")];
+    // Juneteenth became a federal holiday in 2021
+    if year >= 2021 {
+        fixed_dates.push((fixed(6, 19), "Juneteenth"));
+    }
+    fixed_dates.extend([
+        (fixed(7, 4), "Independence Day"),
+        (fixed(11, 11), "Veterans Day"),
+        (fixed(12, 25), "Christmas Day"),
+    ]);
+
+    let mut holidays = vec![
+        federal(nth_weekday(year, 1, Weekday::Mon, 3), "Martin Luther King Jr. Day"),
+        federal(nth_weekday(year, 2, Weekday::Mon, 3), "Washington's Birthday"),
+        federal(last_weekday(year, 5, Weekday::Mon), "Memorial Day"),
+        federal(nth_weekday(year, 9, Weekday::Mon, 1), "Labor Day"),
+        federal(nth_weekday(year, 10, Weekday::Mon, 2), "Columbus Day"),
+        federal(nth_weekday(year, 11, Weekday::Thu, 4), "Thanksgiving Day"),
+    ];
+    for (date, name) in fixed_dates {
+        holidays.push(federal(date, name));
+        if observed(date) != date {
+            holidays.push(federal(observed(date), &format!("{} (observed)", name)));
+        }
+    }
+    holidays.sort_by_key(|holiday| holiday.date);
+    holidays
+}
+
+// Federal holidays in [start, end). Neighbouring years are included for observed days that cross over.
+pub fn federal_holidays_in_range(start: NaiveDate, end: NaiveDate) -> Vec<Holiday> {
+    (start.year() - 1..=end.year() + 1)
+        .flat_map(federal_holidays)
+        .filter(|holiday| holiday.date >= start && holiday.date < end)
+        .collect()
+}
+
+pub async fn closures_in_range(
+    db: &Pool<Postgres>,
+    start: NaiveDate,
+    end: NaiveDate,
+) -> Result<Vec<CompanyClosure>, sqlx::Error> {
+    sqlx::query_as::<_, CompanyClosure>(
+        "SELECT closure_id, closure_date, closure_name
+            FROM company_closures
+            WHERE closure_date >= $1 AND closure_date < $2
+            ORDER BY closure_date",
+    )
+    .bind(start)
+    .bind(end)
+    .fetch_all(db)
+    .await
+}
+
+pub async fn closures_from(db: &Pool<Postgres>, from: NaiveDate) -> Result<Vec<CompanyClosure>, sqlx::Error> {
+    sqlx::query_as::<_, CompanyClosure>(
+        "SELECT closure_id, closure_date, closure_name
+            FROM company_closures
+            WHERE closure_date >= $1
+            ORDER BY closure_date",
+    )
+    .bind(from)
+    .fetch_all(db)
+    .await
+}
+
+// One closure per date, re-adding a date renames it.
+pub async fn add_closure(db: &Pool<Postgres>, date: NaiveDate, name: &str) -> Result<(), sqlx::Error> {
+    sqlx::query(
+        "INSERT INTO company_closures (closure_date, closure_name) VALUES ($1, $2)
+            ON CONFLICT (closure_date) DO UPDATE SET closure_name = EXCLUDED.closure_name",
+    )
+    .bind(date)
+    .bind(name)
+    .execute(db)
+    .await
+    .map(|_| ())
+}
+
+pub async fn delete_closure(db: &Pool<Postgres>, closure_id: i32) -> Result<u64, sqlx::Error> {
+    sqlx::query("DELETE FROM company_closures WHERE closure_id = $1")
+        .bind(closure_id)
+        .execute(db)
+        .await
+        .map(|result| result.rows_affected())
+}
+
+// Holiday names by date. A closure on a federal holiday shows both.
+pub fn holidays_by_date(federal: Vec<Holiday>, closures: Vec<CompanyClosure>) -> BTreeMap<NaiveDate, String> {
+    let closures = closures.into_iter().map(|closure| Holiday {
+        date: closure.closure_date,
+        name: closure.closure_name,
+        closure: true,
+    });
+    let mut by_date: BTreeMap<NaiveDate, String> = BTreeMap::new();
+    for holiday in federal.into_iter().chain(closures) {
+        by_date
+            .entry(holiday.date)
+            .and_modify(|names| {
+                names.push_str(", ");
+                names.push_str(&holiday.name);
+            })
+            .or_insert(holiday.name);
+    }
+    by_date
+}
+
+// A failed closures query still leaves the federal holidays on the calendar.
+pub async fn holidays_in_range(db: &Pool<Postgres>, start: NaiveDate, end: NaiveDate) -> BTreeMap<NaiveDate, String> {
+    let closures = closures_in_range(db, start, end).await.unwrap_or_else(|err| {
+        println!("Error loading company closures: {}", err);
+        vec![]
+    });
+    holidays_by_date(federal_holidays_in_range(start, end), closures)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn date(y: i32, m: u32, d: u32) -> NaiveDate {
+        NaiveDate::from_ymd_opt(y, m, d).unwrap()
+    }
+
+    fn named(year: i32, name: &str) -> NaiveDate {
+        federal_holidays(year).into_iter().find(|h| h.name == name).unwrap().date
+    }
+
+    #[test]
+    fn month_lengths_follow_leap_years() {
+        assert_eq!(days_in_month(2023, 2), 28);
+        assert_eq!(days_in_month(2024, 2), 29);
+        assert_eq!(days_in_month(1900, 2), 28);
+        assert_eq!(days_in_month(2000, 2), 29);
+        assert_eq!(days_in_month(2023, 12), 31);
+        assert_eq!(days_in_month(2023, 4), 30);
+        assert_eq!(days_in_month(2023, 13), 0);
+    }
+
+    #[test]
+    fn floating_holidays_move_with_the_year() {
+        assert_eq!(named(2023, "Thanksgiving Day"), date(2023, 11, 23));
+        assert_eq!(named(2024, "Thanksgiving Day"), date(2024, 11, 28));
+        assert_eq!(named(2023, "Memorial Day"), date(2023, 5, 29));
+        assert_eq!(named(2024, "Memorial Day"), date(2024, 5, 27));
+        assert_eq!(named(2024, "Martin Luther King Jr. Day"), date(2024, 1, 15));
+        assert_eq!(named(2023, "Labor Day"), date(2023, 9, 4));
+        assert!(federal_holidays(2020).iter().all(|h| h.name != "Juneteenth"));
+    }
+
+    #[test]
+    fn weekend_holidays_are_observed_on_weekdays() {
+        // Veterans Day 2023 is a Saturday, Christmas 2022 a Sunday
+        assert_eq!(named(2023, "Veterans Day (observed)"), date(2023, 11, 10));
+        assert_eq!(named(2022, "Christmas Day (observed)"), date(2022, 12, 26));
+        // New Year's Day 2022 is a Saturday, observed the last day of 2021
+        let range = federal_holidays_in_range(date(2021, 12, 1), date(2022, 1, 1));
+        assert!(range.iter().any(|h| h.date == date(2021, 12, 31) && h.name == "New Year's Day (observed)"));
+
+        let closures = vec![CompanyClosure {
+            closure_id: 1,
+            closure_date: date(2023, 11, 24),
+            closure_name: "Day after Thanksgiving".to_string(),
+        }];
+        let by_date = holidays_by_date(federal_holidays_in_range(date(2023, 11, 1), date(2023, 12, 1)), closures);
+        assert_eq!(by_date.get(&date(2023, 11, 23)).map(|s| s.as_str()), Some("Thanksgiving Day"));
+        assert_eq!(by_date.get(&date(2023, 11, 24)).map(|s| s.as_str()), Some("Day after Thanksgiving"));
+        assert_eq!(by_date.len(), 4);
+    }
+}
@@ -0,0 +1,1107 @@
+use std::{
+    borrow::Cow,
+    collections::{HashMap, HashSet},
+};
+
+use chrono::{
+    DateTime, Datelike, Days, Duration, FixedOffset, Months, NaiveDate, NaiveDateTime, NaiveTime, TimeZone, Utc,
+    Weekday,
+};
+use serde::{Deserialize, Serialize};
+use sqlx::{FromRow, Pool, Postgres, QueryBuilder};
+
+use super::holidays::days_in_month;
+use crate::linfa::consult_offset;
+
+// Imports consultants' outside calendars (.ics) as busy blocks. Only what scheduling needs is read:
+// VEVENT start/end, RRULE/RDATE/EXDATE, RECURRENCE-ID overrides, and VTIMEZONE definitions. Events
+// marked CANCELLED or TRANSPARENT (free) aren't busy. Recurring events are expanded in their own
+// wall-clock time and converted per occurrence, so a 9am weekly meeting stays 9am across DST.
+// A TZID with no VTIMEZONE falls back to a built-in table of US zones, then to consult_offset(). Each
+// zone's transitions over the import window are worked out once per parse.
+
+// Caps on how far a single RRULE is expanded
+const MAX_PERIODS: i64 = 50_000;
+const MAX_OCCURRENCES: usize = 2_000;
+// Rows per INSERT, well under Postgres' bind limit
+const INSERT_CHUNK: usize = 1_000;
+
+#[derive(Debug, Clone, PartialEq)]
+struct ContentLine {
+    name: String,
+    params: Vec<(String, String)>,
+    value: String,
+}
+
+impl ContentLine {
+    fn param(&self, name: &str) -> Option<&str> {
+        self.params.iter().find(|(key, _)| key == name).map(|(_, value)| value.as_str())
+    }
+}
+
+#[derive(Debug, Default)]
+struct Component {
+    name: String,
+    lines: Vec<ContentLine>,
+    children: Vec<Component>,
+}
+
+impl Component {
+    fn prop(&self, name: &str) -> Option<&ContentLine> {
+        self.lines.iter().find(|line| line.name == name)
+    }
+
+    fn props<'a>(&'a self, name: &'a str) -> impl Iterator<Item = &'a ContentLine> + 'a {
+        self.lines.iter().filter(move |line| line.name == name)
+    }
+
+    fn value(&self, name: &str) -> Option<&str> {
+        self.prop(name).map(|line| line.value.as_str())
+    }
+}
+
+// Joins folded lines (CRLF or LF followed by a space or tab)
+fn unfold(text: &str) -> Vec<String> {
+    let mut lines: Vec<String> = vec![];
+    for raw in text.lines() {
+        match raw.strip_prefix(' ').or_else(|| raw.strip_prefix('\t')) {
+            Some(rest) if !lines.is_empty() => lines.last_mut().unwrap().push_str(rest),
+            _ => lines.push(raw.to_owned()),
+        }
+    }
+    lines
+}
+
+fn split_unquoted(text: &str, sep: char) -> Vec<&str> {
+    let mut parts = vec![];
+    let mut in_quotes = false;
+    let mut from = 0;
+    for (i, c) in text.char_indices() {
+        if c == '"' {
+            in_quotes = !in_quotes;
+        } else if c == sep && !in_quotes {
+            parts.push(&text[from..i]);
+            from = i + c.len_utf8();
+        }
+    }
+    parts.push(&text[from..]);
+    parts
+}
+
+fn parse_line(line: &str) -> Option<ContentLine> {
+    let mut in_quotes = false;
+    let value_at = line.char_indices().find_map(|(i, c)| match c {
+        '"' => {
+            in_quotes = !in_quotes;
+            None
+        }
+        ':' if !in_quotes => Some(i),
+        _ => None,
+    })?;
+    let mut head = split_unquoted(&line[..value_at], ';').into_iter();
+    let name = head.next()?.trim().to_ascii_uppercase();
+    let params = head
+        .filter_map(|param| param.split_once('='))
+        .map(|(key, value)| (key.trim().to_ascii_uppercase(), value.trim_matches('"').to_owned()))
+        .collect();
+    Some(ContentLine {
+        name,
+        params,
+        value: line[value_at + 1..].to_owned(),
+    })
+}
+
+fn unescape_text(value: &str) -> String {
+    let mut text = String::with_capacity(value.len());
+    let mut chars = value.chars();
+    while let Some(c) = chars.next() {
+        if c == '\\' {
+            match chars.next() {
+                Some('n') | Some('N') => text.push('\n'),
+                Some(other) => text.push(other),
+                None => {}
+            }
+        } else {
+            text.push(c);
+        }
+    }
+    text
+}
+
+fn parse_calendar(text: &str) -> Result<Component, String> {
+    let mut stack = vec![Component::default()];
+    for line in unfold(text) {
+        if line.trim().is_empty() {
+            continue;
+        }
+        let line = match parse_line(&line) {
+            Some(line) => line,
+            // Tolerate junk lines rather than rejecting the whole file
+            None => continue,
+        };
+        match line.name.as_str() {
+            "BEGIN" => stack.push(Component {
+                name: line.value.trim().to_ascii_uppercase(),
+                ..Default::default()
+            }),
+            "END" => {
+                if stack.len() < 2 {
+                    return Err(format!("Unexpected END:{}", line.value));
+                }
+                let done = stack.pop().unwrap();
+                if done.name != line.value.trim().to_ascii_uppercase() {
+                    return Err(format!("BEGIN:{} closed by END:{}", done.name, line.value));
+                }
+                stack.last_mut().unwrap().children.push(done);
+            }
+            _ => stack.last_mut().unwrap().lines.push(line),
+        }
+    }
+    if stack.len() != 1 {
+        return Err(format!("BEGIN:{} is never closed", stack.last().unwrap().name));
+    }
+    stack
+        .pop()
+        .unwrap()
+        .children
+        .into_iter()
+        .find(|component| component.name == "VCALENDAR")
+        .ok_or_else(|| "Not an iCalendar file".to_owned())
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum IcsTime {
+    Utc(NaiveDateTime),
+    // Floating when there is no TZID
+    Local(NaiveDateTime, Option<String>),
+    Date(NaiveDate),
+}
+
+fn parse_time_value(value: &str, tzid: Option<&str>, is_date: bool) -> Result<IcsTime, String> {
+    let value = value.trim();
+    let parsed = if is_date || value.len() == 8 {
+        NaiveDate::parse_from_str(value, "%Y%m%d").map(IcsTime::Date)
+    } else if let Some(utc) = value.strip_suffix('Z') {
+        NaiveDateTime::parse_from_str(utc, "%Y%m%dT%H%M%S").map(IcsTime::Utc)
+    } else {
+        NaiveDateTime::parse_from_str(value, "%Y%m%dT%H%M%S").map(|dt| IcsTime::Local(dt, tzid.map(str::to_owned)))
+    };
+    parsed.map_err(|_| format!("Bad date-time {}", value))
+}
+
+// EXDATE and RDATE can list several values on one line
+fn line_times(line: &ContentLine) -> Result<Vec<IcsTime>, String> {
+    let is_date = line.param("VALUE") == Some("DATE");
+    line.value
+        .split(',')
+        .filter(|value| !value.trim().is_empty())
+        .map(|value| parse_time_value(value, line.param("TZID"), is_date))
+        .collect()
+}
+
+fn line_time(line: &ContentLine) -> Result<IcsTime, String> {
+    line_times(line)?
+        .into_iter()
+        .next()
+        .ok_or_else(|| format!("{} has no value", line.name))
+}
+
+// P1W, P1DT2H, PT90M, -PT15M. None for anything malformed or too long to represent.
+fn parse_duration(value: &str) -> Option<Duration> {
+    let value = value.trim();
+    let (negative, value) = match value.strip_prefix('-') {
+        Some(rest) => (true, rest),
+        None => (false, value.strip_prefix('+').unwrap_or(value)),
+    };
+    let mut rest = value.strip_prefix('P')?;
+    let mut total = Duration::zero();
+    let mut in_time = false;
+    while !rest.is_empty() {
+        if let Some(after) = rest.strip_prefix('T') {
+            in_time = true;
+            rest = after;
+            continue;
+        }
+        let digits = rest.find(|c: char| !c.is_ascii_digit())?;
+        let amount: i64 = rest[..digits].parse().ok()?;
+        let unit = rest[digits..].chars().next()?;
+        let unit_seconds = match (unit, in_time) {
+            ('W', false) => 7 * 86_400,
+            ('D', false) => 86_400,
+            ('H', true) => 3_600,
+            ('M', true) => 60,
+            ('S', true) => 1,
+            _ => return None,
+        };
+        let millis = amount.checked_mul(unit_seconds)?.checked_mul(1_000)?;
+        total = total.checked_add(&Duration::milliseconds(millis))?;
+        rest = &rest[digits + 1..];
+    }
+    Some(if negative { -total } else { total })
+}
+
+// -0500, +0530, -050000
+fn parse_utc_offset(value: &str) -> Option<FixedOffset> {
+    let value = value.trim();
+    let sign = match value.get(..1)? {
+        "+" => 1,
+        "-" => -1,
+        _ => return None,
+    };
+    let hours: i32 = value.get(1..3)?.parse().ok()?;
+    let minutes: i32 = value.get(3..5)?.parse().ok()?;
+    let seconds: i32 = value.get(5..7).and_then(|s| s.parse().ok()).unwrap_or(0);
+    FixedOffset::east_opt(sign * (hours * 3600 + minutes * 60 + seconds))
+}
+
+fn parse_weekday(value: &str) -> Option<Weekday> {
+    match value {
+        "MO" => Some(Weekday::Mon),
+        "TU" => Some(Weekday::Tue),
+        "WE" => Some(Weekday::Wed),
+        "TH" => Some(Weekday::Thu),
+        "FR" => Some(Weekday::Fri),
+        "SA" => Some(Weekday::Sat),
+        "SU" => Some(Weekday::Sun),
+        _ => None,
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Freq {
+    Daily,
+    Weekly,
+    Monthly,
+    Yearly,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+struct Rrule {
+    freq: Freq,
+    interval: u32,
+    count: Option<usize>,
+    until: Option<IcsTime>,
+    // (ordinal, weekday), e.g. 2SU or -1FR. Ordinals only apply to MONTHLY and YEARLY.
+    by_day: Vec<(Option<i32>, Weekday)>,
+    by_month_day: Vec<i32>,
+    by_month: Vec<u32>,
+}
+
+fn parse_rrule(value: &str) -> Result<Rrule, String> {
+    let mut freq = None;
+    let mut rrule = Rrule {
+        freq: Freq::Daily,
+        interval: 1,
+        count: None,
+        until: None,
+        by_day: vec![],
+        by_month_day: vec![],
+        by_month: vec![],
+    };
+    let bad = || format!("Unsupported RRULE {}", value);
+    for part in value.split(';').filter(|part| !part.is_empty()) {
+        let (key, val) = part.split_once('=').ok_or_else(bad)?;
+        match key.to_ascii_uppercase().as_str() {
+            "FREQ" => {
+                freq = Some(match val {
+                    "DAILY" => Freq::Daily,
+                    "WEEKLY" => Freq::Weekly,
+                    "MONTHLY" => Freq::Monthly,
+                    "YEARLY" => Freq::Yearly,
+                    _ => return Err(bad()),
+                })
+            }
+            "INTERVAL" => rrule.interval = val.parse().ok().filter(|n| *n > 0).ok_or_else(bad)?,
+            "COUNT" => rrule.count = Some(val.parse().map_err(|_| bad())?),
+            "UNTIL" => rrule.until = Some(parse_time_value(val, None, false)?),
+            "BYDAY" => {
+                for day in val.split(',') {
+                    let split = day.len().checked_sub(2).ok_or_else(bad)?;
+                    let weekday = day.get(split..).and_then(parse_weekday).ok_or_else(bad)?;
+                    let ordinal = match day.get(..split).ok_or_else(bad)? {
+                        "" => None,
+                        n => Some(n.trim_start_matches('+').parse::<i32>().map_err(|_| bad())?),
+                    };
+                    rrule.by_day.push((ordinal, weekday));
+                }
+            }
+            "BYMONTHDAY" => {
+                for day in val.split(',') {
+                    rrule.by_month_day.push(day.parse().map_err(|_| bad())?);
+                }
+            }
+            "BYMONTH" => {
+                for month in val.split(',') {
+                    rrule.by_month.push(month.parse().map_err(|_| bad())?);
+                }
+            }
+            // WKST and the rest don't change which days a busy block lands on often enough to matter
+            _ => {}
+        }
+    }
+    rrule.freq = freq.ok_or_else(bad)?;
+    Ok(rrule)
+}
+
+fn week_monday(date: NaiveDate) -> NaiveDate {
+    date - Duration::days(date.weekday().num_days_from_monday() as i64)
+}
+
+impl Rrule {
+    // UNTIL in the same wall-clock frame as DTSTART
+    fn until_local(&self, offset: FixedOffset) -> Option<NaiveDateTime> {
+        match &self.until {
+            Some(IcsTime::Utc(utc)) => utc.checked_add_signed(Duration::seconds(offset.local_minus_utc() as i64)),
+            Some(IcsTime::Local(local, _)) => Some(*local),
+            Some(IcsTime::Date(date)) => date.and_hms_opt(23, 59, 59),
+            None => None,
+        }
+    }
+
+    fn day_matches(&self, date: NaiveDate) -> bool {
+        (self.by_month.is_empty() || self.by_month.contains(&date.month()))
+            && (self.by_day.is_empty() || self.by_day.iter().any(|(_, weekday)| *weekday == date.weekday()))
+            && (self.by_month_day.is_empty() || self.by_month_day.contains(&(date.day() as i32)))
+    }
+
+    fn month_dates(&self, first: NaiveDate, default_day: u32) -> Vec<NaiveDate> {
+        let len = days_in_month(first.year(), first.month()) as i32;
+        let mut dates = vec![];
+        if !self.by_month_day.is_empty() {
+            for day in &self.by_month_day {
+                let day = if *day > 0 { *day } else { len + day + 1 };
+                if (1..=len).contains(&day) {
+                    dates.push(first.with_day(day as u32).unwrap());
+                }
+            }
+            if !self.by_day.is_empty() {
+                dates.retain(|date| self.by_day.iter().any(|(_, weekday)| *weekday == date.weekday()));
+            }
+        } else if !self.by_day.is_empty() {
+            for (ordinal, weekday) in &self.by_day {
+                let all = (0..len)
+                    .map(|offset| first + Duration::days(offset as i64))
+                    .filter(|date| date.weekday() == *weekday)
+                    .collect::<Vec<NaiveDate>>();
+                match ordinal {
+                    None => dates.extend(all),
+                    Some(n) if *n > 0 => dates.extend(all.get(*n as usize - 1)),
+                    Some(n) => dates.extend(all.len().checked_sub(n.unsigned_abs() as usize).and_then(|i| all.get(i))),
+                }
+            }
+        } else if let Some(date) = first.with_day(default_day) {
+            dates.push(date);
+        }
+        dates
+    }
+
+    // First day of the nth period, and the candidate dates in it. None once the period is past the
+    // dates chrono can represent, which ends the expansion.
+    fn period(&self, dtstart: NaiveDate, n: i64) -> Option<(NaiveDate, Vec<NaiveDate>)> {
+        let step = n.checked_mul(self.interval as i64)?;
+        match self.freq {
+            Freq::Daily => {
+                let date = dtstart.checked_add_days(Days::new(step as u64))?;
+                let dates = if self.day_matches(date) { vec![date] } else { vec![] };
+                Some((date, dates))
+            }
+            Freq::Weekly => {
+                let start = week_monday(dtstart).checked_add_days(Days::new(step.checked_mul(7)? as u64))?;
+                let weekdays = if self.by_day.is_empty() {
+                    vec![dtstart.weekday()]
+                } else {
+                    self.by_day.iter().map(|(_, weekday)| *weekday).collect()
+                };
+                let dates = (0..7)
+                    .filter_map(|offset| start.checked_add_days(Days::new(offset)))
+                    .filter(|date| weekdays.contains(&date.weekday()))
+                    .filter(|date| self.by_month.is_empty() || self.by_month.contains(&date.month()))
+                    .collect();
+                Some((start, dates))
+            }
+            Freq::Monthly => {
+                let first = dtstart.with_day(1)?.checked_add_months(Months::new(u32::try_from(step).ok()?))?;
+                let dates = if self.by_month.is_empty() || self.by_month.contains(&first.month()) {
+                    self.month_dates(first, dtstart.day())
+                } else {
+                    vec![]
+                };
+                Some((first, dates))
+            }
+            Freq::Yearly => {
+                let year = dtstart.year().checked_add(i32::try_from(step).ok()?)?;
+                let first = NaiveDate::from_ymd_opt(year, 1, 1)?;
+                let months = if self.by_month.is_empty() { vec![dtstart.month()] } else { self.by_month.clone() };
+                let dates = months
+                    .into_iter()
+                    .filter_map(|month| NaiveDate::from_ymd_opt(year, month, 1))
+                    .flat_map(|month_first| {
+                        if self.by_day.is_empty() && self.by_month_day.is_empty() {
+                            month_first.with_day(dtstart.day()).into_iter().collect()
+                        } else {
+                            self.month_dates(month_first, dtstart.day())
+                        }
+                    })
+                    .collect();
+                Some((first, dates))
+            }
+        }
+    }
+
+    // Occurrence starts in DTSTART's wall-clock time, from `from` up to and including `through`.
+    // COUNT is spent from DTSTART on, so occurrences before the import window still count toward it.
+    // MAX_OCCURRENCES only caps what's returned, so an old series still reaches the window.
+    fn expand(
+        &self,
+        dtstart: NaiveDateTime,
+        until: Option<NaiveDateTime>,
+        from: NaiveDateTime,
+        through: NaiveDateTime,
+    ) -> Vec<NaiveDateTime> {
+        let limit = until.map_or(through, |until| until.min(through));
+        let mut occurrences = vec![];
+        let mut counted = 0;
+        for n in 0..MAX_PERIODS {
+            let (period_start, mut dates) = match self.period(dtstart.date(), n) {
+                Some(period) => period,
+                None => break,
+            };
+            if period_start.and_time(NaiveTime::MIN) > limit {
+                break;
+            }
+            dates.sort();
+            dates.dedup();
+            for date in dates {
+                let occurrence = date.and_time(dtstart.time());
+                if occurrence < dtstart {
+                    continue;
+                }
+                if occurrence > limit
+                    || self.count.is_some_and(|count| counted >= count)
+                    || occurrences.len() >= MAX_OCCURRENCES
+                {
+                    return occurrences;
+                }
+                counted += 1;
+                if occurrence >= from {
+                    occurrences.push(occurrence);
+                }
+            }
+        }
+        occurrences
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+struct ZoneRule {
+    // Wall-clock time in offset_from
+    start: NaiveDateTime,
+    offset_from: FixedOffset,
+    offset_to: FixedOffset,
+    rrule: Option<Rrule>,
+}
+
+// A zone's transitions over the import window, expanded once per parse so converting each
+// occurrence is a lookup rather than a re-expansion of every rule from its DTSTART
+#[derive(Debug, Clone, PartialEq)]
+struct Transitions {
+    from: NaiveDateTime,
+    through: NaiveDateTime,
+    // Offset in effect at `from`
+    initial: FixedOffset,
+    // Onsets after `from`, in order
+    onsets: Vec<(NaiveDateTime, FixedOffset)>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+struct Zone {
+    rules: Vec<ZoneRule>,
+    window: Option<Transitions>,
+}
+
+fn hours(offset: i32) -> FixedOffset {
+    FixedOffset::east_opt(offset * 3600).unwrap()
+}
+
+impl Zone {
+    fn fixed(offset: FixedOffset) -> Zone {
+        Zone {
+            rules: vec![ZoneRule {
+                start: NaiveDate::from_ymd_opt(1970, 1, 1).unwrap().and_time(NaiveTime::MIN),
+                offset_from: offset,
+                offset_to: offset,
+                rrule: None,
+            }],
+            window: None,
+        }
+    }
+
+    // DST from the second Sunday in March to the first Sunday in November, as since 2007
+    fn us(standard: i32) -> Zone {
+        let rule = |month: u32, day: u32, from: i32, to: i32, by_day: (i32, Weekday)| ZoneRule {
+            start: NaiveDate::from_ymd_opt(2007, month, day).unwrap().and_hms_opt(2, 0, 0).unwrap(),
+            offset_from: hours(from),
+            offset_to: hours(to),
+            rrule: Some(Rrule {
+                freq: Freq::Yearly,
+                interval: 1,
+                count: None,
+                until: None,
+                by_day: vec![(Some(by_day.0), by_day.1)],
+                by_month_day: vec![],
+                by_month: vec![month],
+            }),
+        };
+        Zone {
+            rules: vec![
+                rule(3, 11, standard, standard + 1, (2, Weekday::Sun)),
+                rule(11, 4, standard + 1, standard, (1, Weekday::Sun)),
+            ],
+            window: None,
+        }
+    }
+
+    fn known(tzid: &str) -> Option<Zone> {
+        match tzid.trim_start_matches('/') {
+            "UTC" | "Etc/UTC" | "GMT" | "Etc/GMT" | "Z" => Some(Zone::fixed(hours(0))),
+            "America/New_York" | "US/Eastern" | "Eastern Standard Time" => Some(Zone::us(-5)),
+            "America/Chicago" | "US/Central" | "Central Standard Time" => Some(Zone::us(-6)),
+            "America/Denver" | "US/Mountain" | "Mountain Standard Time" => Some(Zone::us(-7)),
+            "America/Phoenix" | "US/Arizona" | "US Mountain Standard Time" => Some(Zone::fixed(hours(-7))),
+            "America/Los_Angeles" | "US/Pacific" | "Pacific Standard Time" => Some(Zone::us(-8)),
+            "America/Anchorage" | "US/Alaska" | "Alaskan Standard Time" => Some(Zone::us(-9)),
+            "Pacific/Honolulu" | "US/Hawaii" | "Hawaiian Standard Time" => Some(Zone::fixed(hours(-10))),
+            _ => None,
+        }
+    }
+
+    fn from_component(component: &Component) -> Option<(String, Zone)> {
+        let tzid = component.value("TZID")?.to_owned();
+        let rules = component
+            .children
+            .iter()
+            .filter(|child| child.name == "STANDARD" || child.name == "DAYLIGHT")
+            .filter_map(|child| {
+                let start = match line_time(child.prop("DTSTART")?).ok()? {
+                    IcsTime::Local(local, _) | IcsTime::Utc(local) => local,
+                    IcsTime::Date(date) => date.and_time(NaiveTime::MIN),
+                };
+                Some(ZoneRule {
+                    start,
+                    offset_from: parse_utc_offset(child.value("TZOFFSETFROM")?)?,
+                    offset_to: parse_utc_offset(child.value("TZOFFSETTO")?)?,
+                    rrule: child.value("RRULE").and_then(|rrule| parse_rrule(rrule).ok()),
+                })
+            })
+            .collect::<Vec<ZoneRule>>();
+        if rules.is_empty() {
+            None
+        } else {
+            Some((tzid, Zone { rules, window: None }))
+        }
+    }
+
+    fn with_window(mut self, from: NaiveDateTime, through: NaiveDateTime) -> Zone {
+        let initial = self.offset_from_rules(from);
+        let mut onsets = vec![];
+        for rule in &self.rules {
+            match &rule.rrule {
+                Some(rrule) => onsets.extend(
+                    rrule
+                        .expand(rule.start, rrule.until_local(rule.offset_from), from, through)
+                        .into_iter()
+                        .filter(|onset| *onset > from)
+                        .map(|onset| (onset, rule.offset_to)),
+                ),
+                None if rule.start > from && rule.start <= through => onsets.push((rule.start, rule.offset_to)),
+                None => {}
+            }
+        }
+        // Stable, so on a tie the earlier rule wins as it does in offset_from_rules
+        onsets.sort_by_key(|(onset, _)| *onset);
+        onsets.dedup_by_key(|(onset, _)| *onset);
+        self.window = Some(Transitions {
+            from,
+            through,
+            initial,
+            onsets,
+        });
+        self
+    }
+
+    // Looked up in the window when it covers `local`, worked out from the rules otherwise
+    fn offset_at(&self, local: NaiveDateTime) -> FixedOffset {
+        match self.window.as_ref().filter(|window| window.from <= local && local <= window.through) {
+            Some(window) => match window.onsets.partition_point(|(onset, _)| *onset <= local) {
+                0 => window.initial,
+                i => window.onsets[i - 1].1,
+            },
+            None => self.offset_from_rules(local),
+        }
+    }
+
+    // Offset in effect at a wall-clock time: that of the latest transition at or before it
+    fn offset_from_rules(&self, local: NaiveDateTime) -> FixedOffset {
+        let mut latest: Option<(NaiveDateTime, FixedOffset)> = None;
+        for rule in &self.rules {
+            let onset = match &rule.rrule {
+                Some(rrule) => rrule
+                    .expand(rule.start, rrule.until_local(rule.offset_from), rule.start, local)
+                    .last()
+                    .copied(),
+                None => Some(rule.start).filter(|start| *start <= local),
+            };
+            if let Some(onset) = onset {
+                if latest.is_none_or(|(at, _)| onset > at) {
+                    latest = Some((onset, rule.offset_to));
+                }
+            }
+        }
+        latest
+            .map(|(_, offset)| offset)
+            .or_else(|| self.rules.iter().min_by_key(|rule| rule.start).map(|rule| rule.offset_from))
+            .unwrap_or_else(consult_offset)
+    }
+}
+
+struct Zones {
+    defined: HashMap<String, Zone>,
+    fallback: Zone,
+}
+
+impl Zones {
+    fn zone(&self, tzid: Option<&str>) -> Cow<'_, Zone> {
+        match tzid {
+            Some(tzid) => match self.defined.get(tzid) {
+                Some(zone) => Cow::Borrowed(zone),
+                None => Zone::known(tzid).map(Cow::Owned).unwrap_or(Cow::Borrowed(&self.fallback)),
+            },
+            None => Cow::Borrowed(&self.fallback),
+        }
+    }
+
+    // The wall-clock frame a time repeats in, with its zone
+    fn local_frame(&self, time: &IcsTime) -> (NaiveDateTime, Cow<'_, Zone>) {
+        match time {
+            IcsTime::Utc(utc) => (*utc, Cow::Owned(Zone::fixed(hours(0)))),
+            IcsTime::Local(local, tzid) => (*local, self.zone(tzid.as_deref())),
+            IcsTime::Date(date) => (date.and_time(NaiveTime::MIN), Cow::Borrowed(&self.fallback)),
+        }
+    }
+
+    fn to_utc(&self, time: &IcsTime) -> DateTime<Utc> {
+        let (local, zone) = self.local_frame(time);
+        to_utc(local, &zone)
+    }
+}
+
+fn to_utc(local: NaiveDateTime, zone: &Zone) -> DateTime<Utc> {
+    zone.offset_at(local).from_local_datetime(&local).unwrap().with_timezone(&Utc)
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct BusyInterval {
+    pub uid: String,
+    pub summary: Option<String>,
+    pub start: DateTime<Utc>,
+    pub end: DateTime<Utc>,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct ParsedBusy {
+    pub events: usize,
+    pub intervals: Vec<BusyInterval>,
+    // Why events were left out, for the import summary
+    pub skipped: Vec<String>,
+}
+
+fn event_length(event: &Component, start: &IcsTime, zones: &Zones) -> Result<Duration, String> {
+    if let Some(end) = event.prop("DTEND") {
+        return Ok(match (start, line_time(end)?) {
+            (IcsTime::Date(start), IcsTime::Date(end)) => Duration::days((end - *start).num_days()),
+            (start, end) => zones.to_utc(&end) - zones.to_utc(start),
+        });
+    }
+    if let Some(duration) = event.value("DURATION") {
+        return parse_duration(duration).ok_or_else(|| format!("Bad DURATION {}", duration));
+    }
+    // RFC 5545 defaults: an all-day event lasts the day, a timed one is a point in time
+    Ok(match start {
+        IcsTime::Date(_) => Duration::days(1),
+        _ => Duration::zero(),
+    })
+}
+
+// Starts from `from` through `through`, give or take a day for zone offsets
+fn event_starts(
+    event: &Component,
+    start: &IcsTime,
+    zones: &Zones,
+    from: DateTime<Utc>,
+    through: DateTime<Utc>,
+) -> Result<Vec<DateTime<Utc>>, String> {
+    let (local, zone) = zones.local_frame(start);
+    let mut starts = match event.value("RRULE") {
+        Some(rrule) => {
+            let rrule = parse_rrule(rrule)?;
+            // Far enough either side of the window in any zone's wall-clock time
+            let from_local = from.naive_utc().checked_sub_signed(Duration::hours(14)).unwrap_or(NaiveDateTime::MIN);
+            let through_local = through.naive_utc() + Duration::hours(14);
+            rrule
+                .expand(local, rrule.until_local(zone.offset_at(local)), from_local, through_local)
+                .into_iter()
+                .map(|occurrence| to_utc(occurrence, &zone))
+                .collect()
+        }
+        None => vec![to_utc(local, &zone)],
+    };
+    for rdate in event.props("RDATE") {
+        // PERIOD values aren't supported, just start times
+        if rdate.param("VALUE") != Some("PERIOD") {
+            starts.extend(line_times(rdate)?.iter().map(|time| zones.to_utc(time)));
+        }
+    }
+
+    let mut excluded_at = HashSet::new();
+    let mut excluded_days = HashSet::new();
+    for exdate in event.props("EXDATE") {
+        for time in line_times(exdate)? {
+            match time {
+                IcsTime::Date(date) => {
+                    excluded_days.insert(date);
+                }
+                time => {
+                    excluded_at.insert(zones.to_utc(&time));
+                }
+            }
+        }
+    }
+    starts.retain(|start| {
+        let local_day = start.with_timezone(&zone.offset_at(start.naive_utc())).date_naive();
+        !excluded_at.contains(start) && !excluded_days.contains(&local_day)
+    });
+    starts.sort();
+    starts.dedup();
+    Ok(starts)
+}
+
+// Busy time overlapping [from, to)
+pub fn busy_intervals(text: &str, from: DateTime<Utc>, to: DateTime<Utc>) -> Result<ParsedBusy, String> {
+    let calendar = parse_calendar(text)?;
+    let events = calendar
+        .children
+        .iter()
+        .filter(|child| child.name == "VEVENT")
+        .collect::<Vec<&Component>>();
+    let mut defined = calendar
+        .children
+        .iter()
+        .filter(|child| child.name == "VTIMEZONE")
+        .filter_map(Zone::from_component)
+        .collect::<HashMap<String, Zone>>();
+    // Built-in zones the events name without defining, so they're expanded once too
+    for tzid in events.iter().flat_map(|event| event.lines.iter()).filter_map(|line| line.param("TZID")) {
+        if !defined.contains_key(tzid) {
+            if let Some(zone) = Zone::known(tzid) {
+                defined.insert(tzid.to_owned(), zone);
+            }
+        }
+    }
+    // Wide enough for occurrences just outside the window in any zone's wall-clock time
+    let window_from = from.naive_utc().checked_sub_signed(Duration::days(2)).unwrap_or(NaiveDateTime::MIN);
+    let window_through = to.naive_utc().checked_add_signed(Duration::days(2)).unwrap_or(NaiveDateTime::MAX);
+    let zones = Zones {
+        defined: defined
+            .into_iter()
+            .map(|(tzid, zone)| (tzid, zone.with_window(window_from, window_through)))
+            .collect(),
+        fallback: Zone::fixed(consult_offset()),
+    };
+
+    // Instances replaced by a RECURRENCE-ID override come out of the master's expansion
+    let mut overridden = HashSet::new();
+    for event in &events {
+        if let (Some(uid), Some(recurrence_id)) = (event.value("UID"), event.prop("RECURRENCE-ID")) {
+            if let Ok(time) = line_time(recurrence_id) {
+                overridden.insert((uid.to_owned(), zones.to_utc(&time)));
+            }
+        }
+    }
+
+    let mut parsed = ParsedBusy {
+        events: events.len(),
+        ..Default::default()
+    };
+    for event in events {
+        let uid = event.value("UID").unwrap_or("").to_owned();
+        let summary = event.value("SUMMARY").map(unescape_text);
+        let label = summary.clone().unwrap_or_else(|| uid.clone());
+        if event.value("STATUS").is_some_and(|status| status.eq_ignore_ascii_case("CANCELLED"))
+            || event.value("TRANSP").is_some_and(|transp| transp.eq_ignore_ascii_case("TRANSPARENT"))
+        {
+            continue;
+        }
+        let start = match event.prop("DTSTART").map(line_time) {
+            Some(Ok(start)) => start,
+            Some(Err(err)) => {
+                parsed.skipped.push(format!("{}: {}", label, err));
+                continue;
+            }
+            None => {
+                parsed.skipped.push(format!("{}: no DTSTART", label));
+                continue;
+            }
+        };
+        let length = match event_length(event, &start, &zones) {
+            Ok(length) if length > Duration::zero() => length,
+            Ok(_) => continue,
+            Err(err) => {
+                parsed.skipped.push(format!("{}: {}", label, err));
+                continue;
+            }
+        };
+        let is_override = event.prop("RECURRENCE-ID").is_some();
+        // Anything starting this early still overlaps the window
+        let earliest = from.checked_sub_signed(length).unwrap_or(DateTime::<Utc>::MIN_UTC);
+        let starts = match event_starts(event, &start, &zones, earliest, to) {
+            Ok(starts) => starts,
+            Err(err) => {
+                parsed.skipped.push(format!("{}: {}", label, err));
+                continue;
+            }
+        };
+        for busy_start in starts {
+            if !is_override && overridden.contains(&(uid.clone(), busy_start)) {
+                continue;
+            }
+            let busy_end = match busy_start.checked_add_signed(length) {
+                Some(busy_end) => busy_end,
+                None => {
+                    parsed.skipped.push(format!("{}: ends too far in the future", label));
+                    break;
+                }
+            };
+            if busy_end > from && busy_start < to {
+                parsed.intervals.push(BusyInterval {
+                    uid: uid.clone(),
+                    summary: summary.clone(),
+                    start: busy_start,
+                    end: busy_end,
+                });
+            }
+        }
+    }
+    parsed.intervals.sort_by_key(|interval| interval.start);
+    Ok(parsed)
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize, FromRow)]
+pub struct BusySource {
+    pub source: String,
+    pub blocks: i64,
+    pub first_start: Option<DateTime<Utc>>,
+    pub last_end: Option<DateTime<Utc>>,
+    pub imported_at: Option<DateTime<Utc>>,
+}
+
+// Re-importing a source replaces its blocks, so a refreshed export doesn't double up.
+pub async fn replace_busy_blocks(
+    db: &Pool<Postgres>,
+    consultant_id: i32,
+    source: &str,
+    intervals: &[BusyInterval],
+) -> Result<usize, sqlx::Error> {
+    let mut tx = db.begin().await?;
+    sqlx::query("DELETE FROM consultant_busy_blocks WHERE consultant_id = $1 AND source = $2")
+        .bind(consultant_id)
+        .bind(source)
+        .execute(&mut *tx)
+        .await?;
+    for chunk in intervals.chunks(INSERT_CHUNK) {
+        let mut query = QueryBuilder::<Postgres>::new(
+            "INSERT INTO consultant_busy_blocks (consultant_id, source, uid, summary, busy_start, busy_end) ",
+        );
+        query.push_values(chunk, |mut row, interval| {
+            row.push_bind(consultant_id)
+                .push_bind(source)
+                .push_bind(&interval.uid)
+                .push_bind(&interval.summary)
+                .push_bind(interval.start)
+                .push_bind(interval.end);
+        });
+        query.build().execute(&mut *tx).await?;
+    }
+    tx.commit().await?;
+    Ok(intervals.len())
+}
+
+pub async fn busy_sources(db: &Pool<Postgres>, consultant_id: i32) -> Result<Vec<BusySource>, sqlx::Error> {
+    sqlx::query_as::<_, BusySource>(
+        "SELECT source, COUNT(*) AS blocks, MIN(busy_start) AS first_start, MAX(busy_end) AS last_end,
+                MAX(created_at) AS imported_at
+            FROM consultant_busy_blocks
+            WHERE consultant_id = $1
+            GROUP BY source
+            ORDER BY source",
+    )
+    .bind(consultant_id)
+    .fetch_all(db)
+    .await
+}
+
+pub async fn clear_busy_source(db: &Pool<Postgres>, consultant_id: i32, source: &str) -> Result<u64, sqlx::Error> {
+    sqlx::query("DELETE FROM consultant_busy_blocks WHERE consultant_id = $1 AND source = $2")
+        .bind(consultant_id)
+        .bind(source)
+        .execute(db)
+        .await
+        .map(|result| result.rows_affected())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn utc(y: i32, m: u32, d: u32, h: u32, min: u32) -> DateTime<Utc> {
+        Utc.with_ymd_and_hms(y, m, d, h, min, 0).unwrap()
+    }
+
+    fn wrap(body: &str) -> String {
+        format!("BEGIN:VCALENDAR\r\nVERSION:2.0\r\n{}END:VCALENDAR\r\n", body)
+    }
+
+    #[test]
+    fn sample_event_is_one_block() {
+        let parsed = busy_intervals(include_str!("../../event.ics"), utc(1996, 1, 1, 0, 0), utc(1997, 1, 1, 0, 0)).unwrap();
+        assert_eq!(parsed.events, 1);
+        assert!(parsed.skipped.is_empty());
+        assert_eq!(
+            parsed.intervals,
+            vec![BusyInterval {
+                uid: "b68378cf-872d-44f1-9703-5e3725c56e71".to_string(),
+                summary: Some("Networld+Interop Conference".to_string()),
+                start: utc(1996, 9, 18, 14, 30),
+                end: utc(1996, 9, 20, 22, 0),
+            }]
+        );
+        // Outside the window nothing is kept
+        let later = busy_intervals(include_str!("../../event.ics"), utc(2023, 1, 1, 0, 0), utc(2024, 1, 1, 0, 0)).unwrap();
+        assert!(later.intervals.is_empty());
+    }
+
+    #[test]
+    fn weekly_rule_keeps_wall_clock_time_across_dst() {
+        let ics = wrap(
+            "BEGIN:VEVENT\r\nUID:standup\r\nSUMMARY:Standup\r\n\
+             DTSTART;TZID=America/Chicago:20240304T090000\r\nDURATION:PT30M\r\n\
+             RRULE:FREQ=WEEKLY;COUNT=3;BYDAY=MO\r\n\
+             EXDATE;TZID=America/Chicago:20240311T090000\r\nEND:VEVENT\r\n",
+        );
+        let parsed = busy_intervals(&ics, utc(2024, 1, 1, 0, 0), utc(2025, 1, 1, 0, 0)).unwrap();
+        let starts = parsed.intervals.iter().map(|i| i.start).collect::<Vec<_>>();
+        // CST before March 10th, CDT after. The 11th is excluded but still counts toward COUNT.
+        assert_eq!(starts, vec![utc(2024, 3, 4, 15, 0), utc(2024, 3, 18, 14, 0)]);
+        assert_eq!(parsed.intervals[0].end, utc(2024, 3, 4, 15, 30));
+    }
+
+    #[test]
+    fn vtimezone_overrides_and_free_time() {
+        let ics = wrap(
+            "BEGIN:VTIMEZONE\r\nTZID:Custom Eastern\r\n\
+             BEGIN:STANDARD\r\nDTSTART:19701101T020000\r\nRRULE:FREQ=YEARLY;BYMONTH=11;BYDAY=1SU\r\n\
+             TZOFFSETFROM:-0400\r\nTZOFFSETTO:-0500\r\nEND:STANDARD\r\n\
+             BEGIN:DAYLIGHT\r\nDTSTART:19700308T020000\r\nRRULE:FREQ=YEARLY;BYMONTH=3;BYDAY=2SU\r\n\
+             TZOFFSETFROM:-0500\r\nTZOFFSETTO:-0400\r\nEND:DAYLIGHT\r\nEND:VTIMEZONE\r\n\
+             BEGIN:VEVENT\r\nUID:review\r\nSUMMARY:Month end review\\, finance\r\n\
+             DTSTART;TZID=Custom Eastern:20231027T150000\r\nDTEND;TZID=Custom Eastern:20231027T160000\r\n\
+             RRULE:FREQ=MONTHLY;BYDAY=-1FR;UNTIL=20231231T235959Z\r\nEND:VEVENT\r\n\
+             BEGIN:VEVENT\r\nUID:review\r\nRECURRENCE-ID;TZID=Custom Eastern:20231124T150000\r\n\
+             DTSTART;TZID=Custom Eastern:20231122T100000\r\nDTEND;TZID=Custom Eastern:20231122T110000\r\nEND:VEVENT\r\n\
+             BEGIN:VEVENT\r\nUID:lunch\r\nDTSTART:20231101T170000Z\r\nDTEND:20231101T180000Z\r\nTRANSP:TRANSPARENT\r\nEND:VEVENT\r\n\
+             BEGIN:VEVENT\r\nUID:offsite\r\nDTSTART;VALUE=DATE:20231205\r\nEND:VEVENT\r\n",
+        );
+        let parsed = busy_intervals(&ics, utc(2023, 10, 1, 0, 0), utc(2024, 1, 1, 0, 0)).unwrap();
+        let starts = parsed.intervals.iter().map(|i| (i.uid.as_str(), i.start)).collect::<Vec<_>>();
+        assert_eq!(
+            starts,
+            vec![
+                // EDT in October, EST after November 5th
+                ("review", utc(2023, 10, 27, 19, 0)),
+                // The override moves November's review to the 22nd
+                ("review", utc(2023, 11, 22, 15, 0)),
+                ("offsite", utc(2023, 12, 5, 6, 0)),
+                ("review", utc(2023, 12, 29, 20, 0)),
+            ]
+        );
+        assert_eq!(parsed.intervals[0].summary.as_deref(), Some("Month end review, finance"));
+        // All-day events block the whole (consult_offset) day
+        assert_eq!(parsed.intervals[2].end - parsed.intervals[2].start, Duration::days(1));
+    }
+
+    #[test]
+    fn old_daily_series_reaches_the_window() {
+        let ics = wrap(
+            "BEGIN:VEVENT\r\nUID:daily\r\nDTSTART:20150105T150000Z\r\nDURATION:PT1H\r\n\
+             RRULE:FREQ=DAILY\r\nEND:VEVENT\r\n",
+        );
+        let parsed = busy_intervals(&ics, utc(2023, 11, 1, 0, 0), utc(2023, 11, 3, 0, 0)).unwrap();
+        let starts = parsed.intervals.iter().map(|i| i.start).collect::<Vec<_>>();
+        assert_eq!(starts, vec![utc(2023, 11, 1, 15, 0), utc(2023, 11, 2, 15, 0)]);
+    }
+
+    #[test]
+    fn oversized_values_are_skipped() {
+        let ics = wrap(
+            "BEGIN:VEVENT\r\nUID:endless\r\nDTSTART:20231101T170000Z\r\nDURATION:P99999999999999999W\r\nEND:VEVENT\r\n\
+             BEGIN:VEVENT\r\nUID:far\r\nDTSTART:20231101T170000Z\r\nDURATION:P99999999W\r\nEND:VEVENT\r\n\
+             BEGIN:VEVENT\r\nUID:sparse\r\nDTSTART:20231101T170000Z\r\nDURATION:PT1H\r\n\
+             RRULE:FREQ=DAILY;INTERVAL=4000000000\r\nEND:VEVENT\r\n\
+             BEGIN:VEVENT\r\nUID:odd\r\nDTSTART:20231101T170000Z\r\nDURATION:PT1H\r\nRRULE:FREQ=WEEKLY;BYDAY=éA\r\nEND:VEVENT\r\n",
+        );
+        let parsed = busy_intervals(&ics, utc(2023, 1, 1, 0, 0), utc(2024, 1, 1, 0, 0)).unwrap();
+        assert_eq!(parsed.skipped.len(), 3);
+        let starts = parsed.intervals.iter().map(|i| (i.uid.as_str(), i.start)).collect::<Vec<_>>();
+        assert_eq!(starts, vec![("sparse", utc(2023, 11, 1, 17, 0))]);
+    }
+
+    #[test]
+    fn windowed_zones_match_the_rules() {
+        let from = utc(2023, 10, 1, 0, 0).naive_utc();
+        let through = utc(2024, 12, 1, 0, 0).naive_utc();
+        let zone = Zone::us(-5);
+        let windowed = zone.clone().with_window(from, through);
+        assert_eq!(windowed.window.as_ref().map(|window| window.onsets.len()), Some(3));
+        let mut local = from - Duration::days(3);
+        while local < through + Duration::days(3) {
+            assert_eq!(windowed.offset_at(local), zone.offset_from_rules(local), "{}", local);
+            local += Duration::hours(1);
+        }
+    }
+
+    #[test]
+    fn rules_and_durations_parse() {
+        assert_eq!(parse_duration("PT1H30M"), Some(Duration::minutes(90)));
+        assert_eq!(parse_duration("P1DT2H"), Some(Duration::hours(26)));
+        assert_eq!(parse_duration("P2W"), Some(Duration::weeks(2)));
+        assert_eq!(parse_duration("1H"), None);
+        assert_eq!(parse_duration("P99999999999999999W"), None);
+        assert!(parse_rrule("FREQ=WEEKLY;BYDAY=éA").is_err());
+        assert_eq!(parse_utc_offset("-0500"), FixedOffset::west_opt(5 * 3600));
+        assert!(parse_rrule("FREQ=HOURLY").is_err());
+        let rrule = parse_rrule("FREQ=MONTHLY;INTERVAL=2;BYMONTHDAY=-1;COUNT=3").unwrap();
+        let start = NaiveDate::from_ymd_opt(2024, 1, 31).unwrap().and_hms_opt(9, 0, 0).unwrap();
+        let dates = rrule
+            .expand(start, None, start, start + Duration::days(365))
+            .iter()
+            .map(|dt| dt.date())
+            .collect::<Vec<_>>();
+        assert_eq!(
+            dates,
+            vec![
+                NaiveDate::from_ymd_opt(2024, 1, 31).unwrap(),
+                NaiveDate::from_ymd_opt(2024, 3, 31).unwrap(),
+                NaiveDate::from_ymd_opt(2024, 5, 31).unwrap(),
+            ]
+        );
+    }
+}
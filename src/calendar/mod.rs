@@ -0,0 +1,8 @@
+pub mod agenda;
+pub mod availability;
+pub mod booking;
+pub mod export;
+pub mod holidays;
+pub mod import;
+pub mod pdf;
+pub mod view;
@@ -0,0 +1,236 @@
+// Just enough PDF to print a text schedule: US Letter pages of left-aligned lines in the standard
+// Helvetica faces, so no fonts are embedded. Text is written in WinAnsiEncoding, which covers Latin-1
+// and the usual typographic punctuation. Anything else prints as '?'.
+
+const PAGE_WIDTH: f32 = 612.0;
+const PAGE_HEIGHT: f32 = 792.0;
+const MARGIN: f32 = 54.0;
+// Helvetica runs about half an em per character, close enough to decide where to wrap
+const AVERAGE_CHAR_WIDTH: f32 = 0.5;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TextStyle {
+    Title,
+    Heading,
+    Body,
+    Detail,
+}
+
+impl TextStyle {
+    fn font(&self) -> &'static str {
+        match self {
+            TextStyle::Title | TextStyle::Heading => "F2",
+            TextStyle::Body | TextStyle::Detail => "F1",
+        }
+    }
+
+    fn size(&self) -> f32 {
+        match self {
+            TextStyle::Title => 16.0,
+            TextStyle::Heading => 12.0,
+            TextStyle::Body => 10.0,
+            TextStyle::Detail => 9.0,
+        }
+    }
+
+    fn leading(&self) -> f32 {
+        self.size() * 1.35
+    }
+}
+
+#[derive(Debug, Default)]
+pub struct PdfText {
+    pages: Vec<String>,
+    current: String,
+    // Baseline of the next line, measured down from the top margin
+    used: f32,
+}
+
+impl PdfText {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    // Wraps at word boundaries and starts a new page when the current one is full
+    pub fn line(&mut self, style: TextStyle, text: &str, indent: f32) {
+        let max_chars = ((PAGE_WIDTH - 2.0 * MARGIN - indent) / (style.size() * AVERAGE_CHAR_WIDTH)) as usize;
+        for part in wrap(text, max_chars) {
+            if self.used + style.leading() > PAGE_HEIGHT - 2.0 * MARGIN {
+                self.break_page();
+            }
+            self.used += style.leading();
+            self.current.push_str(&format!(
+                "BT /{} {} Tf {:.1} {:.1} Td ({}) Tj ET\n",
+                style.font(),
+                style.size(),
+                MARGIN + indent,
+                PAGE_HEIGHT - MARGIN - self.used,
+                encode(&part)
+            ));
+        }
+    }
+
+    pub fn gap(&mut self, points: f32) {
+        self.used += points;
+    }
+
+    pub fn break_page(&mut self) {
+        self.pages.push(std::mem::take(&mut self.current));
+        self.used = 0.0;
+    }
+
+    pub fn finish(mut self) -> Vec<u8> {
+        if !self.current.is_empty() || self.pages.is_empty() {
+            self.break_page();
+        }
+        // 1 catalog, 2 page tree, 3 and 4 fonts, then a page and its content stream per page
+        let page_ids = (0..self.pages.len()).map(|i| 5 + 2 * i).collect::<Vec<usize>>();
+        let mut objects = vec![
+            "<< /Type /Catalog /Pages 2 0 R >>".to_owned(),
+            format!(
+                "<< /Type /Pages /Kids [{}] /Count {} >>",
+                page_ids.iter().map(|id| format!("{} 0 R", id)).collect::<Vec<String>>().join(" "),
+                page_ids.len()
+            ),
+            "<< /Type /Font /Subtype /Type1 /BaseFont /Helvetica /Encoding /WinAnsiEncoding >>".to_owned(),
+            "<< /Type /Font /Subtype /Type1 /BaseFont /Helvetica-Bold /Encoding /WinAnsiEncoding >>".to_owned(),
+        ];
+        for (page, id) in self.pages.iter().zip(&page_ids) {
+            objects.push(format!(
+                "<< /Type /Page /Parent 2 0 R /MediaBox [0 0 {} {}] /Resources << /Font << /F1 3 0 R /F2 4 0 R >> >> /Contents {} 0 R >>",
+                PAGE_WIDTH,
+                PAGE_HEIGHT,
+                id + 1
+            ));
+            objects.push(format!("<< /Length {} >>\nstream\n{}\nendstream", page.len(), page));
+        }
+
+        let mut out = String::from("%PDF-1.4\n");
+        let mut offsets = vec![];
+        for (i, object) in objects.iter().enumerate() {
+            offsets.push(out.len());
+            out.push_str(&format!("{} 0 obj\n{}\nendobj\n", i + 1, object));
+        }
+        let xref_at = out.len();
+        out.push_str(&format!("xref\n0 {}\n0000000000 65535 f \n", objects.len() + 1));
+        for offset in offsets {
+            out.push_str(&format!("{:010} 00000 n \n", offset));
+        }
+        out.push_str(&format!(
+            "trailer\n<< /Size {} /Root 1 0 R >>\nstartxref\n{}\n%%EOF\n",
+            objects.len() + 1,
+            xref_at
+        ));
+        out.into_bytes()
+    }
+}
+
+fn wrap(text: &str, max_chars: usize) -> Vec<String> {
+    let mut lines = vec![];
+    let mut line = String::new();
+    for word in text.split_whitespace() {
+        let word_len = word.chars().count();
+        let line_len = line.chars().count();
+        if line_len > 0 && line_len + 1 + word_len > max_chars {
+            lines.push(std::mem::take(&mut line));
+        }
+        if !line.is_empty() {
+            line.push(' ');
+        }
+        line.push_str(word);
+        // A single word longer than the line is cut rather than run off the page
+        while line.chars().count() > max_chars {
+            let rest = line.chars().skip(max_chars).collect::<String>();
+            lines.push(line.chars().take(max_chars).collect());
+            line = rest;
+        }
+    }
+    if !line.is_empty() || lines.is_empty() {
+        lines.push(line);
+    }
+    lines
+}
+
+// A PDF string literal in WinAnsiEncoding, kept to printable ASCII with octal escapes
+fn encode(text: &str) -> String {
+    let mut out = String::new();
+    for c in text.chars() {
+        let byte = match c {
+            '(' | ')' | '\\' => {
+                out.push('\\');
+                out.push(c);
+                continue;
+            }
+            ' '..='~' => {
+                out.push(c);
+                continue;
+            }
+            '\u{a0}'..='\u{ff}' => c as u32 as u8,
+            '€' => 0x80,
+            '…' => 0x85,
+            '‘' => 0x91,
+            '’' => 0x92,
+            '“' => 0x93,
+            '”' => 0x94,
+            '•' => 0x95,
+            '–' => 0x96,
+            '—' => 0x97,
+            c if c.is_whitespace() => {
+                out.push(' ');
+                continue;
+            }
+            _ => {
+                out.push('?');
+                continue;
+            }
+        };
+        out.push_str(&format!("\\{:03o}", byte));
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn text_is_escaped_for_pdf_strings() {
+        assert_eq!(encode("Suite (B) \\ 2"), "Suite \\(B\\) \\\\ 2");
+        assert_eq!(encode("Café – 5€ 日"), "Caf\\351 \\226 5\\200 ?");
+        assert_eq!(encode("a\tb"), "a b");
+    }
+
+    #[test]
+    fn long_text_wraps_on_words() {
+        assert_eq!(wrap("one two three four", 9), vec!["one two", "three", "four"]);
+        assert_eq!(wrap("abcdefghij", 4), vec!["abcd", "efgh", "ij"]);
+        assert_eq!(wrap("", 4), vec![""]);
+    }
+
+    #[test]
+    fn xref_points_at_each_object() {
+        let mut pdf = PdfText::new();
+        pdf.line(TextStyle::Title, "Agenda", 0.0);
+        for i in 0..80 {
+            pdf.line(TextStyle::Body, &format!("Line {}", i), 12.0);
+        }
+        let bytes = pdf.finish();
+        let text = String::from_utf8(bytes).unwrap();
+        assert!(text.starts_with("%PDF-1.4\n"));
+        assert!(text.ends_with("%%EOF\n"));
+        // 80 body lines don't fit on one Letter page
+        assert!(text.contains("/Count 2"));
+
+        let xref_at = text.rsplit("startxref\n").next().unwrap().lines().next().unwrap().parse::<usize>().unwrap();
+        assert!(text[xref_at..].starts_with("xref\n0 9\n"));
+        let offsets = text[xref_at..]
+            .lines()
+            .skip(3)
+            .take(8)
+            .map(|entry| entry[..10].parse::<usize>().unwrap())
+            .collect::<Vec<usize>>();
+        for (i, offset) in offsets.iter().enumerate() {
+            assert!(text[*offset..].starts_with(&format!("{} 0 obj\n", i + 1)));
+        }
+    }
+}
@@ -0,0 +1,410 @@
+use std::collections::BTreeMap;
+
+use chrono::{DateTime, Datelike, Duration, Months, NaiveDate, NaiveTime, TimeZone, Utc};
+use serde::{Deserialize, Serialize};
+use sqlx::{FromRow, Pool, Postgres, QueryBuilder};
+
+use super::holidays::days_in_month;
+use crate::config::SelectOption;
+use crate::linfa::{consult_offset, meeting_time::day_bounds};
+
+// Month, week and day views over consults. The /event/calendar handler turns a CalendarQuery into a
+// view and anchor date, this works out which dates are on screen, loads the consults in that range and
+// lays them out by day. Weeks run Sunday to Saturday to match the month grid's columns. Days and times
+// are in consult_offset(), the same as the consult forms, so a late consult stays on the day it was
+// booked for.
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum CalendarView {
+    Month,
+    Week,
+    Day,
+}
+
+impl CalendarView {
+    pub fn from_param(param: Option<&str>) -> CalendarView {
+        match param {
+            Some("week") => CalendarView::Week,
+            Some("day") => CalendarView::Day,
+            _ => CalendarView::Month,
+        }
+    }
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            CalendarView::Month => "month",
+            CalendarView::Week => "week",
+            CalendarView::Day => "day",
+        }
+    }
+}
+
+// Everything comes in as strings so the filter selects' empty "All" option parses.
+#[derive(Debug, Clone, Default, Deserialize)]
+pub struct CalendarQuery {
+    pub view: Option<String>,
+    pub date: Option<String>,
+    pub consultant_id: Option<String>,
+    pub location_id: Option<String>,
+    pub client_id: Option<String>,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Serialize, Deserialize)]
+pub struct CalendarFilters {
+    pub consultant_id: Option<i32>,
+    pub location_id: Option<i32>,
+    pub client_id: Option<i32>,
+}
+
+fn filter_id(param: &Option<String>) -> Option<i32> {
+    param.as_deref().and_then(|id| id.parse().ok())
+}
+
+impl CalendarQuery {
+    pub fn view(&self) -> CalendarView {
+        CalendarView::from_param(self.view.as_deref())
+    }
+
+    pub fn date(&self, today: NaiveDate) -> NaiveDate {
+        self.date
+            .as_deref()
+            .and_then(|date| NaiveDate::parse_from_str(date, "%Y-%m-%d").ok())
+            .unwrap_or(today)
+    }
+
+    pub fn filters(&self) -> CalendarFilters {
+        CalendarFilters {
+            consultant_id: filter_id(&self.consultant_id),
+            location_id: filter_id(&self.location_id),
+            client_id: filter_id(&self.client_id),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize, FromRow)]
+pub struct CalendarConsult {
+    pub id: i32,
+    pub slug: String,
+    pub consult_purpose_id: i32,
+    pub consult_start: DateTime<Utc>,
+    pub consult_end: Option<DateTime<Utc>>,
+    pub client_name: Option<String>,
+    pub consultant_name: Option<String>,
+    pub location_name: String,
+    // Requested from a booking link and not yet confirmed
+    pub pending: bool,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct CalendarEntry {
+    pub consult: CalendarConsult,
+    pub start_time: String,
+    pub end_time: Option<String>,
+}
+
+impl From<CalendarConsult> for CalendarEntry {
+    fn from(consult: CalendarConsult) -> Self {
+        CalendarEntry {
+            start_time: consult.consult_start.with_timezone(&consult_offset()).format("%H:%M").to_string(),
+            end_time: consult.consult_end.map(|end| end.with_timezone(&consult_offset()).format("%H:%M").to_string()),
+            consult,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct CalendarDay {
+    pub date: NaiveDate,
+    pub day: u32,
+    pub weekday: String,
+    // False for the leading/trailing days a month grid shows from its neighbours
+    pub in_month: bool,
+    pub is_today: bool,
+    pub holiday: Option<String>,
+    // Who's off, and the filtered consultant's working hours
+    pub availability: Vec<String>,
+    pub consults: Vec<CalendarEntry>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct CalendarPage {
+    pub view: CalendarView,
+    pub title: String,
+    pub date: NaiveDate,
+    pub weeks: Vec<Vec<CalendarDay>>,
+    pub filters: CalendarFilters,
+    pub prev_url: String,
+    pub next_url: String,
+    pub today_url: String,
+    pub month_url: String,
+    pub week_url: String,
+    pub day_url: String,
+    pub consultant_options: Vec<SelectOption>,
+    pub location_options: Vec<SelectOption>,
+    pub client_options: Vec<SelectOption>,
+}
+
+// The day a time falls on in the consults' offset
+pub fn local_date(time: DateTime<Utc>) -> NaiveDate {
+    time.with_timezone(&consult_offset()).date_naive()
+}
+
+fn local_time(date: NaiveDate, time: NaiveTime) -> DateTime<Utc> {
+    consult_offset().from_local_datetime(&date.and_time(time)).unwrap().with_timezone(&Utc)
+}
+
+fn week_start(date: NaiveDate) -> NaiveDate {
+    date - Duration::days(date.weekday().num_days_from_sunday() as i64)
+}
+
+fn first_of_month(date: NaiveDate) -> NaiveDate {
+    date.with_day(1).unwrap()
+}
+
+// Dates on screen, end exclusive. A month view covers whole weeks.
+pub fn visible_range(view: CalendarView, date: NaiveDate) -> (NaiveDate, NaiveDate) {
+    match view {
+        CalendarView::Month => {
+            let first = first_of_month(date);
+            let last = first + Duration::days(days_in_month(first.year(), first.month()) as i64 - 1);
+            (week_start(first), week_start(last) + Duration::days(7))
+        }
+        CalendarView::Week => {
+            let start = week_start(date);
+            (start, start + Duration::days(7))
+        }
+        CalendarView::Day => (date, date + Duration::days(1)),
+    }
+}
+
+// Anchor date for the prev (-1) / next (+1) buttons
+pub fn step(view: CalendarView, date: NaiveDate, dir: i32) -> NaiveDate {
+    match view {
+        CalendarView::Month if dir < 0 => first_of_month(date).checked_sub_months(Months::new(1)).unwrap_or(date),
+        CalendarView::Month => first_of_month(date).checked_add_months(Months::new(1)).unwrap_or(date),
+        CalendarView::Week => date + Duration::days(7 * dir as i64),
+        CalendarView::Day => date + Duration::days(dir as i64),
+    }
+}
+
+pub fn calendar_title(view: CalendarView, date: NaiveDate) -> String {
+    match view {
+        CalendarView::Month => date.format("%B %Y").to_string(),
+        CalendarView::Week => format!("Week of {}", week_start(date).format("%b %-d, %Y")),
+        CalendarView::Day => date.format("%A, %B %-d, %Y").to_string(),
+    }
+}
+
+pub fn calendar_url(view: CalendarView, date: NaiveDate, filters: &CalendarFilters) -> String {
+    let mut url = format!("/event/calendar?view={}&date={}", view.as_str(), date.format("%Y-%m-%d"));
+    for (name, id) in [
+        ("consultant_id", filters.consultant_id),
+        ("location_id", filters.location_id),
+        ("client_id", filters.client_id),
+    ] {
+        if let Some(id) = id {
+            url.push_str(&format!("&{}={}", name, id));
+        }
+    }
+    url
+}
+
+// Consults must be ordered by start. Rows of 7 days for month and week, a single day for the day view.
+pub fn lay_out(
+    view: CalendarView,
+    date: NaiveDate,
+    today: NaiveDate,
+    consults: Vec<CalendarConsult>,
+    holidays: &BTreeMap<NaiveDate, String>,
+    availability: &BTreeMap<NaiveDate, Vec<String>>,
+) -> Vec<Vec<CalendarDay>> {
+    let (start, end) = visible_range(view, date);
+    let mut consults = consults.into_iter().peekable();
+    let mut days = vec![];
+    let mut day = start;
+    while day < end {
+        let mut entries = vec![];
+        while let Some(consult) = consults.next_if(|consult| local_date(consult.consult_start) <= day) {
+            if local_date(consult.consult_start) == day {
+                entries.push(CalendarEntry::from(consult));
+            }
+        }
+        days.push(CalendarDay {
+            date: day,
+            day: day.day(),
+            weekday: day.format("%a").to_string(),
+            in_month: view != CalendarView::Month || day.month() == date.month(),
+            is_today: day == today,
+            holiday: holidays.get(&day).cloned(),
+            availability: availability.get(&day).cloned().unwrap_or_default(),
+            consults: entries,
+        });
+        day += Duration::days(1);
+    }
+    let row_len = if view == CalendarView::Day { 1 } else { 7 };
+    days.chunks(row_len).map(|row| row.to_vec()).collect()
+}
+
+// Where a dragged consult lands: the new day, at a new start time if one was picked, keeping its
+// length. The day and time are local like the rest of the calendar.
+pub fn moved_window(
+    start: DateTime<Utc>,
+    end: Option<DateTime<Utc>>,
+    to_date: NaiveDate,
+    start_time: Option<NaiveTime>,
+) -> (DateTime<Utc>, DateTime<Utc>) {
+    let length = end.map_or(Duration::hours(1), |end| end - start);
+    let new_start = local_time(to_date, start_time.unwrap_or(start.with_timezone(&consult_offset()).time()));
+    (new_start, new_start + length)
+}
+
+// A resize moves the end on the consult's own day
+pub fn resized_end(start: DateTime<Utc>, end_time: NaiveTime) -> Option<DateTime<Utc>> {
+    Some(local_time(local_date(start), end_time)).filter(|end| *end > start)
+}
+
+pub fn parse_time(time: &str) -> Option<NaiveTime> {
+    NaiveTime::parse_from_str(time, "%H:%M").ok()
+}
+
+pub async fn consults_in_range(
+    db: &Pool<Postgres>,
+    start: NaiveDate,
+    end: NaiveDate,
+    filters: &CalendarFilters,
+) -> Result<Vec<CalendarConsult>, sqlx::Error> {
+    let mut query = QueryBuilder::<Postgres>::new(
+        "SELECT consults.id, consults.slug, consult_purpose_id, consult_start, consult_end,
+                consultant_f_name || ' ' || consultant_l_name AS consultant_name,
+                COALESCE(client_company_name, client_f_name || ' ' || client_l_name) AS client_name,
+                location_name, booking_status = 'pending' AS pending
+            FROM consults
+            LEFT JOIN consultants ON consultants.id = consults.consultant_id
+            INNER JOIN clients ON clients.id = consults.client_id
+            INNER JOIN locations ON locations.id = consults.location_id
+            WHERE cancelled_at IS NULL AND consult_start >= ",
+    );
+    query.push_bind(day_bounds(start).0);
+    query.push(" AND consult_start < ");
+    query.push_bind(day_bounds(end).0);
+    if let Some(id) = filters.consultant_id {
+        query.push(" AND consults.consultant_id = ").push_bind(id);
+    }
+    if let Some(id) = filters.location_id {
+        query.push(" AND consults.location_id = ").push_bind(id);
+    }
+    if let Some(id) = filters.client_id {
+        query.push(" AND consults.client_id = ").push_bind(id);
+    }
+    query.push(" ORDER BY consult_start");
+    query.build_query_as::<CalendarConsult>().fetch_all(db).await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::calendar::holidays::{federal_holidays_in_range, holidays_by_date};
+
+    fn date(y: i32, m: u32, d: u32) -> NaiveDate {
+        NaiveDate::from_ymd_opt(y, m, d).unwrap()
+    }
+
+    fn consult(id: i32, start: DateTime<Utc>) -> CalendarConsult {
+        CalendarConsult {
+            id,
+            slug: format!("slug-{}", id),
+            consult_purpose_id: 1,
+            consult_start: start,
+            consult_end: Some(start + Duration::hours(1)),
+            client_name: Some("Client".to_string()),
+            consultant_name: None,
+            location_name: "Office".to_string(),
+            pending: false,
+        }
+    }
+
+    #[test]
+    fn ranges_cover_whole_weeks_and_steps_move_by_view() {
+        // November 2023 starts on a Wednesday and ends on a Thursday
+        assert_eq!(visible_range(CalendarView::Month, date(2023, 11, 15)), (date(2023, 10, 29), date(2023, 12, 3)));
+        assert_eq!(visible_range(CalendarView::Week, date(2023, 11, 24)), (date(2023, 11, 19), date(2023, 11, 26)));
+        assert_eq!(visible_range(CalendarView::Day, date(2023, 11, 24)), (date(2023, 11, 24), date(2023, 11, 25)));
+        assert_eq!(step(CalendarView::Month, date(2023, 12, 31), 1), date(2024, 1, 1));
+        assert_eq!(step(CalendarView::Month, date(2024, 1, 15), -1), date(2023, 12, 1));
+        assert_eq!(step(CalendarView::Week, date(2023, 11, 24), -1), date(2023, 11, 17));
+        // February 2024 has 29 days, the 29th is a Thursday
+        assert_eq!(visible_range(CalendarView::Month, date(2024, 2, 10)), (date(2024, 1, 28), date(2024, 3, 3)));
+    }
+
+    fn local(d: u32, h: u32, min: u32) -> DateTime<Utc> {
+        consult_offset().with_ymd_and_hms(2023, 11, d, h, min, 0).unwrap().with_timezone(&Utc)
+    }
+
+    #[test]
+    fn consults_land_on_their_day() {
+        // Consult 4 is 23:30 local on the 20th, already the 21st in UTC
+        let consults = vec![
+            consult(1, local(20, 9, 0)),
+            consult(2, local(20, 14, 30)),
+            consult(4, local(20, 23, 30)),
+            consult(3, local(24, 10, 0)),
+        ];
+        let weeks = lay_out(CalendarView::Week, date(2023, 11, 22), date(2023, 11, 22), consults, &BTreeMap::new(), &BTreeMap::new());
+        assert_eq!(weeks.len(), 1);
+        let week = &weeks[0];
+        assert_eq!(week[1].date, date(2023, 11, 20));
+        assert_eq!(week[1].consults.iter().map(|e| e.consult.id).collect::<Vec<_>>(), vec![1, 2, 4]);
+        assert_eq!(week[1].consults[1].start_time, "14:30");
+        assert_eq!(week[1].consults[2].start_time, "23:30");
+        assert!(week[2].consults.is_empty());
+        assert_eq!(week[5].consults.len(), 1);
+        assert!(week[3].is_today);
+
+        let (start, end) = visible_range(CalendarView::Month, date(2023, 11, 1));
+        let holidays = holidays_by_date(federal_holidays_in_range(start, end), vec![]);
+        let availability = BTreeMap::from([(date(2023, 11, 24), vec!["Pat Doe off".to_string()])]);
+        let month = lay_out(CalendarView::Month, date(2023, 11, 1), date(2023, 11, 22), vec![], &holidays, &availability);
+        assert_eq!(month.len(), 5);
+        assert!(!month[0][0].in_month);
+        assert_eq!(month[3][4].holiday.as_deref(), Some("Thanksgiving Day"));
+        assert_eq!(month[3][5].holiday, None);
+        assert_eq!(month[3][5].availability, vec!["Pat Doe off".to_string()]);
+        assert!(month[3][4].availability.is_empty());
+    }
+
+    #[test]
+    fn moves_keep_length_and_resizes_stay_on_the_day() {
+        let start = local(20, 9, 0);
+        let end = start + Duration::minutes(90);
+        let (moved_start, moved_end) = moved_window(start, Some(end), date(2023, 11, 22), None);
+        assert_eq!(moved_start, local(22, 9, 0));
+        assert_eq!(moved_end - moved_start, Duration::minutes(90));
+        let (moved_start, moved_end) = moved_window(start, None, date(2023, 11, 20), parse_time("14:30"));
+        assert_eq!(moved_start, local(20, 14, 30));
+        assert_eq!(moved_end - moved_start, Duration::hours(1));
+        // 19:00 local is past midnight UTC, the move keeps the local day and time
+        let (moved_start, _) = moved_window(local(20, 19, 0), None, date(2023, 11, 22), None);
+        assert_eq!(moved_start, local(22, 19, 0));
+
+        assert_eq!(resized_end(start, parse_time("11:15").unwrap()), Some(local(20, 11, 15)));
+        assert_eq!(resized_end(start, parse_time("08:00").unwrap()), None);
+        assert_eq!(resized_end(local(20, 17, 0), parse_time("19:00").unwrap()), Some(local(20, 19, 0)));
+        assert_eq!(parse_time("9am"), None);
+    }
+
+    #[test]
+    fn urls_keep_filters() {
+        let filters = CalendarQuery {
+            consultant_id: Some("3".to_string()),
+            client_id: Some("".to_string()),
+            ..Default::default()
+        }
+        .filters();
+        assert_eq!(filters, CalendarFilters { consultant_id: Some(3), location_id: None, client_id: None });
+        assert_eq!(
+            calendar_url(CalendarView::Week, date(2023, 11, 24), &filters),
+            "/event/calendar?view=week&date=2023-11-24&consultant_id=3"
+        );
+    }
+}
@@ -3,10 +3,14 @@ use actix_web::{web::Data, HttpRequest};
 use chrono::{DateTime, Utc};
 use futures_util::{stream, Stream, StreamExt};
 use lazy_static::lazy_static;
-use lettre::{message::header::ContentType, transport::stub::StubTransport, Message, Transport};
+use lettre::{
+    message::{header::ContentType, Attachment, MultiPart, SinglePart},
+    transport::stub::StubTransport,
+    Message, Transport,
+};
 use mini_markdown::render;
 use rand::distributions::{Distribution, Uniform};
-use redis::{FromRedisValue, from_redis_value, RedisResult, Value, ErrorKind};
+use redis::{AsyncCommands, RedisResult};
 use regex::Regex;
 use serde::{Deserialize, Serialize};
 use serde_yaml::{self};
@@ -24,8 +28,9 @@ use struct_iterable::Iterable;
 use validator::{Validate, ValidationError, ValidationErrors};
 use std::{collections::hash_map::DefaultHasher, hash::{Hash, Hasher}};
 
+use crate::redis_mod::typed;
 use crate::scopes::consult::OwnedQuery;
-use crate::{AppState, HeaderValueExt, ValidatedUser, RedisState};
+use crate::{AppState, CachedSession, HeaderValueExt, ValidatedUser, RedisState};
 
 lazy_static! {
     pub static ref RE_USERNAME: Regex = Regex::new(r"^[a-zA-Z0-9]{4,}$").unwrap();
@@ -136,17 +141,6 @@ pub struct SelectOptionsVec {
     pub vec: Vec<SelectOption>
 }
 
-impl FromRedisValue for SelectOptionsVec {
-    fn from_redis_value(v: &Value) -> RedisResult<Self> {
-        let v: String = from_redis_value(v)?;
-        let result: Self = match serde_json::from_str::<Self>(&v) {
-          Ok(v) => v,
-          Err(_err) => return Err((ErrorKind::TypeError, "Parse to JSON Failed").into())
-        };
-        Ok(result)
-    }
-}
-
 impl From<(i32, Option<String>)> for SelectOption {
     fn from(pair: (i32, Option<String>)) -> Self {
         let (value, key) = pair;
@@ -157,17 +151,6 @@ impl From<(i32, Option<String>)> for SelectOption {
     }
 }
 
-impl FromRedisValue for SelectOption {
-    fn from_redis_value(v: &Value) -> RedisResult<Self> {
-        let v: String = from_redis_value(v)?;
-        let result: Self = match serde_json::from_str::<Self>(&v) {
-          Ok(v) => v,
-          Err(_err) => return Err((ErrorKind::TypeError, "Parse to JSON Failed").into())
-        };
-        Ok(result)
-    }
-}
-
 #[derive(Debug, Validate, Serialize, FromRow, Clone, Deserialize)]
 pub struct StringSelectOption {
     pub value: String,
@@ -965,10 +948,83 @@ pub fn get_validation_response(is_valid: Result<(), ValidationErrors>) -> FormEr
     validation_response
 }
 
+// A field where a stale edit and the saved record disagree.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct FieldConflict {
+    pub field: String,
+    pub yours: String,
+    pub current: String,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct EditConflictTemplate {
+    pub conflicts: Vec<FieldConflict>,
+    // Swapped into the form's hidden version input, so submitting again overwrites on purpose.
+    pub updated_at: Option<DateTime<Utc>>,
+}
+
+fn conflict_value(val: &serde_json::Value) -> String {
+    match val {
+        serde_json::Value::Null => String::new(),
+        serde_json::Value::String(s) => s.trim().to_owned(),
+        other => other.to_string(),
+    }
+}
+
+// Compares a submitted form to the saved record field by field. Only fields both have are compared,
+// and empty strings match NULLs since that's how an empty input comes back.
+pub fn edit_conflicts<A: Serialize, B: Serialize>(submitted: &A, current: &B) -> Vec<FieldConflict> {
+    let (submitted, current) = match (serde_json::to_value(submitted), serde_json::to_value(current)) {
+        (Ok(serde_json::Value::Object(submitted)), Ok(serde_json::Value::Object(current))) => (submitted, current),
+        _ => return vec![],
+    };
+    submitted
+        .iter()
+        .filter(|(field, _)| field.as_str() != "updated_at")
+        .filter_map(|(field, yours)| {
+            let (yours, current) = (conflict_value(yours), conflict_value(current.get(field)?));
+            (yours != current).then(|| FieldConflict {
+                field: field.clone(),
+                yours,
+                current,
+            })
+        })
+        .collect()
+}
+
+// How long a login stays cached in Redis. The session row itself lasts longer.
+pub const SESSION_CACHE_SECS: usize = 86400;
+
+// Never past the session's own expiry. None once it has expired.
+pub fn session_cache_secs(expires: DateTime<Utc>, now: DateTime<Utc>) -> Option<usize> {
+    let left = (expires - now).num_seconds();
+    (left > 0).then(|| (left as usize).min(SESSION_CACHE_SECS))
+}
+
+pub async fn cache_session(con: &mut deadpool_redis::Connection, session_id: &str, session: &CachedSession) {
+    if let Some(ttl) = session_cache_secs(session.expires, Utc::now()) {
+        let _: RedisResult<()> = typed::set_ex(con, session_id, session, ttl).await;
+    }
+}
+
 pub async fn validate_and_get_user(
     cookie: &actix_web::http::header::HeaderValue,
     state: &Data<AppState>,
 ) -> Result<Option<ValidatedUser>, crate::ValError> {
+    session_user(cookie, &state.db).await
+}
+
+pub async fn session_user(
+    cookie: &actix_web::http::header::HeaderValue,
+    db: &Pool<Postgres>,
+) -> Result<Option<ValidatedUser>, crate::ValError> {
+    Ok(active_session(cookie, db).await?.map(|session| session.user))
+}
+
+pub async fn active_session(
+    cookie: &actix_web::http::header::HeaderValue,
+    db: &Pool<Postgres>,
+) -> Result<Option<CachedSession>, crate::ValError> {
     println!("Validating {}", format!("{:?}", cookie.clone()));
     let session_id = if cookie.to_string().split(" ").collect::<Vec<&str>>().len() > 1 {
         cookie.to_string().split(" ").collect::<Vec<&str>>()[1].to_string()
@@ -976,8 +1032,8 @@ pub async fn validate_and_get_user(
         cookie.to_string()
     };
     dbg!(&session_id);
-    match sqlx::query_as::<_, ValidatedUser>(
-        "SELECT username, email, user_type_id, user_subs, client_subs, consult_subs, location_subs, consultant_subs, user_settings.list_view
+    match sqlx::query_as::<_, CachedSession>(
+        "SELECT username, email, user_type_id, user_subs, client_subs, consult_subs, location_subs, consultant_subs, user_settings.list_view, expires
         FROM users
         LEFT JOIN user_sessions ON user_sessions.user_id = users.id
         LEFT JOIN user_settings ON user_settings.user_id = users.id
@@ -985,7 +1041,7 @@ pub async fn validate_and_get_user(
         AND expires > NOW()",
     )
     .bind(session_id)
-    .fetch_optional(&state.db)
+    .fetch_optional(db)
     .await
     {
         Ok(user_option) => Ok(user_option),
@@ -998,31 +1054,45 @@ pub async fn validate_and_get_user(
     }
 }
 
+// Sessions are cached in Redis at login. A miss, an expired entry, or Redis being down, falls back to the
+// session row in Postgres and re-caches it when Redis is there to take it.
 pub async fn redis_validate_and_get_user(
     cookie: &actix_web::http::header::HeaderValue,
     r_state: &Data<RedisState>,
 ) -> Result<ValidatedUser, crate::ValError> {
     println!("Redis Validation");
-    let mut con = r_state.r_pool.get().await.unwrap();
-    match redis::cmd("GET")
-    .arg(cookie.to_string())
-    .query_async(&mut con)
-    .await
-    {
-        Ok(user) => Ok(user),
-        Err(err) => {
-            dbg!(&err);
-            Err(crate::ValError {
-                // FIXME: msg
-                error: format!("Not in Redis = You must not be verified: {}", err),
-            })
+    let key = cookie.to_string();
+    let mut con = r_state.conn().await;
+    if let Some(con) = con.as_mut() {
+        let cached: RedisResult<Option<CachedSession>> = typed::get(con, &key).await;
+        match cached {
+            Ok(Some(session)) if session.expires > Utc::now() => return Ok(session.user),
+            Ok(_) => {}
+            Err(err) => {
+                dbg!(&err);
+                r_state.record_error(&err);
+            }
         }
     }
+    match active_session(cookie, &r_state.db).await? {
+        Some(session) => {
+            if let Some(con) = con.as_mut() {
+                cache_session(con, &key, &session).await;
+            }
+            Ok(session.user)
+        }
+        None => Err(crate::ValError {
+            error: "You must not be verified: no active session".to_owned(),
+        }),
+    }
 }
 
 pub struct SendEmailInput {
     to_email: String,
     msg: String,
+    subject: Option<String>,
+    // iTIP method (REQUEST, CANCEL) and the iCalendar body to send with it
+    calendar: Option<(String, String)>,
 }
 
 impl From<(&str, &str)> for SendEmailInput {
@@ -1031,19 +1101,53 @@ impl From<(&str, &str)> for SendEmailInput {
         SendEmailInput {
             to_email: to_email.to_string(),
             msg: msg.to_string(),
+            subject: None,
+            calendar: None,
         }
     }
 }
 
+impl SendEmailInput {
+    pub fn subject(mut self, subject: &str) -> Self {
+        self.subject = Some(subject.to_owned());
+        self
+    }
+
+    pub fn calendar(mut self, method: &str, ics: String) -> Self {
+        self.calendar = Some((method.to_owned(), ics));
+        self
+    }
+}
+
+// Mail clients show the text/calendar alternative as an invite with accept/decline. The same
+// calendar is attached as invite.ics for clients that don't.
+fn invite_body(msg: &str, method: &str, ics: &str) -> MultiPart {
+    let calendar_type = ContentType::parse(&format!("text/calendar; method={}; charset=UTF-8", method)).unwrap();
+    MultiPart::mixed()
+        .multipart(
+            MultiPart::alternative()
+                .singlepart(SinglePart::plain(msg.to_owned()))
+                .singlepart(SinglePart::builder().content_type(calendar_type).body(ics.to_owned())),
+        )
+        .singlepart(Attachment::new("invite.ics".to_owned()).body(ics.to_owned(), ContentType::parse("application/ics").unwrap()))
+}
+
 pub async fn send_email(email_input: SendEmailInput) -> Result<(), String> {
-    let email = Message::builder()
+    // A bad address on file is the job's error, retried and dead-lettered like any other
+    let to = email_input
+        .to_email
+        .parse()
+        .map_err(|err| format!("Invalid email address {:?}: {}", email_input.to_email, err))?;
+    let builder = Message::builder()
         .from("NoBody <nobody@domain.tld>".parse().unwrap())
         .reply_to("Yuin <yuin@domain.tld>".parse().unwrap())
-        .to(email_input.to_email.parse().unwrap())
-        .subject("Happy new year")
-        .header(ContentType::TEXT_PLAIN)
-        .body(email_input.msg.to_owned())
-        .unwrap();
+        .to(to)
+        .subject(email_input.subject.as_deref().unwrap_or("Happy new year"));
+    let email = match &email_input.calendar {
+        Some((method, ics)) => builder.multipart(invite_body(&email_input.msg, method, ics)),
+        None => builder.header(ContentType::TEXT_PLAIN).body(email_input.msg.to_owned()),
+    }
+    .map_err(|err| format!("Error building email: {}", err))?;
 
     // dbg!(&email);
 
@@ -1088,4 +1192,47 @@ mod tests {
         let result = send_email(email_input).await;
         assert!(result.is_ok());
     }
+
+    #[test]
+    async fn send_email_rejects_bad_addresses() {
+        let result = send_email(SendEmailInput::from(("not an address", "Hi"))).await;
+        assert!(result.unwrap_err().starts_with("Invalid email address"));
+    }
+
+    #[test]
+    async fn invites_carry_the_calendar_method() {
+        let ics = "BEGIN:VCALENDAR\r\nMETHOD:REQUEST\r\nEND:VCALENDAR\r\n";
+        let body = String::from_utf8(invite_body("You're invited", "REQUEST", ics).formatted()).unwrap();
+        assert!(body.contains("text/calendar; method=REQUEST;"));
+        assert!(body.contains("filename=\"invite.ics\""));
+        let email_input = SendEmailInput::from(("JimboTest@Test.com", "You're invited"))
+            .subject("Invitation")
+            .calendar("REQUEST", ics.to_owned());
+        assert!(send_email(email_input).await.is_ok());
+    }
+
+    #[test]
+    async fn edit_conflicts_only_report_changed_shared_fields() {
+        let submitted = serde_json::json!({"client_city": "Omaha", "client_dob": "", "account_id": 2, "updated_at": "x", "linfa_assign": "on"});
+        let current = serde_json::json!({"client_city": "Lincoln", "client_dob": null, "account_id": 2, "updated_at": "y"});
+        let conflicts = edit_conflicts(&submitted, &current);
+        assert_eq!(
+            conflicts,
+            vec![FieldConflict {
+                field: "client_city".to_owned(),
+                yours: "Omaha".to_owned(),
+                current: "Lincoln".to_owned(),
+            }]
+        );
+    }
+
+    #[test]
+    async fn cached_sessions_never_outlive_the_session() {
+        let now = DateTime::parse_from_rfc3339("2023-11-24T12:00:00Z").unwrap().with_timezone(&Utc);
+        let in_secs = |secs: i64| now + chrono::Duration::seconds(secs);
+        assert_eq!(session_cache_secs(in_secs(90), now), Some(90));
+        assert_eq!(session_cache_secs(in_secs(SESSION_CACHE_SECS as i64 * 30), now), Some(SESSION_CACHE_SECS));
+        assert_eq!(session_cache_secs(now, now), None);
+        assert_eq!(session_cache_secs(in_secs(-5), now), None);
+    }
 }
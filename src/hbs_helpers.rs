@@ -3,8 +3,8 @@ use handlebars::handlebars_helper;
 use serde::{Deserialize, Serialize};
 
 use crate::{
-    config::{UserSubscriptions, FilterOptions}, models::model_location::LocationList, scopes::event::CalendarData,
-    Entity,
+    config::{UserSubscriptions, FilterOptions}, models::model_location::LocationList,
+    redis_mod::live_updates::LiveEntity, Entity,
 };
 
 handlebars_helper!(to_title_case: |s: String| {
@@ -40,9 +40,15 @@ handlebars_helper!(str_in: |s_1: String, list: Vec<String>| {
     list.contains(&s_1)
 });
 
+// Counted in chars so short text and multi-byte characters don't panic
+pub const PREVIEW_CHARS: usize = 50;
+
+pub fn preview(text: &str) -> String {
+    text.chars().take(PREVIEW_CHARS).collect()
+}
+
 handlebars_helper!(preview_text: |str: String| {
-    let prev = &str[0..50];
-    prev
+    preview(&str)
 });
 
 handlebars_helper!(form_rte: |slug: String, entity_type_id: i32| {
@@ -60,17 +66,6 @@ handlebars_helper!(form_rte: |slug: String, entity_type_id: i32| {
     }
 });
 
-handlebars_helper!(cal_rte: |dir: String, month: i32, year: i32| {
-    String::from("calendar/move?dir=") + &dir + "&year=" + &year.to_string() + "&month=" + &month.to_string()
-});
-
-handlebars_helper!(is_holiday: |day: String, holidays: Vec<(i32, String)>| {
-    let day_list = holidays.iter().map(|holiday| holiday.0).collect::<Vec<i32>>();
-    let int = day.parse::<i32>().unwrap_or(0);
-    day_list.contains(&int)
-});
-
-
 handlebars_helper!(sort_rte: |key: String, entity_type_id: i32, dir: String| {
     match entity_type_id {
         1 => String::from("/admin/list?key=") + &key + "&dir=" + &dir,
@@ -287,6 +282,13 @@ handlebars_helper!(get_search_rte: |entity_type_id: i32, opts: FilterOptions| {
     }
 });
 
+// SSE event that should refresh this entity's table, empty when the entity has no live updates.
+handlebars_helper!(live_event: |entity_type_id: i32| {
+    LiveEntity::from_entity_type_id(entity_type_id)
+        .map(|entity| entity.changed_event())
+        .unwrap_or_default()
+});
+
 handlebars_helper!(get_table_title: |entity_type_id: i32| {
     match entity_type_id {
         1 => String::from("Users"),
@@ -301,48 +303,6 @@ handlebars_helper!(get_table_title: |entity_type_id: i32| {
     }
 });
 
-// Calendar Helpers
-
-handlebars_helper!(first_week: |day: u32, cal_data: CalendarData| {
-    // n - first_d_o_m + 1 (Thurs = 5. Tues = 3.)
-    // i32 for negative
-    let offset_int: i32 = day as i32 - cal_data.first_day_of_month as i32 + 1 as i32;
-    if offset_int > 0 {
-        offset_int.to_string()
-    } else {
-        "".to_string()
-    }
-});
-
-handlebars_helper!(second_week: |day: u32, cal_data: CalendarData| {
-    // + 1 + 7
-    let offset_int: i32 = day as i32 - cal_data.first_day_of_month as i32 + 8 as i32;
-    offset_int.to_string()
-});
-
-handlebars_helper!(third_week: |day: u32, cal_data: CalendarData| {
-    // + 1 + 14
-    let offset_int: i32 = day as i32 - cal_data.first_day_of_month as i32 + 15 as i32;
-    offset_int.to_string()
-});
-
-handlebars_helper!(fourth_week: |day: u32, cal_data: CalendarData| {
-    // + 1 + 21
-    let offset_int: i32 = day as i32 - cal_data.first_day_of_month as i32 + 22 as i32;
-    offset_int.to_string()
-});
-
-handlebars_helper!(fifth_week: |day: u32, cal_data: CalendarData| {
-    // + 1 + 28
-    let offset_int: i32 = day as i32 - cal_data.first_day_of_month as i32 + 28 as i32;
-    // FIXME Account for Feb & 30 day months
-    if offset_int < 31 {
-        offset_int.to_string()
-    } else {
-        "".to_string()
-    }
-});
-
 handlebars_helper!(get_month_name: |month: u32| {
     match month {
         1 => "January",
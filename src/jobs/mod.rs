@@ -0,0 +1,222 @@
+use chrono::Utc;
+use deadpool_redis::Pool as RedisPool;
+use image::imageops::FilterType;
+use serde::{Deserialize, Serialize};
+use sqlx::{Pool, Postgres};
+
+use crate::{
+    calendar::export::{
+        consult_calendar, ics_consult_by_id, invite_recipients, invite_subject, invite_text, itip_calendar, IcsConsult,
+        ItipMethod,
+    },
+    config::{send_email, SendEmailInput},
+    jobs::queue::enqueue,
+    linfa::churn::write_churn_scores,
+};
+
+pub mod periodic;
+pub mod queue;
+pub mod schedule;
+pub mod worker;
+
+// Work that used to run inline in request handlers. Handlers enqueue a Job and return, a worker
+// (in-server, or `--worker` as its own process) picks it up. Jobs are stored as JSON in Redis, so
+// keep variants to plain data.
+
+// Kept out of ./static, which is served to anyone. Consults' .ics are only handed out through the
+// authenticated and tokenized calendar handlers.
+pub const ICS_DIR: &str = "./data/ics/";
+// Where older builds wrote them, cleared as each consult is regenerated
+const PUBLIC_ICS_DIR: &str = "./static/ics/";
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub enum Job {
+    SendEmail {
+        to_email: String,
+        msg: String,
+        // Jobs queued before subjects were added have none
+        #[serde(default)]
+        subject: Option<String>,
+    },
+    // Resizes `source` into `destination` and removes `source`.
+    ResizeImage {
+        source: String,
+        destination: String,
+        width: u32,
+        height: u32,
+    },
+    // Rewrites the consult's .ics from its current row, so retries and reschedules stay in sync
+    GenerateIcs {
+        consult_id: i32,
+    },
+    // Fans out into a SendInvite for the client and one for the consultant, so a bad address retries on its own
+    SendInvites {
+        consult_id: i32,
+        method: ItipMethod,
+    },
+    // Emails one attendee an iTIP invite built from the consult's current row
+    SendInvite {
+        consult_id: i32,
+        method: ItipMethod,
+        to_email: String,
+    },
+    ScoreChurn {
+        horizon_days: i64,
+    },
+    SendConsultReminders {
+        hours_ahead: i64,
+    },
+    PurgeExpired,
+    SendDigests {
+        days: i64,
+    },
+}
+
+// r_pool lets a job fan out into more jobs
+#[derive(Clone)]
+pub struct JobContext {
+    pub db: Pool<Postgres>,
+    pub r_pool: RedisPool,
+}
+
+impl Job {
+    pub fn name(&self) -> &'static str {
+        match self {
+            Job::SendEmail { .. } => "Send email",
+            Job::ResizeImage { .. } => "Resize image",
+            Job::GenerateIcs { .. } => "Generate ICS",
+            Job::SendInvites { .. } => "Send invites",
+            Job::SendInvite { .. } => "Send invite",
+            Job::ScoreChurn { .. } => "Score churn",
+            Job::SendConsultReminders { .. } => "Consult reminders",
+            Job::PurgeExpired => "Purge expired",
+            Job::SendDigests { .. } => "Subscription digests",
+        }
+    }
+
+    pub async fn run(&self, ctx: &JobContext) -> Result<(), String> {
+        match self {
+            Job::SendEmail { to_email, msg, subject } => {
+                let email = SendEmailInput::from((to_email.as_str(), msg.as_str()));
+                send_email(match subject {
+                    Some(subject) => email.subject(subject),
+                    None => email,
+                })
+                .await
+            }
+            Job::ResizeImage {
+                source,
+                destination,
+                width,
+                height,
+            } => {
+                let (source, destination, width, height) =
+                    (source.clone(), destination.clone(), *width, *height);
+                actix_web::rt::task::spawn_blocking(move || -> Result<(), String> {
+                    let img = image::open(&source).map_err(|e| e.to_string())?;
+                    img.resize_exact(width, height, FilterType::Nearest)
+                        .save(&destination)
+                        .map_err(|e| e.to_string())?;
+                    // Only once the resized copy exists, so a retry still has its source
+                    std::fs::remove_file(&source).map_err(|e| e.to_string())
+                })
+                .await
+                .map_err(|e| e.to_string())?
+            }
+            Job::GenerateIcs { consult_id } => {
+                let consult = ics_consult_by_id(&ctx.db, *consult_id)
+                    .await
+                    .map_err(|e| format!("Error in DB {}", e))?;
+                std::fs::create_dir_all(ICS_DIR).map_err(|e| e.to_string())?;
+                consult_calendar(&[consult], &format!("Consult #{}", consult_id), Utc::now())
+                    .save_file(ics_path(*consult_id))
+                    .map_err(|e| e.to_string())?;
+                let _ = std::fs::remove_file(format!("{}consult-{}.ics", PUBLIC_ICS_DIR, consult_id));
+                Ok(())
+            }
+            Job::SendInvites { consult_id, method } => {
+                let consult = ics_consult_by_id(&ctx.db, *consult_id)
+                    .await
+                    .map_err(|e| format!("Error in DB {}", e))?;
+                if request_outdated(&consult, *method) {
+                    return Ok(());
+                }
+                for to_email in invite_recipients(&consult) {
+                    let job = Job::SendInvite {
+                        consult_id: *consult_id,
+                        method: *method,
+                        to_email: to_email.to_owned(),
+                    };
+                    enqueue(&ctx.r_pool, job).await?;
+                }
+                Ok(())
+            }
+            Job::SendInvite {
+                consult_id,
+                method,
+                to_email,
+            } => {
+                let consult = ics_consult_by_id(&ctx.db, *consult_id)
+                    .await
+                    .map_err(|e| format!("Error in DB {}", e))?;
+                if request_outdated(&consult, *method) {
+                    return Ok(());
+                }
+                let text = invite_text(&consult, *method);
+                let email = SendEmailInput::from((to_email.as_str(), text.as_str()))
+                    .subject(&invite_subject(&consult, *method))
+                    .calendar(method.as_str(), itip_calendar(&consult, *method, Utc::now()).to_string());
+                send_email(email).await
+            }
+            Job::ScoreChurn { horizon_days } => write_churn_scores(&ctx.db, *horizon_days)
+                .await
+                .map(|scored| println!("Churn scores written for {} clients", scored)),
+            Job::SendConsultReminders { hours_ahead } => {
+                periodic::send_consult_reminders(ctx, *hours_ahead).await
+            }
+            Job::PurgeExpired => periodic::purge_expired(ctx).await,
+            Job::SendDigests { days } => periodic::send_digests(ctx, *days).await,
+        }
+    }
+}
+
+// A REQUEST for a consult cancelled since it was queued would put it back on calendars after the CANCEL
+fn request_outdated(consult: &IcsConsult, method: ItipMethod) -> bool {
+    method == ItipMethod::Request && consult.cancelled_at.is_some()
+}
+
+pub fn ics_path(consult_id: i32) -> String {
+    format!("{}consult-{}.ics", ICS_DIR, consult_id)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn jobs_round_trip_as_json() {
+        let job = Job::ResizeImage {
+            source: "./static/images/consultants/a-b.jpg".to_string(),
+            destination: "./static/images/consultants/a.png".to_string(),
+            width: 200,
+            height: 200,
+        };
+        let restored: Job = serde_json::from_str(&serde_json::to_string(&job).unwrap()).unwrap();
+        assert_eq!(restored, job);
+        assert_eq!(restored.name(), "Resize image");
+        assert_eq!(ics_path(12), "./data/ics/consult-12.ics");
+
+        let invite = Job::SendInvites { consult_id: 12, method: ItipMethod::Cancel };
+        let restored: Job = serde_json::from_str(&serde_json::to_string(&invite).unwrap()).unwrap();
+        assert_eq!(restored, invite);
+
+        let invite = Job::SendInvite {
+            consult_id: 12,
+            method: ItipMethod::Request,
+            to_email: "ops@acme.test".to_string(),
+        };
+        let restored: Job = serde_json::from_str(&serde_json::to_string(&invite).unwrap()).unwrap();
+        assert_eq!(restored.name(), "Send invite");
+        assert_eq!(restored, invite);
+    }
+}
@@ -0,0 +1,195 @@
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use sqlx::FromRow;
+
+use super::{queue::enqueue, Job, JobContext};
+use crate::linfa::consult_offset;
+
+// Bodies of the scheduled jobs (see schedule.rs). Emails fan out as their own SendEmail jobs so one
+// bad address retries on its own.
+
+pub const REMINDER_HOURS_AHEAD: i64 = 24;
+pub const DIGEST_DAYS: i64 = 7;
+const RESET_REQUEST_TTL_HOURS: i32 = 24;
+// Lines per digest email. The rest are summarized as a count.
+const DIGEST_MAX_LINES: usize = 20;
+
+#[derive(Debug, Clone, Serialize, Deserialize, FromRow)]
+pub struct ConsultReminder {
+    pub consult_id: i32,
+    pub consult_start: DateTime<Utc>,
+    pub client_email: String,
+    pub consultant_email: Option<String>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, FromRow)]
+pub struct DigestRow {
+    pub user_id: i32,
+    pub email: String,
+    pub msg: String,
+}
+
+pub fn reminder_msg(reminder: &ConsultReminder) -> String {
+    format!(
+        "Reminder: consult #{} starts {}",
+        reminder.consult_id,
+        reminder.consult_start.with_timezone(&consult_offset()).format("%b %-d, %-I:%M %p")
+    )
+}
+
+pub async fn send_consult_reminders(ctx: &JobContext, hours_ahead: i64) -> Result<(), String> {
+    let reminders = sqlx::query_as::<_, ConsultReminder>(
+        "SELECT consults.id AS consult_id, consults.consult_start, clients.client_email, users.email AS consultant_email
+            FROM consults
+            INNER JOIN clients ON clients.id = consults.client_id
+            LEFT JOIN consultants ON consultants.id = consults.consultant_id
+            LEFT JOIN users ON users.id = consultants.user_id
+            WHERE consults.reminder_sent_at IS NULL
+            AND consults.cancelled_at IS NULL
+            AND consults.booking_status = 'confirmed'
+            AND consults.consult_start > NOW()
+            AND consults.consult_start <= NOW() + make_interval(hours => $1)",
+    )
+    .bind(hours_ahead as i32)
+    .fetch_all(&ctx.db)
+    .await
+    .map_err(|e| format!("Error in DB {}", e))?;
+
+    let mut sent: Vec<i32> = vec![];
+    for reminder in &reminders {
+        let msg = reminder_msg(reminder);
+        let subject = format!("Reminder: consult #{}", reminder.consult_id);
+        let recipients = std::iter::once(&reminder.client_email).chain(reminder.consultant_email.iter());
+        let mut queued = true;
+        for to_email in recipients {
+            let job = Job::SendEmail {
+                to_email: to_email.clone(),
+                msg: msg.clone(),
+                subject: Some(subject.clone()),
+            };
+            if let Err(err) = enqueue(&ctx.r_pool, job).await {
+                println!("Error queueing reminder for consult {}: {}", reminder.consult_id, err);
+                queued = false;
+            }
+        }
+        // Unqueued ones are picked up again on the next run
+        if queued {
+            sent.push(reminder.consult_id);
+        }
+    }
+
+    sqlx::query("UPDATE consults SET reminder_sent_at = NOW() WHERE id = ANY($1)")
+        .bind(&sent)
+        .execute(&ctx.db)
+        .await
+        .map_err(|e| format!("Error in DB {}", e))?;
+    println!("Consult reminders queued for {} of {} consults", sent.len(), reminders.len());
+    Ok(())
+}
+
+pub async fn purge_expired(ctx: &JobContext) -> Result<(), String> {
+    let sessions = sqlx::query("DELETE FROM user_sessions WHERE expires < NOW()")
+        .execute(&ctx.db)
+        .await
+        .map_err(|e| format!("Error in DB {}", e))?;
+    let resets = sqlx::query("DELETE FROM reset_password_requests WHERE created_at < NOW() - make_interval(hours => $1)")
+        .bind(RESET_REQUEST_TTL_HOURS)
+        .execute(&ctx.db)
+        .await
+        .map_err(|e| format!("Error in DB {}", e))?;
+    println!(
+        "Purged {} expired sessions and {} reset requests",
+        sessions.rows_affected(),
+        resets.rows_affected()
+    );
+    Ok(())
+}
+
+// One email body per user, from their notification rows in the window.
+pub fn digest_bodies(rows: &[DigestRow], days: i64) -> Vec<(String, String)> {
+    let mut digests: Vec<(i32, String, Vec<&str>)> = vec![];
+    for row in rows {
+        match digests.last_mut() {
+            Some((user_id, _, msgs)) if *user_id == row.user_id => msgs.push(&row.msg),
+            _ => digests.push((row.user_id, row.email.clone(), vec![&row.msg])),
+        }
+    }
+    digests
+        .into_iter()
+        .map(|(_, email, msgs)| {
+            let mut body = format!(
+                "{} updates to your subscriptions in the last {} days:\n",
+                msgs.len(),
+                days
+            );
+            for msg in msgs.iter().take(DIGEST_MAX_LINES) {
+                body.push_str(&format!("- {}\n", msg));
+            }
+            if msgs.len() > DIGEST_MAX_LINES {
+                body.push_str(&format!("...and {} more\n", msgs.len() - DIGEST_MAX_LINES));
+            }
+            (email, body)
+        })
+        .collect()
+}
+
+pub async fn send_digests(ctx: &JobContext, days: i64) -> Result<(), String> {
+    let rows = sqlx::query_as::<_, DigestRow>(
+        "SELECT users.id AS user_id, users.email, notifications.msg
+            FROM notifications
+            INNER JOIN users ON users.id = notifications.user_id
+            WHERE notifications.created_at > NOW() - make_interval(days => $1)
+            ORDER BY users.id, notifications.created_at DESC",
+    )
+    .bind(days as i32)
+    .fetch_all(&ctx.db)
+    .await
+    .map_err(|e| format!("Error in DB {}", e))?;
+
+    let digests = digest_bodies(&rows, days);
+    for (to_email, msg) in &digests {
+        let job = Job::SendEmail {
+            to_email: to_email.clone(),
+            msg: msg.clone(),
+            subject: Some("Updates to your subscriptions".to_owned()),
+        };
+        enqueue(&ctx.r_pool, job).await?;
+    }
+    println!("Queued {} subscription digests", digests.len());
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::TimeZone;
+
+    fn row(user_id: i32, msg: &str) -> DigestRow {
+        DigestRow { user_id, email: format!("user{}@example.com", user_id), msg: msg.to_owned() }
+    }
+
+    #[test]
+    fn reminders_use_the_consult_offset() {
+        let reminder = ConsultReminder {
+            consult_id: 12,
+            consult_start: Utc.with_ymd_and_hms(2023, 11, 28, 15, 0, 0).unwrap(),
+            client_email: "ops@acme.test".to_owned(),
+            consultant_email: None,
+        };
+        assert_eq!(reminder_msg(&reminder), "Reminder: consult #12 starts Nov 28, 9:00 AM");
+    }
+
+    #[test]
+    fn digests_group_by_user_and_cap_lines() {
+        let mut rows = vec![row(1, "Client #7 edited"), row(1, "Location #5 edited"), row(2, "Consult #3 added")];
+        rows.extend((0..25).map(|i| row(3, &format!("Client #{} edited", i))));
+        let digests = digest_bodies(&rows, 7);
+        assert_eq!(digests.len(), 3);
+        assert_eq!(digests[0].0, "user1@example.com");
+        assert_eq!(
+            digests[0].1,
+            "2 updates to your subscriptions in the last 7 days:\n- Client #7 edited\n- Location #5 edited\n"
+        );
+        assert!(digests[2].1.ends_with("...and 5 more\n"));
+    }
+}
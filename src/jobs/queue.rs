@@ -0,0 +1,312 @@
+use chrono::{DateTime, Utc};
+use deadpool_redis::Pool as RedisPool;
+use redis::{AsyncCommands, RedisResult, Script};
+use serde::{Deserialize, Serialize};
+use uuid::Uuid;
+
+use super::Job;
+use crate::redis_mod::typed::{self, RedisModel};
+
+// Redis layout:
+//   job:v1:<id>       JSON JobRecord (status, attempts, last error). Finished jobs expire after RECORD_TTL.
+//   jobs:queue        LIST of ids ready to run. LPUSH in, BRPOPLPUSH out.
+//   jobs:processing   LIST of ids a worker has taken.
+//   jobs:claimed      ZSET of the ids in jobs:processing, scored by when a worker took them. Ids held
+//                     past VISIBILITY_TIMEOUT_SECS are reclaimed as failed attempts.
+//   jobs:delayed      ZSET of ids waiting for a retry, scored by run-at epoch seconds.
+//   jobs:dead         LIST of ids that ran out of attempts.
+//   jobs:completed    LIST of recently completed ids, capped at HISTORY_LEN.
+
+pub const QUEUE_KEY: &str = "jobs:queue";
+pub const PROCESSING_KEY: &str = "jobs:processing";
+pub const CLAIMED_KEY: &str = "jobs:claimed";
+pub const DELAYED_KEY: &str = "jobs:delayed";
+pub const DEAD_KEY: &str = "jobs:dead";
+pub const COMPLETED_KEY: &str = "jobs:completed";
+
+pub const MAX_ATTEMPTS: u32 = 5;
+const BACKOFF_BASE_SECS: i64 = 10;
+const BACKOFF_MAX_SECS: i64 = 60 * 60;
+const RECORD_TTL: usize = 60 * 60 * 24 * 7;
+const HISTORY_LEN: isize = 100;
+// Longer than any job should run. A job still in jobs:processing after this belongs to a worker that
+// died or was restarted mid-run.
+pub const VISIBILITY_TIMEOUT_SECS: i64 = 30 * 60;
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub enum JobStatus {
+    Queued,
+    Running,
+    Retrying,
+    Completed,
+    Dead,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct JobRecord {
+    pub id: String,
+    pub job: Job,
+    pub status: JobStatus,
+    pub attempts: u32,
+    pub last_error: Option<String>,
+    pub enqueued_at: DateTime<Utc>,
+    pub updated_at: DateTime<Utc>,
+    pub run_at: Option<DateTime<Utc>>,
+}
+
+impl RedisModel for JobRecord {
+    const NAMESPACE: &'static str = "job";
+    const VERSION: u32 = 1;
+}
+
+impl JobRecord {
+    pub fn new(job: Job) -> JobRecord {
+        let now = Utc::now();
+        JobRecord {
+            id: Uuid::new_v4().simple().to_string(),
+            job,
+            status: JobStatus::Queued,
+            attempts: 0,
+            last_error: None,
+            enqueued_at: now,
+            updated_at: now,
+            run_at: None,
+        }
+    }
+
+    // What happens after a failed attempt: retry later, or dead-letter once out of attempts.
+    pub fn fail(&mut self, err: String, now: DateTime<Utc>) {
+        self.last_error = Some(err);
+        self.updated_at = now;
+        if self.attempts >= MAX_ATTEMPTS {
+            self.status = JobStatus::Dead;
+            self.run_at = None;
+        } else {
+            self.status = JobStatus::Retrying;
+            self.run_at = Some(now + chrono::Duration::seconds(backoff_secs(self.attempts)));
+        }
+    }
+}
+
+// 10s, 20s, 40s ... capped at an hour.
+pub fn backoff_secs(attempts: u32) -> i64 {
+    let exp = attempts.saturating_sub(1).min(20);
+    (BACKOFF_BASE_SECS * 2_i64.pow(exp)).min(BACKOFF_MAX_SECS)
+}
+
+fn redis_err(err: impl std::fmt::Display) -> String {
+    format!("Error in Redis {}", err)
+}
+
+pub async fn save_record(con: &mut deadpool_redis::Connection, record: &JobRecord) -> Result<(), String> {
+    let saved: RedisResult<()> = match record.status {
+        JobStatus::Completed | JobStatus::Dead => typed::set_ex(con, &record.id, record, RECORD_TTL).await,
+        _ => typed::set(con, &record.id, record).await,
+    };
+    saved.map_err(redis_err)
+}
+
+pub async fn load_record(con: &mut deadpool_redis::Connection, id: &str) -> Result<Option<JobRecord>, String> {
+    typed::get(con, id).await.map_err(redis_err)
+}
+
+// Returns the job id, which doubles as the status lookup key.
+pub async fn enqueue(r_pool: &RedisPool, job: Job) -> Result<String, String> {
+    let mut con = r_pool.get().await.map_err(redis_err)?;
+    let record = JobRecord::new(job);
+    save_record(&mut con, &record).await?;
+    con.lpush::<_, _, ()>(QUEUE_KEY, &record.id).await.map_err(redis_err)?;
+    Ok(record.id)
+}
+
+pub async fn job_status(r_pool: &RedisPool, id: &str) -> Result<Option<JobRecord>, String> {
+    let mut con = r_pool.get().await.map_err(redis_err)?;
+    load_record(&mut con, id).await
+}
+
+// Moves retries whose backoff has elapsed back onto the queue.
+pub async fn promote_due(con: &mut deadpool_redis::Connection, now: DateTime<Utc>) -> Result<usize, String> {
+    let due: Vec<String> = con
+        .zrangebyscore(DELAYED_KEY, "-inf", now.timestamp())
+        .await
+        .map_err(redis_err)?;
+    let mut promoted = 0;
+    for id in due {
+        // Only the worker that wins the ZREM requeues it
+        let removed: usize = con.zrem(DELAYED_KEY, &id).await.map_err(redis_err)?;
+        if removed > 0 {
+            con.lpush::<_, _, ()>(QUEUE_KEY, &id).await.map_err(redis_err)?;
+            promoted += 1;
+        }
+    }
+    Ok(promoted)
+}
+
+// Called right after BRPOPLPUSH. Time spent waiting in the queue or in backoff doesn't count.
+pub async fn claim(con: &mut deadpool_redis::Connection, id: &str, now: DateTime<Utc>) -> Result<(), String> {
+    con.zadd::<_, _, _, ()>(CLAIMED_KEY, id, now.timestamp()).await.map_err(redis_err)
+}
+
+// Off jobs:claimed first, so an id is never in jobs:processing with an old claim
+pub async fn release(con: &mut deadpool_redis::Connection, id: &str) -> Result<(), String> {
+    con.zrem::<_, _, ()>(CLAIMED_KEY, id).await.map_err(redis_err)?;
+    con.lrem::<_, _, ()>(PROCESSING_KEY, 1, id).await.map_err(redis_err)
+}
+
+// Only while the id is still being processed, so one finished meanwhile isn't left behind in jobs:claimed
+const START_CLAIM_CLOCK: &str = r"
+if redis.call('LPOS', KEYS[1], ARGV[1]) then
+  redis.call('ZADD', KEYS[2], 'NX', ARGV[2], ARGV[1])
+end
+return 0
+";
+
+pub fn is_stalled(claimed_at: i64, now: DateTime<Utc>) -> bool {
+    now.timestamp() - claimed_at > VISIBILITY_TIMEOUT_SECS
+}
+
+// Stalled jobs count as a failed attempt and go the usual retry or dead letter way.
+pub async fn reclaim_stalled(con: &mut deadpool_redis::Connection, now: DateTime<Utc>) -> Result<usize, String> {
+    let ids: Vec<String> = con.lrange(PROCESSING_KEY, 0, -1).await.map_err(redis_err)?;
+    let mut reclaimed = 0;
+    for id in ids {
+        let claimed_at: Option<i64> = con.zscore(CLAIMED_KEY, &id).await.map_err(redis_err)?;
+        match claimed_at {
+            Some(claimed_at) if is_stalled(claimed_at, now) => {}
+            Some(_) => continue,
+            // Taken a moment ago and not claimed yet, or its worker died in between. Start the clock
+            // without overwriting the worker's own claim.
+            None => {
+                Script::new(START_CLAIM_CLOCK)
+                    .key(PROCESSING_KEY)
+                    .key(CLAIMED_KEY)
+                    .arg(&id)
+                    .arg(now.timestamp())
+                    .invoke_async::<_, ()>(con)
+                    .await
+                    .map_err(redis_err)?;
+                continue;
+            }
+        }
+        // Only the worker that wins the ZREM reclaims it
+        let removed: usize = con.zrem(CLAIMED_KEY, &id).await.map_err(redis_err)?;
+        if removed == 0 {
+            continue;
+        }
+        match load_record(con, &id).await? {
+            Some(mut record) if matches!(record.status, JobStatus::Queued | JobStatus::Running | JobStatus::Retrying) => {
+                record.fail("Worker stopped before the job finished".to_owned(), now);
+                finish(con, &record).await?;
+                reclaimed += 1;
+            }
+            // Already finished, or the record expired. Nothing left to run.
+            _ => release(con, &id).await?,
+        }
+    }
+    Ok(reclaimed)
+}
+
+pub async fn finish(con: &mut deadpool_redis::Connection, record: &JobRecord) -> Result<(), String> {
+    save_record(con, record).await?;
+    release(con, &record.id).await?;
+    match (record.status, record.run_at) {
+        (JobStatus::Completed, _) => {
+            con.lpush::<_, _, ()>(COMPLETED_KEY, &record.id).await.map_err(redis_err)?;
+            con.ltrim::<_, ()>(COMPLETED_KEY, 0, HISTORY_LEN - 1).await.map_err(redis_err)?;
+        }
+        (JobStatus::Retrying, Some(run_at)) => {
+            con.zadd::<_, _, _, ()>(DELAYED_KEY, &record.id, run_at.timestamp()).await.map_err(redis_err)?;
+        }
+        (JobStatus::Dead, _) => {
+            con.lpush::<_, _, ()>(DEAD_KEY, &record.id).await.map_err(redis_err)?;
+        }
+        _ => {}
+    }
+    Ok(())
+}
+
+// Admin action: give a dead job a fresh set of attempts.
+pub async fn retry_dead(r_pool: &RedisPool, id: &str) -> Result<(), String> {
+    let mut con = r_pool.get().await.map_err(redis_err)?;
+    let removed: usize = con.lrem(DEAD_KEY, 1, id).await.map_err(redis_err)?;
+    if removed == 0 {
+        return Err(format!("Job {} is not in the dead letter list", id));
+    }
+    let mut record = load_record(&mut con, id)
+        .await?
+        .ok_or_else(|| format!("Job {} has expired", id))?;
+    record.status = JobStatus::Queued;
+    record.attempts = 0;
+    record.run_at = None;
+    record.updated_at = Utc::now();
+    save_record(&mut con, &record).await?;
+    con.lpush::<_, _, ()>(QUEUE_KEY, id).await.map_err(redis_err)?;
+    Ok(())
+}
+
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub struct JobOverview {
+    pub queued: Vec<JobRecord>,
+    pub running: Vec<JobRecord>,
+    pub retrying: Vec<JobRecord>,
+    pub dead: Vec<JobRecord>,
+    pub completed: Vec<JobRecord>,
+}
+
+async fn records(con: &mut deadpool_redis::Connection, ids: Vec<String>) -> Result<Vec<JobRecord>, String> {
+    let records: Vec<Option<JobRecord>> = typed::mget(con, &ids).await.map_err(redis_err)?;
+    Ok(records.into_iter().flatten().collect())
+}
+
+pub async fn job_overview(r_pool: &RedisPool, limit: isize) -> Result<JobOverview, String> {
+    let mut con = r_pool.get().await.map_err(redis_err)?;
+    let queued: Vec<String> = con.lrange(QUEUE_KEY, 0, limit - 1).await.map_err(redis_err)?;
+    let running: Vec<String> = con.lrange(PROCESSING_KEY, 0, limit - 1).await.map_err(redis_err)?;
+    let retrying: Vec<String> = con.zrange(DELAYED_KEY, 0, limit - 1).await.map_err(redis_err)?;
+    let dead: Vec<String> = con.lrange(DEAD_KEY, 0, limit - 1).await.map_err(redis_err)?;
+    let completed: Vec<String> = con.lrange(COMPLETED_KEY, 0, limit - 1).await.map_err(redis_err)?;
+    Ok(JobOverview {
+        queued: records(&mut con, queued).await?,
+        running: records(&mut con, running).await?,
+        retrying: records(&mut con, retrying).await?,
+        dead: records(&mut con, dead).await?,
+        completed: records(&mut con, completed).await?,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::TimeZone;
+
+    #[test]
+    fn backoff_doubles_and_caps() {
+        assert_eq!(backoff_secs(1), 10);
+        assert_eq!(backoff_secs(2), 20);
+        assert_eq!(backoff_secs(4), 80);
+        assert_eq!(backoff_secs(30), BACKOFF_MAX_SECS);
+    }
+
+    #[test]
+    fn failures_retry_then_dead_letter() {
+        let now = Utc.with_ymd_and_hms(2023, 11, 24, 12, 0, 0).unwrap();
+        let mut record = JobRecord::new(Job::ScoreChurn { horizon_days: 90 });
+        record.attempts = 1;
+        record.fail("boom".to_string(), now);
+        assert_eq!(record.status, JobStatus::Retrying);
+        assert_eq!(record.run_at, Some(now + chrono::Duration::seconds(10)));
+
+        record.attempts = MAX_ATTEMPTS;
+        record.fail("boom again".to_string(), now);
+        assert_eq!(record.status, JobStatus::Dead);
+        assert_eq!(record.run_at, None);
+        assert_eq!(record.last_error.as_deref(), Some("boom again"));
+    }
+
+    #[test]
+    fn jobs_stall_after_the_visibility_timeout() {
+        let now = Utc.with_ymd_and_hms(2023, 11, 24, 12, 0, 0).unwrap();
+        assert!(!is_stalled(now.timestamp() - VISIBILITY_TIMEOUT_SECS, now));
+        assert!(is_stalled(now.timestamp() - VISIBILITY_TIMEOUT_SECS - 1, now));
+    }
+}
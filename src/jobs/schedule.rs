@@ -0,0 +1,308 @@
+use std::time::Duration as StdDuration;
+
+use chrono::{DateTime, Datelike, Duration, TimeZone, Timelike, Utc};
+use deadpool_redis::Pool as RedisPool;
+use redis::AsyncCommands;
+use serde::{Deserialize, Serialize};
+
+use super::{
+    periodic::{DIGEST_DAYS, REMINDER_HOURS_AHEAD},
+    queue::enqueue,
+    Job,
+};
+use crate::linfa::churn::CHURN_HORIZON_DAYS;
+
+// Timed work. Each task has a cron expression and the Job it enqueues when due, so the actual work
+// runs on the job queue with its retries. State lives in Redis at `schedule:<name>` (cron, last_run,
+// next_run, last_job_id), and a per-run lock keeps several app instances from firing the same run.
+
+const TICK: StdDuration = StdDuration::from_secs(30);
+const LOCK_TTL: usize = 60 * 60;
+// How far ahead next_after looks before giving up on an expression that never matches (e.g. Feb 30).
+const SEARCH_YEARS: i64 = 5;
+
+pub struct ScheduledTask {
+    pub name: &'static str,
+    pub description: &'static str,
+    pub cron: &'static str,
+    pub job: fn() -> Job,
+}
+
+pub fn scheduled_tasks() -> Vec<ScheduledTask> {
+    vec![
+        ScheduledTask {
+            name: "consult-reminders",
+            description: "Email clients and consultants ahead of upcoming consults",
+            cron: "*/15 * * * *",
+            job: || Job::SendConsultReminders { hours_ahead: REMINDER_HOURS_AHEAD },
+        },
+        ScheduledTask {
+            name: "expired-cleanup",
+            description: "Purge expired sessions and password reset requests",
+            cron: "0 * * * *",
+            job: || Job::PurgeExpired,
+        },
+        ScheduledTask {
+            name: "churn-retraining",
+            description: "Rescore client churn risk",
+            cron: "0 2 * * *",
+            job: || Job::ScoreChurn { horizon_days: CHURN_HORIZON_DAYS },
+        },
+        ScheduledTask {
+            name: "subscription-digest",
+            description: "Weekly digest of subscribed entity changes",
+            cron: "0 8 * * 1",
+            job: || Job::SendDigests { days: DIGEST_DAYS },
+        },
+    ]
+}
+
+// Standard 5 field cron: minute hour day-of-month month day-of-week, all in UTC. Fields take `*`,
+// numbers, `a-b` ranges, `/n` steps and comma lists. Sunday is 0 or 7. Like cron, when both
+// day fields are restricted a day matching either one runs.
+#[derive(Debug, Clone, PartialEq)]
+pub struct CronExpr {
+    minutes: Vec<u32>,
+    hours: Vec<u32>,
+    days: Vec<u32>,
+    months: Vec<u32>,
+    weekdays: Vec<u32>,
+    any_day: bool,
+    any_weekday: bool,
+}
+
+fn parse_field(field: &str, min: u32, max: u32) -> Result<Vec<u32>, String> {
+    let mut values = vec![];
+    for part in field.split(',') {
+        let (range, step) = match part.split_once('/') {
+            Some((range, step)) => (range, step.parse::<u32>().map_err(|_| format!("Bad step in {}", part))?),
+            None => (part, 1),
+        };
+        if step == 0 {
+            return Err(format!("Bad step in {}", part));
+        }
+        let (start, end) = if range == "*" {
+            (min, max)
+        } else if let Some((start, end)) = range.split_once('-') {
+            (
+                start.parse::<u32>().map_err(|_| format!("Bad range in {}", part))?,
+                end.parse::<u32>().map_err(|_| format!("Bad range in {}", part))?,
+            )
+        } else {
+            let value = range.parse::<u32>().map_err(|_| format!("Bad value in {}", part))?;
+            // `5/15` means from 5 to the end in steps of 15
+            if part.contains('/') { (value, max) } else { (value, value) }
+        };
+        if start < min || end > max || start > end {
+            return Err(format!("{} is outside {}-{}", part, min, max));
+        }
+        values.extend((start..=end).step_by(step as usize));
+    }
+    values.sort_unstable();
+    values.dedup();
+    Ok(values)
+}
+
+impl CronExpr {
+    pub fn parse(expr: &str) -> Result<CronExpr, String> {
+        let fields: Vec<&str> = expr.split_whitespace().collect();
+        if fields.len() != 5 {
+            return Err(format!("Expected 5 fields in cron expression {:?}", expr));
+        }
+        let mut weekdays: Vec<u32> = parse_field(fields[4], 0, 7)?
+            .into_iter()
+            .map(|day| day % 7)
+            .collect();
+        weekdays.sort_unstable();
+        weekdays.dedup();
+        Ok(CronExpr {
+            minutes: parse_field(fields[0], 0, 59)?,
+            hours: parse_field(fields[1], 0, 23)?,
+            days: parse_field(fields[2], 1, 31)?,
+            months: parse_field(fields[3], 1, 12)?,
+            weekdays,
+            any_day: fields[2] == "*",
+            any_weekday: fields[4] == "*",
+        })
+    }
+
+    fn day_matches(&self, t: &DateTime<Utc>) -> bool {
+        let day = self.days.contains(&t.day());
+        let weekday = self.weekdays.contains(&t.weekday().num_days_from_sunday());
+        match (self.any_day, self.any_weekday) {
+            (false, false) => day || weekday,
+            _ => day && weekday,
+        }
+    }
+
+    // The first matching minute strictly after `after`.
+    pub fn next_after(&self, after: DateTime<Utc>) -> Option<DateTime<Utc>> {
+        let mut t = Utc
+            .with_ymd_and_hms(after.year(), after.month(), after.day(), after.hour(), after.minute(), 0)
+            .single()?
+            + Duration::minutes(1);
+        let limit = after + Duration::days(366 * SEARCH_YEARS);
+        while t <= limit {
+            if !self.months.contains(&t.month()) {
+                let (year, month) = if t.month() == 12 { (t.year() + 1, 1) } else { (t.year(), t.month() + 1) };
+                t = Utc.with_ymd_and_hms(year, month, 1, 0, 0, 0).single()?;
+            } else if !self.day_matches(&t) {
+                t = Utc.with_ymd_and_hms(t.year(), t.month(), t.day(), 0, 0, 0).single()? + Duration::days(1);
+            } else if !self.hours.contains(&t.hour()) {
+                t = Utc.with_ymd_and_hms(t.year(), t.month(), t.day(), t.hour(), 0, 0).single()? + Duration::hours(1);
+            } else if !self.minutes.contains(&t.minute()) {
+                t = t + Duration::minutes(1);
+            } else {
+                return Some(t);
+            }
+        }
+        None
+    }
+}
+
+fn state_key(name: &str) -> String {
+    format!("schedule:{}", name)
+}
+
+fn parse_time(val: Option<String>) -> Option<DateTime<Utc>> {
+    val.and_then(|val| DateTime::parse_from_rfc3339(&val).ok())
+        .map(|t| t.with_timezone(&Utc))
+}
+
+async fn tick(r_pool: &RedisPool, task: &ScheduledTask, now: DateTime<Utc>) -> Result<(), String> {
+    let cron = CronExpr::parse(task.cron)?;
+    let mut con = r_pool.get().await.map_err(|e| format!("Error in Redis {}", e))?;
+    let key = state_key(task.name);
+    let (stored_cron, next_run): (Option<String>, Option<String>) = redis::cmd("HMGET")
+        .arg(&key)
+        .arg("cron")
+        .arg("next_run")
+        .query_async(&mut con)
+        .await
+        .map_err(|e| format!("Error in Redis {}", e))?;
+    let next_run = match parse_time(next_run) {
+        // A changed expression starts over from now
+        Some(next_run) if stored_cron.as_deref() == Some(task.cron) => next_run,
+        _ => {
+            let next_run = cron.next_after(now).ok_or("Cron expression never matches")?;
+            con.hset_multiple::<_, _, _, ()>(&key, &[("cron", task.cron.to_string()), ("next_run", next_run.to_rfc3339())])
+                .await
+                .map_err(|e| format!("Error in Redis {}", e))?;
+            return Ok(());
+        }
+    };
+    if next_run > now {
+        return Ok(());
+    }
+
+    // Only one instance wins a given run
+    let lock: Option<String> = redis::cmd("SET")
+        .arg(format!("schedule_lock:{}:{}", task.name, next_run.timestamp()))
+        .arg(1)
+        .arg("NX")
+        .arg("EX")
+        .arg(LOCK_TTL)
+        .query_async(&mut con)
+        .await
+        .map_err(|e| format!("Error in Redis {}", e))?;
+    if lock.is_none() {
+        return Ok(());
+    }
+
+    // Runs missed while the app was down collapse into this one
+    let job_id = enqueue(r_pool, (task.job)()).await?;
+    let following = cron.next_after(now).ok_or("Cron expression never matches")?;
+    con.hset_multiple::<_, _, _, ()>(
+        &key,
+        &[
+            ("last_run", now.to_rfc3339()),
+            ("next_run", following.to_rfc3339()),
+            ("last_job_id", job_id),
+        ],
+    )
+    .await
+    .map_err(|e| format!("Error in Redis {}", e))
+}
+
+// Runs for the life of the process. Safe to run on every instance.
+pub async fn run_scheduler(r_pool: RedisPool) {
+    loop {
+        let now = Utc::now();
+        for task in scheduled_tasks() {
+            if let Err(err) = tick(&r_pool, &task, now).await {
+                println!("Scheduled task {} failed: {}", task.name, err);
+            }
+        }
+        actix_web::rt::time::sleep(TICK).await;
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ScheduleStatus {
+    pub name: String,
+    pub description: String,
+    pub cron: String,
+    pub last_run: Option<DateTime<Utc>>,
+    pub next_run: Option<DateTime<Utc>>,
+    pub last_job_id: Option<String>,
+}
+
+pub async fn schedule_status(r_pool: &RedisPool) -> Result<Vec<ScheduleStatus>, String> {
+    let mut con = r_pool.get().await.map_err(|e| format!("Error in Redis {}", e))?;
+    let mut statuses = vec![];
+    for task in scheduled_tasks() {
+        let (last_run, next_run, last_job_id): (Option<String>, Option<String>, Option<String>) = redis::cmd("HMGET")
+            .arg(state_key(task.name))
+            .arg("last_run")
+            .arg("next_run")
+            .arg("last_job_id")
+            .query_async(&mut con)
+            .await
+            .map_err(|e| format!("Error in Redis {}", e))?;
+        statuses.push(ScheduleStatus {
+            name: task.name.to_owned(),
+            description: task.description.to_owned(),
+            cron: task.cron.to_owned(),
+            last_run: parse_time(last_run),
+            next_run: parse_time(next_run),
+            last_job_id,
+        });
+    }
+    Ok(statuses)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn at(y: i32, mo: u32, d: u32, h: u32, mi: u32) -> DateTime<Utc> {
+        Utc.with_ymd_and_hms(y, mo, d, h, mi, 0).unwrap()
+    }
+
+    #[test]
+    fn parses_steps_ranges_and_lists() {
+        let cron = CronExpr::parse("*/15 9-17 * * 1-5").unwrap();
+        assert_eq!(cron.minutes, vec![0, 15, 30, 45]);
+        assert_eq!(cron.hours, (9..=17).collect::<Vec<u32>>());
+        assert_eq!(cron.weekdays, vec![1, 2, 3, 4, 5]);
+        assert_eq!(CronExpr::parse("0 0 * * 0,7").unwrap().weekdays, vec![0]);
+        assert!(CronExpr::parse("60 * * * *").is_err());
+        assert!(CronExpr::parse("* * *").is_err());
+        for task in scheduled_tasks() {
+            assert!(CronExpr::parse(task.cron).is_ok(), "{}", task.name);
+        }
+    }
+
+    #[test]
+    fn next_after_finds_following_match() {
+        // 2023-11-24 is a Friday
+        let now = at(2023, 11, 24, 10, 7);
+        assert_eq!(CronExpr::parse("*/15 * * * *").unwrap().next_after(now), Some(at(2023, 11, 24, 10, 15)));
+        assert_eq!(CronExpr::parse("0 2 * * *").unwrap().next_after(now), Some(at(2023, 11, 25, 2, 0)));
+        assert_eq!(CronExpr::parse("0 8 * * 1").unwrap().next_after(now), Some(at(2023, 11, 27, 8, 0)));
+        assert_eq!(CronExpr::parse("30 6 1 1 *").unwrap().next_after(now), Some(at(2024, 1, 1, 6, 30)));
+        // Exactly on a match moves to the next one
+        assert_eq!(CronExpr::parse("0 * * * *").unwrap().next_after(at(2023, 11, 24, 10, 0)), Some(at(2023, 11, 24, 11, 0)));
+        assert_eq!(CronExpr::parse("0 0 30 2 *").unwrap().next_after(now), None);
+    }
+}
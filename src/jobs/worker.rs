@@ -0,0 +1,75 @@
+use std::time::Duration;
+
+use chrono::Utc;
+use deadpool_redis::Pool as RedisPool;
+use redis::AsyncCommands;
+
+use super::{
+    queue::{claim, finish, load_record, promote_due, reclaim_stalled, release, save_record, JobStatus, PROCESSING_KEY, QUEUE_KEY},
+    JobContext,
+};
+
+// How long BRPOPLPUSH blocks before the loop comes back around to promote due retries and reclaim
+// stalled jobs.
+const POLL_TIMEOUT_SECS: usize = 5;
+const ERROR_DELAY: Duration = Duration::from_secs(5);
+
+// Runs for the life of the process. Several can run side by side, in one process or many.
+pub async fn run_worker(r_pool: RedisPool, ctx: JobContext) {
+    loop {
+        if let Err(err) = work_once(&r_pool, &ctx).await {
+            println!("Job worker error: {}", err);
+            actix_web::rt::time::sleep(ERROR_DELAY).await;
+        }
+    }
+}
+
+async fn work_once(r_pool: &RedisPool, ctx: &JobContext) -> Result<(), String> {
+    let mut con = r_pool.get().await.map_err(|e| format!("Error in Redis {}", e))?;
+    promote_due(&mut con, Utc::now()).await?;
+    reclaim_stalled(&mut con, Utc::now()).await?;
+
+    let id: Option<String> = con
+        .brpoplpush(QUEUE_KEY, PROCESSING_KEY, POLL_TIMEOUT_SECS)
+        .await
+        .map_err(|e| format!("Error in Redis {}", e))?;
+    let id = match id {
+        Some(id) => id,
+        None => return Ok(()),
+    };
+
+    claim(&mut con, &id, Utc::now()).await?;
+
+    let mut record = match load_record(&mut con, &id).await? {
+        Some(record) => record,
+        None => {
+            // Record expired or was never written, nothing to run
+            return release(&mut con, &id).await;
+        }
+    };
+    record.status = JobStatus::Running;
+    record.attempts += 1;
+    record.updated_at = Utc::now();
+    save_record(&mut con, &record).await?;
+
+    // Run in its own task so a panicking job fails like any other error instead of taking the worker with it
+    let job = record.job.clone();
+    let job_ctx = ctx.clone();
+    let outcome = match actix_web::rt::spawn(async move { job.run(&job_ctx).await }).await {
+        Ok(outcome) => outcome,
+        Err(err) => Err(format!("Job panicked: {}", err)),
+    };
+    match outcome {
+        Ok(()) => {
+            record.status = JobStatus::Completed;
+            record.last_error = None;
+            record.run_at = None;
+            record.updated_at = Utc::now();
+        }
+        Err(err) => {
+            println!("Job {} ({}) failed: {}", record.id, record.job.name(), err);
+            record.fail(err, Utc::now());
+        }
+    }
+    finish(&mut con, &record).await
+}
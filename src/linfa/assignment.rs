@@ -0,0 +1,516 @@
+use std::{fs::File, io::Write};
+
+use chrono::{DateTime, Duration, Timelike, Utc};
+use linfa::{prelude::Predict, traits::Fit, Dataset};
+use linfa_logistic::MultiLogisticRegression;
+use linfa_trees::{DecisionTree, SplitQuality};
+use ndarray::{Array1, Array2};
+use serde::{Deserialize, Serialize};
+use sqlx::{FromRow, Pool, Postgres};
+use uuid::Uuid;
+
+use crate::{calendar::availability::load_availability, scopes::consult::LinfaPredictionInput};
+
+use super::{
+    consult_offset,
+    features::{FeaturePipeline, RawFeatures},
+    meeting_time::load_busy_blocks,
+    tree_svg::tree_to_svg,
+    LinfaPredictionResult, CONSULT_FEATURES,
+};
+
+// Strategies only ever see data that was loaded up front, so the live path (create_consult)
+// and the evaluation harness run exactly the same code. Selected per account via accounts.assignment_strategy_id.
+
+// Consults counted towards a consultant's load are the ones within this many days of the new consult.
+const LOAD_WINDOW_DAYS: i64 = 14;
+
+#[derive(Debug, Clone, Serialize, FromRow, Deserialize)]
+pub struct AssignmentRow {
+    pub consultant_id: i32,
+    pub consult_purpose_id: i32,
+    pub client_type_id: i32,
+    pub client_id: i32,
+    pub specialty_id: i32,
+    pub territory_id: i32,
+    pub location_id: i32,
+    pub notes: Option<String>,
+    pub consult_result_id: i32,
+    pub num_attendees: i32,
+    pub consult_start: DateTime<Utc>,
+    pub consult_end: Option<DateTime<Utc>>,
+}
+
+impl AssignmentRow {
+    pub fn as_input(&self) -> LinfaPredictionInput {
+        let meeting_duration = self
+            .consult_end
+            .map(|end| (end - self.consult_start).num_minutes() as i32)
+            .unwrap_or(60);
+        LinfaPredictionInput {
+            meeting_duration: meeting_duration,
+            consult_purpose_id: self.consult_purpose_id,
+            territory_id: self.territory_id,
+            specialty_id: self.specialty_id,
+            client_type: self.client_type_id,
+            hour_of_day: self.consult_start.with_timezone(&consult_offset()).hour() as i32,
+            location_id: self.location_id,
+            client_id: self.client_id,
+            notes_length: self.notes.as_ref().map(|n| n.chars().count() as i32).unwrap_or(0),
+            received_follow_up: (self.consult_result_id == 1) as i32,
+            num_attendees: self.num_attendees,
+        }
+    }
+
+    // Same columns as as_input().raw_features(), but a missing consult_end stays missing (imputed by the pipeline).
+    pub fn raw_features(&self) -> RawFeatures {
+        let mut raw = self.as_input().raw_features();
+        if self.consult_end.is_none() {
+            raw[7] = None;
+        }
+        raw
+    }
+}
+
+#[derive(Debug, Clone, Serialize, FromRow, Deserialize)]
+pub struct CandidateConsultant {
+    pub id: i32,
+    pub specialty_id: i32,
+    pub territory_id: i32,
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct AssignmentData {
+    // Sorted by consult_start
+    pub history: Vec<AssignmentRow>,
+    pub consultants: Vec<CandidateConsultant>,
+}
+
+impl AssignmentData {
+    // Everything a strategy would have known right before `as_of`.
+    pub fn before(&self, as_of: DateTime<Utc>) -> AssignmentData {
+        AssignmentData {
+            history: self.history.iter().filter(|row| row.consult_start < as_of).cloned().collect(),
+            consultants: self.consultants.clone(),
+        }
+    }
+
+    // Pipeline is fit on the same rows it encodes, so nothing after `as_of` leaks into the encodings.
+    fn training_set(&self, as_of: DateTime<Utc>) -> Option<(FeaturePipeline, Array2<f32>, Array1<usize>)> {
+        let rows = self
+            .history
+            .iter()
+            .filter(|row| row.consult_start < as_of)
+            .collect::<Vec<&AssignmentRow>>();
+        if rows.is_empty() {
+            return None;
+        }
+        let raw = rows.iter().map(|row| row.raw_features()).collect::<Vec<RawFeatures>>();
+        let outcomes = rows.iter().map(|row| row.consult_result_id == 1).collect::<Vec<bool>>();
+        let pipeline = FeaturePipeline::fit(&CONSULT_FEATURES, &raw, &outcomes);
+        let features = pipeline.transform(&raw);
+        let targets = rows.iter().map(|row| row.consultant_id as usize).collect::<Array1<usize>>();
+        Some((pipeline, features, targets))
+    }
+
+    // Only these consultants can be picked. Their history goes too, so the trained strategies can't predict anyone else.
+    pub fn restricted_to(&self, consultant_ids: &[i32]) -> AssignmentData {
+        AssignmentData {
+            history: self.history.iter().filter(|row| consultant_ids.contains(&row.consultant_id)).cloned().collect(),
+            consultants: self.consultants.iter().filter(|c| consultant_ids.contains(&c.id)).cloned().collect(),
+        }
+    }
+
+    fn last_assigned(&self, consultant_id: i32, as_of: DateTime<Utc>) -> Option<DateTime<Utc>> {
+        self.history
+            .iter()
+            .filter(|row| row.consultant_id == consultant_id && row.consult_start < as_of)
+            .map(|row| row.consult_start)
+            .max()
+    }
+
+    fn load(&self, consultant_id: i32, as_of: DateTime<Utc>) -> usize {
+        let window = Duration::days(LOAD_WINDOW_DAYS);
+        self.history
+            .iter()
+            .filter(|row| {
+                row.consultant_id == consultant_id
+                    && row.consult_start >= as_of - window
+                    && row.consult_start < as_of + window
+            })
+            .count()
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Assignment {
+    pub consultant_id: i32,
+    pub texfile: Option<String>,
+}
+
+impl From<i32> for Assignment {
+    fn from(consultant_id: i32) -> Self {
+        Assignment { consultant_id: consultant_id, texfile: None }
+    }
+}
+
+pub trait AssignmentStrategy {
+    fn name(&self) -> &'static str;
+    // None when the strategy cannot decide (no history, no candidates). Callers fall back to the form value.
+    fn assign(&self, data: &AssignmentData, input: &LinfaPredictionInput, as_of: DateTime<Utc>) -> Option<Assignment>;
+}
+
+pub struct DecisionTreeStrategy {
+    // Write the fitted tree (TikZ + SVG) and its feature pipeline to ./static/linfa/consults/ for audit. Off for evaluation runs.
+    pub export_tree: bool,
+}
+
+pub fn fit_decision_tree(data: &AssignmentData, as_of: DateTime<Utc>) -> Option<(DecisionTree<f32, usize>, FeaturePipeline)> {
+    let (pipeline, features, targets) = data.training_set(as_of)?;
+    let dataset = Dataset::new(features, targets).with_feature_names(pipeline.feature_names());
+    let model = DecisionTree::params()
+        .split_quality(SplitQuality::Gini)
+        .fit(&dataset)
+        .ok()?;
+    Some((model, pipeline))
+}
+
+pub fn decision_tree_svg(model: &DecisionTree<f32, usize>, pipeline: &FeaturePipeline, sample: Option<&[f32]>) -> String {
+    let names = pipeline.feature_names();
+    let names = names.iter().map(String::as_str).collect::<Vec<&str>>();
+    tree_to_svg(model, &names, sample, consultant_leaf_label)
+}
+
+pub fn consultant_leaf_label(consultant_id: &usize) -> String {
+    format!("consultant #{}", consultant_id)
+}
+
+impl AssignmentStrategy for DecisionTreeStrategy {
+    fn name(&self) -> &'static str {
+        "decision_tree"
+    }
+
+    fn assign(&self, data: &AssignmentData, input: &LinfaPredictionInput, as_of: DateTime<Utc>) -> Option<Assignment> {
+        let (model, pipeline) = fit_decision_tree(data, as_of)?;
+        let sample = pipeline.transform(&[input.raw_features()]);
+        let predictions = model.predict(&sample);
+        let consultant_id = *predictions.get(0)? as i32;
+
+        // Create Decision Tree file for each generation for audit/review/records. FIXME: Export to Storage (GCP)
+        // The .svg is what /consult/{slug} shows, with this consult's path through the tree highlighted.
+        let texfile = if self.export_tree {
+            let filename = Uuid::new_v4().to_string();
+            let svg = decision_tree_svg(&model, &pipeline, sample.row(0).as_slice());
+            File::create(format!("./static/linfa/consults/{}.tex", filename))
+                .and_then(|mut f| f.write_all(model.export_to_tikz().with_legend().to_string().as_bytes()))
+                .and_then(|_| File::create(format!("./static/linfa/consults/{}.svg", filename)))
+                .and_then(|mut f| f.write_all(svg.as_bytes()))
+                .and_then(|_| File::create(format!("./static/linfa/consults/{}.pipeline.json", filename)))
+                .and_then(|mut f| f.write_all(pipeline.to_json().as_bytes()))
+                .ok()
+                .map(|_| filename)
+        } else {
+            None
+        };
+
+        Some(Assignment { consultant_id, texfile })
+    }
+}
+
+pub struct LogisticRegressionStrategy;
+
+impl AssignmentStrategy for LogisticRegressionStrategy {
+    fn name(&self) -> &'static str {
+        "logistic_regression"
+    }
+
+    fn assign(&self, data: &AssignmentData, input: &LinfaPredictionInput, as_of: DateTime<Utc>) -> Option<Assignment> {
+        let (pipeline, features, targets) = data.training_set(as_of)?;
+        // Needs at least two classes to fit. With one consultant in history there is nothing to learn.
+        let first = targets[0];
+        if targets.iter().all(|t| *t == first) {
+            return Some(Assignment::from(first as i32));
+        }
+        let dataset = Dataset::new(features.mapv(|v| v as f64), targets);
+        let model = MultiLogisticRegression::<f64>::default()
+            .max_iterations(100)
+            .fit(&dataset)
+            .ok()?;
+        let predictions = model.predict(&pipeline.transform(&[input.raw_features()]).mapv(|v| v as f64));
+        Some(Assignment::from(*predictions.get(0)? as i32))
+    }
+}
+
+pub struct RoundRobinTerritoryStrategy;
+
+impl AssignmentStrategy for RoundRobinTerritoryStrategy {
+    fn name(&self) -> &'static str {
+        "round_robin_territory"
+    }
+
+    fn assign(&self, data: &AssignmentData, input: &LinfaPredictionInput, as_of: DateTime<Utc>) -> Option<Assignment> {
+        let in_territory = data
+            .consultants
+            .iter()
+            .filter(|c| c.territory_id == input.territory_id)
+            .collect::<Vec<&CandidateConsultant>>();
+        let candidates = if in_territory.is_empty() {
+            data.consultants.iter().collect()
+        } else {
+            in_territory
+        };
+        // Never assigned sorts first (None < Some), then whoever has waited longest.
+        candidates
+            .into_iter()
+            .min_by_key(|c| (data.last_assigned(c.id, as_of), c.id))
+            .map(|c| Assignment::from(c.id))
+    }
+}
+
+pub struct LeastLoadedStrategy;
+
+impl AssignmentStrategy for LeastLoadedStrategy {
+    fn name(&self) -> &'static str {
+        "least_loaded"
+    }
+
+    fn assign(&self, data: &AssignmentData, input: &LinfaPredictionInput, as_of: DateTime<Utc>) -> Option<Assignment> {
+        // Widen the pool until someone qualifies: specialty + territory, specialty, territory, anyone.
+        let filters: [&dyn Fn(&CandidateConsultant) -> bool; 4] = [
+            &|c| c.specialty_id == input.specialty_id && c.territory_id == input.territory_id,
+            &|c| c.specialty_id == input.specialty_id,
+            &|c| c.territory_id == input.territory_id,
+            &|_| true,
+        ];
+        filters.iter().find_map(|filter| {
+            data.consultants
+                .iter()
+                .filter(|c| filter(c))
+                .min_by_key(|c| (data.load(c.id, as_of), c.id))
+                .map(|c| Assignment::from(c.id))
+        })
+    }
+}
+
+pub fn strategy_from_id(assignment_strategy_id: i32) -> Box<dyn AssignmentStrategy> {
+    match assignment_strategy_id {
+        2 => Box::new(RoundRobinTerritoryStrategy),
+        3 => Box::new(LeastLoadedStrategy),
+        4 => Box::new(LogisticRegressionStrategy),
+        _ => Box::new(DecisionTreeStrategy { export_tree: true }),
+    }
+}
+
+pub fn all_strategies() -> Vec<Box<dyn AssignmentStrategy>> {
+    vec![
+        Box::new(DecisionTreeStrategy { export_tree: false }),
+        Box::new(RoundRobinTerritoryStrategy),
+        Box::new(LeastLoadedStrategy),
+        Box::new(LogisticRegressionStrategy),
+    ]
+}
+
+// Strategies learn only from consults that happened or will: cancelled ones and requests nobody has
+// confirmed yet aren't assignments anyone made.
+pub async fn load_assignment_data(db: &Pool<Postgres>) -> Result<AssignmentData, String> {
+    let history = sqlx::query_as::<_, AssignmentRow>(
+        "SELECT consults.consultant_id, consult_purpose_id, client_type_id, consults.client_id, clients.specialty_id, clients.territory_id, location_id, notes, consult_result_id, num_attendees, consult_start, consult_end
+                FROM consults INNER JOIN clients ON consults.client_id = clients.id
+                WHERE consults.consultant_id IS NOT NULL AND cancelled_at IS NULL AND booking_status = 'confirmed'
+                ORDER BY consult_start",
+    )
+    .fetch_all(db)
+    .await
+    .map_err(|e| format!("Error in DB {}", e))?;
+
+    let consultants = sqlx::query_as::<_, CandidateConsultant>(
+        "SELECT id, specialty_id, territory_id FROM consultants ORDER BY id",
+    )
+    .fetch_all(db)
+    .await
+    .map_err(|e| format!("Error in DB {}", e))?;
+
+    Ok(AssignmentData { history, consultants })
+}
+
+#[derive(Debug, Serialize, FromRow, Deserialize)]
+struct AccountStrategy {
+    assignment_strategy_id: i32,
+}
+
+pub async fn account_strategy_id(client_id: i32, db: &Pool<Postgres>) -> i32 {
+    match sqlx::query_as::<_, AccountStrategy>(
+        "SELECT accounts.assignment_strategy_id FROM accounts INNER JOIN clients ON clients.account_id = accounts.id WHERE clients.id = $1",
+    )
+    .bind(client_id)
+    .fetch_optional(db)
+    .await
+    {
+        Ok(Some(row)) => row.assignment_strategy_id,
+        _ => 1,
+    }
+}
+
+// Consultants who are working, not on approved time off and free of consults and outside calendars for the window
+pub async fn available_consultants(
+    db: &Pool<Postgres>,
+    consultants: &[CandidateConsultant],
+    start: DateTime<Utc>,
+    end: DateTime<Utc>,
+) -> Result<Vec<i32>, String> {
+    let availability = load_availability(db, start, end)
+        .await
+        .map_err(|e| format!("Error in DB {}", e))?;
+    let mut busy = load_busy_blocks(db, start.with_timezone(&consult_offset()).date_naive()).await?;
+    let last_day = (end - Duration::seconds(1)).with_timezone(&consult_offset()).date_naive();
+    if last_day != start.with_timezone(&consult_offset()).date_naive() {
+        busy.extend(load_busy_blocks(db, last_day).await?);
+    }
+    Ok(consultants
+        .iter()
+        .filter(|c| availability.is_available(c.id, start, end))
+        .filter(|c| !busy.iter().any(|b| b.consultant_id == c.id && b.overlaps(start, end)))
+        .map(|c| c.id)
+        .collect())
+}
+
+// Entry point for create_consult. Uses whichever strategy the client's account is configured for, choosing
+// among the consultants free for [start, end). When nobody is free it picks from everyone and the conflict
+// check in create_consult explains why.
+pub async fn assign_consultant(
+    input: &LinfaPredictionInput,
+    start: DateTime<Utc>,
+    end: DateTime<Utc>,
+    db: &Pool<Postgres>,
+) -> Result<LinfaPredictionResult, String> {
+    let assignment_strategy_id = account_strategy_id(input.client_id, db).await;
+    let data = load_assignment_data(db).await?;
+    let available = available_consultants(db, &data.consultants, start, end).await?;
+    let data = if available.is_empty() { data } else { data.restricted_to(&available) };
+    let strategy = strategy_from_id(assignment_strategy_id);
+    match strategy.assign(&data, input, Utc::now()) {
+        Some(assignment) => Ok(LinfaPredictionResult(
+            assignment.texfile.unwrap_or_default(),
+            assignment.consultant_id,
+        )),
+        None => Err(format!("{} could not assign a consultant", strategy.name())),
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct EvaluationReport {
+    pub strategy: String,
+    pub evaluated: usize,
+    pub matched: usize,
+    pub accuracy: f32,
+}
+
+// Replays the most recent `holdout` fraction of completed consults. Each one is assigned using only what
+// was known before it started and scored against the consultant that was actually sent.
+pub fn evaluate(strategy: &dyn AssignmentStrategy, data: &AssignmentData, holdout: f32, now: DateTime<Utc>) -> EvaluationReport {
+    let completed = data
+        .history
+        .iter()
+        .filter(|row| row.consult_end.map_or(false, |end| end < now))
+        .collect::<Vec<&AssignmentRow>>();
+    let num_test = ((completed.len() as f32) * holdout).ceil() as usize;
+    let test_rows = &completed[completed.len() - num_test.min(completed.len())..];
+
+    let matched = test_rows
+        .iter()
+        .filter(|row| {
+            strategy
+                .assign(&data.before(row.consult_start), &row.as_input(), row.consult_start)
+                .map_or(false, |a| a.consultant_id == row.consultant_id)
+        })
+        .count();
+
+    EvaluationReport {
+        strategy: strategy.name().to_string(),
+        evaluated: test_rows.len(),
+        matched: matched,
+        accuracy: if test_rows.is_empty() { 0.0 } else { matched as f32 / test_rows.len() as f32 },
+    }
+}
+
+// CPU bound for a long history, so callers on the server run it with web::block
+pub fn compare_strategies(data: &AssignmentData, holdout: f32, now: DateTime<Utc>) -> Vec<EvaluationReport> {
+    all_strategies()
+        .iter()
+        .map(|strategy| evaluate(strategy.as_ref(), data, holdout, now))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::TimeZone;
+
+    use super::*;
+
+    fn row(consultant_id: i32, territory_id: i32, day: u32) -> AssignmentRow {
+        let start = Utc.with_ymd_and_hms(2023, 5, day, 10, 0, 0).unwrap();
+        AssignmentRow {
+            consultant_id,
+            consult_purpose_id: 1,
+            client_type_id: 1,
+            client_id: 1,
+            specialty_id: 1,
+            territory_id,
+            location_id: 1,
+            notes: None,
+            consult_result_id: 1,
+            num_attendees: 1,
+            consult_start: start,
+            consult_end: Some(start + Duration::hours(1)),
+        }
+    }
+
+    fn consultant(id: i32, specialty_id: i32, territory_id: i32) -> CandidateConsultant {
+        CandidateConsultant { id, specialty_id, territory_id }
+    }
+
+    fn data() -> AssignmentData {
+        AssignmentData {
+            history: vec![row(1, 2, 1), row(2, 2, 2), row(1, 2, 3)],
+            consultants: vec![consultant(1, 1, 2), consultant(2, 1, 2), consultant(3, 4, 2), consultant(4, 1, 3)],
+        }
+    }
+
+    #[test]
+    fn round_robin_picks_longest_waiting_in_territory() {
+        let as_of = Utc.with_ymd_and_hms(2023, 5, 10, 0, 0, 0).unwrap();
+        let input = row(0, 2, 10).as_input();
+        // Consultant 3 has never been assigned in territory 2
+        assert_eq!(RoundRobinTerritoryStrategy.assign(&data(), &input, as_of), Some(Assignment::from(3)));
+    }
+
+    #[test]
+    fn least_loaded_stays_within_specialty_and_territory() {
+        let as_of = Utc.with_ymd_and_hms(2023, 5, 4, 0, 0, 0).unwrap();
+        let input = row(0, 2, 4).as_input();
+        // 1 and 2 match specialty 1 / territory 2. 1 has two consults in the window, 2 has one.
+        assert_eq!(LeastLoadedStrategy.assign(&data(), &input, as_of), Some(Assignment::from(2)));
+    }
+
+    #[test]
+    fn restricted_data_only_offers_available_consultants() {
+        let as_of = Utc.with_ymd_and_hms(2023, 5, 10, 0, 0, 0).unwrap();
+        let input = row(0, 2, 10).as_input();
+        let data = data().restricted_to(&[1, 2]);
+        assert_eq!(data.consultants.iter().map(|c| c.id).collect::<Vec<i32>>(), vec![1, 2]);
+        assert_eq!(data.history.len(), 3);
+        // 3 would be next in line but is unavailable, 2 has waited longer than 1
+        assert_eq!(RoundRobinTerritoryStrategy.assign(&data, &input, as_of), Some(Assignment::from(2)));
+        let data = data.restricted_to(&[1]);
+        assert!(data.history.iter().all(|row| row.consultant_id == 1));
+        assert_eq!(LeastLoadedStrategy.assign(&data, &input, as_of), Some(Assignment::from(1)));
+    }
+
+    #[test]
+    fn evaluate_only_uses_prior_history() {
+        let now = Utc.with_ymd_and_hms(2023, 6, 1, 0, 0, 0).unwrap();
+        let report = evaluate(&DecisionTreeStrategy { export_tree: false }, &data(), 0.34, now);
+        assert_eq!(report.evaluated, 2);
+        assert_eq!(report.strategy, "decision_tree");
+    }
+}
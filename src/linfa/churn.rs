@@ -0,0 +1,276 @@
+use std::collections::HashMap;
+
+use chrono::{DateTime, Duration, Utc};
+use linfa::{traits::Fit, Dataset};
+use linfa_logistic::LogisticRegression;
+use ndarray::Array1;
+use serde::{Deserialize, Serialize};
+use sqlx::{FromRow, Pool, Postgres, QueryBuilder};
+
+use super::features::{Encoding, FeaturePipeline, FeatureSpec, RawFeatures, FEATURE_PIPELINE_VERSION};
+
+// Will this client go quiet? Scores each client's risk of NOT booking another consult within
+// `horizon_days`. Trained on a snapshot taken `horizon_days` ago, where the outcome is already known.
+
+pub const CHURN_HORIZON_DAYS: i64 = 90;
+
+// Matches ChurnFeatures::raw_features()
+pub const CHURN_FEATURES: [FeatureSpec; 9] = [
+    FeatureSpec { name: "client_type_id", encoding: Encoding::OneHot },
+    FeatureSpec { name: "specialty_id", encoding: Encoding::OneHot },
+    FeatureSpec { name: "territory_id", encoding: Encoding::OneHot },
+    FeatureSpec { name: "num_consults", encoding: Encoding::Numeric },
+    FeatureSpec { name: "days_since_last", encoding: Encoding::Numeric },
+    FeatureSpec { name: "avg_gap_days", encoding: Encoding::Numeric },
+    FeatureSpec { name: "follow_up_rate", encoding: Encoding::Numeric },
+    FeatureSpec { name: "num_attachments", encoding: Encoding::Numeric },
+    FeatureSpec { name: "avg_attendees", encoding: Encoding::Numeric },
+];
+
+#[derive(Debug, Clone, Serialize, FromRow, Deserialize)]
+pub struct ChurnClient {
+    pub client_id: i32,
+    pub client_type_id: i32,
+    pub specialty_id: i32,
+    pub territory_id: i32,
+}
+
+#[derive(Debug, Clone, Serialize, FromRow, Deserialize)]
+pub struct ChurnConsult {
+    pub client_id: i32,
+    pub consult_start: DateTime<Utc>,
+    pub consult_result_id: i32,
+    pub num_attendees: i32,
+    pub num_attachments: i32,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct ChurnFeatures {
+    pub client_type_id: f64,
+    pub specialty_id: f64,
+    pub territory_id: f64,
+    pub num_consults: f64,
+    pub days_since_last: f64,
+    pub avg_gap_days: f64,
+    pub follow_up_rate: f64,
+    pub num_attachments: f64,
+    pub avg_attendees: f64,
+}
+
+impl ChurnFeatures {
+    pub fn raw_features(&self) -> RawFeatures {
+        vec![
+            Some(self.client_type_id as f32),
+            Some(self.specialty_id as f32),
+            Some(self.territory_id as f32),
+            Some(self.num_consults as f32),
+            Some(self.days_since_last as f32),
+            Some(self.avg_gap_days as f32),
+            Some(self.follow_up_rate as f32),
+            Some(self.num_attachments as f32),
+            Some(self.avg_attendees as f32),
+        ]
+    }
+}
+
+// Only consults before `as_of` count. None when the client had no consult yet (nothing to churn from).
+pub fn client_features(client: &ChurnClient, consults: &[&ChurnConsult], as_of: DateTime<Utc>) -> Option<ChurnFeatures> {
+    let mut starts = consults
+        .iter()
+        .filter(|c| c.consult_start < as_of)
+        .collect::<Vec<&&ChurnConsult>>();
+    if starts.is_empty() {
+        return None;
+    }
+    starts.sort_by_key(|c| c.consult_start);
+    let n = starts.len() as f64;
+    let first = starts[0].consult_start;
+    let last = starts[starts.len() - 1].consult_start;
+    let avg_gap_days = if starts.len() > 1 {
+        (last - first).num_days() as f64 / (n - 1.0)
+    } else {
+        0.0
+    };
+    Some(ChurnFeatures {
+        client_type_id: client.client_type_id as f64,
+        specialty_id: client.specialty_id as f64,
+        territory_id: client.territory_id as f64,
+        num_consults: n,
+        days_since_last: (as_of - last).num_days() as f64,
+        avg_gap_days: avg_gap_days,
+        follow_up_rate: starts.iter().filter(|c| c.consult_result_id == 1).count() as f64 / n,
+        num_attachments: starts.iter().map(|c| c.num_attachments as f64).sum(),
+        avg_attendees: starts.iter().map(|c| c.num_attendees as f64).sum::<f64>() / n,
+    })
+}
+
+// true = no consult booked in [as_of, as_of + horizon)
+pub fn churned(consults: &[&ChurnConsult], as_of: DateTime<Utc>, horizon: Duration) -> bool {
+    !consults
+        .iter()
+        .any(|c| c.consult_start >= as_of && c.consult_start < as_of + horizon)
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct ChurnScore {
+    pub client_id: i32,
+    pub risk_score: f64,
+}
+
+pub fn score_clients(
+    clients: &[ChurnClient],
+    consults: &[ChurnConsult],
+    now: DateTime<Utc>,
+    horizon_days: i64,
+) -> Vec<ChurnScore> {
+    let horizon = Duration::days(horizon_days);
+    let snapshot = now - horizon;
+    let mut by_client: HashMap<i32, Vec<&ChurnConsult>> = HashMap::new();
+    for consult in consults {
+        by_client.entry(consult.client_id).or_default().push(consult);
+    }
+    let no_consults: Vec<&ChurnConsult> = vec![];
+
+    let mut train_rows = vec![];
+    let mut train_labels = vec![];
+    let mut score_rows = vec![];
+    for client in clients {
+        let client_consults = by_client.get(&client.client_id).unwrap_or(&no_consults);
+        if let Some(features) = client_features(client, client_consults, snapshot) {
+            train_rows.push(features.raw_features());
+            train_labels.push(churned(client_consults, snapshot, horizon));
+        }
+        match client_features(client, client_consults, now) {
+            Some(features) => score_rows.push((client.client_id, Some(features.raw_features()))),
+            None => score_rows.push((client.client_id, None)),
+        }
+    }
+
+    let base_rate = if train_labels.is_empty() {
+        0.5
+    } else {
+        train_labels.iter().filter(|l| **l).count() as f64 / train_labels.len() as f64
+    };
+
+    let model = if train_labels.iter().any(|l| *l) && train_labels.iter().any(|l| !*l) {
+        let pipeline = FeaturePipeline::fit(&CHURN_FEATURES, &train_rows, &train_labels);
+        let features = pipeline.transform(&train_rows).mapv(|v| v as f64);
+        let dataset = Dataset::new(features, Array1::from(train_labels));
+        LogisticRegression::default()
+            .max_iterations(100)
+            .fit(&dataset)
+            .ok()
+            .map(|model| (model, pipeline))
+    } else {
+        None
+    };
+
+    score_rows
+        .into_iter()
+        .map(|(client_id, features)| {
+            let risk_score = match (features, &model) {
+                // Never booked anything: nothing to retain yet, treat as highest risk
+                (None, _) => 1.0,
+                (Some(row), Some((model, pipeline))) => {
+                    let x = pipeline.transform(&[row]).mapv(|v| v as f64);
+                    let p = model.predict_probabilities(&x)[0];
+                    if model.labels().pos.class { p } else { 1.0 - p }
+                }
+                // One class in history, fall back to the observed rate
+                (Some(_), None) => base_rate,
+            };
+            ChurnScore { client_id, risk_score }
+        })
+        .collect()
+}
+
+pub async fn load_churn_data(db: &Pool<Postgres>) -> Result<(Vec<ChurnClient>, Vec<ChurnConsult>), String> {
+    let clients = sqlx::query_as::<_, ChurnClient>(
+        "SELECT id AS client_id, client_type_id, specialty_id, territory_id FROM clients ORDER BY id",
+    )
+    .fetch_all(db)
+    .await
+    .map_err(|e| format!("Error in DB {}", e))?;
+
+    let consults = sqlx::query_as::<_, ChurnConsult>(
+        "SELECT client_id, consult_start, consult_result_id, num_attendees, COALESCE(array_length(consult_attachments, 1), 0) AS num_attachments
+                FROM consults",
+    )
+    .fetch_all(db)
+    .await
+    .map_err(|e| format!("Error in DB {}", e))?;
+
+    Ok((clients, consults))
+}
+
+// Batch job entry point. Rewrites client_risk_scores, returns the number of clients scored.
+pub async fn write_churn_scores(db: &Pool<Postgres>, horizon_days: i64) -> Result<usize, String> {
+    let (clients, consults) = load_churn_data(db).await?;
+    let scores = score_clients(&clients, &consults, Utc::now(), horizon_days);
+    if scores.is_empty() {
+        return Ok(0);
+    }
+
+    let mut query: QueryBuilder<Postgres> =
+        QueryBuilder::new("INSERT INTO client_risk_scores (client_id, risk_score, horizon_days, feature_version, scored_at) ");
+    query.push_values(&scores, |mut b, score| {
+        b.push_bind(score.client_id)
+            .push_bind(score.risk_score as f32)
+            .push_bind(horizon_days as i32)
+            .push_bind(FEATURE_PIPELINE_VERSION as i32)
+            .push("NOW()");
+    });
+    query.push(
+        " ON CONFLICT (client_id) DO UPDATE SET risk_score = EXCLUDED.risk_score, horizon_days = EXCLUDED.horizon_days, feature_version = EXCLUDED.feature_version, scored_at = EXCLUDED.scored_at",
+    );
+    query
+        .build()
+        .execute(db)
+        .await
+        .map_err(|e| format!("Error in DB {}", e))?;
+
+    Ok(scores.len())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::TimeZone;
+
+    fn client(client_id: i32) -> ChurnClient {
+        ChurnClient { client_id, client_type_id: 1, specialty_id: 1, territory_id: 1 }
+    }
+
+    fn consult(client_id: i32, days_ago: i64, now: DateTime<Utc>) -> ChurnConsult {
+        ChurnConsult {
+            client_id,
+            consult_start: now - Duration::days(days_ago),
+            consult_result_id: 1,
+            num_attendees: 2,
+            num_attachments: 1,
+        }
+    }
+
+    #[test]
+    fn features_ignore_future_consults() {
+        let now = Utc.with_ymd_and_hms(2023, 11, 1, 0, 0, 0).unwrap();
+        let consults = vec![consult(1, 30, now), consult(1, 10, now), consult(1, -5, now)];
+        let refs = consults.iter().collect::<Vec<&ChurnConsult>>();
+        let features = client_features(&client(1), &refs, now).unwrap();
+        assert_eq!(features.num_consults, 2.0);
+        assert_eq!(features.days_since_last, 10.0);
+        assert_eq!(features.avg_gap_days, 20.0);
+        assert!(!churned(&refs, now - Duration::days(20), Duration::days(15)));
+        assert!(churned(&refs, now - Duration::days(9), Duration::days(5)));
+    }
+
+    #[test]
+    fn client_without_consults_is_highest_risk() {
+        let now = Utc.with_ymd_and_hms(2023, 11, 1, 0, 0, 0).unwrap();
+        let consults = vec![consult(1, 200, now), consult(1, 20, now)];
+        let scores = score_clients(&[client(1), client(2)], &consults, now, 90);
+        assert_eq!(scores[1], ChurnScore { client_id: 2, risk_score: 1.0 });
+        // Client 1 booked again within the horizon of its snapshot, the only label seen
+        assert_eq!(scores[0].risk_score, 0.0);
+    }
+}
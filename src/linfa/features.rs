@@ -0,0 +1,201 @@
+use std::collections::HashMap;
+
+use ndarray::Array2;
+use serde::{Deserialize, Serialize};
+
+// Turns raw rows (ids, counts, durations) into model input. Ids are categories, not magnitudes, so
+// they are one-hot or target encoded instead of cast to f32. Numeric columns are standardized and
+// missing values are imputed instead of unwrapped. Bump the version whenever encodings change, it is
+// persisted with every exported model so old artifacts can be told apart.
+pub const FEATURE_PIPELINE_VERSION: u32 = 1;
+
+// Pulls target encoded categories towards the global rate until they have this many rows.
+const TARGET_SMOOTHING: f32 = 5.0;
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub enum Encoding {
+    OneHot,
+    // Smoothed follow-up rate of the category. For high cardinality ids (client, location).
+    Target,
+    Numeric,
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct FeatureSpec {
+    pub name: &'static str,
+    pub encoding: Encoding,
+}
+
+// One value per FeatureSpec, in spec order. None is a missing value.
+pub type RawFeatures = Vec<Option<f32>>;
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub enum FittedColumn {
+    OneHot { name: String, categories: Vec<i64> },
+    Target { name: String, rates: HashMap<i64, f32>, prior: f32 },
+    Numeric { name: String, mean: f32, std: f32 },
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct FeaturePipeline {
+    pub version: u32,
+    pub columns: Vec<FittedColumn>,
+}
+
+fn category(value: f32) -> i64 {
+    value.round() as i64
+}
+
+impl FeaturePipeline {
+    // `outcomes` (one per row) are only used by Target columns.
+    pub fn fit(specs: &[FeatureSpec], rows: &[RawFeatures], outcomes: &[bool]) -> FeaturePipeline {
+        let prior = if outcomes.is_empty() {
+            0.5
+        } else {
+            outcomes.iter().filter(|o| **o).count() as f32 / outcomes.len() as f32
+        };
+
+        let columns = specs
+            .iter()
+            .enumerate()
+            .map(|(idx, spec)| {
+                let values = rows.iter().map(|row| row.get(idx).copied().flatten());
+                match spec.encoding {
+                    Encoding::OneHot => {
+                        let mut categories = values.flatten().map(category).collect::<Vec<i64>>();
+                        categories.sort();
+                        categories.dedup();
+                        FittedColumn::OneHot { name: spec.name.to_string(), categories }
+                    }
+                    Encoding::Target => {
+                        let mut counts: HashMap<i64, (f32, f32)> = HashMap::new();
+                        for (value, outcome) in values.zip(outcomes.iter()) {
+                            if let Some(value) = value {
+                                let entry = counts.entry(category(value)).or_insert((0.0, 0.0));
+                                entry.0 += if *outcome { 1.0 } else { 0.0 };
+                                entry.1 += 1.0;
+                            }
+                        }
+                        let rates = counts
+                            .into_iter()
+                            .map(|(cat, (hits, n))| (cat, (hits + prior * TARGET_SMOOTHING) / (n + TARGET_SMOOTHING)))
+                            .collect();
+                        FittedColumn::Target { name: spec.name.to_string(), rates, prior }
+                    }
+                    Encoding::Numeric => {
+                        let present = values.flatten().collect::<Vec<f32>>();
+                        let n = present.len().max(1) as f32;
+                        let mean = present.iter().sum::<f32>() / n;
+                        let var = present.iter().map(|v| (v - mean).powi(2)).sum::<f32>() / n;
+                        let std = if var > 0.0 { var.sqrt() } else { 1.0 };
+                        FittedColumn::Numeric { name: spec.name.to_string(), mean, std }
+                    }
+                }
+            })
+            .collect();
+
+        FeaturePipeline { version: FEATURE_PIPELINE_VERSION, columns }
+    }
+
+    pub fn num_features(&self) -> usize {
+        self.columns
+            .iter()
+            .map(|col| match col {
+                FittedColumn::OneHot { categories, .. } => categories.len(),
+                _ => 1,
+            })
+            .sum()
+    }
+
+    pub fn feature_names(&self) -> Vec<String> {
+        self.columns
+            .iter()
+            .flat_map(|col| match col {
+                FittedColumn::OneHot { name, categories } => {
+                    categories.iter().map(|c| format!("{}={}", name, c)).collect::<Vec<String>>()
+                }
+                FittedColumn::Target { name, .. } => vec![format!("{}_rate", name)],
+                FittedColumn::Numeric { name, .. } => vec![name.clone()],
+            })
+            .collect()
+    }
+
+    // Unseen categories and missing values never fail: all-zero one-hot, the prior, or the mean.
+    pub fn transform_row(&self, row: &RawFeatures) -> Vec<f32> {
+        let mut out = Vec::with_capacity(self.num_features());
+        for (idx, col) in self.columns.iter().enumerate() {
+            let value = row.get(idx).copied().flatten();
+            match col {
+                FittedColumn::OneHot { categories, .. } => {
+                    let cat = value.map(category);
+                    out.extend(categories.iter().map(|c| if Some(*c) == cat { 1.0 } else { 0.0 }));
+                }
+                FittedColumn::Target { rates, prior, .. } => {
+                    out.push(value.and_then(|v| rates.get(&category(v)).copied()).unwrap_or(*prior));
+                }
+                FittedColumn::Numeric { mean, std, .. } => {
+                    out.push((value.unwrap_or(*mean) - mean) / std);
+                }
+            }
+        }
+        out
+    }
+
+    pub fn transform(&self, rows: &[RawFeatures]) -> Array2<f32> {
+        let width = self.num_features();
+        let flat = rows.iter().flat_map(|row| self.transform_row(row)).collect::<Vec<f32>>();
+        Array2::from_shape_vec((rows.len(), width), flat).unwrap()
+    }
+
+    pub fn to_json(&self) -> String {
+        serde_json::to_string(self).unwrap()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SPECS: [FeatureSpec; 3] = [
+        FeatureSpec { name: "purpose", encoding: Encoding::OneHot },
+        FeatureSpec { name: "client", encoding: Encoding::Target },
+        FeatureSpec { name: "duration", encoding: Encoding::Numeric },
+    ];
+
+    fn pipeline() -> FeaturePipeline {
+        let rows = vec![
+            vec![Some(1.), Some(10.), Some(30.)],
+            vec![Some(3.), Some(10.), Some(90.)],
+            vec![Some(1.), Some(20.), None],
+        ];
+        FeaturePipeline::fit(&SPECS, &rows, &[true, true, false])
+    }
+
+    #[test]
+    fn one_hot_target_and_numeric_columns() {
+        let p = pipeline();
+        assert_eq!(p.feature_names(), vec!["purpose=1", "purpose=3", "client_rate", "duration"]);
+        let row = p.transform_row(&vec![Some(3.), Some(10.), Some(90.)]);
+        assert_eq!(&row[0..2], &[0., 1.]);
+        // (2 hits + 2/3 * 5) / (2 + 5)
+        assert!((row[2] - (2.0 + 2.0 / 3.0 * 5.0) / 7.0).abs() < 1e-6);
+        assert!((row[3] - 1.0).abs() < 1e-6);
+    }
+
+    #[test]
+    fn missing_and_unseen_values_do_not_panic() {
+        let p = pipeline();
+        let row = p.transform_row(&vec![Some(7.), None, None]);
+        assert_eq!(row, vec![0., 0., 2.0 / 3.0, 0.]);
+        // Short rows are treated as missing too
+        assert_eq!(p.transform(&[vec![]]).dim(), (1, 4));
+    }
+
+    #[test]
+    fn round_trips_through_json() {
+        let p = pipeline();
+        let restored: FeaturePipeline = serde_json::from_str(&p.to_json()).unwrap();
+        assert_eq!(restored, p);
+        assert_eq!(restored.version, FEATURE_PIPELINE_VERSION);
+    }
+}
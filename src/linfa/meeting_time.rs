@@ -0,0 +1,307 @@
+use chrono::{DateTime, Duration, NaiveDate, NaiveTime, TimeZone, Timelike, Utc};
+use linfa::{prelude::Predict, traits::Fit, Dataset};
+use linfa_trees::{DecisionTree, SplitQuality};
+use ndarray::Array1;
+use serde::{Deserialize, Serialize};
+use sqlx::{FromRow, Pool, Postgres};
+
+use super::{
+    assignment::AssignmentRow,
+    consult_offset,
+    features::{Encoding, FeaturePipeline, FeatureSpec, RawFeatures},
+};
+
+// What hour should we hold the meeting? Trained only on consults that led to a follow up
+// (consult_result_id = 1), so the label is "the hour that worked" for similar consults.
+
+// Local hours a consult may start at.
+const FIRST_HOUR: u32 = 8;
+const LAST_HOUR: u32 = 17;
+const DEFAULT_DURATION_MINUTES: i64 = 60;
+
+pub const MEETING_TIME_FEATURES: [FeatureSpec; 6] = [
+    FeatureSpec { name: "consult_purpose_id", encoding: Encoding::OneHot },
+    FeatureSpec { name: "client_type", encoding: Encoding::OneHot },
+    FeatureSpec { name: "specialty_id", encoding: Encoding::OneHot },
+    FeatureSpec { name: "territory_id", encoding: Encoding::OneHot },
+    FeatureSpec { name: "location_id", encoding: Encoding::OneHot },
+    FeatureSpec { name: "num_attendees", encoding: Encoding::Numeric },
+];
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct MeetingTimeInput {
+    pub consult_purpose_id: i32,
+    pub client_type: i32,
+    pub specialty_id: i32,
+    pub territory_id: i32,
+    pub location_id: i32,
+    pub num_attendees: i32,
+}
+
+impl MeetingTimeInput {
+    pub fn raw_features(&self) -> RawFeatures {
+        vec![
+            Some(self.consult_purpose_id as f32),
+            Some(self.client_type as f32),
+            Some(self.specialty_id as f32),
+            Some(self.territory_id as f32),
+            Some(self.location_id as f32),
+            Some(self.num_attendees as f32),
+        ]
+    }
+}
+
+impl From<&AssignmentRow> for MeetingTimeInput {
+    fn from(row: &AssignmentRow) -> Self {
+        MeetingTimeInput {
+            consult_purpose_id: row.consult_purpose_id,
+            client_type: row.client_type_id,
+            specialty_id: row.specialty_id,
+            territory_id: row.territory_id,
+            location_id: row.location_id,
+            num_attendees: row.num_attendees,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct MeetingTimeSuggestion {
+    pub hour_of_day: u32,
+    pub duration_minutes: i64,
+}
+
+fn is_successful(row: &AssignmentRow, as_of: DateTime<Utc>) -> bool {
+    row.consult_result_id == 1 && row.consult_end.map_or(false, |end| end < as_of)
+}
+
+fn local_hour(dt: DateTime<Utc>) -> u32 {
+    dt.with_timezone(&consult_offset()).hour()
+}
+
+// Median duration of successful consults with the same purpose, rounded to 15 minutes.
+fn suggested_duration(successful: &[&AssignmentRow], consult_purpose_id: i32) -> i64 {
+    let mut durations = successful
+        .iter()
+        .filter(|row| row.consult_purpose_id == consult_purpose_id)
+        .filter_map(|row| row.consult_end.map(|end| (end - row.consult_start).num_minutes()))
+        .filter(|minutes| *minutes > 0)
+        .collect::<Vec<i64>>();
+    if durations.is_empty() {
+        return DEFAULT_DURATION_MINUTES;
+    }
+    durations.sort();
+    let median = durations[durations.len() / 2];
+    (((median + 7) / 15) * 15).max(15)
+}
+
+pub fn suggest_meeting_time(
+    history: &[AssignmentRow],
+    input: &MeetingTimeInput,
+    as_of: DateTime<Utc>,
+) -> Option<MeetingTimeSuggestion> {
+    let successful = history
+        .iter()
+        .filter(|row| is_successful(row, as_of))
+        .collect::<Vec<&AssignmentRow>>();
+    if successful.is_empty() {
+        return None;
+    }
+    let raw = successful
+        .iter()
+        .map(|row| MeetingTimeInput::from(*row).raw_features())
+        .collect::<Vec<RawFeatures>>();
+    // Every row is a success, so there is nothing to target encode against. OneHot/Numeric only.
+    let pipeline = FeaturePipeline::fit(&MEETING_TIME_FEATURES, &raw, &[]);
+    let features = pipeline.transform(&raw);
+    let targets = successful
+        .iter()
+        .map(|row| local_hour(row.consult_start) as usize)
+        .collect::<Array1<usize>>();
+    let dataset = Dataset::new(features, targets).with_feature_names(pipeline.feature_names());
+    let model = DecisionTree::params()
+        .split_quality(SplitQuality::Gini)
+        .fit(&dataset)
+        .ok()?;
+    let predictions = model.predict(&pipeline.transform(&[input.raw_features()]));
+    let hour_of_day = (*predictions.get(0)? as u32).clamp(FIRST_HOUR, LAST_HOUR);
+
+    Some(MeetingTimeSuggestion {
+        hour_of_day,
+        duration_minutes: suggested_duration(&successful, input.consult_purpose_id),
+    })
+}
+
+#[derive(Debug, Clone, Serialize, FromRow, Deserialize)]
+pub struct BusyBlock {
+    pub consultant_id: i32,
+    pub busy_start: DateTime<Utc>,
+    pub busy_end: DateTime<Utc>,
+}
+
+impl BusyBlock {
+    pub fn overlaps(&self, start: DateTime<Utc>, end: DateTime<Utc>) -> bool {
+        self.busy_start < end && start < self.busy_end
+    }
+}
+
+pub fn day_bounds(date: NaiveDate) -> (DateTime<Utc>, DateTime<Utc>) {
+    let start = consult_offset()
+        .from_local_datetime(&date.and_time(NaiveTime::MIN))
+        .unwrap()
+        .with_timezone(&Utc);
+    (start, start + Duration::days(1))
+}
+
+// A consult has to start at FIRST_HOUR or later and be over by the end of the LAST_HOUR slot, on one
+// local day.
+pub fn within_business_hours(start: DateTime<Utc>, end: DateTime<Utc>) -> bool {
+    let local_start = start.with_timezone(&consult_offset());
+    let local_end = end.with_timezone(&consult_offset());
+    let closing = local_start.date_naive().and_hms_opt(LAST_HOUR + 1, 0, 0).unwrap();
+    start < end && local_start.hour() >= FIRST_HOUR && local_end.naive_local() <= closing
+}
+
+// Consults without an end are treated as the usual hour long block. Imported calendar events
+// (consultant_busy_blocks) count the same as consults.
+pub async fn load_busy_blocks(db: &Pool<Postgres>, date: NaiveDate) -> Result<Vec<BusyBlock>, String> {
+    let (day_start, day_end) = day_bounds(date);
+    sqlx::query_as::<_, BusyBlock>(
+        "SELECT consultant_id, consult_start AS busy_start, COALESCE(consult_end, consult_start + INTERVAL '1 hour') AS busy_end
+                FROM consults
+                WHERE consultant_id IS NOT NULL AND cancelled_at IS NULL AND consult_start < $2 AND COALESCE(consult_end, consult_start + INTERVAL '1 hour') > $1
+            UNION ALL
+            SELECT consultant_id, busy_start, busy_end
+                FROM consultant_busy_blocks
+                WHERE busy_start < $2 AND busy_end > $1",
+    )
+    .bind(day_start)
+    .bind(day_end)
+    .fetch_all(db)
+    .await
+    .map_err(|e| format!("Error in DB {}", e))
+}
+
+// What a consultant already has between start and end, from consults and imported calendars.
+// `ignore_slug` leaves out the consult being edited.
+pub async fn consultant_conflicts(
+    db: &Pool<Postgres>,
+    consultant_id: i32,
+    start: DateTime<Utc>,
+    end: DateTime<Utc>,
+    ignore_slug: Option<&str>,
+) -> Result<Vec<BusyBlock>, String> {
+    sqlx::query_as::<_, BusyBlock>(
+        "SELECT consultant_id, consult_start AS busy_start, COALESCE(consult_end, consult_start + INTERVAL '1 hour') AS busy_end
+                FROM consults
+                WHERE consultant_id = $1 AND cancelled_at IS NULL AND consult_start < $3 AND COALESCE(consult_end, consult_start + INTERVAL '1 hour') > $2
+                    AND ($4::TEXT IS NULL OR slug <> $4)
+            UNION ALL
+            SELECT consultant_id, busy_start, busy_end
+                FROM consultant_busy_blocks
+                WHERE consultant_id = $1 AND busy_start < $3 AND busy_end > $2
+            ORDER BY busy_start",
+    )
+    .bind(consultant_id)
+    .bind(start)
+    .bind(end)
+    .bind(ignore_slug)
+    .fetch_all(db)
+    .await
+    .map_err(|e| format!("Error in DB {}", e))
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SuggestedSlot {
+    pub date: String,
+    pub start_time: String,
+    pub end_time: String,
+    pub recommended: bool,
+    pub available_consultants: usize,
+}
+
+// Walks outward from the predicted hour and keeps the slots where at least one of `consultant_ids` is free.
+pub fn suggested_slots(
+    date: NaiveDate,
+    suggestion: &MeetingTimeSuggestion,
+    busy: &[BusyBlock],
+    consultant_ids: &[i32],
+    limit: usize,
+) -> Vec<SuggestedSlot> {
+    let mut hours = (FIRST_HOUR..=LAST_HOUR).collect::<Vec<u32>>();
+    hours.sort_by_key(|h| ((*h as i32 - suggestion.hour_of_day as i32).abs(), *h));
+
+    let (day_start, _) = day_bounds(date);
+    hours
+        .into_iter()
+        .filter_map(|hour| {
+            let start = day_start + Duration::hours(hour as i64);
+            let end = start + Duration::minutes(suggestion.duration_minutes);
+            let available_consultants = consultant_ids
+                .iter()
+                .filter(|id| !busy.iter().any(|b| b.consultant_id == **id && b.overlaps(start, end)))
+                .count();
+            if available_consultants == 0 {
+                return None;
+            }
+            let local_start = start.with_timezone(&consult_offset());
+            let local_end = end.with_timezone(&consult_offset());
+            Some(SuggestedSlot {
+                date: local_start.format("%Y-%m-%d").to_string(),
+                start_time: local_start.format("%H:%M").to_string(),
+                end_time: local_end.format("%H:%M").to_string(),
+                recommended: hour == suggestion.hour_of_day,
+                available_consultants,
+            })
+        })
+        .take(limit)
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn suggestion() -> MeetingTimeSuggestion {
+        MeetingTimeSuggestion { hour_of_day: 10, duration_minutes: 60 }
+    }
+
+    fn date() -> NaiveDate {
+        NaiveDate::from_ymd_opt(2023, 11, 14).unwrap()
+    }
+
+    #[test]
+    fn slots_start_at_predicted_hour_and_fan_out() {
+        let slots = suggested_slots(date(), &suggestion(), &[], &[1], 3);
+        let times = slots.iter().map(|s| s.start_time.as_str()).collect::<Vec<&str>>();
+        assert_eq!(times, vec!["10:00", "09:00", "11:00"]);
+        assert!(slots[0].recommended);
+    }
+
+    #[test]
+    fn business_hours_are_local() {
+        let (day_start, _) = day_bounds(date());
+        let at = |minutes: i64| day_start + Duration::minutes(minutes);
+        assert!(within_business_hours(at(8 * 60), at(9 * 60)));
+        assert!(within_business_hours(at(17 * 60), at(18 * 60)));
+        assert!(!within_business_hours(at(7 * 60 + 30), at(8 * 60 + 30)));
+        assert!(!within_business_hours(at(17 * 60 + 30), at(18 * 60 + 30)));
+        assert!(!within_business_hours(at(10 * 60), at(10 * 60)));
+    }
+
+    #[test]
+    fn busy_consultant_removes_slot() {
+        let (day_start, _) = day_bounds(date());
+        let busy = vec![BusyBlock {
+            consultant_id: 1,
+            busy_start: day_start + Duration::minutes(10 * 60 + 30),
+            busy_end: day_start + Duration::hours(12),
+        }];
+        let slots = suggested_slots(date(), &suggestion(), &busy, &[1], 3);
+        let times = slots.iter().map(|s| s.start_time.as_str()).collect::<Vec<&str>>();
+        assert_eq!(times, vec!["09:00", "08:00", "12:00"]);
+        // A second consultant keeps the hour open
+        let slots = suggested_slots(date(), &suggestion(), &busy, &[1, 2], 1);
+        assert_eq!(slots[0].start_time, "10:00");
+        assert_eq!(slots[0].available_consultants, 1);
+    }
+}
@@ -0,0 +1,56 @@
+use chrono::FixedOffset;
+
+use crate::scopes::consult::LinfaPredictionInput;
+
+use self::features::{Encoding, FeatureSpec, RawFeatures};
+
+pub mod assignment;
+pub mod churn;
+pub mod features;
+pub mod meeting_time;
+pub mod tree_svg;
+
+// Which consultant should we send?
+// What hour should we hold the meeting?
+// If what hour - labels will be the hour_of_day and set received_follow_up to 1 (true)
+
+// Column order of every raw consult row handed to the feature pipeline. Matches LinfaPredictionInput::raw_features().
+pub const CONSULT_FEATURES: [FeatureSpec; 11] = [
+    FeatureSpec { name: "consult_purpose_id", encoding: Encoding::OneHot },
+    FeatureSpec { name: "client_id", encoding: Encoding::Target },
+    FeatureSpec { name: "client_type", encoding: Encoding::OneHot },
+    FeatureSpec { name: "specialty_id", encoding: Encoding::OneHot },
+    FeatureSpec { name: "territory_id", encoding: Encoding::OneHot },
+    FeatureSpec { name: "location_id", encoding: Encoding::Target },
+    FeatureSpec { name: "notes_length", encoding: Encoding::Numeric },
+    FeatureSpec { name: "meeting_duration", encoding: Encoding::Numeric },
+    FeatureSpec { name: "hour_of_day", encoding: Encoding::Numeric },
+    FeatureSpec { name: "received_follow_up", encoding: Encoding::Numeric },
+    FeatureSpec { name: "num_attendees", encoding: Encoding::Numeric },
+];
+
+// Consult times are entered on the forms as -06:00 (see create_consult). hour_of_day features use the same clock.
+pub fn consult_offset() -> FixedOffset {
+    FixedOffset::west_opt(6 * 3600).unwrap()
+}
+
+impl LinfaPredictionInput {
+    pub fn raw_features(&self) -> RawFeatures {
+        vec![
+            Some(self.consult_purpose_id as f32),
+            Some(self.client_id as f32),
+            Some(self.client_type as f32),
+            Some(self.specialty_id as f32),
+            Some(self.territory_id as f32),
+            Some(self.location_id as f32),
+            Some(self.notes_length as f32),
+            Some(self.meeting_duration as f32),
+            Some(self.hour_of_day as f32),
+            Some(self.received_follow_up as f32),
+            Some(self.num_attendees as f32),
+        ]
+    }
+}
+
+// (texfile_uuid, consultant_id). texfile is empty when the strategy has no tree to export.
+pub struct LinfaPredictionResult(pub String, pub i32);
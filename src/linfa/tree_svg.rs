@@ -0,0 +1,193 @@
+use linfa::Label;
+use linfa_trees::{DecisionTree, TreeNode};
+
+// Renders a fitted DecisionTree as a standalone SVG (inline styles, so it also works through <img>).
+// When a sample is given, the nodes/edges it passes through on the way to its leaf are highlighted.
+
+const NODE_WIDTH: f32 = 150.0;
+const NODE_HEIGHT: f32 = 40.0;
+const H_GAP: f32 = 20.0;
+const V_GAP: f32 = 50.0;
+const MARGIN: f32 = 10.0;
+
+const PATH_COLOR: &str = "#d9480f";
+const NODE_FILL: &str = "#f1f3f5";
+const LEAF_FILL: &str = "#e7f5ff";
+const STROKE: &str = "#868e96";
+
+struct SvgNode {
+    x: f32,
+    y: f32,
+    label: String,
+    leaf: bool,
+    on_path: bool,
+}
+
+struct SvgEdge {
+    from: (f32, f32),
+    to: (f32, f32),
+    label: &'static str,
+    on_path: bool,
+}
+
+struct Layout<'a> {
+    feature_names: &'a [&'a str],
+    sample: Option<&'a [f32]>,
+    nodes: Vec<SvgNode>,
+    edges: Vec<SvgEdge>,
+    next_leaf: usize,
+    max_depth: usize,
+}
+
+fn escape(s: &str) -> String {
+    s.replace('&', "&amp;").replace('<', "&lt;").replace('>', "&gt;").replace('"', "&quot;")
+}
+
+impl<'a> Layout<'a> {
+    // Leaves are laid out left to right, parents are centered over their children. Returns the node's x.
+    fn place<L: Label, F: Fn(&L) -> String>(
+        &mut self,
+        node: &TreeNode<f32, L>,
+        depth: usize,
+        on_path: bool,
+        leaf_label: &F,
+    ) -> f32 {
+        self.max_depth = self.max_depth.max(depth);
+        let y = MARGIN + depth as f32 * (NODE_HEIGHT + V_GAP);
+        let children = node
+            .children()
+            .into_iter()
+            .filter_map(|child| child.as_deref())
+            .collect::<Vec<&TreeNode<f32, L>>>();
+
+        if node.is_leaf() || children.len() < 2 {
+            let x = MARGIN + self.next_leaf as f32 * (NODE_WIDTH + H_GAP);
+            self.next_leaf += 1;
+            let label = node.prediction().map(|p| leaf_label(&p)).unwrap_or_default();
+            self.nodes.push(SvgNode { x, y, label, leaf: true, on_path });
+            return x;
+        }
+
+        let (feature_idx, split_value, _) = node.split();
+        // Same rule as DecisionTree::predict: below the split goes left
+        let goes_left = self.sample.and_then(|s| s.get(feature_idx)).map(|v| *v < split_value);
+        let left_x = self.place(children[0], depth + 1, on_path && goes_left == Some(true), leaf_label);
+        let right_x = self.place(children[1], depth + 1, on_path && goes_left == Some(false), leaf_label);
+        let x = (left_x + right_x) / 2.0;
+
+        let child_y = y + NODE_HEIGHT + V_GAP;
+        let bottom = (x + NODE_WIDTH / 2.0, y + NODE_HEIGHT);
+        self.edges.push(SvgEdge {
+            from: bottom,
+            to: (left_x + NODE_WIDTH / 2.0, child_y),
+            label: "yes",
+            on_path: on_path && goes_left == Some(true),
+        });
+        self.edges.push(SvgEdge {
+            from: bottom,
+            to: (right_x + NODE_WIDTH / 2.0, child_y),
+            label: "no",
+            on_path: on_path && goes_left == Some(false),
+        });
+
+        let feature = self.feature_names.get(feature_idx).copied().unwrap_or("feature");
+        self.nodes.push(SvgNode {
+            x,
+            y,
+            label: format!("{} < {:.2}", feature, split_value),
+            leaf: false,
+            on_path,
+        });
+        x
+    }
+}
+
+pub fn tree_to_svg<L: Label, F: Fn(&L) -> String>(
+    model: &DecisionTree<f32, L>,
+    feature_names: &[&str],
+    sample: Option<&[f32]>,
+    leaf_label: F,
+) -> String {
+    let mut layout = Layout {
+        feature_names,
+        sample,
+        nodes: vec![],
+        edges: vec![],
+        next_leaf: 0,
+        max_depth: 0,
+    };
+    layout.place(model.root_node(), 0, sample.is_some(), &leaf_label);
+
+    let width = 2.0 * MARGIN + layout.next_leaf as f32 * (NODE_WIDTH + H_GAP) - H_GAP;
+    let height = 2.0 * MARGIN + (layout.max_depth + 1) as f32 * (NODE_HEIGHT + V_GAP) - V_GAP;
+
+    let mut svg = format!(
+        r#"<svg xmlns="http://www.w3.org/2000/svg" class="linfa-tree" width="{w}" height="{h}" viewBox="0 0 {w} {h}" font-family="sans-serif" font-size="12">"#,
+        w = width,
+        h = height
+    );
+    for edge in &layout.edges {
+        let (color, stroke_width) = if edge.on_path { (PATH_COLOR, 3) } else { (STROKE, 1) };
+        svg.push_str(&format!(
+            r#"<line x1="{}" y1="{}" x2="{}" y2="{}" stroke="{}" stroke-width="{}"/>"#,
+            edge.from.0, edge.from.1, edge.to.0, edge.to.1, color, stroke_width
+        ));
+        svg.push_str(&format!(
+            r#"<text x="{}" y="{}" text-anchor="middle" fill="{}">{}</text>"#,
+            (edge.from.0 + edge.to.0) / 2.0,
+            (edge.from.1 + edge.to.1) / 2.0,
+            color,
+            edge.label
+        ));
+    }
+    for node in &layout.nodes {
+        let fill = if node.leaf { LEAF_FILL } else { NODE_FILL };
+        let (stroke, stroke_width) = if node.on_path { (PATH_COLOR, 3) } else { (STROKE, 1) };
+        svg.push_str(&format!(
+            r#"<rect x="{}" y="{}" width="{}" height="{}" rx="6" fill="{}" stroke="{}" stroke-width="{}"/>"#,
+            node.x, node.y, NODE_WIDTH, NODE_HEIGHT, fill, stroke, stroke_width
+        ));
+        svg.push_str(&format!(
+            r#"<text x="{}" y="{}" text-anchor="middle" dominant-baseline="middle">{}</text>"#,
+            node.x + NODE_WIDTH / 2.0,
+            node.y + NODE_HEIGHT / 2.0,
+            escape(&node.label)
+        ));
+    }
+    svg.push_str("</svg>");
+    svg
+}
+
+#[cfg(test)]
+mod tests {
+    use linfa::{traits::Fit, Dataset};
+    use linfa_trees::SplitQuality;
+    use ndarray::{array, Array1};
+
+    use super::*;
+
+    fn model() -> DecisionTree<f32, usize> {
+        let features = array![[1., 0.], [2., 0.], [8., 1.], [9., 1.]];
+        let targets: Array1<usize> = array![1, 1, 2, 2];
+        DecisionTree::params()
+            .split_quality(SplitQuality::Gini)
+            .fit(&Dataset::new(features, targets))
+            .unwrap()
+    }
+
+    #[test]
+    fn renders_split_and_leaves() {
+        let svg = tree_to_svg(&model(), &["hour", "<flag>"], None, |c| format!("consultant #{}", c));
+        assert!(svg.starts_with("<svg"));
+        assert!(svg.contains("consultant #1"));
+        assert!(svg.contains("consultant #2"));
+        assert!(!svg.contains(PATH_COLOR));
+    }
+
+    #[test]
+    fn highlights_path_for_sample() {
+        let svg = tree_to_svg(&model(), &["hour", "flag"], Some(&[9., 1.]), |c| format!("consultant #{}", c));
+        // Root, one edge and one leaf
+        assert_eq!(svg.matches(PATH_COLOR).count(), 4);
+    }
+}
@@ -1,4 +1,4 @@
-use std::{env, collections::BTreeMap};
+use std::{env, sync::Arc, time::Instant};
 
 use actix_files::Files;
 use actix_web::{
@@ -12,32 +12,42 @@ use config::{is_dirty, Post, UserFeedData};
 use dotenv::dotenv;
 use handlebars::Handlebars;
 use hbs_helpers::{
-    attachments_rte, construct_opts_url, concat_str_args, fifth_week, first_week, form_rte, fourth_week,
-    get_icon, get_list_view, get_month_name, get_search_rte, get_table_title, int_eq, int_in, is_holiday,
-    is_icon_col, loc_vec_len_ten, lower_and_single, preview_text, second_week, sort_rte, str_eq,
-    subscribe_icon, subscribe_rte, third_week, to_title_case, get_filter_class, str_in, cal_rte
+    attachments_rte, construct_opts_url, concat_str_args, form_rte, get_icon, get_list_view, get_month_name,
+    get_search_rte, get_table_title, int_eq, int_in, is_icon_col, loc_vec_len_ten, lower_and_single,
+    preview_text, sort_rte, str_eq, subscribe_icon, subscribe_rte, to_title_case, get_filter_class, str_in,
+    live_event
 };
 use models::{
     model_admin::AdminUserList, model_consultant::ResponseConsultant, model_location::LocationList,
 };
-use ::redis::{FromRedisValue, RedisResult, from_redis_value, Value, ErrorKind};
-use redis_mod::redis_mod::{redis_connect};
+use jobs::{schedule::run_scheduler, worker::run_worker, JobContext};
+use redis_mod::{
+    circuit_breaker::{BreakerState, CircuitBreaker},
+    live_updates::{relay_live_events, Broadcaster},
+    materialized::{flush_all, materialized_user_feed, warm_all},
+    rate_limit::{rate_limited, CONTACT_US},
+    redis_mod::{redis_connect, REDIS_TIMEOUT},
+    typed::RedisModel,
+};
+use chrono::{DateTime, Utc};
 use serde::{Deserialize, Serialize};
 use serde_json::json;
 use sqlx::{postgres::PgPoolOptions, FromRow, Pool, Postgres};
 use validator::{Validate, ValidationError};
 
 use crate::{
-    config::{get_ip, mock_fixed_table_data, user_feed, validate_and_get_user, ValidationResponse, redis_validate_and_get_user, validate_email, ApiError},
-    linfa::linfa_pred,
+    config::{get_ip, mock_fixed_table_data, validate_and_get_user, ValidationResponse, redis_validate_and_get_user, validate_email, ApiError},
 };
 use deadpool_redis::{redis::{cmd}, Pool as RedisPool};
 use scopes::{
-    admin::admin_scope, auth::auth_scope, client::client_scope, consult::consult_scope, service::service_scope,
-    consultant::consultant_scope, event::event_scope, location::location_scope, user::user_scope,
+    admin::admin_scope, auth::auth_scope, booking::booking_scope, client::client_scope, consult::consult_scope, service::service_scope,
+    consultant::consultant_scope, event::event_scope, live::live_scope, location::location_scope,
+    notification::notification_scope, presence::presence_scope, user::user_scope,
 };
+mod calendar;
 mod config;
 mod hbs_helpers;
+mod jobs;
 mod linfa;
 mod models;
 mod redis_mod;
@@ -61,6 +71,36 @@ pub struct AppState {
 
 pub struct RedisState {
     r_pool: RedisPool,
+    breaker: Arc<CircuitBreaker>,
+    // Where sessions are read from while Redis is unavailable
+    db: Pool<Postgres>,
+}
+
+impl RedisState {
+    // None when Redis is down or the breaker is open. Callers fall back to Postgres.
+    pub async fn conn(&self) -> Option<deadpool_redis::Connection> {
+        if !self.breaker.allow() {
+            return None;
+        }
+        match self.r_pool.get().await {
+            Ok(con) => {
+                self.breaker.record_success();
+                Some(con)
+            }
+            Err(err) => {
+                println!("Redis unavailable: {}", err);
+                self.breaker.record_failure();
+                None
+            }
+        }
+    }
+
+    // For errors from commands on a connection we already have. Only connection-level errors count.
+    pub fn record_error(&self, err: &::redis::RedisError) {
+        if err.is_io_error() || err.is_timeout() || err.is_connection_dropped() || err.is_connection_refusal() {
+            self.breaker.record_failure();
+        }
+    }
 }
 
 #[derive(Debug, Serialize, Deserialize, Clone)]
@@ -114,7 +154,7 @@ async fn index(
         dbg!(cookie.clone());
         match redis_validate_and_get_user(cookie, &r_state).await {
             Ok(user) => {
-                let feed_data = user_feed(&user, &state.db).await;
+                let feed_data = materialized_user_feed(&r_state, &user).await;
                 let template_data = HomepageTemplate {
                     error: None,
                     user: Some(user),
@@ -226,7 +266,6 @@ async fn about_us(
     });
     if let Some(cookie) = headers.get(actix_web::http::header::COOKIE) {
         dbg!(cookie.clone());
-        // let _ = linfa_pred();
         match redis_validate_and_get_user(cookie, &r_state).await {
         // match validate_and_get_user(cookie, &state).await {
             Ok(user) => {
@@ -383,6 +422,70 @@ async fn fixed_table(hb: web::Data<Handlebars<'_>>) -> impl Responder {
     HttpResponse::Ok().body(body)
 }
 
+#[derive(Debug, Serialize, Deserialize)]
+pub struct ServiceHealth {
+    up: bool,
+    latency_ms: u128,
+    error: Option<String>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct HealthReport {
+    status: String,
+    db: ServiceHealth,
+    redis: ServiceHealth,
+    redis_breaker: BreakerState,
+}
+
+async fn check_db(db: &Pool<Postgres>) -> ServiceHealth {
+    let started = Instant::now();
+    let result = actix_web::rt::time::timeout(REDIS_TIMEOUT, sqlx::query("SELECT 1").execute(db)).await;
+    let error = match result {
+        Ok(Ok(_)) => None,
+        Ok(Err(err)) => Some(err.to_string()),
+        Err(_) => Some("Timed out".to_owned()),
+    };
+    ServiceHealth { up: error.is_none(), latency_ms: started.elapsed().as_millis(), error }
+}
+
+async fn check_redis(r_state: &RedisState) -> ServiceHealth {
+    let started = Instant::now();
+    let error = match r_state.conn().await {
+        Some(mut con) => match cmd("PING").query_async::<_, String>(&mut con).await {
+            Ok(_) => None,
+            Err(err) => {
+                r_state.record_error(&err);
+                Some(err.to_string())
+            }
+        },
+        None => Some(format!("Unavailable, circuit breaker {:?}", r_state.breaker.state())),
+    };
+    ServiceHealth { up: error.is_none(), latency_ms: started.elapsed().as_millis(), error }
+}
+
+// 200 while Postgres is up, "degraded" if only Redis is down. 503 without Postgres.
+#[get("/health")]
+async fn health(state: Data<AppState>, r_state: Data<RedisState>) -> impl Responder {
+    let db = check_db(&state.db).await;
+    let redis = check_redis(&r_state).await;
+    let status = match (db.up, redis.up) {
+        (true, true) => "ok",
+        (true, false) => "degraded",
+        (false, _) => "down",
+    };
+    let report = HealthReport {
+        status: status.to_owned(),
+        db,
+        redis,
+        redis_breaker: r_state.breaker.state(),
+    };
+    if report.db.up {
+        HttpResponse::Ok().json(report)
+    } else {
+        HttpResponse::ServiceUnavailable().json(report)
+    }
+}
+
 #[get("/homepage")]
 async fn homepage(
     hb: web::Data<Handlebars<'_>>,
@@ -393,25 +496,13 @@ async fn homepage(
     dbg!(&req);
     // FIXME unwrap()
     let headers = req.headers();
-    let mut con = r_state.r_pool.get().await.unwrap();
-    let mut hp_option: BTreeMap<String, i32> = BTreeMap::new();
-    let prefix = "user-details";
-    hp_option.insert(String::from("user_id"), 12);
-    hp_option.insert(String::from("user_age"), 27);
-    // Set it in Redis
-    let _: () = redis::cmd("HSET")
-        .arg(format!("{}:{}", prefix, "location"))
-        .arg(hp_option)
-        .query_async::<_, ()>(&mut con)
-        .await
-        .expect("failed to execute HSET");
     dbg!(&headers);
     if let Some(cookie) = headers.get(actix_web::http::header::COOKIE) {
         dbg!(&cookie);
         match validate_and_get_user(cookie, &state).await {
             Ok(user_option) => {
                 if let Some(user) = user_option {
-                    let feed_data = user_feed(&user, &state.db).await;
+                    let feed_data = materialized_user_feed(&r_state, &user).await;
                     let template_data = HomepageTemplate {
                         error: None,
                         user: Some(user),
@@ -592,19 +683,19 @@ pub struct ValidatedUser {
     consultant_subs: Vec<i32>,
 }
 
-// assume a task is defined as "<id>-<desc>"
-impl FromRedisValue for ValidatedUser {
-    fn from_redis_value(v: &Value) -> RedisResult<Self> {
-        let v: String = from_redis_value(v)?;
-        let result: Self = match serde_json::from_str::<Self>(&v) {
-          Ok(v) => v,
-          Err(_err) => return Err((ErrorKind::TypeError, "Parse to JSON Failed").into())
-        };
-        Ok(result)
-    }
+// Cached sessions, keyed by session id. The session row's expiry travels with the user so a cached
+// login ends when the session does.
+#[derive(Debug, FromRow, Clone, Serialize, Deserialize, PartialEq)]
+pub struct CachedSession {
+    #[sqlx(flatten)]
+    user: ValidatedUser,
+    expires: DateTime<Utc>,
 }
 
-// Do I even need a ToRedisArgs impl?
+impl RedisModel for CachedSession {
+    const NAMESPACE: &'static str = "session";
+    const VERSION: u32 = 2;
+}
 
 pub trait HeaderValueExt {
     fn to_string(&self) -> String;
@@ -707,7 +798,11 @@ async fn contact_us_submission(
     body: web::Form<ContactUsRequest>,
     req: HttpRequest,
     state: web::Data<AppState>,
+    r_state: web::Data<RedisState>,
 ) -> impl Responder {
+    if let Some(resp) = rate_limited(&req, &r_state, &hb, &CONTACT_US, None).await {
+        return resp;
+    }
     let is_valid = body.validate();
     let ip_addr = get_ip(req);
     let ip_addr_str = ip_addr.to_string();
@@ -782,8 +877,56 @@ async fn main() -> std::io::Result<()> {
     };
 
     let r_pool = redis_connect();
+    // Shared by every worker's RedisState so they all agree on whether Redis is up
+    let redis_breaker = Arc::new(CircuitBreaker::new());
     // let _ = redis_test_data(&r_pool).await;
 
+    // `--warm-views` / `--flush-views` rebuild or drop the Redis materialized views, then exit
+    if env::args().any(|arg| arg == "--warm-views" || arg == "--flush-views") {
+        let r_state = RedisState {
+            r_pool: r_pool.clone(),
+            breaker: redis_breaker.clone(),
+            db: pool.clone(),
+        };
+        if env::args().any(|arg| arg == "--flush-views") {
+            match flush_all(&r_state).await {
+                Ok(deleted) => println!("Flushed {} materialized views", deleted),
+                Err(err) => println!("Flush failed: {}", err),
+            }
+        }
+        if env::args().any(|arg| arg == "--warm-views") {
+            match warm_all(&r_state).await {
+                Ok(report) => println!("Warmed materialized views: {:?}", report),
+                Err(err) => println!("Warm failed: {}", err),
+            }
+        }
+        return Ok(());
+    }
+
+    // `--worker` runs only the job queue, for a worker box separate from the web servers
+    if env::args().any(|arg| arg == "--worker") {
+        println!("Running background job worker");
+        run_worker(r_pool.clone(), JobContext { db: pool, r_pool }).await;
+        return Ok(());
+    }
+    // In-server workers. JOB_WORKERS=0 leaves the queue to separate `--worker` processes.
+    let job_workers = env::var("JOB_WORKERS")
+        .ok()
+        .and_then(|n| n.parse::<usize>().ok())
+        .unwrap_or(1);
+    for _ in 0..job_workers {
+        actix_web::rt::spawn(run_worker(
+            r_pool.clone(),
+            JobContext { db: pool.clone(), r_pool: r_pool.clone() },
+        ));
+    }
+    // Reminders, cleanup, churn retraining and digests, enqueued on their cron schedules
+    actix_web::rt::spawn(run_scheduler(r_pool.clone()));
+
+    // Redis pub/sub -> this instance's SSE streams
+    let broadcaster = Broadcaster::new();
+    actix_web::rt::spawn(relay_live_events(broadcaster.clone()));
+
     // Using GlitchTip. Works with the Rust Sentry SDK
     let _guard = sentry::init("https://ec778decf4e94595b5a48520185298c3@app.glitchtip.com/5073");
 
@@ -799,11 +942,6 @@ async fn main() -> std::io::Result<()> {
     handlebars.register_helper("int_eq", Box::new(int_eq));
     handlebars.register_helper("int_in", Box::new(int_in));
     handlebars.register_helper("get_month_name", Box::new(get_month_name));
-    handlebars.register_helper("first_week", Box::new(first_week));
-    handlebars.register_helper("second_week", Box::new(second_week));
-    handlebars.register_helper("third_week", Box::new(third_week));
-    handlebars.register_helper("fourth_week", Box::new(fourth_week));
-    handlebars.register_helper("fifth_week", Box::new(fifth_week));
     handlebars.register_helper("lower_and_single", Box::new(lower_and_single));
     handlebars.register_helper("construct_opts_url", Box::new(construct_opts_url));
     handlebars.register_helper("concat_str_args", Box::new(concat_str_args));
@@ -820,8 +958,7 @@ async fn main() -> std::io::Result<()> {
     handlebars.register_helper("get_filter_class", Box::new(get_filter_class));
     handlebars.register_helper("get_table_title", Box::new(get_table_title));
     handlebars.register_helper("get_list_view", Box::new(get_list_view));
-    handlebars.register_helper("cal_rte", Box::new(cal_rte));
-    handlebars.register_helper("is_holiday", Box::new(is_holiday));
+    handlebars.register_helper("live_event", Box::new(live_event));
 
     // handlebars.register_helper("gen_vec_len_ten", Box::new(gen_vec_len_ten));
 
@@ -836,7 +973,10 @@ async fn main() -> std::io::Result<()> {
             }))
             .app_data(web::Data::new(RedisState {
                 r_pool: r_pool.clone(),
+                breaker: redis_breaker.clone(),
+                db: pool.clone(),
             }))
+            .app_data(web::Data::new(broadcaster.clone()))
             .app_data(web::Data::new(config.clone()))
             .app_data(web::Data::new(handlebars.clone()))
             .service(auth_scope())
@@ -848,6 +988,10 @@ async fn main() -> std::io::Result<()> {
             .service(client_scope())
             .service(event_scope())
             .service(service_scope())
+            .service(live_scope())
+            .service(notification_scope())
+            .service(presence_scope())
+            .service(booking_scope())
             .service(send_email)
             .service(contact_us)
             .service(contact_us_submission)
@@ -856,6 +1000,7 @@ async fn main() -> std::io::Result<()> {
             .service(fixed_table)
             // .service(responsive_table)
             .service(homepage)
+            .service(health)
             .service(crud_api)
             .service(list_api)
             .service(schedule_api)
@@ -3,5 +3,6 @@ pub mod model_client;
 pub mod model_consult;
 pub mod model_consultant;
 pub mod model_location;
+pub mod model_notification;
 pub mod model_session;
 pub mod model_user;
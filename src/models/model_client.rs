@@ -1,6 +1,6 @@
 use crate::config::{validate_primary_address, validate_secondary_address};
 use crate::config::{SelectOption, StringSelectOption};
-use chrono::NaiveDate;
+use chrono::{DateTime, NaiveDate, Utc};
 use serde::{Deserialize, Serialize};
 use sqlx::FromRow;
 use struct_iterable::Iterable;
@@ -33,6 +33,8 @@ pub struct ClientList {
     pub client_city: String,
     pub client_zip: String,
     pub phone: Option<String>,
+    // % risk of no new consult within the churn horizon. None until the nightly batch has scored the client.
+    pub churn_risk: Option<i32>,
 }
 
 #[derive(Debug, Serialize, Deserialize, FromRow, Validate, Iterable)]
@@ -56,6 +58,8 @@ pub struct ClientPostRequest {
     pub client_dob: Option<String>,
     #[validate(length(equal = 12, message = "Phone must be 12 characters (w/ -)"))]
     pub client_primary_phone: String,
+    // Version the edit form was loaded at. Only sent on edits.
+    pub updated_at: Option<DateTime<Utc>>,
 }
 
 #[derive(Debug, Serialize, Deserialize, FromRow)]
@@ -74,6 +78,7 @@ pub struct ClientFormRequest {
     pub client_zip: String,
     pub client_email: String,
     pub client_primary_phone: String,
+    pub updated_at: Option<DateTime<Utc>>,
 }
 
 #[derive(Debug, Validate, Serialize, FromRow, Deserialize)]
@@ -1,5 +1,4 @@
 use chrono::{DateTime, Utc, serde::{ts_seconds_option, ts_seconds}};
-use redis::{RedisResult, FromRedisValue, ErrorKind, Value, from_redis_value};
 use serde::{Deserialize, Serialize};
 use sqlx::FromRow;
 use validator::Validate;
@@ -25,6 +24,8 @@ pub struct ConsultPost {
     pub consult_end_time: String,
     // #[validate(length(min = 3, message = "Notes must be greater than 3 chars"))]
     pub notes: String,
+    // Version the edit form was loaded at. Only sent on edits.
+    pub updated_at: Option<DateTime<Utc>>,
 }
 
 #[derive(Debug, Validate, Serialize, FromRow, Deserialize)]
@@ -39,6 +40,7 @@ pub struct ConsultFormRequest {
     pub consult_end: Option<DateTime<Utc>>,
     #[validate(length(min = 3, message = "Notes must be greater than 3 chars"))]
     pub notes: Option<String>,
+    pub updated_at: Option<DateTime<Utc>>,
 }
 
 #[derive(Debug, Validate, Serialize, Clone, Deserialize)]
@@ -65,17 +67,6 @@ pub struct ConsultListVec {
     pub vec: Vec<ConsultList>,
 }
 
-impl FromRedisValue for ConsultListVec {
-    fn from_redis_value(v: &Value) -> RedisResult<Self> {
-        let v: String = from_redis_value(v)?;
-        let result: Self = match serde_json::from_str::<Self>(&v) {
-          Ok(v) => v,
-          Err(_err) => return Err((ErrorKind::TypeError, "Parse to JSON Failed").into())
-        };
-        Ok(result)
-    }
-}
-
 #[derive(Debug, Validate, Serialize, FromRow, Clone, Deserialize)]
 pub struct ConsultAttachments {
     // pub consult_id: i32,
@@ -107,6 +98,7 @@ pub struct ConsultWithDates {
     pub client_id: i32,
     #[validate(length(min = 3, message = "Notes must be greater than 3 chars"))]
     pub notes: Option<String>,
+    pub updated_at: Option<DateTime<Utc>>,
 }
 
 #[derive(Debug, Validate, Serialize, FromRow, Deserialize)]
@@ -0,0 +1,29 @@
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use sqlx::FromRow;
+
+#[derive(Debug, Serialize, Clone, FromRow, Deserialize)]
+pub struct Notification {
+    pub notification_id: i32,
+    pub entity_type_id: i32,
+    pub entity_id: i32,
+    pub msg: String,
+    pub read_at: Option<DateTime<Utc>>,
+    pub created_at: DateTime<Utc>,
+}
+
+#[derive(Debug, Serialize, Clone, FromRow, Deserialize)]
+pub struct NotificationUserId {
+    pub user_id: i32,
+}
+
+#[derive(Debug, Serialize, Clone, FromRow, Deserialize)]
+pub struct UnreadCount {
+    pub unread: i64,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct NotificationListTemplate {
+    pub notifications: Vec<Notification>,
+    pub unread: i64,
+}
@@ -0,0 +1,107 @@
+use std::sync::atomic::{AtomicI64, AtomicU32, Ordering};
+
+use chrono::Utc;
+use serde::{Deserialize, Serialize};
+
+// Stops us paying a connect timeout on every request while Redis is down. After FAILURE_THRESHOLD
+// failures in a row the breaker opens and Redis is skipped for OPEN_SECS. After that one request is
+// let through (half-open); success closes the breaker, failure opens it again.
+
+const FAILURE_THRESHOLD: u32 = 3;
+const OPEN_SECS: i64 = 30;
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub enum BreakerState {
+    Closed,
+    Open,
+    HalfOpen,
+}
+
+#[derive(Debug, Default)]
+pub struct CircuitBreaker {
+    failures: AtomicU32,
+    // Epoch seconds. 0 when closed.
+    open_until: AtomicI64,
+}
+
+impl CircuitBreaker {
+    pub fn new() -> CircuitBreaker {
+        CircuitBreaker::default()
+    }
+
+    pub fn state_at(&self, now: i64) -> BreakerState {
+        let open_until = self.open_until.load(Ordering::Relaxed);
+        if open_until == 0 {
+            BreakerState::Closed
+        } else if now < open_until {
+            BreakerState::Open
+        } else {
+            BreakerState::HalfOpen
+        }
+    }
+
+    pub fn state(&self) -> BreakerState {
+        self.state_at(Utc::now().timestamp())
+    }
+
+    pub fn allow_at(&self, now: i64) -> bool {
+        match self.state_at(now) {
+            BreakerState::Closed => true,
+            BreakerState::Open => false,
+            // Push the window out first so only this caller makes the trial request
+            BreakerState::HalfOpen => {
+                let open_until = self.open_until.load(Ordering::Relaxed);
+                self.open_until
+                    .compare_exchange(open_until, now + OPEN_SECS, Ordering::Relaxed, Ordering::Relaxed)
+                    .is_ok()
+            }
+        }
+    }
+
+    pub fn allow(&self) -> bool {
+        self.allow_at(Utc::now().timestamp())
+    }
+
+    pub fn record_success(&self) {
+        self.failures.store(0, Ordering::Relaxed);
+        self.open_until.store(0, Ordering::Relaxed);
+    }
+
+    pub fn record_failure_at(&self, now: i64) {
+        let failures = self.failures.fetch_add(1, Ordering::Relaxed) + 1;
+        if failures >= FAILURE_THRESHOLD {
+            self.open_until.store(now + OPEN_SECS, Ordering::Relaxed);
+        }
+    }
+
+    pub fn record_failure(&self) {
+        self.record_failure_at(Utc::now().timestamp())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn opens_after_repeated_failures_and_recovers() {
+        let breaker = CircuitBreaker::new();
+        let now = 1_700_000_000;
+        breaker.record_failure_at(now);
+        breaker.record_failure_at(now);
+        assert!(breaker.allow_at(now));
+        breaker.record_failure_at(now);
+        assert_eq!(breaker.state_at(now), BreakerState::Open);
+        assert!(!breaker.allow_at(now + 1));
+
+        // One trial request once the window passes
+        let later = now + OPEN_SECS;
+        assert_eq!(breaker.state_at(later), BreakerState::HalfOpen);
+        assert!(breaker.allow_at(later));
+        assert!(!breaker.allow_at(later));
+
+        breaker.record_success();
+        assert_eq!(breaker.state_at(later), BreakerState::Closed);
+        assert!(breaker.allow_at(later));
+    }
+}
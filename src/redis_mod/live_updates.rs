@@ -0,0 +1,194 @@
+use std::time::Duration;
+
+use chrono::{DateTime, Utc};
+use futures_util::StreamExt;
+use redis::{AsyncCommands, RedisResult};
+use serde::{Deserialize, Serialize};
+use tokio::sync::broadcast;
+use uuid::Uuid;
+
+use super::{redis_mod::redis_conn_url, typed::Json};
+use crate::RedisState;
+
+// Writes publish a LiveEvent on LIVE_CHANNEL. Every app instance runs one subscriber that relays the
+// channel into a local Broadcaster, and each open /live/events stream reads from that. Going through
+// Redis (instead of straight to the Broadcaster) is what lets browsers on other instances see the write.
+
+pub const LIVE_CHANNEL: &str = "live_updates";
+// Events kept for slow SSE clients before they start skipping.
+const BROADCAST_CAPACITY: usize = 256;
+const RECONNECT_DELAY: Duration = Duration::from_secs(5);
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub enum LiveEntity {
+    Consult,
+    Client,
+    Location,
+}
+
+impl LiveEntity {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            LiveEntity::Consult => "consult",
+            LiveEntity::Client => "client",
+            LiveEntity::Location => "location",
+        }
+    }
+
+    pub fn from_entity_type_id(entity_type_id: i32) -> Option<LiveEntity> {
+        match entity_type_id {
+            5 => Some(LiveEntity::Location),
+            6 => Some(LiveEntity::Consult),
+            7 => Some(LiveEntity::Client),
+            _ => None,
+        }
+    }
+
+    // SSE event name. Tables listen for it with hx-trigger="sse:<name>".
+    pub fn changed_event(&self) -> String {
+        format!("{}-changed", self.as_str())
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct LiveEvent {
+    pub id: String,
+    pub entity: LiveEntity,
+    pub entity_id: i32,
+    pub action: String,
+    pub summary: String,
+    pub sent_at: DateTime<Utc>,
+    // Users that got a notification row for this write. Their streams also get a `notification` frame.
+    #[serde(default)]
+    pub notify_user_ids: Vec<i32>,
+}
+
+impl LiveEvent {
+    pub fn new(entity: LiveEntity, entity_id: i32, action: &str, summary: String) -> LiveEvent {
+        LiveEvent {
+            id: Uuid::new_v4().simple().to_string(),
+            entity,
+            entity_id,
+            action: action.to_owned(),
+            summary,
+            sent_at: Utc::now(),
+            notify_user_ids: vec![],
+        }
+    }
+
+    pub fn notify(mut self, user_ids: Vec<i32>) -> LiveEvent {
+        self.notify_user_ids = user_ids;
+        self
+    }
+}
+
+#[derive(Clone)]
+pub struct Broadcaster {
+    sender: broadcast::Sender<LiveEvent>,
+}
+
+impl Broadcaster {
+    pub fn new() -> Broadcaster {
+        let (sender, _) = broadcast::channel(BROADCAST_CAPACITY);
+        Broadcaster { sender }
+    }
+
+    pub fn subscribe(&self) -> broadcast::Receiver<LiveEvent> {
+        self.sender.subscribe()
+    }
+
+    // Err only means nobody is listening on this instance right now.
+    pub fn send(&self, event: LiveEvent) {
+        let _ = self.sender.send(event);
+    }
+}
+
+// If Redis is down the event still reaches browsers connected to this instance.
+pub async fn publish_live_event(r_state: &RedisState, broadcaster: &Broadcaster, event: LiveEvent) {
+    let published = match r_state.conn().await {
+        Some(mut con) => con
+            .publish::<_, _, i64>(LIVE_CHANNEL, Json(&event))
+            .await
+            .map_err(|e| e.to_string()),
+        None => Err("Redis unavailable".to_owned()),
+    };
+    if let Err(err) = published {
+        println!("Live event not published to Redis: {}", err);
+        broadcaster.send(event);
+    }
+}
+
+async fn relay_once(broadcaster: &Broadcaster) -> RedisResult<()> {
+    let client = redis::Client::open(redis_conn_url())?;
+    let mut pubsub = client.get_async_connection().await?.into_pubsub();
+    pubsub.subscribe(LIVE_CHANNEL).await?;
+    let mut messages = pubsub.on_message();
+    while let Some(msg) = messages.next().await {
+        match msg.get_payload::<Json<LiveEvent>>() {
+            Ok(Json(event)) => broadcaster.send(event),
+            Err(err) => println!("Ignoring malformed live event: {}", err),
+        }
+    }
+    Ok(())
+}
+
+// Runs for the life of the process, resubscribing whenever the Redis connection drops.
+pub async fn relay_live_events(broadcaster: Broadcaster) {
+    loop {
+        match relay_once(&broadcaster).await {
+            Ok(()) => println!("Live updates subscription closed, resubscribing"),
+            Err(err) => println!("Live updates subscription failed: {}", err),
+        }
+        actix_web::rt::time::sleep(RECONNECT_DELAY).await;
+    }
+}
+
+// One SSE frame. Multi-line data needs a `data:` prefix on every line.
+pub fn sse_frame(event: &str, id: Option<&str>, data: &str) -> String {
+    let mut frame = String::new();
+    if let Some(id) = id {
+        frame.push_str(&format!("id: {}\n", id));
+    }
+    frame.push_str(&format!("event: {}\n", event));
+    for line in data.lines() {
+        frame.push_str(&format!("data: {}\n", line));
+    }
+    if data.is_empty() {
+        frame.push_str("data: \n");
+    }
+    frame.push('\n');
+    frame
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn frames_prefix_every_data_line() {
+        let frame = sse_frame("feed", Some("abc"), "<tr>\n<td>1</td>\n</tr>");
+        assert_eq!(frame, "id: abc\nevent: feed\ndata: <tr>\ndata: <td>1</td>\ndata: </tr>\n\n");
+        assert_eq!(sse_frame("consult-changed", None, ""), "event: consult-changed\ndata: \n\n");
+    }
+
+    #[test]
+    fn events_round_trip_and_reach_local_subscribers() {
+        let event = LiveEvent::new(LiveEntity::Client, 7, "created", "Client #7 added".to_string());
+        let restored: LiveEvent = serde_json::from_str(&serde_json::to_string(&event).unwrap()).unwrap();
+        assert_eq!(restored, event);
+        assert_eq!(restored.entity.changed_event(), "client-changed");
+
+        let broadcaster = Broadcaster::new();
+        let mut rx = broadcaster.subscribe();
+        broadcaster.send(event.clone());
+        assert_eq!(rx.try_recv().unwrap(), event);
+    }
+
+    #[test]
+    fn events_without_recipients_still_parse() {
+        let json = r#"{"id":"abc","entity":"Location","entity_id":5,"action":"updated","summary":"Location #5 edited","sent_at":"2023-11-23T09:00:00Z"}"#;
+        let event: LiveEvent = serde_json::from_str(json).unwrap();
+        assert!(event.notify_user_ids.is_empty());
+        assert_eq!(event.notify(vec![1, 2]).notify_user_ids, vec![1, 2]);
+    }
+}
@@ -0,0 +1,323 @@
+use chrono::{DateTime, Datelike, Duration, NaiveDate, Utc};
+use redis::{AsyncCommands, RedisResult};
+use serde::{Deserialize, Serialize};
+use sqlx::{FromRow, Pool, Postgres};
+
+use super::typed::{self, RedisModel};
+use crate::{
+    config::{user_feed, UserFeedData},
+    RedisState, ValidatedUser,
+};
+
+// Precomputed views kept in Redis under `mv:` (see the RedisModel impls for the key spaces). Reads build a missing view from Postgres and store it.
+// Consult writes call refresh_for_consult (or refresh_for_refs), which rebuilds only the views that consult
+// touches: the feeds of users subscribed to its client/location/consultant, its consultant's upcoming list
+// and its location's weekly counts. A consult moved to another client, location or consultant touches the
+// views of both. Only confirmed consults count; cancelled ones and booking requests still pending are
+// left out. TTLs cover views that drift with time (the feed's 7 day window).
+
+const PREFIX: &str = "mv:";
+const FEED_TTL: usize = 60 * 60;
+const UPCOMING_TTL: usize = 60 * 60;
+const WEEKLY_TTL: usize = 60 * 60 * 24;
+pub const UPCOMING_DAYS: i64 = 14;
+pub const WEEKS_SHOWN: i64 = 8;
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize, FromRow)]
+pub struct UpcomingConsult {
+    pub consult_id: i32,
+    pub client_id: i32,
+    pub location_id: i32,
+    pub consult_start: DateTime<Utc>,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize, FromRow)]
+pub struct WeeklyCount {
+    pub week_start: NaiveDate,
+    pub consults: i64,
+}
+
+#[derive(Debug, Clone, PartialEq, FromRow)]
+pub struct ConsultRefs {
+    pub client_id: i32,
+    pub location_id: i32,
+    pub consultant_id: Option<i32>,
+}
+
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub struct WarmReport {
+    pub feeds: usize,
+    pub consultants: usize,
+    pub locations: usize,
+}
+
+impl RedisModel for UserFeedData {
+    const NAMESPACE: &'static str = "mv:user_feed";
+    const VERSION: u32 = 1;
+}
+
+impl RedisModel for Vec<UpcomingConsult> {
+    const NAMESPACE: &'static str = "mv:consultant_upcoming";
+    const VERSION: u32 = 1;
+}
+
+impl RedisModel for Vec<WeeklyCount> {
+    const NAMESPACE: &'static str = "mv:location_weekly";
+    const VERSION: u32 = 1;
+}
+
+async fn read<T: RedisModel>(r_state: &RedisState, id: &str) -> Option<T> {
+    let mut con = r_state.conn().await?;
+    typed::get(&mut con, id).await.ok().flatten()
+}
+
+async fn write<T: RedisModel>(r_state: &RedisState, id: &str, val: &T, ttl: usize) {
+    if let Some(mut con) = r_state.conn().await {
+        let _: RedisResult<()> = typed::set_ex(&mut con, id, val, ttl).await;
+    }
+}
+
+async fn build_feed(r_state: &RedisState, user: &ValidatedUser) -> UserFeedData {
+    let feed = user_feed(user, &r_state.db).await;
+    // A failed query comes back empty, don't pin that for an hour
+    if feed.consults.is_some() {
+        write(r_state, &user.username, &feed, FEED_TTL).await;
+    }
+    feed
+}
+
+pub async fn materialized_user_feed(r_state: &RedisState, user: &ValidatedUser) -> UserFeedData {
+    match read::<UserFeedData>(r_state, &user.username).await {
+        Some(feed) => feed,
+        None => build_feed(r_state, user).await,
+    }
+}
+
+// After a user's subscriptions change. Their next page load rebuilds it.
+pub async fn drop_user_feed(r_state: &RedisState, username: &str) {
+    if let Some(mut con) = r_state.conn().await {
+        let _: RedisResult<bool> = typed::del::<UserFeedData, _>(&mut con, username).await;
+    }
+}
+
+async fn build_upcoming(r_state: &RedisState, consultant_id: i32) -> Result<Vec<UpcomingConsult>, String> {
+    let upcoming = sqlx::query_as::<_, UpcomingConsult>(
+        "SELECT id AS consult_id, client_id, location_id, consult_start
+            FROM consults
+            WHERE consultant_id = $1
+            AND cancelled_at IS NULL
+            AND booking_status = 'confirmed'
+            AND consult_start >= NOW()
+            AND consult_start < NOW() + make_interval(days => $2)
+            ORDER BY consult_start",
+    )
+    .bind(consultant_id)
+    .bind(UPCOMING_DAYS as i32)
+    .fetch_all(&r_state.db)
+    .await
+    .map_err(|e| format!("Error in DB {}", e))?;
+    write(r_state, &consultant_id.to_string(), &upcoming, UPCOMING_TTL).await;
+    Ok(upcoming)
+}
+
+pub async fn upcoming_for_consultant(r_state: &RedisState, consultant_id: i32) -> Vec<UpcomingConsult> {
+    if let Some(upcoming) = read(r_state, &consultant_id.to_string()).await {
+        return upcoming;
+    }
+    build_upcoming(r_state, consultant_id).await.unwrap_or_else(|err| {
+        println!("{}", err);
+        vec![]
+    })
+}
+
+async fn build_weekly(r_state: &RedisState, location_id: i32) -> Result<Vec<WeeklyCount>, String> {
+    let weekly = sqlx::query_as::<_, WeeklyCount>(
+        "SELECT date_trunc('week', consult_start)::date AS week_start, COUNT(*) AS consults
+            FROM consults
+            WHERE location_id = $1
+            AND cancelled_at IS NULL
+            AND booking_status = 'confirmed'
+            AND consult_start >= date_trunc('week', NOW()) - make_interval(weeks => $2)
+            GROUP BY week_start
+            ORDER BY week_start",
+    )
+    .bind(location_id)
+    .bind(WEEKS_SHOWN as i32 - 1)
+    .fetch_all(&r_state.db)
+    .await
+    .map_err(|e| format!("Error in DB {}", e))?;
+    write(r_state, &location_id.to_string(), &weekly, WEEKLY_TTL).await;
+    Ok(weekly)
+}
+
+pub async fn weekly_for_location(r_state: &RedisState, location_id: i32) -> Vec<WeeklyCount> {
+    if let Some(weekly) = read(r_state, &location_id.to_string()).await {
+        return weekly;
+    }
+    build_weekly(r_state, location_id).await.unwrap_or_else(|err| {
+        println!("{}", err);
+        vec![]
+    })
+}
+
+// Mondays of the last WEEKS_SHOWN weeks, oldest first. Matches Postgres' date_trunc('week').
+pub fn weeks_shown(now: DateTime<Utc>) -> Vec<NaiveDate> {
+    let today = now.date_naive();
+    let monday = today - Duration::days(today.weekday().num_days_from_monday() as i64);
+    (0..WEEKS_SHOWN)
+        .rev()
+        .map(|weeks_ago| monday - Duration::weeks(weeks_ago))
+        .collect()
+}
+
+// One count per week in `weeks`, zero where the location had none.
+pub fn weekly_row(weeks: &[NaiveDate], counts: &[WeeklyCount]) -> Vec<i64> {
+    weeks
+        .iter()
+        .map(|week| {
+            counts
+                .iter()
+                .find(|count| count.week_start == *week)
+                .map_or(0, |count| count.consults)
+        })
+        .collect()
+}
+
+// Called after a consult is created or changed.
+pub async fn refresh_for_consult(r_state: &RedisState, consult_id: i32) {
+    match sqlx::query_as::<_, ConsultRefs>(
+        "SELECT client_id, location_id, consultant_id FROM consults WHERE id = $1",
+    )
+    .bind(consult_id)
+    .fetch_one(&r_state.db)
+    .await
+    {
+        Ok(refs) => refresh_for_refs(r_state, &[refs]).await,
+        Err(err) => println!("Materialized views not refreshed for consult {}: {}", consult_id, err),
+    }
+}
+
+// For writes that already know the consult's refs. A write that can move the consult passes its refs
+// from before too, so the views it left are rebuilt along with the ones it joined.
+pub async fn refresh_for_refs(r_state: &RedisState, refs: &[ConsultRefs]) {
+    let mut client_ids = refs.iter().map(|refs| refs.client_id).collect::<Vec<i32>>();
+    let mut location_ids = refs.iter().map(|refs| refs.location_id).collect::<Vec<i32>>();
+    let mut consultant_ids = refs.iter().filter_map(|refs| refs.consultant_id).collect::<Vec<i32>>();
+    for ids in [&mut client_ids, &mut location_ids, &mut consultant_ids] {
+        ids.sort_unstable();
+        ids.dedup();
+    }
+
+    let subscribers = sqlx::query_as::<_, ValidatedUser>(
+        "SELECT username, email, user_type_id, user_subs, client_subs, consult_subs, location_subs, consultant_subs, user_settings.list_view
+            FROM users
+            LEFT JOIN user_settings ON user_settings.user_id = users.id
+            WHERE client_subs && $1 OR location_subs && $2 OR consultant_subs && $3",
+    )
+    .bind(&client_ids)
+    .bind(&location_ids)
+    .bind(&consultant_ids)
+    .fetch_all(&r_state.db)
+    .await
+    .unwrap_or_default();
+    for user in &subscribers {
+        build_feed(r_state, user).await;
+    }
+    for consultant_id in consultant_ids {
+        let _ = build_upcoming(r_state, consultant_id).await;
+    }
+    for location_id in location_ids {
+        let _ = build_weekly(r_state, location_id).await;
+    }
+}
+
+#[derive(Debug, FromRow)]
+struct Id {
+    id: i32,
+}
+
+async fn ids(db: &Pool<Postgres>, sql: &str) -> Vec<i32> {
+    sqlx::query_as::<_, Id>(sql)
+        .fetch_all(db)
+        .await
+        .map(|rows| rows.into_iter().map(|row| row.id).collect())
+        .unwrap_or_default()
+}
+
+// Builds every consultant and location view, and feeds for users with a live session.
+pub async fn warm_all(r_state: &RedisState) -> Result<WarmReport, String> {
+    if r_state.conn().await.is_none() {
+        return Err("Redis unavailable".to_owned());
+    }
+    let mut report = WarmReport::default();
+    for consultant_id in ids(&r_state.db, "SELECT id FROM consultants").await {
+        build_upcoming(r_state, consultant_id).await?;
+        report.consultants += 1;
+    }
+    for location_id in ids(&r_state.db, "SELECT id FROM locations").await {
+        build_weekly(r_state, location_id).await?;
+        report.locations += 1;
+    }
+    let users = sqlx::query_as::<_, ValidatedUser>(
+        "SELECT DISTINCT ON (users.id) username, email, user_type_id, user_subs, client_subs, consult_subs, location_subs, consultant_subs, user_settings.list_view
+            FROM users
+            INNER JOIN user_sessions ON user_sessions.user_id = users.id
+            LEFT JOIN user_settings ON user_settings.user_id = users.id
+            WHERE user_sessions.expires > NOW()",
+    )
+    .fetch_all(&r_state.db)
+    .await
+    .map_err(|e| format!("Error in DB {}", e))?;
+    for user in &users {
+        build_feed(r_state, user).await;
+        report.feeds += 1;
+    }
+    Ok(report)
+}
+
+// Drops every materialized view. Returns the number of keys removed.
+pub async fn flush_all(r_state: &RedisState) -> Result<usize, String> {
+    let mut con = r_state.conn().await.ok_or("Redis unavailable")?;
+    let mut cursor: u64 = 0;
+    let mut deleted = 0;
+    loop {
+        let (next, keys): (u64, Vec<String>) = redis::cmd("SCAN")
+            .arg(cursor)
+            .arg("MATCH")
+            .arg(format!("{}*", PREFIX))
+            .arg("COUNT")
+            .arg(500)
+            .query_async(&mut con)
+            .await
+            .map_err(|e| format!("Error in Redis {}", e))?;
+        if !keys.is_empty() {
+            deleted += con.del::<_, usize>(&keys).await.map_err(|e| format!("Error in Redis {}", e))?;
+        }
+        if next == 0 {
+            return Ok(deleted);
+        }
+        cursor = next;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::TimeZone;
+
+    #[test]
+    fn weeks_start_on_monday_and_fill_gaps() {
+        // Friday
+        let now = Utc.with_ymd_and_hms(2023, 11, 24, 15, 0, 0).unwrap();
+        let weeks = weeks_shown(now);
+        assert_eq!(weeks.len(), WEEKS_SHOWN as usize);
+        assert_eq!(weeks.last(), Some(&NaiveDate::from_ymd_opt(2023, 11, 20).unwrap()));
+        assert_eq!(weeks.first(), Some(&NaiveDate::from_ymd_opt(2023, 10, 2).unwrap()));
+
+        let counts = vec![
+            WeeklyCount { week_start: NaiveDate::from_ymd_opt(2023, 11, 13).unwrap(), consults: 3 },
+            WeeklyCount { week_start: NaiveDate::from_ymd_opt(2023, 11, 20).unwrap(), consults: 1 },
+        ];
+        assert_eq!(weekly_row(&weeks, &counts), vec![0, 0, 0, 0, 0, 0, 3, 1]);
+    }
+}
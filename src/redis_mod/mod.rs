@@ -1,3 +1,10 @@
+pub mod circuit_breaker;
+pub mod live_updates;
+pub mod materialized;
+pub mod presence;
+pub mod query_cache;
+pub mod rate_limit;
 pub mod redis_mod;
 pub mod redis_publisher;
-pub mod redis_subscriber;
\ No newline at end of file
+pub mod redis_subscriber;
+pub mod typed;
\ No newline at end of file
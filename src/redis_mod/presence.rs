@@ -0,0 +1,78 @@
+use chrono::Utc;
+use redis::{AsyncCommands, RedisResult};
+
+use super::live_updates::LiveEntity;
+use crate::RedisState;
+
+// Who has a record's edit form open. `presence:<entity>:<slug>` is a ZSET of usernames scored by their
+// last heartbeat. An open form heartbeats every HEARTBEAT_SECS, a viewer silent for PRESENCE_TTL is
+// dropped, and the key expires once nobody is left heartbeating.
+
+pub const HEARTBEAT_SECS: i64 = 10;
+const PRESENCE_TTL: i64 = 30;
+
+// Only records with an edit form have presence
+pub fn presence_entity(name: &str) -> Option<LiveEntity> {
+    match name {
+        "consult" => Some(LiveEntity::Consult),
+        "client" => Some(LiveEntity::Client),
+        _ => None,
+    }
+}
+
+fn presence_key(entity: LiveEntity, slug: &str) -> String {
+    format!("presence:{}:{}", entity.as_str(), slug)
+}
+
+fn other_viewers(viewers: Vec<String>, username: &str) -> Vec<String> {
+    viewers.into_iter().filter(|viewer| viewer != username).collect()
+}
+
+// Marks `username` as viewing and returns everyone else still viewing. Empty when Redis is down.
+pub async fn heartbeat(r_state: &RedisState, entity: LiveEntity, slug: &str, username: &str) -> Vec<String> {
+    let mut con = match r_state.conn().await {
+        Some(con) => con,
+        None => return vec![],
+    };
+    let key = presence_key(entity, slug);
+    let now = Utc::now().timestamp();
+    let viewers: RedisResult<(Vec<String>,)> = redis::pipe()
+        .zadd(&key, username, now)
+        .ignore()
+        .zrembyscore(&key, "-inf", now - PRESENCE_TTL)
+        .ignore()
+        .expire(&key, PRESENCE_TTL as usize)
+        .ignore()
+        .zrange(&key, 0, -1)
+        .query_async(&mut con)
+        .await;
+    match viewers {
+        Ok((viewers,)) => other_viewers(viewers, username),
+        Err(err) => {
+            r_state.record_error(&err);
+            println!("Presence heartbeat failed: {}", err);
+            vec![]
+        }
+    }
+}
+
+// After a save the editor's form closes, no need to wait out the TTL.
+pub async fn leave(r_state: &RedisState, entity: LiveEntity, slug: &str, username: &str) {
+    if let Some(mut con) = r_state.conn().await {
+        let _: RedisResult<usize> = con.zrem(presence_key(entity, slug), username).await;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn only_edit_forms_have_presence_and_viewers_exclude_self() {
+        assert_eq!(presence_entity("client"), Some(LiveEntity::Client));
+        assert_eq!(presence_entity("location"), None);
+        assert_eq!(presence_key(LiveEntity::Consult, "abc"), "presence:consult:abc");
+        let viewers = vec!["ann".to_string(), "bob".to_string()];
+        assert_eq!(other_viewers(viewers, "ann"), vec!["bob".to_string()]);
+    }
+}
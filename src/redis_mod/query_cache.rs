@@ -0,0 +1,218 @@
+use std::{collections::HashMap, future::Future};
+
+use chrono::Utc;
+use deadpool_redis::Pool as RedisPool;
+use redis::{AsyncCommands, RedisResult};
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+
+use super::typed::Json;
+use crate::{config::hash_owned_query, scopes::consult::OwnedQuery, RedisState};
+
+// Read-through cache for DB queries. Results live at `query:v<CACHE_VERSION>:<hash_owned_query>` as JSON and every key is
+// added to a `cache_tag:v<CACHE_VERSION>:<entity>` sorted set, so a write to an entity drops every cached query that
+// read it. Tag members are scored by when their key expires and expired ones are pruned on each add, so a tag that
+// isn't written to for a while doesn't keep growing. Redis being unavailable only costs us the cache, the loader
+// still runs.
+
+pub const METRICS_KEY: &str = "cache_metrics";
+// Bump when a cached result type changes shape.
+const CACHE_VERSION: u32 = 2;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum CacheTag {
+    Account,
+    Client,
+    Consult,
+    Consultant,
+    Location,
+}
+
+impl CacheTag {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            CacheTag::Account => "account",
+            CacheTag::Client => "client",
+            CacheTag::Consult => "consult",
+            CacheTag::Consultant => "consultant",
+            CacheTag::Location => "location",
+        }
+    }
+
+    pub fn all() -> [CacheTag; 5] {
+        [
+            CacheTag::Account,
+            CacheTag::Client,
+            CacheTag::Consult,
+            CacheTag::Consultant,
+            CacheTag::Location,
+        ]
+    }
+}
+
+pub fn cache_key(query: &OwnedQuery) -> String {
+    format!("query:v{}:{}", CACHE_VERSION, hash_owned_query(query))
+}
+
+fn tag_key(tag: CacheTag) -> String {
+    format!("cache_tag:v{}:{}", CACHE_VERSION, tag.as_str())
+}
+
+// Hits/misses are counted against the first tag, the entity the query is about.
+fn metric_field(tags: &[CacheTag], outcome: &str) -> String {
+    let tag = tags.first().map(|t| t.as_str()).unwrap_or("untagged");
+    format!("{}:{}", tag, outcome)
+}
+
+pub async fn cached<T, E, F, Fut>(
+    r_state: &RedisState,
+    query: &OwnedQuery,
+    tags: &[CacheTag],
+    ttl: usize,
+    load: F,
+) -> Result<T, E>
+where
+    T: Serialize + DeserializeOwned + Sync,
+    F: FnOnce() -> Fut,
+    Fut: Future<Output = Result<T, E>>,
+{
+    let key = cache_key(query);
+    let mut con = r_state.conn().await;
+
+    if let Some(con) = con.as_mut() {
+        // A value that no longer decodes as T is a miss, it gets overwritten below
+        let exists: RedisResult<Option<Json<T>>> = con.get(&key).await;
+        if let Ok(Some(Json(result))) = exists {
+            let _: RedisResult<i64> = con.hincr(METRICS_KEY, metric_field(tags, "hit"), 1).await;
+            return Ok(result);
+        }
+    }
+
+    let result = load().await?;
+
+    if let Some(con) = con.as_mut() {
+        let _: RedisResult<i64> = con.hincr(METRICS_KEY, metric_field(tags, "miss"), 1).await;
+        let _: RedisResult<()> = con.set_ex(&key, Json(&result), ttl).await;
+        let now = Utc::now().timestamp();
+        for tag in tags {
+            let _: RedisResult<()> = redis::pipe()
+                .zadd(tag_key(*tag), &key, now + ttl as i64)
+                .ignore()
+                .zrembyscore(tag_key(*tag), "-inf", now)
+                .ignore()
+                .query_async(con)
+                .await;
+        }
+    }
+
+    Ok(result)
+}
+
+// Call after every write. Returns the number of cached queries dropped.
+pub async fn invalidate(r_state: &RedisState, tags: &[CacheTag]) -> usize {
+    let mut con = match r_state.conn().await {
+        Some(con) => con,
+        None => {
+            println!("Query cache unavailable, nothing invalidated");
+            return 0;
+        }
+    };
+    let mut deleted = 0;
+    for tag in tags {
+        let keys: Vec<String> = con.zrange(tag_key(*tag), 0, -1).await.unwrap_or_default();
+        if !keys.is_empty() {
+            deleted += con.del::<_, usize>(&keys).await.unwrap_or(0);
+        }
+        let _: RedisResult<usize> = con.del(tag_key(*tag)).await;
+        let _: RedisResult<i64> = con.hincr(METRICS_KEY, format!("{}:invalidated", tag.as_str()), 1).await;
+    }
+    println!("Query cache invalidated {:?}, {} keys dropped", tags, deleted);
+    deleted
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct CacheMetric {
+    pub tag: String,
+    pub hits: i64,
+    pub misses: i64,
+    pub invalidations: i64,
+    pub cached_queries: i64,
+}
+
+impl CacheMetric {
+    pub fn hit_rate(&self) -> f64 {
+        let total = self.hits + self.misses;
+        if total == 0 {
+            0.0
+        } else {
+            self.hits as f64 / total as f64
+        }
+    }
+}
+
+pub fn metrics_from_counts(counts: &HashMap<String, i64>, sizes: &HashMap<String, i64>) -> Vec<CacheMetric> {
+    CacheTag::all()
+        .iter()
+        .map(|tag| {
+            let count = |outcome: &str| *counts.get(&format!("{}:{}", tag.as_str(), outcome)).unwrap_or(&0);
+            CacheMetric {
+                tag: tag.as_str().to_string(),
+                hits: count("hit"),
+                misses: count("miss"),
+                invalidations: count("invalidated"),
+                cached_queries: *sizes.get(tag.as_str()).unwrap_or(&0),
+            }
+        })
+        .collect()
+}
+
+pub async fn cache_metrics(r_pool: &RedisPool) -> Result<Vec<CacheMetric>, String> {
+    let mut con = r_pool.get().await.map_err(|e| format!("Error in Redis {}", e))?;
+    let counts: HashMap<String, i64> = con
+        .hgetall(METRICS_KEY)
+        .await
+        .map_err(|e| format!("Error in Redis {}", e))?;
+    let mut sizes = HashMap::new();
+    let now = Utc::now().timestamp();
+    for tag in CacheTag::all() {
+        let size: i64 = con.zcount(tag_key(tag), now, "+inf").await.unwrap_or(0);
+        sizes.insert(tag.as_str().to_string(), size);
+    }
+    Ok(metrics_from_counts(&counts, &sizes))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::SimpleQuery;
+
+    fn query(id: i32) -> OwnedQuery {
+        OwnedQuery::from(&SimpleQuery {
+            query_str: "SELECT client_type_id FROM clients WHERE id = $1",
+            int_args: Some(vec![id]),
+            str_args: None,
+        })
+    }
+
+    #[test]
+    fn key_depends_on_query_args() {
+        assert!(cache_key(&query(1)).starts_with("query:v2:"));
+        assert_eq!(cache_key(&query(1)), cache_key(&query(1)));
+        assert_ne!(cache_key(&query(1)), cache_key(&query(2)));
+    }
+
+    #[test]
+    fn metrics_are_counted_per_tag() {
+        let counts = HashMap::from([
+            ("client:hit".to_string(), 3),
+            ("client:miss".to_string(), 1),
+            ("client:invalidated".to_string(), 2),
+        ]);
+        let sizes = HashMap::from([("client".to_string(), 4)]);
+        let metrics = metrics_from_counts(&counts, &sizes);
+        let client = metrics.iter().find(|m| m.tag == "client").unwrap();
+        assert_eq!((client.hits, client.misses, client.invalidations, client.cached_queries), (3, 1, 2, 4));
+        assert_eq!(client.hit_rate(), 0.75);
+        let location = metrics.iter().find(|m| m.tag == "location").unwrap();
+        assert_eq!(location.hit_rate(), 0.0);
+    }
+}
@@ -0,0 +1,139 @@
+use std::net::IpAddr;
+
+use actix_web::{http::header::COOKIE, web::Data, HttpRequest, HttpResponse};
+use chrono::Utc;
+use handlebars::Handlebars;
+use redis::{RedisResult, Script};
+
+use crate::{
+    config::{get_ip, redis_validate_and_get_user, ValidationResponse},
+    RedisState,
+};
+
+// Token buckets in Redis, shared by every app instance. A bucket holds `capacity` tokens and gains one
+// back every `refill_secs`. Each request takes one. The whole read-refill-take runs as one Lua script
+// so concurrent requests can't both spend the last token.
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct RateLimit {
+    pub name: &'static str,
+    pub capacity: u32,
+    pub refill_secs: u32,
+}
+
+pub const CONTACT_US: RateLimit = RateLimit { name: "contact-us", capacity: 3, refill_secs: 60 };
+pub const REGISTER: RateLimit = RateLimit { name: "register", capacity: 5, refill_secs: 60 };
+pub const FORGOT_PASSWORD: RateLimit = RateLimit { name: "forgot-password", capacity: 3, refill_secs: 300 };
+pub const UPLOAD: RateLimit = RateLimit { name: "upload", capacity: 10, refill_secs: 30 };
+pub const BOOKING: RateLimit = RateLimit { name: "booking", capacity: 5, refill_secs: 300 };
+
+const TOKEN_BUCKET: &str = r"
+local capacity = tonumber(ARGV[1])
+local refill_ms = tonumber(ARGV[2])
+local now = tonumber(ARGV[3])
+local state = redis.call('HMGET', KEYS[1], 'tokens', 'ts')
+local tokens = tonumber(state[1]) or capacity
+local ts = tonumber(state[2]) or now
+tokens = math.min(capacity, tokens + math.max(0, now - ts) / refill_ms)
+local allowed = 0
+local retry_ms = 0
+if tokens >= 1 then
+  allowed = 1
+  tokens = tokens - 1
+else
+  retry_ms = math.ceil((1 - tokens) * refill_ms)
+end
+redis.call('HSET', KEYS[1], 'tokens', tostring(tokens), 'ts', now)
+redis.call('PEXPIRE', KEYS[1], math.ceil(capacity * refill_ms))
+return {allowed, retry_ms}
+";
+
+// Keyed by user when we know one (people behind one office IP), otherwise by IP.
+pub fn rate_key(limit: &RateLimit, ip: IpAddr, user: Option<&str>) -> String {
+    match user {
+        Some(user) => format!("rate:{}:user:{}", limit.name, user),
+        None => format!("rate:{}:ip:{}", limit.name, ip),
+    }
+}
+
+pub fn retry_after_secs(retry_ms: i64) -> i64 {
+    ((retry_ms + 999) / 1000).max(1)
+}
+
+// Ok(None) when allowed, Ok(Some(secs)) with how long to wait when not.
+async fn take_token(r_state: &RedisState, limit: &RateLimit, key: &str) -> Result<Option<i64>, String> {
+    let mut con = r_state.conn().await.ok_or("Redis unavailable")?;
+    let result: RedisResult<(i64, i64)> = Script::new(TOKEN_BUCKET)
+        .key(key)
+        .arg(limit.capacity)
+        .arg(limit.refill_secs as i64 * 1000)
+        .arg(Utc::now().timestamp_millis())
+        .invoke_async(&mut con)
+        .await;
+    match result {
+        Ok((1, _)) => Ok(None),
+        Ok((_, retry_ms)) => Ok(Some(retry_after_secs(retry_ms))),
+        Err(err) => {
+            r_state.record_error(&err);
+            Err(err.to_string())
+        }
+    }
+}
+
+// Call first thing in a handler: `if let Some(resp) = rate_limited(...).await { return resp; }`.
+// The 429 carries a validation fragment that htmx swaps into the form's usual target (main-layout lets
+// 429s swap). If Redis is down requests are let through rather than locking everyone out.
+pub async fn rate_limited(
+    req: &HttpRequest,
+    r_state: &RedisState,
+    hb: &Handlebars<'_>,
+    limit: &RateLimit,
+    user: Option<&str>,
+) -> Option<HttpResponse> {
+    let key = rate_key(limit, get_ip(req.clone()), user);
+    match take_token(r_state, limit, &key).await {
+        Ok(None) => None,
+        Ok(Some(retry_after)) => {
+            let error_msg = format!("Too many requests. Please try again in {} seconds.", retry_after);
+            let validation_response = ValidationResponse::from((error_msg.as_str(), "validation_error"));
+            let body = hb.render("validation", &validation_response).unwrap();
+            Some(
+                HttpResponse::TooManyRequests()
+                    .insert_header(("Retry-After", retry_after.to_string()))
+                    .body(body),
+            )
+        }
+        Err(err) => {
+            println!("Rate limit {} not checked: {}", limit.name, err);
+            None
+        }
+    }
+}
+
+// The signed in user, for limits that should follow them rather than their IP. Only a validated
+// session counts, a made up or rotated cookie falls back to the IP.
+pub async fn session_key(req: &HttpRequest, r_state: &Data<RedisState>) -> Option<String> {
+    let cookie = req.headers().get(COOKIE)?;
+    redis_validate_and_get_user(cookie, r_state).await.ok().map(|user| user.username)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::Ipv4Addr;
+
+    #[test]
+    fn keys_prefer_user_over_ip() {
+        let ip = IpAddr::V4(Ipv4Addr::new(10, 0, 0, 7));
+        assert_eq!(rate_key(&CONTACT_US, ip, None), "rate:contact-us:ip:10.0.0.7");
+        assert_eq!(rate_key(&UPLOAD, ip, Some("abc")), "rate:upload:user:abc");
+    }
+
+    #[test]
+    fn retry_after_rounds_up_to_whole_seconds() {
+        assert_eq!(retry_after_secs(1), 1);
+        assert_eq!(retry_after_secs(1000), 1);
+        assert_eq!(retry_after_secs(1001), 2);
+        assert_eq!(retry_after_secs(0), 1);
+    }
+}
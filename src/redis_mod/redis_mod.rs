@@ -1,11 +1,11 @@
 use chrono::{DateTime, Utc};
 use dotenv::dotenv;
 use futures_util::StreamExt;
-use redis::{Client, Commands, AsyncCommands, ControlFlow, PubSubCommands, from_redis_value, RedisResult, ErrorKind, Value, FromRedisValue, RedisWrite, ToRedisArgs, Msg};
+use redis::{Client, Commands, AsyncCommands, ControlFlow, PubSubCommands, Msg};
 use serde::{Deserialize, Serialize};
 use uuid::Uuid;
 use std::{collections::BTreeMap, env, sync::Arc, thread, time::Duration, ops::Deref};
-use deadpool_redis::{redis::{cmd}, Config, Runtime, Pool, Connection};
+use deadpool_redis::{redis::{cmd}, Config, Runtime, Pool, PoolConfig, Timeouts, Connection};
 
 use crate::redis_mod::{redis_subscriber::subscribe, redis_publisher::publish};
 
@@ -50,26 +50,6 @@ impl PubSubMsg {
     }
 }
 
-impl FromRedisValue for PubSubMsg {
-    fn from_redis_value(v: &Value) -> RedisResult<Self> {
-        let v: String = from_redis_value(v)?;
-        let result: Self = match serde_json::from_str::<Self>(&v) {
-          Ok(v) => v,
-          Err(_err) => return Err((ErrorKind::TypeError, "Parse to JSON Failed").into())
-        };
-        Ok(result)
-    }
-}
-
-impl ToRedisArgs for &PubSubMsg {
-    fn write_redis_args<W>(&self, out: &mut W)
-    where
-        W: ?Sized + RedisWrite,
-    {
-        out.write_arg_fmt(serde_json::to_string(self).expect("Can't serialize Planet as string"))
-    }
-}
-
 impl Ctx {
     pub fn new() -> Ctx {
         dotenv().ok();
@@ -129,7 +109,7 @@ pub async fn set_int(
     Ok(())
 }
 
-pub fn redis_connect() -> Pool {
+pub fn redis_conn_url() -> String {
     //format - host:port
     let redis_host_name = env::var("REDIS_HOSTNAME").unwrap_or(
         env::var("REDIS_HOSTNAME")
@@ -142,9 +122,23 @@ pub fn redis_connect() -> Pool {
             .to_owned()
             .unwrap_or("NoURL".to_string()),
     );
-    let redis_conn_url = format!("redis://:{}@{}:6379", redis_password, redis_host_name);
+    format!("redis://:{}@{}:6379", redis_password, redis_host_name)
+}
+
+pub const REDIS_TIMEOUT: Duration = Duration::from_secs(2);
+
+pub fn redis_connect() -> Pool {
+    let redis_conn_url = redis_conn_url();
 
     let mut cfg = Config::from_url(redis_conn_url);
+    // Without timeouts a get() waits forever on an unreachable Redis
+    let mut pool_cfg = PoolConfig::default();
+    pool_cfg.timeouts = Timeouts {
+        wait: Some(REDIS_TIMEOUT),
+        create: Some(REDIS_TIMEOUT),
+        recycle: Some(REDIS_TIMEOUT),
+    };
+    cfg.pool = Some(pool_cfg);
     let pool = cfg.create_pool(Some(Runtime::Tokio1)).unwrap();
     pool
 
@@ -1,7 +1,7 @@
 use std::{thread, time::Duration, sync::Arc};
 use chrono::Utc;
 use redis::Commands;
-use super::redis_mod::{RedisState, PubSubMsg};
+use super::{redis_mod::{RedisState, PubSubMsg}, typed::Json};
 
 pub fn publish(state: &impl RedisState) {
     let client = Arc::clone(state.client());
@@ -11,7 +11,7 @@ pub fn publish(state: &impl RedisState) {
         for x in 0..3 {
             thread::sleep(Duration::from_millis(500));
             println!("Publish {} to updates.", x);
-            let _: () = conn.publish("updates", (&test_message(x))).unwrap();
+            let _: () = conn.publish("updates", Json(test_message(x))).unwrap();
         }
     });
 }
@@ -1,6 +1,6 @@
 use std::{thread, sync::Arc};
 use redis::{ControlFlow, PubSubCommands};
-use super::redis_mod::{RedisState, PubSubMsg};
+use super::{redis_mod::{RedisState, PubSubMsg}, typed::Json};
 
 pub fn subscribe(state: &impl RedisState) -> thread::JoinHandle<()> {
     let client = Arc::clone(state.client());
@@ -10,8 +10,7 @@ pub fn subscribe(state: &impl RedisState) -> thread::JoinHandle<()> {
 
         conn.subscribe(&["updates"], |msg| {
             let ch = msg.get_channel_name();
-            let payload: String = msg.get_payload().unwrap();
-            let pubsub_msg = serde_json::from_str::<PubSubMsg>(&payload).unwrap();
+            let Json(pubsub_msg): Json<PubSubMsg> = msg.get_payload().unwrap();
             match pubsub_msg.from {
                 3 => ControlFlow::Break(()),
                 a => {
@@ -0,0 +1,119 @@
+use redis::{
+    aio::ConnectionLike, from_redis_value, AsyncCommands, ErrorKind, FromRedisValue, RedisResult, RedisWrite,
+    ToRedisArgs, Value,
+};
+use serde::{de::DeserializeOwned, Serialize};
+
+// JSON values in Redis. `Json<T>` reads and writes any serde type, and RedisModel gives a type its own
+// versioned key space (`<namespace>:v<version>:<id>`). Bump VERSION when a stored type's shape changes:
+// old keys are never read again and expire on their own, instead of failing to deserialize.
+
+pub struct Json<T>(pub T);
+
+impl<T: DeserializeOwned> FromRedisValue for Json<T> {
+    fn from_redis_value(v: &Value) -> RedisResult<Self> {
+        let raw: String = from_redis_value(v)?;
+        serde_json::from_str(&raw)
+            .map(Json)
+            .map_err(|err| (ErrorKind::TypeError, "Parse to JSON Failed", err.to_string()).into())
+    }
+}
+
+impl<T: Serialize> ToRedisArgs for Json<T> {
+    fn write_redis_args<W>(&self, out: &mut W)
+    where
+        W: ?Sized + RedisWrite,
+    {
+        out.write_arg(&serde_json::to_vec(&self.0).expect("Json<T> values must serialize"))
+    }
+}
+
+pub trait RedisModel: Serialize + DeserializeOwned + Sync {
+    const NAMESPACE: &'static str;
+    const VERSION: u32;
+}
+
+pub fn model_key<T: RedisModel>(id: &str) -> String {
+    format!("{}:v{}:{}", T::NAMESPACE, T::VERSION, id)
+}
+
+// A value we can't decode is treated as missing, the caller rebuilds it.
+fn decode<T: DeserializeOwned>(key: &str, raw: Option<String>) -> Option<T> {
+    let raw = raw?;
+    match serde_json::from_str(&raw) {
+        Ok(val) => Some(val),
+        Err(err) => {
+            println!("Ignoring undecodable Redis value at {}: {}", key, err);
+            None
+        }
+    }
+}
+
+pub async fn get<T: RedisModel, C: ConnectionLike + Send>(con: &mut C, id: &str) -> RedisResult<Option<T>> {
+    let key = model_key::<T>(id);
+    let raw: Option<String> = con.get(&key).await?;
+    Ok(decode(&key, raw))
+}
+
+pub async fn mget<T: RedisModel, C: ConnectionLike + Send>(con: &mut C, ids: &[String]) -> RedisResult<Vec<Option<T>>> {
+    if ids.is_empty() {
+        return Ok(vec![]);
+    }
+    let keys: Vec<String> = ids.iter().map(|id| model_key::<T>(id)).collect();
+    let raws: Vec<Option<String>> = redis::cmd("MGET").arg(&keys).query_async(con).await?;
+    Ok(keys.iter().zip(raws).map(|(key, raw)| decode(key, raw)).collect())
+}
+
+pub async fn set<T: RedisModel, C: ConnectionLike + Send>(con: &mut C, id: &str, val: &T) -> RedisResult<()> {
+    con.set(model_key::<T>(id), Json(val)).await
+}
+
+pub async fn set_ex<T: RedisModel, C: ConnectionLike + Send>(
+    con: &mut C,
+    id: &str,
+    val: &T,
+    ttl: usize,
+) -> RedisResult<()> {
+    con.set_ex(model_key::<T>(id), Json(val), ttl).await
+}
+
+pub async fn del<T: RedisModel, C: ConnectionLike + Send>(con: &mut C, id: &str) -> RedisResult<bool> {
+    con.del(model_key::<T>(id)).await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde::Deserialize;
+
+    #[derive(Debug, PartialEq, Serialize, Deserialize)]
+    struct Sample {
+        id: i32,
+        name: String,
+    }
+
+    impl RedisModel for Sample {
+        const NAMESPACE: &'static str = "sample";
+        const VERSION: u32 = 2;
+    }
+
+    #[test]
+    fn json_round_trips_through_redis_values() {
+        let sample = Sample { id: 7, name: "seven".to_string() };
+        let args = Json(&sample).to_redis_args();
+        let Json(restored) = Json::<Sample>::from_redis_value(&Value::Data(args[0].clone())).unwrap();
+        assert_eq!(restored, sample);
+        assert!(Json::<Sample>::from_redis_value(&Value::Data(b"{\"id\":\"x\"}".to_vec())).is_err());
+    }
+
+    #[test]
+    fn keys_are_versioned_and_stale_shapes_read_as_missing() {
+        assert_eq!(model_key::<Sample>("abc"), "sample:v2:abc");
+        assert_eq!(decode::<Sample>("k", Some("{\"id\":7}".to_string())), None);
+        assert_eq!(
+            decode::<Sample>("k", Some("{\"id\":7,\"name\":\"seven\"}".to_string())),
+            Some(Sample { id: 7, name: "seven".to_string() })
+        );
+        assert_eq!(decode::<Sample>("k", None), None);
+    }
+}
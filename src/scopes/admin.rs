@@ -6,7 +6,7 @@ use actix_web::{
     HttpRequest, HttpResponse, Responder, Scope,
 };
 
-use chrono::{DateTime, Utc};
+use chrono::{DateTime, Datelike, NaiveDate, Utc};
 use handlebars::Handlebars;
 use serde::{Deserialize, Serialize};
 use sqlx::FromRow;
@@ -14,9 +14,29 @@ use uuid::Uuid;
 use validator::Validate;
 
 use crate::{
+    calendar::{
+        availability::{review_time_off, time_off_to_review, TimeOff, PENDING},
+        booking::{
+            booking_links, client_email, create_link, decide_booking, is_link_type, pending_consults, revoke_link,
+            BookingStatus, PendingConsult, CONSULTANT_LINK,
+        },
+        export::ItipMethod,
+        holidays::{add_closure, closures_from, delete_closure, federal_holidays, CompanyClosure, Holiday},
+    },
     config::{
         self, subs_from_user, test_subs, FilterOptions, ResponsiveTableData,
         UserAlert, ValidationResponse, ACCEPTED_SECONDARIES, redis_validate_and_get_user, ValidationErrorMap, FormErrorResponse,
+        FixedTableData, SelectOption, TableRow as FixedTableRow,
+    },
+    jobs::{
+        queue::{enqueue, job_overview, retry_dead, JobRecord},
+        schedule::schedule_status,
+        Job,
+    },
+    linfa::{
+        assignment::{compare_strategies, decision_tree_svg, fit_decision_tree, load_assignment_data},
+        churn::CHURN_HORIZON_DAYS,
+        features::FEATURE_PIPELINE_VERSION,
     },
     models::{
         model_admin::{
@@ -27,6 +47,18 @@ use crate::{
             AdminUserPostResponse,
         },
     },
+    redis_mod::{
+        materialized::{
+            flush_all, refresh_for_refs, upcoming_for_consultant, warm_all, weekly_for_location, weekly_row,
+            weeks_shown, ConsultRefs, UPCOMING_DAYS,
+        },
+        live_updates::{publish_live_event, Broadcaster, LiveEntity, LiveEvent},
+        query_cache::{cache_metrics, invalidate, CacheTag},
+    },
+    scopes::{
+        consult::{client_options, consultant_options, regenerate_ics, send_invites},
+        notification::{current_admin_id, notify_subscribers},
+    },
     AppState, RedisState,
 };
 
@@ -39,6 +71,27 @@ pub fn admin_scope() -> Scope {
         .service(admin_home)
         .service(recent_activity)
         .service(get_contact_submissions)
+        .service(assignment_strategies)
+        .service(linfa_model)
+        .service(cache_metrics_handler)
+        .service(jobs_handler)
+        .service(enqueue_churn_scoring)
+        .service(retry_job)
+        .service(schedule_handler)
+        .service(dashboards)
+        .service(warm_dashboards)
+        .service(flush_dashboards)
+        .service(closures)
+        .service(create_closure)
+        .service(remove_closure)
+        .service(time_off)
+        .service(approve_time_off)
+        .service(deny_time_off)
+        .service(bookings)
+        .service(confirm_booking)
+        .service(decline_booking)
+        .service(create_booking_link)
+        .service(revoke_booking_link)
         //.service(edit_subadmin)
         .service(get_users_handler)
 }
@@ -256,6 +309,820 @@ pub async fn get_contact_submissions(
     return HttpResponse::Ok().body(body);
 }
 
+// Same holdout replay for every strategy so the accuracies are directly comparable.
+#[get("/assignment-strategies")]
+async fn assignment_strategies(
+    hb: web::Data<Handlebars<'_>>,
+    req: HttpRequest,
+    r_state: web::Data<RedisState>,
+    data: web::Data<AppState>,
+) -> impl Responder {
+    if current_admin_id(&req, &data, &r_state).await.is_none() {
+        return HttpResponse::Unauthorized().finish();
+    }
+    let reports = match load_assignment_data(&data.db).await {
+        Ok(assignment_data) => web::block(move || compare_strategies(&assignment_data, 0.2, Utc::now()))
+            .await
+            .map_err(|err| err.to_string()),
+        Err(err) => Err(err),
+    };
+    match reports {
+        Ok(reports) => {
+            let table_headers = vec![
+                "strategy".to_owned(),
+                "evaluated".to_owned(),
+                "matched".to_owned(),
+                "accuracy".to_owned(),
+            ];
+            let table_rows = reports
+                .iter()
+                .map(|report| FixedTableRow {
+                    th: report.strategy.clone(),
+                    tds: vec![
+                        report.evaluated.to_string(),
+                        report.matched.to_string(),
+                        format!("{:.1}%", report.accuracy * 100.0),
+                    ],
+                })
+                .collect::<Vec<FixedTableRow>>();
+            let fixed_table_data = FixedTableData {
+                table_headers: table_headers,
+                table_rows: table_rows,
+            };
+            let body = hb.render("fixed-table", &fixed_table_data).unwrap();
+            HttpResponse::Ok().body(body)
+        }
+        Err(err) => {
+            dbg!(&err);
+            let error_msg = "Error occurred while evaluating assignment strategies";
+            let validation_response = ValidationResponse::from((error_msg, "validation_error"));
+            let body = hb.render("validation", &validation_response).unwrap();
+            HttpResponse::Ok().body(body)
+        }
+    }
+}
+
+#[get("/cache")]
+async fn cache_metrics_handler(
+    hb: web::Data<Handlebars<'_>>,
+    r_state: web::Data<RedisState>,
+) -> impl Responder {
+    match cache_metrics(&r_state.r_pool).await {
+        Ok(metrics) => {
+            let table_headers = vec![
+                "entity".to_owned(),
+                "hits".to_owned(),
+                "misses".to_owned(),
+                "hit rate".to_owned(),
+                "invalidations".to_owned(),
+                "cached queries".to_owned(),
+            ];
+            let table_rows = metrics
+                .iter()
+                .map(|metric| FixedTableRow {
+                    th: metric.tag.clone(),
+                    tds: vec![
+                        metric.hits.to_string(),
+                        metric.misses.to_string(),
+                        format!("{:.1}%", metric.hit_rate() * 100.0),
+                        metric.invalidations.to_string(),
+                        metric.cached_queries.to_string(),
+                    ],
+                })
+                .collect::<Vec<FixedTableRow>>();
+            let fixed_table_data = FixedTableData {
+                table_headers: table_headers,
+                table_rows: table_rows,
+            };
+            let body = hb.render("fixed-table", &fixed_table_data).unwrap();
+            HttpResponse::Ok().body(body)
+        }
+        Err(err) => {
+            dbg!(&err);
+            let error_msg = "Error occurred while reading query cache metrics";
+            let validation_response = ValidationResponse::from((error_msg, "validation_error"));
+            let body = hb.render("validation", &validation_response).unwrap();
+            HttpResponse::Ok().body(body)
+        }
+    }
+}
+
+#[get("/schedule")]
+async fn schedule_handler(
+    hb: web::Data<Handlebars<'_>>,
+    r_state: web::Data<RedisState>,
+) -> impl Responder {
+    let fmt = |time: Option<DateTime<Utc>>| {
+        time.map(|time| time.format("%b %-d, %-I:%M %p UTC").to_string())
+            .unwrap_or("-".to_owned())
+    };
+    match schedule_status(&r_state.r_pool).await {
+        Ok(statuses) => {
+            let table_headers = vec![
+                "task".to_owned(),
+                "description".to_owned(),
+                "cron (UTC)".to_owned(),
+                "last run".to_owned(),
+                "next run".to_owned(),
+                "last job".to_owned(),
+            ];
+            let table_rows = statuses
+                .into_iter()
+                .map(|status| FixedTableRow {
+                    th: status.name,
+                    tds: vec![
+                        status.description,
+                        status.cron,
+                        fmt(status.last_run),
+                        fmt(status.next_run),
+                        status.last_job_id.unwrap_or("-".to_owned()),
+                    ],
+                })
+                .collect::<Vec<FixedTableRow>>();
+            let fixed_table_data = FixedTableData {
+                table_headers: table_headers,
+                table_rows: table_rows,
+            };
+            let body = hb.render("fixed-table", &fixed_table_data).unwrap();
+            HttpResponse::Ok().body(body)
+        }
+        Err(err) => {
+            dbg!(&err);
+            let error_msg = "Error occurred while reading the schedule";
+            let validation_response = ValidationResponse::from((error_msg, "validation_error"));
+            let body = hb.render("validation", &validation_response).unwrap();
+            HttpResponse::Ok().body(body)
+        }
+    }
+}
+
+#[derive(Debug, FromRow)]
+struct NamedId {
+    id: i32,
+    name: String,
+}
+
+#[derive(Debug, Serialize)]
+pub struct DashboardsTemplate {
+    pub locations: FixedTableData,
+    pub consultants: FixedTableData,
+    pub message: Option<String>,
+}
+
+async fn named_ids(db: &sqlx::Pool<sqlx::Postgres>, sql: &str) -> Vec<NamedId> {
+    sqlx::query_as::<_, NamedId>(sql).fetch_all(db).await.unwrap_or_else(|err| {
+        dbg!(&err);
+        vec![]
+    })
+}
+
+// Reads only the materialized views (building any that are missing), no per-request aggregation.
+async fn render_dashboards(hb: &Handlebars<'_>, r_state: &RedisState, message: Option<String>) -> HttpResponse {
+    let weeks = weeks_shown(Utc::now());
+    let mut location_headers = vec!["location".to_owned()];
+    location_headers.extend(weeks.iter().map(|week| week.format("%b %-d").to_string()));
+    let mut location_rows = vec![];
+    for location in named_ids(&r_state.db, "SELECT id, location_name AS name FROM locations ORDER BY location_name").await {
+        let counts = weekly_for_location(r_state, location.id).await;
+        location_rows.push(FixedTableRow {
+            th: location.name,
+            tds: weekly_row(&weeks, &counts).iter().map(|count| count.to_string()).collect(),
+        });
+    }
+
+    let mut consultant_rows = vec![];
+    for consultant in named_ids(
+        &r_state.db,
+        "SELECT id, consultant_f_name || ' ' || consultant_l_name AS name FROM consultants ORDER BY consultant_l_name",
+    )
+    .await
+    {
+        let upcoming = upcoming_for_consultant(r_state, consultant.id).await;
+        consultant_rows.push(FixedTableRow {
+            th: consultant.name,
+            tds: vec![
+                upcoming.len().to_string(),
+                upcoming
+                    .first()
+                    .map(|next| next.consult_start.format("%b %-d, %-I:%M %p").to_string())
+                    .unwrap_or("-".to_owned()),
+            ],
+        });
+    }
+
+    let template_data = DashboardsTemplate {
+        locations: FixedTableData {
+            table_headers: location_headers,
+            table_rows: location_rows,
+        },
+        consultants: FixedTableData {
+            table_headers: vec![
+                "consultant".to_owned(),
+                format!("consults next {} days", UPCOMING_DAYS),
+                "next consult".to_owned(),
+            ],
+            table_rows: consultant_rows,
+        },
+        message,
+    };
+    let body = hb.render("admin/dashboards", &template_data).unwrap();
+    HttpResponse::Ok().body(body)
+}
+
+#[get("/dashboards")]
+async fn dashboards(
+    hb: web::Data<Handlebars<'_>>,
+    r_state: web::Data<RedisState>,
+) -> impl Responder {
+    render_dashboards(&hb, &r_state, None).await
+}
+
+#[post("/dashboards/warm")]
+async fn warm_dashboards(
+    hb: web::Data<Handlebars<'_>>,
+    req: HttpRequest,
+    data: web::Data<AppState>,
+    r_state: web::Data<RedisState>,
+) -> impl Responder {
+    if current_admin_id(&req, &data, &r_state).await.is_none() {
+        return HttpResponse::Unauthorized().finish();
+    }
+    let message = match warm_all(&r_state).await {
+        Ok(report) => format!(
+            "Warmed {} feeds, {} consultant and {} location views",
+            report.feeds, report.consultants, report.locations
+        ),
+        Err(err) => format!("Warm failed: {}", err),
+    };
+    render_dashboards(&hb, &r_state, Some(message)).await
+}
+
+#[post("/dashboards/flush")]
+async fn flush_dashboards(
+    hb: web::Data<Handlebars<'_>>,
+    req: HttpRequest,
+    data: web::Data<AppState>,
+    r_state: web::Data<RedisState>,
+) -> impl Responder {
+    if current_admin_id(&req, &data, &r_state).await.is_none() {
+        return HttpResponse::Unauthorized().finish();
+    }
+    let message = match flush_all(&r_state).await {
+        Ok(deleted) => format!("Flushed {} views. They rebuild as they're read.", deleted),
+        Err(err) => format!("Flush failed: {}", err),
+    };
+    // Rendering here would rebuild everything straight away
+    let body = hb.render("validation", &ValidationResponse::from((message.as_str(), "validation_success"))).unwrap();
+    HttpResponse::Ok().body(body)
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct ClosuresTemplate {
+    pub year: i32,
+    pub federal: Vec<Holiday>,
+    pub closures: Vec<CompanyClosure>,
+    pub message: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct ClosurePost {
+    pub closure_date: String,
+    pub closure_name: String,
+}
+
+// Federal holidays are computed, so only closures can be added or removed here.
+async fn render_closures(hb: &Handlebars<'_>, data: &AppState, message: Option<String>) -> HttpResponse {
+    let today = Utc::now().date_naive();
+    match closures_from(&data.db, today).await {
+        Ok(closures) => {
+            let template_data = ClosuresTemplate {
+                year: today.year(),
+                federal: federal_holidays(today.year()),
+                closures,
+                message,
+            };
+            let body = hb.render("admin/closures", &template_data).unwrap();
+            HttpResponse::Ok().body(body)
+        }
+        Err(err) => {
+            dbg!(&err);
+            let error_msg = "Error occurred while loading company closures";
+            let validation_response = ValidationResponse::from((error_msg, "validation_error"));
+            let body = hb.render("validation", &validation_response).unwrap();
+            HttpResponse::Ok().body(body)
+        }
+    }
+}
+
+#[get("/closures")]
+async fn closures(
+    hb: web::Data<Handlebars<'_>>,
+    data: web::Data<AppState>,
+) -> impl Responder {
+    render_closures(&hb, &data, None).await
+}
+
+#[post("/closures")]
+async fn create_closure(
+    hb: web::Data<Handlebars<'_>>,
+    req: HttpRequest,
+    r_state: web::Data<RedisState>,
+    data: web::Data<AppState>,
+    body: web::Form<ClosurePost>,
+) -> impl Responder {
+    if current_admin_id(&req, &data, &r_state).await.is_none() {
+        return HttpResponse::Unauthorized().finish();
+    }
+    let name = body.closure_name.trim();
+    let message = match NaiveDate::parse_from_str(&body.closure_date, "%Y-%m-%d") {
+        Ok(_) if name.is_empty() => "Closure needs a name".to_owned(),
+        Ok(date) => match add_closure(&data.db, date, name).await {
+            Ok(()) => format!("Closed on {}", date.format("%b %-d, %Y")),
+            Err(err) => {
+                dbg!(&err);
+                "Unable to save the closure".to_owned()
+            }
+        },
+        Err(_) => "Closure needs a valid date".to_owned(),
+    };
+    render_closures(&hb, &data, Some(message)).await
+}
+
+#[post("/closures/{closure_id}/delete")]
+async fn remove_closure(
+    hb: web::Data<Handlebars<'_>>,
+    req: HttpRequest,
+    r_state: web::Data<RedisState>,
+    data: web::Data<AppState>,
+    path: web::Path<i32>,
+) -> impl Responder {
+    if current_admin_id(&req, &data, &r_state).await.is_none() {
+        return HttpResponse::Unauthorized().finish();
+    }
+    let message = match delete_closure(&data.db, path.into_inner()).await {
+        Ok(0) => "Closure not found".to_owned(),
+        Ok(_) => "Closure removed".to_owned(),
+        Err(err) => {
+            dbg!(&err);
+            "Unable to remove the closure".to_owned()
+        }
+    };
+    render_closures(&hb, &data, Some(message)).await
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct TimeOffTemplate {
+    pub pending: Vec<TimeOff>,
+    pub reviewed: Vec<TimeOff>,
+    pub message: Option<String>,
+}
+
+// Pending requests need a decision. Decided ones are listed until they've passed.
+async fn render_time_off(hb: &Handlebars<'_>, data: &AppState, message: Option<String>) -> HttpResponse {
+    match time_off_to_review(&data.db, Utc::now().date_naive()).await {
+        Ok(requests) => {
+            let (pending, reviewed) = requests.into_iter().partition(|request| request.status == PENDING);
+            let template_data = TimeOffTemplate { pending, reviewed, message };
+            let body = hb.render("admin/time-off", &template_data).unwrap();
+            HttpResponse::Ok().body(body)
+        }
+        Err(err) => {
+            dbg!(&err);
+            let error_msg = "Error occurred while loading time off requests";
+            let validation_response = ValidationResponse::from((error_msg, "validation_error"));
+            let body = hb.render("validation", &validation_response).unwrap();
+            HttpResponse::Ok().body(body)
+        }
+    }
+}
+
+async fn decide_time_off(
+    hb: &Handlebars<'_>,
+    req: &HttpRequest,
+    data: &web::Data<AppState>,
+    r_state: &web::Data<RedisState>,
+    time_off_id: i32,
+    approve: bool,
+) -> HttpResponse {
+    let reviewed_by = match current_admin_id(req, data, r_state).await {
+        Some(user_id) => user_id,
+        None => return HttpResponse::Unauthorized().finish(),
+    };
+    let message = match review_time_off(&data.db, time_off_id, approve, reviewed_by).await {
+        Ok(Some(_)) if approve => "Time off approved".to_owned(),
+        Ok(Some(_)) => "Time off denied".to_owned(),
+        Ok(None) => "Request not found or already decided".to_owned(),
+        Err(err) => {
+            dbg!(&err);
+            "Unable to save the decision".to_owned()
+        }
+    };
+    render_time_off(hb, data, Some(message)).await
+}
+
+#[get("/time-off")]
+async fn time_off(
+    hb: web::Data<Handlebars<'_>>,
+    req: HttpRequest,
+    data: web::Data<AppState>,
+    r_state: web::Data<RedisState>,
+) -> impl Responder {
+    if current_admin_id(&req, &data, &r_state).await.is_none() {
+        return HttpResponse::Unauthorized().finish();
+    }
+    render_time_off(&hb, &data, None).await
+}
+
+#[post("/time-off/{time_off_id}/approve")]
+async fn approve_time_off(
+    hb: web::Data<Handlebars<'_>>,
+    req: HttpRequest,
+    data: web::Data<AppState>,
+    r_state: web::Data<RedisState>,
+    path: web::Path<i32>,
+) -> impl Responder {
+    decide_time_off(&hb, &req, &data, &r_state, path.into_inner(), true).await
+}
+
+#[post("/time-off/{time_off_id}/deny")]
+async fn deny_time_off(
+    hb: web::Data<Handlebars<'_>>,
+    req: HttpRequest,
+    data: web::Data<AppState>,
+    r_state: web::Data<RedisState>,
+    path: web::Path<i32>,
+) -> impl Responder {
+    decide_time_off(&hb, &req, &data, &r_state, path.into_inner(), false).await
+}
+
+#[derive(Debug, Serialize)]
+pub struct BookingLinkRow {
+    pub link_id: i32,
+    pub link_name: String,
+    pub kind: &'static str,
+    pub url: String,
+}
+
+#[derive(Debug, Serialize)]
+pub struct BookingsTemplate {
+    pub pending: Vec<PendingConsult>,
+    pub links: Vec<BookingLinkRow>,
+    pub client_options: Vec<SelectOption>,
+    pub consultant_options: Vec<SelectOption>,
+    pub message: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct BookingLinkPost {
+    pub entity_type_id: i32,
+    // String so the select's empty option parses
+    pub entity_id: Option<String>,
+}
+
+// Self-service requests waiting on a decision, and the public links clients book through
+async fn render_bookings(
+    hb: &Handlebars<'_>,
+    req: &HttpRequest,
+    data: &web::Data<AppState>,
+    r_state: &web::Data<RedisState>,
+    message: Option<String>,
+) -> HttpResponse {
+    let (pending, links) = match (pending_consults(&data.db).await, booking_links(&data.db).await) {
+        (Ok(pending), Ok(links)) => (pending, links),
+        (Err(err), _) | (_, Err(err)) => {
+            dbg!(&err);
+            let error_msg = "Error occurred while loading booking requests";
+            let validation_response = ValidationResponse::from((error_msg, "validation_error"));
+            let body = hb.render("validation", &validation_response).unwrap();
+            return HttpResponse::Ok().body(body);
+        }
+    };
+    let info = req.connection_info();
+    let links = links
+        .into_iter()
+        .map(|link| BookingLinkRow {
+            link_id: link.link_id,
+            link_name: link.link_name,
+            kind: if link.entity_type_id == CONSULTANT_LINK { "Consultant" } else { "Client" },
+            url: format!("{}://{}/book/{}", info.scheme(), info.host(), link.token),
+        })
+        .collect();
+    let template_data = BookingsTemplate {
+        pending,
+        links,
+        client_options: client_options(data, r_state).await.vec,
+        consultant_options: consultant_options(data, r_state).await.vec,
+        message,
+    };
+    let body = hb.render("admin/bookings", &template_data).unwrap();
+    HttpResponse::Ok().body(body)
+}
+
+// Confirming sends the calendar invite, declining frees the slot and lets the client know
+async fn decide_booking_request(
+    hb: &Handlebars<'_>,
+    req: &HttpRequest,
+    data: &web::Data<AppState>,
+    r_state: &web::Data<RedisState>,
+    broadcaster: &Broadcaster,
+    consult_id: i32,
+    decision: BookingStatus,
+) -> HttpResponse {
+    let consult = match decide_booking(&data.db, consult_id, decision).await {
+        Ok(Some(consult)) => consult,
+        Ok(None) => return render_bookings(hb, req, data, r_state, Some("Request not found or already decided".to_owned())).await,
+        Err(err) => {
+            dbg!(&err);
+            return render_bookings(hb, req, data, r_state, Some("Unable to save the decision".to_owned())).await;
+        }
+    };
+    invalidate(r_state, &[CacheTag::Consult]).await;
+    let refs = ConsultRefs {
+        client_id: consult.client_id,
+        location_id: consult.location_id,
+        consultant_id: consult.consultant_id,
+    };
+    refresh_for_refs(r_state, &[refs]).await;
+    let (summary, action) = match decision {
+        BookingStatus::Declined => (format!("Consult #{} declined", consult.id), "cancelled"),
+        _ => (format!("Consult #{} confirmed", consult.id), "updated"),
+    };
+    let mut targets = vec![(7, consult.client_id), (5, consult.location_id), (6, consult.id)];
+    if let Some(consultant_id) = consult.consultant_id {
+        targets.push((4, consultant_id));
+    }
+    let notified = notify_subscribers(&data.db, &targets, &summary).await;
+    publish_live_event(r_state, broadcaster, LiveEvent::new(LiveEntity::Consult, consult.id, action, summary.clone()).notify(notified)).await;
+    regenerate_ics(r_state, consult.id).await;
+    if decision == BookingStatus::Declined {
+        match client_email(&data.db, consult.client_id).await {
+            Ok(to_email) => {
+                let msg = format!(
+                    "We're sorry, we couldn't confirm your consult request #{}. Please pick another time or call us.",
+                    consult.id
+                );
+                let subject = Some("Your consult request couldn't be confirmed".to_owned());
+                if let Err(err) = enqueue(&r_state.r_pool, Job::SendEmail { to_email, msg, subject }).await {
+                    println!("Error queueing decline email for consult {}: {}", consult.id, err);
+                }
+            }
+            Err(err) => println!("Error loading client email for consult {}: {}", consult.id, err),
+        }
+    } else {
+        send_invites(r_state, consult.id, ItipMethod::Request).await;
+    }
+    render_bookings(hb, req, data, r_state, Some(summary)).await
+}
+
+#[get("/bookings")]
+async fn bookings(
+    hb: web::Data<Handlebars<'_>>,
+    req: HttpRequest,
+    data: web::Data<AppState>,
+    r_state: web::Data<RedisState>,
+) -> impl Responder {
+    if current_admin_id(&req, &data, &r_state).await.is_none() {
+        return HttpResponse::Unauthorized().finish();
+    }
+    render_bookings(&hb, &req, &data, &r_state, None).await
+}
+
+#[post("/bookings/{consult_id}/confirm")]
+async fn confirm_booking(
+    hb: web::Data<Handlebars<'_>>,
+    req: HttpRequest,
+    data: web::Data<AppState>,
+    r_state: web::Data<RedisState>,
+    broadcaster: web::Data<Broadcaster>,
+    path: web::Path<i32>,
+) -> impl Responder {
+    if current_admin_id(&req, &data, &r_state).await.is_none() {
+        return HttpResponse::Unauthorized().finish();
+    }
+    decide_booking_request(&hb, &req, &data, &r_state, &broadcaster, path.into_inner(), BookingStatus::Confirmed).await
+}
+
+#[post("/bookings/{consult_id}/decline")]
+async fn decline_booking(
+    hb: web::Data<Handlebars<'_>>,
+    req: HttpRequest,
+    data: web::Data<AppState>,
+    r_state: web::Data<RedisState>,
+    broadcaster: web::Data<Broadcaster>,
+    path: web::Path<i32>,
+) -> impl Responder {
+    if current_admin_id(&req, &data, &r_state).await.is_none() {
+        return HttpResponse::Unauthorized().finish();
+    }
+    decide_booking_request(&hb, &req, &data, &r_state, &broadcaster, path.into_inner(), BookingStatus::Declined).await
+}
+
+#[post("/booking-links")]
+async fn create_booking_link(
+    body: web::Form<BookingLinkPost>,
+    hb: web::Data<Handlebars<'_>>,
+    req: HttpRequest,
+    data: web::Data<AppState>,
+    r_state: web::Data<RedisState>,
+) -> impl Responder {
+    let user_id = match current_admin_id(&req, &data, &r_state).await {
+        Some(user_id) => user_id,
+        None => return HttpResponse::Unauthorized().finish(),
+    };
+    let entity_id = body.entity_id.as_deref().and_then(|id| id.parse::<i32>().ok());
+    let message = match entity_id {
+        Some(entity_id) if is_link_type(body.entity_type_id) => {
+            match create_link(&data.db, body.entity_type_id, entity_id, user_id).await {
+                Ok(()) => "Booking link ready".to_owned(),
+                Err(err) => {
+                    dbg!(&err);
+                    "Unable to create the booking link".to_owned()
+                }
+            }
+        }
+        _ => "Choose who the link is for".to_owned(),
+    };
+    render_bookings(&hb, &req, &data, &r_state, Some(message)).await
+}
+
+#[post("/booking-links/{link_id}/revoke")]
+async fn revoke_booking_link(
+    hb: web::Data<Handlebars<'_>>,
+    req: HttpRequest,
+    data: web::Data<AppState>,
+    r_state: web::Data<RedisState>,
+    path: web::Path<i32>,
+) -> impl Responder {
+    if current_admin_id(&req, &data, &r_state).await.is_none() {
+        return HttpResponse::Unauthorized().finish();
+    }
+    let message = match revoke_link(&data.db, path.into_inner()).await {
+        Ok(0) => "Booking link not found".to_owned(),
+        Ok(_) => "Booking link revoked".to_owned(),
+        Err(err) => {
+            dbg!(&err);
+            "Unable to revoke the booking link".to_owned()
+        }
+    };
+    render_bookings(&hb, &req, &data, &r_state, Some(message)).await
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct JobRow {
+    pub id: String,
+    pub name: String,
+    pub attempts: u32,
+    pub last_error: Option<String>,
+    pub updated_at_fmt: String,
+    pub run_at_fmt: Option<String>,
+}
+
+impl From<&JobRecord> for JobRow {
+    fn from(record: &JobRecord) -> Self {
+        JobRow {
+            id: record.id.clone(),
+            name: record.job.name().to_owned(),
+            attempts: record.attempts,
+            last_error: record.last_error.clone(),
+            updated_at_fmt: record.updated_at.format("%b %-d, %-I:%M:%S").to_string(),
+            run_at_fmt: record.run_at.map(|run_at| run_at.format("%b %-d, %-I:%M:%S").to_string()),
+        }
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct JobSection {
+    pub title: String,
+    pub retryable: bool,
+    pub jobs: Vec<JobRow>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct JobsTemplate {
+    pub sections: Vec<JobSection>,
+    pub message: Option<String>,
+}
+
+fn job_section(title: &str, records: &[JobRecord], retryable: bool) -> JobSection {
+    JobSection {
+        title: title.to_owned(),
+        retryable,
+        jobs: records.iter().map(JobRow::from).collect(),
+    }
+}
+
+async fn render_jobs(hb: &Handlebars<'_>, r_state: &RedisState, message: Option<String>) -> HttpResponse {
+    match job_overview(&r_state.r_pool, 50).await {
+        Ok(overview) => {
+            let template_data = JobsTemplate {
+                sections: vec![
+                    job_section("Running", &overview.running, false),
+                    job_section("Queued", &overview.queued, false),
+                    job_section("Retrying", &overview.retrying, false),
+                    job_section("Failed", &overview.dead, true),
+                    job_section("Completed", &overview.completed, false),
+                ],
+                message,
+            };
+            let body = hb.render("admin/jobs", &template_data).unwrap();
+            HttpResponse::Ok().body(body)
+        }
+        Err(err) => {
+            dbg!(&err);
+            let error_msg = "Error occurred while reading the job queue";
+            let validation_response = ValidationResponse::from((error_msg, "validation_error"));
+            let body = hb.render("validation", &validation_response).unwrap();
+            HttpResponse::Ok().body(body)
+        }
+    }
+}
+
+#[get("/jobs")]
+async fn jobs_handler(
+    hb: web::Data<Handlebars<'_>>,
+    req: HttpRequest,
+    data: web::Data<AppState>,
+    r_state: web::Data<RedisState>,
+) -> impl Responder {
+    if current_admin_id(&req, &data, &r_state).await.is_none() {
+        return HttpResponse::Unauthorized().finish();
+    }
+    render_jobs(&hb, &r_state, None).await
+}
+
+#[post("/jobs/score-churn")]
+async fn enqueue_churn_scoring(
+    hb: web::Data<Handlebars<'_>>,
+    req: HttpRequest,
+    data: web::Data<AppState>,
+    r_state: web::Data<RedisState>,
+) -> impl Responder {
+    if current_admin_id(&req, &data, &r_state).await.is_none() {
+        return HttpResponse::Unauthorized().finish();
+    }
+    let message = match enqueue(&r_state.r_pool, Job::ScoreChurn { horizon_days: CHURN_HORIZON_DAYS }).await {
+        Ok(id) => format!("Churn scoring queued as job {}", id),
+        Err(err) => format!("Unable to queue churn scoring: {}", err),
+    };
+    render_jobs(&hb, &r_state, Some(message)).await
+}
+
+#[post("/jobs/{id}/retry")]
+async fn retry_job(
+    hb: web::Data<Handlebars<'_>>,
+    req: HttpRequest,
+    data: web::Data<AppState>,
+    r_state: web::Data<RedisState>,
+    path: web::Path<String>,
+) -> impl Responder {
+    if current_admin_id(&req, &data, &r_state).await.is_none() {
+        return HttpResponse::Unauthorized().finish();
+    }
+    let id = path.into_inner();
+    let message = match retry_dead(&r_state.r_pool, &id).await {
+        Ok(()) => format!("Job {} requeued", id),
+        Err(err) => err,
+    };
+    render_jobs(&hb, &r_state, Some(message)).await
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct LinfaModelData {
+    pub num_rows: usize,
+    pub num_consultants: usize,
+    pub feature_version: u32,
+    pub tree_svg: Option<String>,
+}
+
+// The decision tree as it would be fit right now for a new consult.
+#[get("/model")]
+async fn linfa_model(
+    hb: web::Data<Handlebars<'_>>,
+    data: web::Data<AppState>,
+) -> impl Responder {
+    match load_assignment_data(&data.db).await {
+        Ok(assignment_data) => {
+            let tree_svg = fit_decision_tree(&assignment_data, Utc::now())
+                .map(|(model, pipeline)| decision_tree_svg(&model, &pipeline, None));
+            let model_data = LinfaModelData {
+                num_rows: assignment_data.history.len(),
+                num_consultants: assignment_data.consultants.len(),
+                feature_version: FEATURE_PIPELINE_VERSION,
+                tree_svg: tree_svg,
+            };
+            let body = hb.render("admin/linfa-model", &model_data).unwrap();
+            HttpResponse::Ok().body(body)
+        }
+        Err(err) => {
+            dbg!(&err);
+            let error_msg = "Error occurred while loading the Linfa model data";
+            let validation_response = ValidationResponse::from((error_msg, "validation_error"));
+            let body = hb.render("validation", &validation_response).unwrap();
+            HttpResponse::Ok().body(body)
+        }
+    }
+}
+
 #[get("/list/{user_type_id}")]
 pub async fn get_users_handler(
     opts: web::Query<FilterOptions>,
@@ -5,7 +5,7 @@ use actix_web::{
 };
 use argonautica::{Hasher, Verifier};
 use chrono::{DateTime, Duration, Utc};
-use deadpool_redis::{Connection, Pool};
+use deadpool_redis::Connection;
 use handlebars::Handlebars;
 use redis::{AsyncCommands, RedisResult};
 use serde::{Deserialize, Serialize};
@@ -17,9 +17,15 @@ use validator::{Validate, ValidationError};
 use crate::{config::ValidationResponse, AppState, HeaderValueExt, RedisState};
 use crate::{
     config::{
-        get_ip, send_email, user_feed, SendEmailInput, RE_EMAIL, RE_SPECIAL_CHAR, RE_USERNAME,
+        cache_session, get_ip, RE_EMAIL, RE_SPECIAL_CHAR, RE_USERNAME,
     },
-    HomepageTemplate, ValidatedUser,
+    jobs::{queue::enqueue, Job},
+    redis_mod::{
+        materialized::materialized_user_feed,
+        rate_limit::{rate_limited, FORGOT_PASSWORD, REGISTER},
+        typed,
+    },
+    CachedSession, HomepageTemplate, ValidatedUser,
 };
 
 #[derive(Debug, Serialize, Deserialize, Clone)]
@@ -144,9 +150,14 @@ pub struct CreateUserBody {
 #[post("/register")]
 async fn register_user(
     state: Data<AppState>,
+    r_state: Data<RedisState>,
+    req: HttpRequest,
     body: Json<CreateUserBody>,
     hb: web::Data<Handlebars<'_>>,
 ) -> impl Responder {
+    if let Some(resp) = rate_limited(&req, &r_state, &hb, &REGISTER, None).await {
+        return resp;
+    }
     let is_valid = body.validate();
     if is_valid.is_err() {
         return HttpResponse::InternalServerError().json(format!("{:?}", is_valid.err().unwrap()));
@@ -261,16 +272,16 @@ async fn basic_auth(
                             location_subs: user.location_subs,
                             consultant_subs: user.consultant_subs,
                         };
-                        // Set in Redis
-                        let mut con = r_state.r_pool.get().await.unwrap();
-                        // let prefix = &session.session_id;
-                        // The old way
-                        // let con = push_subs(&user.user_subs, "user_subs", &session.session_id, con).await;
-                        // let mut users: BTreeMap<String, &str> = BTreeMap::new();
-                        let j = serde_json::to_string(&user).unwrap();
-                        // sessionId => ValidatedUser
-                        // 86400 sec = one day
-                        let _: RedisResult<bool> = con.set_ex(session.session_id, &j, 86400).await;
+                        // Set in Redis. If it's down the session is read from Postgres instead.
+                        if let Some(mut con) = r_state.conn().await {
+                            // let prefix = &session.session_id;
+                            // The old way
+                            // let con = push_subs(&user.user_subs, "user_subs", &session.session_id, con).await;
+                            // let mut users: BTreeMap<String, &str> = BTreeMap::new();
+                            // session:v2:<sessionId> => CachedSession
+                            let cached = CachedSession { user: user.clone(), expires };
+                            cache_session(&mut con, &session.session_id, &cached).await;
+                        }
                         // let _: () = redis::cmd("SET")
                         //     // .arg(format!("{}:{}", prefix, "serialized_user"))
                         //     .arg(session.session_id)
@@ -278,7 +289,7 @@ async fn basic_auth(
                         //     .query_async::<_, ()>(&mut con)
                         //     .await
                         //     .expect("failed to execute SET for ValidatedUser");
-                        let feed_data = user_feed(&user, &state.db).await;
+                        let feed_data = materialized_user_feed(&r_state, &user).await;
                         let template_data = HomepageTemplate {
                             error: None,
                             user: Some(user.clone()),
@@ -345,8 +356,8 @@ async fn register_form(
     return HttpResponse::Ok().body(body);
 }
 
-pub async fn remove_redis_keys(cookie: &HeaderValue, pool: &Pool) -> Result<(), String> {
-    let mut con = pool.get().await.unwrap();
+pub async fn remove_redis_keys(cookie: &HeaderValue, r_state: &RedisState) -> Result<(), String> {
+    let mut con = r_state.conn().await.ok_or("Redis unavailable".to_owned())?;
     // DEL operation
     // let deleted_serialized: RedisResult<bool> = con.del(&cookie.to_string()).await;
     // match deleted_serialized {
@@ -382,7 +393,7 @@ pub async fn remove_redis_keys(cookie: &HeaderValue, pool: &Pool) -> Result<(),
     //     },
     //     Err(err) => return Err(format!("Error: {}", err))
     // }
-    let deleted: RedisResult<bool> = con.del(cookie.to_string()).await;
+    let deleted: RedisResult<bool> = typed::del::<CachedSession, _>(&mut con, &cookie.to_string()).await;
     match deleted {
         Ok(true) => {
             println!("Key deleted");
@@ -415,9 +426,10 @@ async fn logout(
         {
             Ok(expires) => {
                 dbg!(&expires);
-                let result = remove_redis_keys(&cookie, &r_state.r_pool).await;
-                if result.is_err() {
-                    panic!("Redis keys not deleted for user session");
+                // The session row is already expired. A cached copy we can't delete lapses on its own
+                // after SESSION_CACHE_SECS.
+                if let Err(err) = remove_redis_keys(&cookie, &r_state).await {
+                    println!("Redis keys not deleted for user session: {}", err);
                 };
                 let body = hb.render("index", &expires).unwrap();
                 return HttpResponse::Ok()
@@ -543,11 +555,15 @@ pub struct ForgotPasswordResponse {
 #[post("/forgot-password")]
 async fn forgot_password(
     state: Data<AppState>,
+    r_state: Data<RedisState>,
     req: HttpRequest,
     body: web::Form<ForgotPasswordBody>,
     hb: web::Data<Handlebars<'_>>,
 ) -> impl Responder {
     println!("in forgot pass");
+    if let Some(resp) = rate_limited(&req, &r_state, &hb, &FORGOT_PASSWORD, None).await {
+        return resp;
+    }
     let ip_addr = get_ip(req);
     let is_valid = body.validate();
     if is_valid.is_err() {
@@ -568,11 +584,12 @@ async fn forgot_password(
     {
         Ok(resp) => {
             let created_at_fmt = resp.created_at.format("%b %-d, %-I:%M").to_string();
-            let email_input = SendEmailInput::from((
-                body.email.as_str(),
-                format!("A password reset was requested on {}", created_at_fmt).as_str(),
-            ));
-            match send_email(email_input).await {
+            let job = Job::SendEmail {
+                to_email: body.email.to_string(),
+                msg: format!("A password reset was requested on {}", created_at_fmt),
+                subject: Some("Password reset requested".to_owned()),
+            };
+            match enqueue(&r_state.r_pool, job).await {
                 Ok(_) => {
                     let success_msg = "Reset Password link has been sent.";
                     let validation_response =
@@ -580,8 +597,9 @@ async fn forgot_password(
                     let body = hb.render("validation", &validation_response).unwrap();
                     return HttpResponse::Ok().body(body);
                 }
-                Err(e) => {
-                    let error_msg = "Unable to send Reset Password link. Please ensure you have entered a valid email address.";
+                Err(err) => {
+                    println!("Error queueing reset email: {}", err);
+                    let error_msg = "Unable to send Reset Password link. Please try again shortly.";
                     let validation_response =
                         ValidationResponse::from((error_msg, "validation_error"));
                     let body = hb.render("validation", &validation_response).unwrap();
@@ -0,0 +1,374 @@
+use actix_web::{get, post, web, HttpRequest, HttpResponse, Responder, Scope};
+use chrono::{Duration, NaiveDate, Utc};
+use handlebars::Handlebars;
+use serde::{Deserialize, Serialize};
+use validator::Validate;
+
+use crate::{
+    calendar::booking::{
+        client_email, client_id_by_email, consultant_ids, hold_request, link_by_token, slots_for, take_request,
+        BookingLink, BookingRequest, BookingStatus, OpenSlot, BOOKING_DAYS_AHEAD, CLIENT_LINK, CONSULTANT_LINK,
+    },
+    config::{consult_purpose_options, SelectOption, ValidationResponse},
+    jobs::{queue::enqueue, Job},
+    linfa::consult_offset,
+    models::model_consult::ConsultPost,
+    redis_mod::{
+        live_updates::Broadcaster,
+        rate_limit::{rate_limited, BOOKING},
+    },
+    scopes::consult::{book_consult, location_options, BookingError},
+    AppState, RedisState,
+};
+
+// Public pages behind booking links. No session: the token is the credential, and staff revoke it if it leaks.
+pub fn booking_scope() -> Scope {
+    web::scope("/book")
+        .service(confirm_request)
+        .service(booking_page)
+        .service(booking_slots)
+        .service(request_booking)
+}
+
+#[derive(Debug, Serialize)]
+pub struct BookingPageTemplate {
+    pub token: String,
+    pub link_name: String,
+    // Consultant links don't know who the client is
+    pub asks_email: bool,
+    pub purpose_options: Vec<SelectOption>,
+    pub location_options: Vec<SelectOption>,
+    pub min_date: NaiveDate,
+    pub max_date: NaiveDate,
+}
+
+#[derive(Debug, Serialize)]
+pub struct BookingSlotsTemplate {
+    pub date: Option<NaiveDate>,
+    pub slots: Vec<OpenSlot>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct SlotsQuery {
+    pub date: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct BookingRequestPost {
+    pub consult_purpose_id: i32,
+    pub location_id: i32,
+    pub date: String,
+    pub start_time: Option<String>,
+    pub email: Option<String>,
+    pub num_attendees: Option<i32>,
+    pub notes: Option<String>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct BookingRequestedTemplate {
+    pub link_name: String,
+    pub date: String,
+    pub start_time: String,
+}
+
+fn booking_error(hb: &Handlebars<'_>, error_msg: &str) -> HttpResponse {
+    let validation_response = ValidationResponse::from((error_msg, "validation_error"));
+    let body = hb.render("validation", &validation_response).unwrap();
+    HttpResponse::BadRequest()
+        .header("HX-Retarget", "#booking_errors")
+        .body(body)
+}
+
+fn link_not_found(hb: &Handlebars<'_>) -> HttpResponse {
+    let validation_response = ValidationResponse::from(("This booking link is no longer active", "validation_error"));
+    let body = hb.render("validation", &validation_response).unwrap();
+    HttpResponse::NotFound().body(body)
+}
+
+fn local_today() -> NaiveDate {
+    Utc::now().with_timezone(&consult_offset()).date_naive()
+}
+
+// A consultant link only ever offers that consultant. A client link offers anyone free.
+async fn candidates(state: &AppState, link: &BookingLink) -> Result<Vec<i32>, sqlx::Error> {
+    match link.entity_type_id {
+        CONSULTANT_LINK => Ok(vec![link.entity_id]),
+        _ => consultant_ids(&state.db).await,
+    }
+}
+
+async fn open_slots_on(state: &AppState, link: &BookingLink, date: NaiveDate) -> Result<Vec<OpenSlot>, String> {
+    let consultant_ids = candidates(state, link).await.map_err(|e| format!("Error in DB {}", e))?;
+    slots_for(&state.db, date, Utc::now(), &consultant_ids).await
+}
+
+#[get("/{token}")]
+async fn booking_page(
+    hb: web::Data<Handlebars<'_>>,
+    state: web::Data<AppState>,
+    r_state: web::Data<RedisState>,
+    path: web::Path<String>,
+) -> impl Responder {
+    let link = match link_by_token(&state.db, &path.into_inner()).await {
+        Ok(Some(link)) => link,
+        Ok(None) => return link_not_found(&hb),
+        Err(err) => {
+            dbg!(&err);
+            return link_not_found(&hb);
+        }
+    };
+    let today = local_today();
+    let template_data = BookingPageTemplate {
+        asks_email: link.entity_type_id == CONSULTANT_LINK,
+        token: link.token,
+        link_name: link.link_name,
+        purpose_options: consult_purpose_options(),
+        location_options: location_options(&state, &r_state).await.vec,
+        min_date: today,
+        max_date: today + Duration::days(BOOKING_DAYS_AHEAD),
+    };
+    let body = hb.render("booking/page", &template_data).unwrap();
+    HttpResponse::Ok().body(body)
+}
+
+#[get("/{token}/slots")]
+async fn booking_slots(
+    query: web::Query<SlotsQuery>,
+    hb: web::Data<Handlebars<'_>>,
+    state: web::Data<AppState>,
+    path: web::Path<String>,
+) -> impl Responder {
+    let link = match link_by_token(&state.db, &path.into_inner()).await {
+        Ok(Some(link)) => link,
+        _ => return link_not_found(&hb),
+    };
+    let date = query
+        .date
+        .as_deref()
+        .and_then(|date| NaiveDate::parse_from_str(date, "%Y-%m-%d").ok());
+    let slots = match date {
+        Some(date) => match open_slots_on(&state, &link, date).await {
+            Ok(slots) => slots,
+            Err(err) => {
+                dbg!(&err);
+                return booking_error(&hb, "Unable to load open times. Please try again.");
+            }
+        },
+        None => vec![],
+    };
+    let body = hb.render("booking/slots", &BookingSlotsTemplate { date, slots }).unwrap();
+    HttpResponse::Ok().body(body)
+}
+
+// Books a request as a pending consult and emails the client a receipt. The slot is checked again here,
+// so one taken since the page loaded, or since the confirmation email went out, is refused.
+async fn place_request(
+    hb: &Handlebars<'_>,
+    state: &web::Data<AppState>,
+    r_state: &web::Data<RedisState>,
+    broadcaster: &web::Data<Broadcaster>,
+    link: &BookingLink,
+    request: &BookingRequest,
+) -> HttpResponse {
+    let slot = match open_slots_on(state, link, request.date).await {
+        Ok(slots) => slots.into_iter().find(|slot| slot.start_time == request.start_time),
+        Err(err) => {
+            dbg!(&err);
+            return booking_error(hb, "Unable to check open times. Please try again.");
+        }
+    };
+    let slot = match slot {
+        Some(slot) => slot,
+        None => return booking_error(hb, "That time is no longer open. Please pick another."),
+    };
+    let post = booking_post(link, request, &slot);
+    match book_consult(&post, BookingStatus::Pending, state, r_state, broadcaster).await {
+        Ok(consult) => {
+            let requested = requested_template(link, request);
+            match client_email(&state.db, request.client_id).await {
+                Ok(to_email) => {
+                    let msg = format!(
+                        "We received your request for a consult on {} at {}. We'll email you an invitation once it's confirmed. (Request #{})",
+                        requested.date, requested.start_time, consult.id
+                    );
+                    let subject = Some("We received your consult request".to_owned());
+                    if let Err(err) = enqueue(&r_state.r_pool, Job::SendEmail { to_email, msg, subject }).await {
+                        println!("Error queueing booking receipt for consult {}: {}", consult.id, err);
+                    }
+                }
+                Err(err) => println!("Error loading client email for consult {}: {}", consult.id, err),
+            }
+            let body = hb.render("booking/requested", &requested).unwrap();
+            HttpResponse::Ok().body(body)
+        }
+        Err(BookingError::Invalid(_)) => booking_error(hb, "Please choose a purpose and a location"),
+        Err(BookingError::Unavailable(_)) => booking_error(hb, "That time is no longer open. Please pick another."),
+        Err(err) => {
+            dbg!(&err);
+            booking_error(hb, "Unable to save your request. Please try again or call us.")
+        }
+    }
+}
+
+fn booking_post(link: &BookingLink, request: &BookingRequest, slot: &OpenSlot) -> ConsultPost {
+    let date = request.date.format("%Y-%m-%d").to_string();
+    ConsultPost {
+        consult_purpose_id: request.consult_purpose_id,
+        client_id: request.client_id,
+        consultant_id: slot.consultant_ids[0],
+        location_id: request.location_id,
+        attachment_path: None,
+        // Client links let the account's assignment strategy pick among whoever is free
+        linfa_assign: (link.entity_type_id == CLIENT_LINK).then(|| "on".to_owned()),
+        num_attendees: request.num_attendees,
+        // Until it's held, like the consult form's default
+        consult_result_id: 2,
+        consult_start_date: date.clone(),
+        consult_start_time: slot.start_time.clone(),
+        consult_end_date: date,
+        consult_end_time: slot.end_time.clone(),
+        notes: request.notes.clone(),
+        updated_at: None,
+    }
+}
+
+fn requested_template(link: &BookingLink, request: &BookingRequest) -> BookingRequestedTemplate {
+    BookingRequestedTemplate {
+        link_name: link.link_name.clone(),
+        date: request.date.format("%A, %B %-d %Y").to_string(),
+        start_time: request.start_time.clone(),
+    }
+}
+
+// A consultant link asks for the client's email. The request is held and a confirmation link mailed to
+// that address, and the reply is the same whether or not we know the email.
+async fn hold_for_confirmation(
+    hb: &Handlebars<'_>,
+    req: &HttpRequest,
+    state: &web::Data<AppState>,
+    r_state: &web::Data<RedisState>,
+    link: &BookingLink,
+    mut request: BookingRequest,
+    email: &str,
+) -> HttpResponse {
+    match client_id_by_email(&state.db, email).await {
+        Ok(Some(client_id)) => {
+            request.client_id = client_id;
+            match hold_request(&r_state.r_pool, &request).await {
+                Ok(token) => {
+                    let confirm_url = {
+                        let info = req.connection_info();
+                        format!("{}://{}/book/confirm/{}", info.scheme(), info.host(), token)
+                    };
+                    let requested = requested_template(link, &request);
+                    let msg = format!(
+                        "Please confirm your request for a consult with {} on {} at {}: {} \
+                        The link works for an hour. If you didn't ask for this, you can ignore this email.",
+                        requested.link_name, requested.date, requested.start_time, confirm_url
+                    );
+                    let to_email = email.trim().to_owned();
+                    let subject = Some("Confirm your consult request".to_owned());
+                    if let Err(err) = enqueue(&r_state.r_pool, Job::SendEmail { to_email, msg, subject }).await {
+                        println!("Error queueing booking confirmation for client {}: {}", client_id, err);
+                    }
+                }
+                Err(err) => println!("Error holding booking request for client {}: {}", client_id, err),
+            }
+        }
+        Ok(None) => {}
+        Err(err) => println!("Error looking up booking email: {}", err),
+    }
+    let body = hb.render("booking/check-email", &requested_template(link, &request)).unwrap();
+    HttpResponse::Ok().body(body)
+}
+
+#[post("/{token}")]
+async fn request_booking(
+    body: web::Form<BookingRequestPost>,
+    hb: web::Data<Handlebars<'_>>,
+    req: HttpRequest,
+    state: web::Data<AppState>,
+    r_state: web::Data<RedisState>,
+    broadcaster: web::Data<Broadcaster>,
+    path: web::Path<String>,
+) -> impl Responder {
+    if let Some(resp) = rate_limited(&req, &r_state, &hb, &BOOKING, None).await {
+        return resp;
+    }
+    let link = match link_by_token(&state.db, &path.into_inner()).await {
+        Ok(Some(link)) => link,
+        _ => return link_not_found(&hb),
+    };
+    let date = match NaiveDate::parse_from_str(&body.date, "%Y-%m-%d") {
+        Ok(date) => date,
+        Err(_) => return booking_error(&hb, "Choose a day"),
+    };
+    let start_time = match body.start_time.as_deref() {
+        Some(start_time) if !start_time.is_empty() => start_time,
+        _ => return booking_error(&hb, "Choose a time"),
+    };
+    let slot = match open_slots_on(&state, &link, date).await {
+        Ok(slots) => slots.into_iter().find(|slot| slot.start_time == start_time),
+        Err(err) => {
+            dbg!(&err);
+            return booking_error(&hb, "Unable to check open times. Please try again.");
+        }
+    };
+    let slot = match slot {
+        Some(slot) => slot,
+        None => return booking_error(&hb, "That time is no longer open. Please pick another."),
+    };
+    let request = BookingRequest {
+        link_token: link.token.clone(),
+        // A client link is the client. A consultant link fills it in from the email once it's confirmed.
+        client_id: link.entity_id,
+        consult_purpose_id: body.consult_purpose_id,
+        location_id: body.location_id,
+        date,
+        start_time: start_time.to_owned(),
+        num_attendees: body.num_attendees.unwrap_or(1).max(1),
+        notes: body.notes.clone().unwrap_or_default(),
+    };
+    if booking_post(&link, &request, &slot).validate().is_err() {
+        return booking_error(&hb, "Please choose a purpose and a location");
+    }
+    match link.entity_type_id {
+        CLIENT_LINK => place_request(&hb, &state, &r_state, &broadcaster, &link, &request).await,
+        _ => {
+            let email = body.email.as_deref().unwrap_or_default();
+            hold_for_confirmation(&hb, &req, &state, &r_state, &link, request, email).await
+        }
+    }
+}
+
+// Opened from the confirmation email
+#[get("/confirm/{request_token}")]
+async fn confirm_request(
+    hb: web::Data<Handlebars<'_>>,
+    state: web::Data<AppState>,
+    r_state: web::Data<RedisState>,
+    broadcaster: web::Data<Broadcaster>,
+    path: web::Path<String>,
+) -> impl Responder {
+    let request = match take_request(&r_state.r_pool, &path.into_inner()).await {
+        Ok(Some(request)) => request,
+        Ok(None) => {
+            let validation_response = ValidationResponse::from((
+                "This confirmation link has expired or was already used. Please request a time again.",
+                "validation_error",
+            ));
+            let body = hb.render("validation", &validation_response).unwrap();
+            return HttpResponse::NotFound().body(body);
+        }
+        Err(err) => {
+            dbg!(&err);
+            return booking_error(&hb, "Unable to confirm your request. Please try again shortly.");
+        }
+    };
+    // Revoked since the email went out
+    let link = match link_by_token(&state.db, &request.link_token).await {
+        Ok(Some(link)) => link,
+        _ => return link_not_found(&hb),
+    };
+    place_request(&hb, &state, &r_state, &broadcaster, &link, &request).await
+}
@@ -1,19 +1,25 @@
 use actix_web::{get, patch, post, web, HttpRequest, HttpResponse, Responder, Scope};
-use redis::{AsyncCommands, RedisResult, RedisError};
 
 use crate::{
     config::{
-        self, get_validation_response, subs_from_user, FilterOptions,
+        self, edit_conflicts, get_validation_response, subs_from_user, EditConflictTemplate, FilterOptions,
         FormErrorResponse, ResponsiveTableData, SelectOption, UserAlert, ValidationErrorMap,
-        ValidationResponse, redis_validate_and_get_user, SimpleQuery, SelectOptionsVec, hash_query,
+        ValidationResponse, redis_validate_and_get_user, SimpleQuery, SelectOptionsVec,
     },
     models::model_client::{
         ClientFormRequest, ClientFormTemplate, ClientList, ClientPostRequest, ClientPostResponse,
     },
-    AppState, RedisState, redis_mod::{redis_mod::Ctx, redis_publisher::publish}, scopes::location::FullPageTemplateData,
+    AppState, RedisState,
+    redis_mod::{
+        live_updates::{publish_live_event, Broadcaster, LiveEntity, LiveEvent},
+        presence::leave,
+        query_cache::{invalidate, CacheTag},
+    },
+    scopes::{consult::select_options, location::FullPageTemplateData, notification::notify_subscribers},
 };
 use chrono::NaiveDate;
 use handlebars::Handlebars;
+use sqlx::{Pool, Postgres};
 use validator::Validate;
 
 pub fn client_scope() -> Scope {
@@ -26,6 +32,28 @@ pub fn client_scope() -> Scope {
         .service(client_edit_form)
 }
 
+fn client_sort_key(opts: &FilterOptions) -> &'static str {
+    match opts.key.as_deref() {
+        Some("client_name") => "client_name",
+        Some("specialty_name") => "specialty_name",
+        Some("client_email") => "client_email",
+        Some("address") => "address",
+        Some("client_city") => "client_city",
+        Some("client_zip") => "client_zip",
+        Some("phone") => "phone",
+        Some("client_type_id") => "clients.client_type_id",
+        Some("churn_risk") => "churn_risk",
+        _ => "clients.id",
+    }
+}
+
+fn sort_dir(opts: &FilterOptions) -> &'static str {
+    match opts.dir.as_deref() {
+        Some("DESC") => "DESC",
+        _ => "ASC",
+    }
+}
+
 // col names transformed into table headers. use aliases.
 #[get("/list")]
 pub async fn get_clients_handler(
@@ -42,8 +70,10 @@ pub async fn get_clients_handler(
                 let limit = opts.limit.unwrap_or(10);
                 let offset = (opts.page.unwrap_or(1) - 1) * limit;
 
-                let query_result = sqlx::query_as!(
-                    ClientList,
+                let sort_key = client_sort_key(&opts);
+                let sort_dir = sort_dir(&opts);
+                // Sort column/dir come from whitelists above, everything else is bound
+                let query_str = format!(
                     "SELECT 
                         clients.id,
                         clients.client_type_id,
@@ -54,16 +84,20 @@ pub async fn get_clients_handler(
                         client_address_one AS address,
                         client_city,
                         client_zip,
-                        client_primary_phone AS phone
+                        client_primary_phone AS phone,
+                        ROUND(client_risk_scores.risk_score * 100)::INTEGER AS churn_risk
                     FROM clients
                     INNER JOIN specialties ON specialties.id = clients.specialty_id
-                    ORDER by id
+                    LEFT JOIN client_risk_scores ON client_risk_scores.client_id = clients.id
+                    ORDER by {} {} NULLS LAST, clients.id
                     LIMIT $1 OFFSET $2",
-                    limit as i32,
-                    offset as i32
-                )
-                .fetch_all(&state.db)
-                .await;
+                    sort_key, sort_dir
+                );
+                let query_result = sqlx::query_as::<_, ClientList>(&query_str)
+                    .bind(limit as i32)
+                    .bind(offset as i32)
+                    .fetch_all(&state.db)
+                    .await;
 
                 dbg!(&query_result);
 
@@ -122,10 +156,6 @@ async fn client_form(
 ) -> impl Responder {
     println!("client_form firing");
 
-    let ctx = Ctx::new();
-    // let handle = subscribe(&ctx);
-    publish(&ctx);
-
     // let _ = get_n_pages(8).await;
 
     let account_options = account_options(&state, &r_state).await;
@@ -149,47 +179,18 @@ async fn account_options(state: &web::Data<AppState>, r_state: &web::Data<RedisS
         int_args: None,
         str_args: None,
     };
-    let query_hash = hash_query(&simple_query);
-    // FIXME: Search for key in Redis before reaching for DB
-    let mut con = r_state.r_pool.get().await.unwrap();
-    let prefix = "query";
-    let key = format!("{:?}:{:?}", prefix, query_hash);
-
-    let exists: Result<SelectOptionsVec, RedisError> = con.get(&key).await;
-
-    dbg!(&exists);
-
-    if exists.is_ok() {
-        println!("Getting account_options from Redis");
-        let result = exists.unwrap();
-        return result;
-    } else {
-        println!("Getting account_options from DB");
-        let account_result = sqlx::query_as::<_, SelectOption>(simple_query.query_str)
-        .fetch_all(&state.db)
-        .await;
-
-        dbg!(&query_hash);
-        if account_result.is_err() {
-            let err = "Error occurred while fetching account option KVs";
-            let default_options = SelectOption {
-                key: Some("No accounts Found".to_owned()),
-                value: 0,
-            };
-            // default_options
-            dbg!("Incoming Panic");
-        }
-
-        let account_options = account_result.unwrap();
-        let sov = SelectOptionsVec {
-            vec: account_options,
-        };
-        // Cache the query in Redis -- `query:hash_value => result_hash`
-        let val = serde_json::to_string(&sov).unwrap();
-        let _: RedisResult<bool> = con.set_ex(key, &val, 86400).await;
+    select_options(simple_query, &[CacheTag::Account], 86400, state, r_state).await
+}
 
-        return sov;
-    }
+pub async fn client_by_slug(db: &Pool<Postgres>, slug: &str) -> Result<ClientFormRequest, sqlx::Error> {
+    sqlx::query_as::<_, ClientFormRequest>(
+        "SELECT client_company_name, client_f_name, client_l_name, slug, client_address_one, client_address_two, client_city, client_state, client_zip, client_email, client_dob, account_id, specialty_id, client_primary_phone, updated_at
+            FROM clients 
+            WHERE slug = $1",
+    )
+    .bind(slug)
+    .fetch_one(db)
+    .await
 }
 
 #[get("/form/{slug}")]
@@ -201,15 +202,7 @@ async fn client_edit_form(
 ) -> impl Responder {
     let loc_slug = path.into_inner();
 
-    let query_result = sqlx::query_as!(
-        ClientFormRequest,
-        "SELECT client_company_name, client_f_name, client_l_name, slug, client_address_one, client_address_two, client_city, client_state, client_zip, client_email, client_dob, account_id, specialty_id, client_primary_phone
-            FROM clients 
-            WHERE slug = $1",
-            loc_slug
-    )
-    .fetch_one(&state.db)
-    .await;
+    let query_result = client_by_slug(&state.db, &loc_slug).await;
 
     dbg!(&query_result);
 
@@ -240,6 +233,7 @@ async fn create_client(
     req: HttpRequest,
     state: web::Data<AppState>,
     r_state: web::Data<RedisState>,
+    broadcaster: web::Data<Broadcaster>,
 ) -> impl Responder {
     if let Some(cookie) = req.headers().get(actix_web::http::header::COOKIE) {
         match redis_validate_and_get_user(cookie, &r_state).await {
@@ -288,19 +282,9 @@ async fn create_client(
                     {
                         Ok(loc) => {
                             dbg!(loc.id);
-                            // Del / Invalidate Redis Key to force a DB fetch
-                            let mut con = r_state.r_pool.get().await.unwrap();
-                            let key = format!("{}:{}", "query", "client_options");
-                            let deleted: RedisResult<bool> = con.del(&key).await;
-                            match deleted {
-                                Ok(true) => {
-                                    println!("Key deleted");
-                                },
-                                Ok(false) => {
-                                    println!("Key not found {}", &key);
-                                },
-                                Err(err) => println!("Error: {}", err)
-                            }
+                            invalidate(&r_state, &[CacheTag::Client]).await;
+                            let summary = format!("Client #{} added", loc.id);
+                            publish_live_event(&r_state, &broadcaster, LiveEvent::new(LiveEntity::Client, loc.id, "created", summary)).await;
                             let user_alert = UserAlert::from((format!("Client added successfully: client_id #{:?}", loc.id).as_str(), "alert_success"));
                             let body = hb.render("crud-api-inner", &user_alert).unwrap();
                             return HttpResponse::Ok().body(body);
@@ -339,6 +323,7 @@ async fn patch_client(
     state: web::Data<AppState>,
     path: web::Path<String>,
     r_state: web::Data<RedisState>,
+    broadcaster: web::Data<Broadcaster>,
 ) -> impl Responder {
     if let Some(cookie) = req.headers().get(actix_web::http::header::COOKIE) {
         match redis_validate_and_get_user(cookie, &r_state).await {
@@ -374,7 +359,7 @@ async fn patch_client(
                         .header("HX-Retarget", "#client_errors")
                         .body(body);
                 }else{
-                    // Valid input so perform query
+                    // Valid input so perform query. Only applies if nobody saved since this form was loaded.
                     match sqlx::query_as::<_, ClientPostResponse>(
                         "UPDATE clients 
                             SET client_company_name = $1,
@@ -388,8 +373,10 @@ async fn patch_client(
                                 client_primary_phone = $9,
                                 client_email = $10,
                                 account_id = $11,
-                                specialty_id = $12
+                                specialty_id = $12,
+                                updated_at = NOW()
                             WHERE slug = $13
+                            AND updated_at IS NOT DISTINCT FROM $14
                             RETURNING id",
                     )
                     .bind(&body.client_company_name)
@@ -404,12 +391,18 @@ async fn patch_client(
                     .bind(&body.client_email)
                     .bind(&body.account_id)
                     .bind(&body.specialty_id)
-                    .bind(client_slug)
-                    .fetch_one(&state.db)
+                    .bind(&client_slug)
+                    .bind(body.updated_at)
+                    .fetch_optional(&state.db)
                     .await
                     {
-                        Ok(client) => {
+                        Ok(Some(client)) => {
                             dbg!(client.id);
+                            leave(&r_state, LiveEntity::Client, &client_slug, &user.username).await;
+                            invalidate(&r_state, &[CacheTag::Client]).await;
+                            let summary = format!("Client #{} edited", client.id);
+                            let notified = notify_subscribers(&state.db, &[(7, client.id)], &summary).await;
+                            publish_live_event(&r_state, &broadcaster, LiveEvent::new(LiveEntity::Client, client.id, "updated", summary).notify(notified)).await;
                             let user_alert = UserAlert::from((
                                 format!("Client edited successfully: client_id #{:?}", client.id).as_str(),
                                 "alert_success",
@@ -421,6 +414,27 @@ async fn patch_client(
                             let body = hb.render("list-api", &full_page_data).unwrap();
                             return HttpResponse::Ok().body(body);
                         }
+                        // Stale version. Show what changed since instead of overwriting it.
+                        Ok(None) => {
+                            let template_data = match client_by_slug(&state.db, &client_slug).await {
+                                Ok(current) => EditConflictTemplate {
+                                    conflicts: edit_conflicts(&*body, &current),
+                                    updated_at: current.updated_at,
+                                },
+                                Err(err) => {
+                                    dbg!(&err);
+                                    let validation_response = ValidationResponse::from(("Client no longer exists", "validation_error"));
+                                    let body = hb.render("validation", &validation_response).unwrap();
+                                    return HttpResponse::NotFound()
+                                        .header("HX-Retarget", "#client_errors")
+                                        .body(body);
+                                }
+                            };
+                            let body = hb.render("forms/edit-conflict", &template_data).unwrap();
+                            return HttpResponse::Conflict()
+                                .header("HX-Retarget", "#client_errors")
+                                .body(body);
+                        }
                         Err(err) => {
                             dbg!(&err);
                             let user_alert = UserAlert::from((
@@ -521,6 +535,7 @@ mod tests {
             client_email: "Email@email.com".to_string(),
             account_id: 1,
             specialty_id: 2,
+            updated_at: None,
         };
         let template_data = ClientFormTemplate {
             entity: Some(mock_client_with_dates),
@@ -7,31 +7,47 @@ use std::{
 
 use actix_multipart::Multipart;
 use actix_web::{
-    get, http::header::CONTENT_LENGTH, post, web, HttpRequest, HttpResponse, Responder, Scope,
+    get, http::header::CONTENT_LENGTH, patch, post, web, HttpRequest, HttpResponse, Responder, Scope,
 };
-use chrono::{DateTime, Timelike, Utc, Duration};
-use deadpool_redis::{Pool as RedisPool};
+use chrono::{DateTime, Duration, FixedOffset, Timelike, Utc};
 use futures_util::TryStreamExt;
 use handlebars::Handlebars;
 use mime::{Mime, APPLICATION_JSON, APPLICATION_PDF, IMAGE_GIF, IMAGE_JPEG, IMAGE_PNG, TEXT_CSV};
-use redis::{RedisResult, AsyncCommands, RedisError, ErrorKind, FromRedisValue, from_redis_value, Value};
 use serde::{Deserialize, Serialize};
 use sqlx::{postgres::PgRow, Error, FromRow, Pool, Postgres, QueryBuilder, Row};
 use struct_iterable::Iterable;
 use uuid::Uuid;
-use validator::Validate;
+use validator::{Validate, ValidationErrors};
 
 use crate::{
+    calendar::{availability::load_availability, booking::BookingStatus, export::ItipMethod},
     config::{
         consult_purpose_options, consult_result_options, mime_type_id_from_path, subs_from_user,
         FilterOptions, ResponsiveTableData, SelectOption, UserAlert, ValidationResponse, 
-        get_validation_response, redis_validate_and_get_user, SelectOptionsVec, SimpleQuery, hash_query, hash_owned_query,
+        get_validation_response, redis_validate_and_get_user, SelectOptionsVec, SimpleQuery,
+        edit_conflicts, EditConflictTemplate,
+    },
+    jobs::{queue::enqueue, Job},
+    linfa::{
+        assignment::{assign_consultant, load_assignment_data},
+        meeting_time::{
+            consultant_conflicts, load_busy_blocks, suggest_meeting_time, suggested_slots, MeetingTimeInput,
+            SuggestedSlot,
+        },
+        consult_offset,
     },
-    linfa::linfa_pred,
     models::model_consult::{
         ConsultAttachments, ConsultFormRequest, ConsultFormTemplate, ConsultList, ConsultPost,
         ConsultWithDates, ConsultListVec,
     },
+    redis_mod::{
+        live_updates::{publish_live_event, Broadcaster, LiveEntity, LiveEvent},
+        materialized::{refresh_for_consult, refresh_for_refs, ConsultRefs},
+        presence::leave,
+        query_cache::{cached, invalidate, CacheTag},
+        rate_limit::{rate_limited, session_key, UPLOAD},
+    },
+    scopes::{location::FullPageTemplateData, notification::notify_subscribers},
     AppState, RedisState,
 };
 
@@ -41,13 +57,44 @@ pub fn consult_scope() -> Scope {
         .service(consult_form)
         .service(consult_edit_form)
         .service(create_consult)
+        .service(patch_consult)
+        .service(cancel_consult)
         .service(get_consults_handler)
         .service(get_attachments)
         .service(upload)
         .service(availability)
+        .service(suggested_slots_handler)
+        // Keep last. Catches every other single segment path.
+        .service(consult_detail)
 }
 
-async fn location_options(state: &web::Data<AppState>, r_state: &web::Data<RedisState>) -> SelectOptionsVec {
+// Empty options on a DB error, the form still renders.
+pub async fn select_options(
+    simple_query: SimpleQuery,
+    tags: &[CacheTag],
+    ttl: usize,
+    state: &web::Data<AppState>,
+    r_state: &web::Data<RedisState>,
+) -> SelectOptionsVec {
+    let owned_query = OwnedQuery::from(&simple_query);
+    let db = &state.db;
+    let result = cached(r_state, &owned_query, tags, ttl, || async move {
+        sqlx::query_as::<_, SelectOption>(simple_query.query_str)
+            .fetch_all(db)
+            .await
+            .map(|vec| SelectOptionsVec { vec })
+    })
+    .await;
+    match result {
+        Ok(sov) => sov,
+        Err(err) => {
+            dbg!(&err);
+            SelectOptionsVec { vec: vec![] }
+        }
+    }
+}
+
+pub async fn location_options(state: &web::Data<AppState>, r_state: &web::Data<RedisState>) -> SelectOptionsVec {
     let simple_query = SimpleQuery {
         query_str: "SELECT id AS value, location_name AS key 
         FROM locations 
@@ -55,146 +102,27 @@ async fn location_options(state: &web::Data<AppState>, r_state: &web::Data<Redis
         int_args: None,
         str_args: None,
     };
-    // Not hashing the query for options. Its always the same. Fixed Redis key so we can delete & invalidate when a record is added.
-    // let query_hash = hash_query(&simple_query);
-    // Search for key in Redis before reaching for DB
-    let mut con = r_state.r_pool.get().await.unwrap();
-    let prefix = "query";
-    let key = format!("{}:{}", prefix, "location_options");
-
-    let exists: Result<SelectOptionsVec, RedisError> = con.get(&key).await;
-    dbg!(&exists);
-
-    if exists.is_ok() {
-        println!("Getting location_options from Redis");
-        let result = exists.unwrap();
-        return result;
-    } else {
-        println!("Getting location_options from DB");
-        let location_result = sqlx::query_as::<>(simple_query.query_str)
-        .fetch_all(&state.db)
-        .await;
-
-        if location_result.is_err() {
-            let err = "Error occurred while fetching location option KVs";
-            let default_options = SelectOption {
-                key: Some("No Locations Found".to_owned()),
-                value: 0,
-            };
-            // default_options
-            dbg!("Incoming Panic");
-        }
-
-        let location_options = location_result.unwrap();
-        let sov = SelectOptionsVec {
-            vec: location_options,
-        };
-        // Cache the query in Redis -- `query:hash_value => result_hash`
-        let val = serde_json::to_string(&sov).unwrap();
-        let _: RedisResult<bool> = con.set_ex(key, &val, 86400).await;
-
-        return sov;
-    }   
+    select_options(simple_query, &[CacheTag::Location], 86400, state, r_state).await
 }
 
-// FIXME: Just pass pools?
-async fn consultant_options(state: &web::Data<AppState>, r_state: &web::Data<RedisState>) -> SelectOptionsVec {
+pub async fn consultant_options(state: &web::Data<AppState>, r_state: &web::Data<RedisState>) -> SelectOptionsVec {
     let simple_query = SimpleQuery {
         query_str: "SELECT CONCAT(consultant_f_name, ' ',consultant_l_name) AS key, id AS value 
         FROM consultants ORDER BY key",
         int_args: None,
         str_args: None,
     };
-    // Not hashing the query for options. Its always the same. Fixed Redis key so we can delete & invalidate when a record is added.
-    // let query_hash = hash_query(&simple_query);
-    // Search for key in Redis before reaching for DB
-    let mut con = r_state.r_pool.get().await.unwrap();
-    let prefix = "query";
-    let key = format!("{}:{}", prefix, "consultant_options");
-
-    let exists: Result<SelectOptionsVec, RedisError> = con.get(&key).await;
-    dbg!(&exists);
-
-    if exists.is_ok() {
-        println!("Getting consultant_options from Redis");
-        let result = exists.unwrap();
-        return result;
-    } else {
-        println!("Getting consultant_options from DB");
-        let consultant_result = sqlx::query_as::<_, SelectOption>(simple_query.query_str)
-        .fetch_all(&state.db)
-        .await;
-
-        if consultant_result.is_err() {
-            let err = "Error occurred while fetching location option KVs";
-            let default_options = SelectOption {
-                key: Some("No Consultant Found".to_owned()),
-                value: 0,
-            };
-            // default_options
-            dbg!("Incoming Panic");
-        }
-
-        let consultant_options = consultant_result.unwrap();
-        let sov = SelectOptionsVec {
-            vec: consultant_options,
-        };
-        // Cache the query in Redis -- `query:hash_value => result_hash`
-        let val = serde_json::to_string(&sov).unwrap();
-        let _: RedisResult<bool> = con.set_ex(key, &val, 120).await;
-
-        return sov;
-    }
+    select_options(simple_query, &[CacheTag::Consultant], 120, state, r_state).await
 }
 
-async fn client_options(state: &web::Data<AppState>, r_state: &web::Data<RedisState>) -> SelectOptionsVec {
+pub async fn client_options(state: &web::Data<AppState>, r_state: &web::Data<RedisState>) -> SelectOptionsVec {
     let simple_query = SimpleQuery {
         query_str: "SELECT COALESCE(client_company_name, CONCAT(client_f_name, ' ', client_l_name)) AS key, id AS value 
         FROM clients ORDER BY key",
         int_args: None,
         str_args: None,
     };
-    // Not hashing the query for options. Its always the same. Fixed Redis key so we can delete & invalidate when a record is added.
-    // let query_hash = hash_query(&simple_query);
-    // Search for key in Redis before reaching for DB
-    let mut con = r_state.r_pool.get().await.unwrap();
-    let prefix = "query";
-    let key = format!("{}:{}", prefix, "client_options");
-
-    let exists: Result<SelectOptionsVec, RedisError> = con.get(&key).await;
-
-    dbg!(&exists);
-
-    if exists.is_ok() {
-        println!("Getting client_options from Redis");
-        let result = exists.unwrap();
-        return result;
-    } else {
-        println!("Getting client_options from DB");
-        let client_result = sqlx::query_as::<_, SelectOption>(simple_query.query_str)
-        .fetch_all(&state.db)
-        .await;
-
-        if client_result.is_err() {
-            let err = "Error occurred while fetching location option KVs";
-            let default_options = SelectOption {
-                key: Some("No Client Found".to_owned()),
-                value: 0,
-            };
-            // default_options
-            dbg!("Incoming Panic");
-        }
-
-        let client_options = client_result.unwrap();
-        let sov = SelectOptionsVec {
-            vec: client_options,
-        };
-        // Cache the query in Redis -- `query:hash_value => result_hash`
-        let val = serde_json::to_string(&sov).unwrap();
-        let _: RedisResult<bool> = con.set_ex(key, &val, 86400).await;
-
-        return sov;
-    }
+    select_options(simple_query, &[CacheTag::Client], 86400, state, r_state).await
 }
 
 #[derive(Debug, Serialize, FromRow, Deserialize)]
@@ -222,71 +150,34 @@ pub struct ClientDetailResult {
     pub territory_id: i32,
 }
 
-impl FromRedisValue for ClientDetailResult {
-    fn from_redis_value(v: &Value) -> RedisResult<Self> {
-        let v: String = from_redis_value(v)?;
-        let result: Self = match serde_json::from_str::<Self>(&v) {
-          Ok(v) => v,
-          Err(_err) => return Err((ErrorKind::TypeError, "Parse to JSON Failed").into())
-        };
-        Ok(result)
-    }
-}
-
 pub struct ClientDetails(i32, i32, i32);
 
 pub async fn get_client_details(
     client_id: i32,
     db: &Pool<Postgres>,
-    pool: &RedisPool,
+    r_state: &RedisState,
 ) -> Result<ClientDetails, String> {
     let simple_query = SimpleQuery {
         query_str: "SELECT client_type_id, specialty_id, territory_id FROM clients WHERE id = $1",
         int_args: Some(vec![client_id]),
         str_args: None,
     };
-    let query_hash = hash_query(&simple_query);
-    // FIXME: Search for key in Redis before reaching for DB
-    let mut con = pool.get().await.unwrap();
-    let prefix = "query";
-    let key = format!("{:?}:{:?}", prefix, query_hash);
-
-    let exists: Result<ClientDetailResult, RedisError> = con.get(&key).await;
-
-    dbg!(&exists);
-
-    if exists.is_ok() {
-        println!("Getting client_details from Redis");
-        let client_details = exists.unwrap();
-        return                 
-            Ok(ClientDetails(
-                client_details.client_type_id,
-                client_details.specialty_id,
-                client_details.territory_id,
-            ));
-    } else {
-        println!("Getting client_details from DB");
-        match sqlx::query_as::<_, ClientDetailResult>(simple_query.query_str)
-        .bind(client_id)
-        .fetch_optional(db)
-        .await
-        {
-            Ok(client_details_opt) => {
-                let client_details = client_details_opt.unwrap();
-                // Cache the query in Redis -- `query:hash_value => result_hash`
-                let val = serde_json::to_string(&client_details).unwrap();
-                let _: RedisResult<bool> = con.set_ex(key, &val, 86400).await;
-
-                Ok(ClientDetails(
-                    client_details.client_type_id,
-                    client_details.specialty_id,
-                    client_details.territory_id,
-                ))
-            }
-            Err(_) => Err("Error in Client Details".to_string()),
-        }
-    }
-    // ClientDetails(1,1,2)
+    let owned_query = OwnedQuery::from(&simple_query);
+    let client_details = cached(r_state, &owned_query, &[CacheTag::Client], 86400, || async move {
+        sqlx::query_as::<_, ClientDetailResult>(simple_query.query_str)
+            .bind(client_id)
+            .fetch_optional(db)
+            .await
+            .map_err(|_| "Error in Client Details".to_string())?
+            .ok_or_else(|| format!("Client #{} not found", client_id))
+    })
+    .await?;
+
+    Ok(ClientDetails(
+        client_details.client_type_id,
+        client_details.specialty_id,
+        client_details.territory_id,
+    ))
 }
 
 #[derive(Debug, Serialize, FromRow, Deserialize)]
@@ -294,8 +185,210 @@ pub struct ConsultResponse {
     id: i32,
 }
 
+#[derive(Debug, Serialize, Deserialize, FromRow, Clone)]
+pub struct PatchedConsult {
+    id: i32,
+    rescheduled: bool,
+    pending: bool,
+    prev_client_id: i32,
+    prev_location_id: i32,
+    prev_consultant_id: Option<i32>,
+}
+
 use crate::linfa::LinfaPredictionResult;
 
+// Start and end from the form's date/time inputs. No end date means an hour long consult.
+fn consult_window(body: &ConsultPost) -> (DateTime<FixedOffset>, DateTime<FixedOffset>) {
+    let consult_start_string =
+        body.consult_start_date.clone() + " " + &body.consult_start_time + ":00 -06:00";
+    let consult_start_dt =
+        DateTime::parse_from_str(&consult_start_string, "%Y-%m-%d %H:%M:%S %z").unwrap();
+    let consult_end_dt = if body.consult_end_date.is_empty() {
+        consult_start_dt + Duration::hours(1)
+    } else {
+        let consult_end_string = body.consult_end_date.clone() + " " + &body.consult_end_time + ":00 -06:00";
+        DateTime::parse_from_str(&consult_end_string, "%Y-%m-%d %H:%M:%S %z").unwrap()
+    };
+    (consult_start_dt, consult_end_dt)
+}
+
+// Why a booking didn't go through
+#[derive(Debug)]
+pub enum BookingError {
+    Invalid(ValidationErrors),
+    Unavailable(String),
+    Attachment(sqlx::Error),
+    Db(sqlx::Error),
+}
+
+#[derive(Debug, Clone)]
+pub struct BookedConsult {
+    pub id: i32,
+    pub consultant_id: i32,
+}
+
+// Linfa picks the consultant when the form asks it to. Anything going wrong falls back to the form's choice.
+async fn choose_consultant(
+    body: &ConsultPost,
+    start: DateTime<FixedOffset>,
+    end: DateTime<FixedOffset>,
+    db: &Pool<Postgres>,
+    r_state: &RedisState,
+) -> LinfaPredictionResult {
+    if body.linfa_assign.is_none() {
+        return LinfaPredictionResult("".to_string(), body.consultant_id);
+    }
+    let cd = match get_client_details(body.client_id, db, r_state).await {
+        Ok(cd) => cd,
+        Err(err) => {
+            dbg!(&err);
+            return LinfaPredictionResult("".to_string(), body.consultant_id);
+        }
+    };
+    let input = LinfaPredictionInput {
+        client_type: cd.0,
+        specialty_id: cd.1,
+        territory_id: cd.2,
+        meeting_duration: (end - start).num_minutes() as i32,
+        hour_of_day: start.naive_local().hour() as i32,
+        location_id: body.location_id,
+        client_id: body.client_id,
+        consult_purpose_id: body.consult_purpose_id,
+        notes_length: body.notes.chars().count() as i32,
+        // We are predicting for the optimal result, which is a follow up consult (1)
+        received_follow_up: 1,
+        num_attendees: body.num_attendees,
+    };
+    match assign_consultant(&input, start.with_timezone(&Utc), end.with_timezone(&Utc), db).await {
+        Ok(result) => result,
+        Err(err) => {
+            dbg!(&err);
+            LinfaPredictionResult("".to_string(), body.consultant_id)
+        }
+    }
+}
+
+// Every new consult goes through here, whichever form it came from: validation, Linfa assignment, the
+// availability and conflict checks, the attachment, then cache invalidation, notifications, the .ics and invites.
+// Pending consults (self-service requests) hold their slot but only get invites once staff confirm them.
+pub async fn book_consult(
+    body: &ConsultPost,
+    status: BookingStatus,
+    state: &AppState,
+    r_state: &RedisState,
+    broadcaster: &Broadcaster,
+) -> Result<BookedConsult, BookingError> {
+    body.validate().map_err(BookingError::Invalid)?;
+    let (consult_start_dt, consult_end_dt) = consult_window(body);
+    let LinfaPredictionResult(texfile, consultant_id) =
+        choose_consultant(body, consult_start_dt, consult_end_dt, &state.db, r_state).await;
+    if let Some(error_msg) = conflict_message(
+        &state.db,
+        consultant_id,
+        consult_start_dt.with_timezone(&Utc),
+        consult_end_dt.with_timezone(&Utc),
+        None,
+    )
+    .await
+    {
+        return Err(BookingError::Unavailable(error_msg));
+    }
+
+    // The attachment only sticks if the consult does
+    let mut tx = state.db.begin().await.map_err(BookingError::Db)?;
+    let attachment_path = body.attachment_path.as_deref().map(str::trim).filter(|path| !path.is_empty());
+    let consult_attachments = match attachment_path {
+        Some(path) => {
+            let attachment = sqlx::query_as::<_, AttachmentResponse>(
+                "INSERT INTO attachments (path, user_id, mime_type_id, channel, short_desc) VALUES ($1, $2, $3, $4, $5) RETURNING attachment_id",
+            )
+            .bind(path)
+            // FIXME
+            .bind(body.client_id)
+            .bind(mime_type_id_from_path(path))
+            .bind("upload")
+            .bind("Replace me with genuine desc")
+            .fetch_one(&mut *tx)
+            .await
+            .map_err(BookingError::Attachment)?;
+            Some(vec![attachment.attachment_id])
+        }
+        None => None,
+    };
+    let consult = sqlx::query_as::<_, ConsultResponse>(
+        "INSERT INTO consults (consult_purpose_id, consult_result_id, consultant_id, client_id, location_id, consult_start, consult_end, num_attendees, notes, consult_attachments, texfile, booking_status) VALUES ($1, $2, $3, $4, $5, $6, $7, $8, NULLIF($9, ''), $10, NULLIF($11, ''), $12) RETURNING id",
+    )
+    .bind(body.consult_purpose_id)
+    .bind(body.consult_result_id)
+    .bind(consultant_id)
+    .bind(body.client_id)
+    .bind(body.location_id)
+    .bind(consult_start_dt)
+    .bind(consult_end_dt)
+    .bind(body.num_attendees)
+    .bind(body.notes.clone())
+    .bind(consult_attachments)
+    .bind(texfile)
+    .bind(status.as_str())
+    .fetch_one(&mut *tx)
+    .await
+    .map_err(BookingError::Db)?;
+    tx.commit().await.map_err(BookingError::Db)?;
+
+    invalidate(r_state, &[CacheTag::Consult]).await;
+    refresh_for_consult(r_state, consult.id).await;
+    let summary = match status {
+        BookingStatus::Pending => format!("Consult #{} requested", consult.id),
+        _ => format!("Consult #{} booked", consult.id),
+    };
+    let targets = [(7, body.client_id), (5, body.location_id), (4, consultant_id)];
+    let notified = notify_subscribers(&state.db, &targets, &summary).await;
+    publish_live_event(r_state, broadcaster, LiveEvent::new(LiveEntity::Consult, consult.id, "created", summary).notify(notified)).await;
+    regenerate_ics(r_state, consult.id).await;
+    if status == BookingStatus::Confirmed {
+        send_invites(r_state, consult.id, ItipMethod::Request).await;
+    }
+    Ok(BookedConsult { id: consult.id, consultant_id })
+}
+
+// How the consult form shows the outcome of book_consult. Problems land in #consult_errors.
+pub fn booking_response(hb: &Handlebars<'_>, result: Result<BookedConsult, BookingError>) -> HttpResponse {
+    match result {
+        Ok(consult) => {
+            let user_alert = UserAlert::from((format!("Consult added successfully: ID #{:?}", consult.id).as_str(), "alert_success"));
+            let body = hb.render("crud-api-inner", &user_alert).unwrap();
+            HttpResponse::Ok().body(body)
+        }
+        Err(BookingError::Invalid(errors)) => {
+            let validation_response = get_validation_response(Err(errors));
+            let body = hb.render("forms/form-validation", &validation_response).unwrap();
+            HttpResponse::BadRequest()
+                .header("HX-Retarget", "#consult_errors")
+                .body(body)
+        }
+        Err(BookingError::Unavailable(error_msg)) => {
+            let validation_response = ValidationResponse::from((error_msg.as_str(), "validation_error"));
+            let body = hb.render("validation", &validation_response).unwrap();
+            HttpResponse::BadRequest()
+                .header("HX-Retarget", "#consult_errors")
+                .body(body)
+        }
+        Err(BookingError::Attachment(err)) => {
+            dbg!(&err);
+            let user_alert = UserAlert::from((format!("Error Adding the Attachment: {:?}", err).as_str(), "alert_error"));
+            let body = hb.render("crud-api", &user_alert).unwrap();
+            HttpResponse::Ok().body(body)
+        }
+        Err(BookingError::Db(err)) => {
+            dbg!(&err);
+            let error_msg = format!("Error occurred in (DB layer): {}.", err);
+            let validation_response = ValidationResponse::from((error_msg.as_str(), "validation_error"));
+            let body = hb.render("validation", &validation_response).unwrap();
+            HttpResponse::Ok().body(body)
+        }
+    }
+}
+
 #[post("/form")]
 async fn create_consult(
     body: web::Form<ConsultPost>,
@@ -303,163 +396,13 @@ async fn create_consult(
     req: HttpRequest,
     state: web::Data<AppState>,
     r_state: web::Data<RedisState>,
+    broadcaster: web::Data<Broadcaster>,
 ) -> impl Responder {
     if let Some(cookie) = req.headers().get(actix_web::http::header::COOKIE) {
         match redis_validate_and_get_user(cookie, &r_state).await {
-            Ok(user) => {
-                let is_valid = body.validate();
-                if is_valid.is_err() {
-                    let validation_response = get_validation_response(is_valid);
-                    // FIXME Copy how Clients does it
-                    let body = hb
-                        .render("forms/form-validation", &validation_response)
-                        .unwrap();
-                    return HttpResponse::BadRequest()
-                        .header("HX-Retarget", "#consult_errors")
-                        .body(body);
-                } else {
-                    // FIXME Make Optional
-                    dbg!(&body);
-                    let consult_start_string =
-                        body.consult_start_date.clone() + " " + &body.consult_start_time + ":00 -06:00";
-                    let consult_start_dt =
-                        DateTime::parse_from_str(&consult_start_string, "%Y-%m-%d %H:%M:%S %z").unwrap();
-                    // End date
-                    let consult_end_string =
-                        if body.consult_end_date.is_empty() {
-                            body.consult_end_date.clone()
-                        } else {
-                            body.consult_end_date.clone() + " " + &body.consult_end_time + ":00 -06:00"
-                        };
-                    let consult_end_dt =
-                        if body.consult_end_date.is_empty() {
-                            consult_start_dt + Duration::hours(1)
-                        } else {
-                            DateTime::parse_from_str(&consult_end_string, "%Y-%m-%d %H:%M:%S %z").unwrap()
-                        };
-                    let consult_start_datetime_utc = consult_start_dt.with_timezone(&Utc);
-                    // Compute consultant_id based on Linfa assign
-                    let linfa_pred_result = if body.linfa_assign.is_some() {
-                        let cd = get_client_details(body.client_id, &state.db, &r_state.r_pool).await.unwrap();
-                        let diff = consult_end_dt - consult_start_dt;
-                        let duration = diff.num_minutes() as i32;
-                        println!("Meeting duration is {}", &duration);
-                        // Build Linfa
-                        let input = LinfaPredictionInput {
-                            client_type: cd.0,
-                            specialty_id: cd.1,
-                            territory_id: cd.2,
-                            meeting_duration: duration,
-                            hour_of_day: consult_start_dt.naive_local().hour() as i32,
-                            location_id: body.location_id,
-                            client_id: body.client_id,
-                            consult_purpose_id: body.consult_purpose_id,
-                            notes_length: body.notes.chars().count() as i32,
-                            // We are predicting for the optimal result, which is a follow up consult (1)
-                            received_follow_up: 1,
-                            num_attendees: body.num_attendees,
-                        };
-                        println!("Linfa will decide");
-                        let result = linfa_pred(&input, &state.db).await;
-                        result
-                        // let id = result.1;
-                        // id
-                    } else {
-                        LinfaPredictionResult("".to_string(), body.consultant_id)
-                    };
-                    
-                    let computed_consultant_id = linfa_pred_result.1;
-                    let texfile = linfa_pred_result.0;
-                    // Get Current User
-                    if body.attachment_path.is_some() && !body.attachment_path.as_ref().unwrap().is_empty() {
-                        let mime_type_id = mime_type_id_from_path(&body.attachment_path.as_ref().unwrap());
-                        let channel = "upload".to_string();
-                        let short_desc = "Replace me with genuine desc".to_string();
-                        match sqlx::query_as::<_, AttachmentResponse>(
-                            "INSERT INTO attachments (path, user_id, mime_type_id, channel, short_desc) VALUES ($1, $2, $3, $4, $5) RETURNING attachment_id",
-                        )
-                        .bind(body.attachment_path.clone().unwrap().trim().to_string())
-                        // FIXME
-                        .bind(body.client_id)
-                        .bind(mime_type_id)
-                        .bind(channel)
-                        .bind(short_desc)
-                        .fetch_one(&state.db)
-                        .await
-                        {
-                            Ok(attachment_resp) => {
-                                let consult_attachments_array = vec![attachment_resp.attachment_id];
-                                match sqlx::query_as::<_, ConsultResponse>(
-                                    "INSERT INTO consults (consult_purpose_id, consult_result_id, consultant_id, client_id, location_id, consult_start, consult_end, num_attendees, notes, consult_attachments, texfile) VALUES ($1, $2, $3, $4, $5, $6, $7, $8, NULLIF($9, ''), $10, NULLIF($11, '')) RETURNING id",
-                                )
-                                .bind(body.consult_purpose_id as i32)
-                                .bind(body.consult_result_id)
-                                .bind(computed_consultant_id)
-                                .bind(body.client_id)
-                                .bind(body.location_id)
-                                .bind(consult_start_dt)
-                                .bind(consult_end_dt)
-                                .bind(body.num_attendees)
-                                .bind(body.notes.clone())
-                                .bind(consult_attachments_array)
-                                .bind(texfile)
-                                .fetch_one(&state.db)
-                                .await
-                                {
-                                    Ok(consult_resp) => {
-                                        let user_alert = UserAlert::from((format!("Consult added successfully: ID #{:?}", consult_resp.id).as_str(), "alert_success"));
-                                        let body = hb.render("crud-api-inner", &user_alert).unwrap();
-                                        return HttpResponse::Ok().body(body);
-                                    }
-                                    Err(err) => {
-                                        dbg!(&err);
-                                        let user_alert = UserAlert::from((format!("Error Updating User After Adding Them As Consult: {:?}", err).as_str(), "alert_error"));
-                                        let body = hb.render("crud-api", &user_alert).unwrap();
-                                        return HttpResponse::Ok().body(body);
-                                    }
-                                }
-                            }
-                            Err(err) => {
-                                dbg!(&err);
-                                let user_alert = UserAlert::from((format!("Error Adding the Attachment: {:?}", err).as_str(), "alert_error"));
-                                let body = hb.render("crud-api", &user_alert).unwrap();
-                                return HttpResponse::Ok().body(body);
-                            }
-                        }
-                    } else {
-                        // FIXME: If end_date null, just add an hour to start
-                        // NULLIF($2, 0) for Ints
-                        match sqlx::query_as::<_, ConsultResponse>(
-                            "INSERT INTO consults (consult_purpose_id, consult_result_id, consultant_id, client_id, location_id, consult_start, consult_end, num_attendees, notes, texfile) VALUES ($1, $2, $3, $4, $5, $6, $7, $8, NULLIF($9, ''), NULLIF($10, '')) RETURNING id",
-                        )
-                        .bind(body.consult_purpose_id as i32)
-                        .bind(body.consult_result_id)
-                        .bind(computed_consultant_id)
-                        .bind(body.client_id)
-                        .bind(body.location_id)
-                        .bind(consult_start_dt)
-                        .bind(consult_end_dt)
-                        .bind(body.num_attendees)
-                        .bind(body.notes.clone())
-                        .bind(texfile)
-                        .fetch_one(&state.db)
-                        .await
-                        {
-                            Ok(consult_resp) => {
-                                let user_alert = UserAlert::from((format!("Consult added successfully: ID #{:?}", consult_resp.id).as_str(), "alert_success"));
-                                let body = hb.render("crud-api-inner", &user_alert).unwrap();
-                                return HttpResponse::Ok().body(body);
-                            }
-                            Err(err) => {
-                                dbg!(&err);
-                                let error_msg = format!("Error occurred in (DB layer): {}.", err);
-                                let validation_response = ValidationResponse::from((error_msg.as_str(), "validation_error"));
-                                let body = hb.render("validation", &validation_response).unwrap();
-                                return HttpResponse::Ok().body(body);
-                            }
-                        }
-                    }
-                }
+            Ok(_user) => {
+                dbg!(&body);
+                booking_response(&hb, book_consult(&body, BookingStatus::Confirmed, &state, &r_state, &broadcaster).await)
             }
             Err(err) => {
                 dbg!(&err);
@@ -478,6 +421,163 @@ async fn create_consult(
     }
 }
 
+// Edits apply only if nobody saved the consult since the form was loaded, otherwise the editor gets a
+// diff against the saved version.
+#[patch("/form/{slug}")]
+async fn patch_consult(
+    body: web::Form<ConsultPost>,
+    hb: web::Data<Handlebars<'_>>,
+    req: HttpRequest,
+    state: web::Data<AppState>,
+    path: web::Path<String>,
+    r_state: web::Data<RedisState>,
+    broadcaster: web::Data<Broadcaster>,
+) -> impl Responder {
+    let cookie = match req.headers().get(actix_web::http::header::COOKIE) {
+        Some(cookie) => cookie,
+        None => {
+            let message = "Your session seems to have expired. Please login again.".to_owned();
+            let body = hb.render("index", &message).unwrap();
+            return HttpResponse::Ok().header("HX-Redirect", "/").body(body);
+        }
+    };
+    let user = match redis_validate_and_get_user(cookie, &r_state).await {
+        Ok(user) => user,
+        Err(err) => {
+            dbg!(&err);
+            let body = hb.render("index", &format!("{:?}", err)).unwrap();
+            return HttpResponse::Ok().header("HX-Redirect", "/").body(body);
+        }
+    };
+    let consult_slug = path.into_inner();
+    let is_valid = body.validate();
+    if is_valid.is_err() {
+        let validation_response = get_validation_response(is_valid);
+        let body = hb.render("forms/form-validation", &validation_response).unwrap();
+        return HttpResponse::BadRequest()
+            .header("HX-Retarget", "#consult_errors")
+            .body(body);
+    }
+
+    let (consult_start_dt, consult_end_dt) = consult_window(&body);
+    if let Some(response) = conflict_response(
+        &hb,
+        &state.db,
+        body.consultant_id,
+        consult_start_dt.with_timezone(&Utc),
+        consult_end_dt.with_timezone(&Utc),
+        Some(&consult_slug),
+    )
+    .await
+    {
+        return response;
+    }
+    // prev is the row as it was, to tell a reschedule (new time, place or consultant) from other edits
+    let updated = sqlx::query_as::<_, PatchedConsult>(
+        "UPDATE consults
+            SET consult_purpose_id = $1,
+                consult_result_id = $2,
+                consultant_id = $3,
+                client_id = $4,
+                location_id = $5,
+                consult_start = $6,
+                consult_end = $7,
+                num_attendees = $8,
+                notes = NULLIF($9, ''),
+                updated_at = NOW(),
+                ics_sequence = consults.ics_sequence + 1
+            FROM (SELECT id, consult_start, consult_end, client_id, location_id, consultant_id FROM consults WHERE slug = $10) AS prev
+            WHERE consults.id = prev.id
+            AND consults.cancelled_at IS NULL
+            AND consults.updated_at IS NOT DISTINCT FROM $11
+            RETURNING consults.id,
+                (prev.consult_start IS DISTINCT FROM consults.consult_start
+                    OR prev.consult_end IS DISTINCT FROM consults.consult_end
+                    OR prev.location_id IS DISTINCT FROM consults.location_id
+                    OR prev.consultant_id IS DISTINCT FROM consults.consultant_id) AS rescheduled,
+                consults.booking_status = 'pending' AS pending,
+                prev.client_id AS prev_client_id,
+                prev.location_id AS prev_location_id,
+                prev.consultant_id AS prev_consultant_id",
+    )
+    .bind(body.consult_purpose_id)
+    .bind(body.consult_result_id)
+    .bind(body.consultant_id)
+    .bind(body.client_id)
+    .bind(body.location_id)
+    .bind(consult_start_dt)
+    .bind(consult_end_dt)
+    .bind(body.num_attendees)
+    .bind(&body.notes)
+    .bind(&consult_slug)
+    .bind(body.updated_at)
+    .fetch_optional(&state.db)
+    .await;
+
+    match updated {
+        Ok(Some(consult_resp)) => {
+            leave(&r_state, LiveEntity::Consult, &consult_slug, &user.username).await;
+            invalidate(&r_state, &[CacheTag::Consult]).await;
+            let prev_refs = ConsultRefs {
+                client_id: consult_resp.prev_client_id,
+                location_id: consult_resp.prev_location_id,
+                consultant_id: consult_resp.prev_consultant_id,
+            };
+            let refs = ConsultRefs {
+                client_id: body.client_id,
+                location_id: body.location_id,
+                consultant_id: Some(body.consultant_id),
+            };
+            refresh_for_refs(&r_state, &[prev_refs, refs]).await;
+            let summary = format!("Consult #{} edited", consult_resp.id);
+            let targets = [(7, body.client_id), (5, body.location_id), (4, body.consultant_id), (6, consult_resp.id)];
+            let notified = notify_subscribers(&state.db, &targets, &summary).await;
+            publish_live_event(&r_state, &broadcaster, LiveEvent::new(LiveEntity::Consult, consult_resp.id, "updated", summary).notify(notified)).await;
+            regenerate_ics(&r_state, consult_resp.id).await;
+            // A pending request has no invite out yet, it gets one when it's confirmed
+            if consult_resp.rescheduled && !consult_resp.pending {
+                send_invites(&r_state, consult_resp.id, ItipMethod::Request).await;
+            }
+            let user_alert = UserAlert::from((format!("Consult edited successfully: ID #{:?}", consult_resp.id).as_str(), "alert_success"));
+            let full_page_data = FullPageTemplateData {
+                user_alert,
+                user: Some(user),
+            };
+            let body = hb.render("list-api", &full_page_data).unwrap();
+            HttpResponse::Ok().body(body)
+        }
+        // Stale version, or cancelled since the form was loaded
+        Ok(None) => match consult_by_slug(&state.db, &consult_slug).await {
+            Ok(current) => {
+                let current = ConsultWithDates::from(current);
+                let template_data = EditConflictTemplate {
+                    conflicts: edit_conflicts(&*body, &current),
+                    updated_at: current.updated_at,
+                };
+                let body = hb.render("forms/edit-conflict", &template_data).unwrap();
+                HttpResponse::Conflict()
+                    .header("HX-Retarget", "#consult_errors")
+                    .body(body)
+            }
+            Err(err) => {
+                dbg!(&err);
+                let validation_response = ValidationResponse::from(("Consult no longer exists", "validation_error"));
+                let body = hb.render("validation", &validation_response).unwrap();
+                HttpResponse::NotFound()
+                    .header("HX-Retarget", "#consult_errors")
+                    .body(body)
+            }
+        },
+        Err(err) => {
+            dbg!(&err);
+            let error_msg = format!("Error occurred in (DB layer): {}.", err);
+            let validation_response = ValidationResponse::from((error_msg.as_str(), "validation_error"));
+            let body = hb.render("validation", &validation_response).unwrap();
+            HttpResponse::Ok().body(body)
+        }
+    }
+}
+
 #[get("/form")]
 async fn consult_form(
     hb: web::Data<Handlebars<'_>>,
@@ -507,7 +607,7 @@ async fn consult_form(
 
 fn get_consult_date(dt: Option<DateTime<Utc>>) -> Option<String> {
     if let Some(date) = dt {
-        let end_dt_str = date.format("%Y-%m-%d %H:%M:%S.%f").to_string();
+        let end_dt_str = date.with_timezone(&consult_offset()).format("%Y-%m-%d %H:%M:%S.%f").to_string();
         let end_date = end_dt_str.split(" ").collect::<Vec<&str>>();
         Some(end_date[0].to_string())
     } else {
@@ -517,7 +617,7 @@ fn get_consult_date(dt: Option<DateTime<Utc>>) -> Option<String> {
 
 fn get_consult_time(dt: Option<DateTime<Utc>>) -> Option<String> {
     if let Some(date) = dt {
-        let end_dt_str = date.format("%Y-%m-%d %H:%M:%S.%f").to_string();
+        let end_dt_str = date.with_timezone(&consult_offset()).format("%Y-%m-%d %H:%M:%S.%f").to_string();
         let end_date = end_dt_str.split(" ").collect::<Vec<&str>>();
         let end_date_str = end_date[1].to_string();
         let time_extract = end_date_str.split(":").collect::<Vec<&str>>();
@@ -532,6 +632,37 @@ fn get_consult_time(dt: Option<DateTime<Utc>>) -> Option<String> {
     }
 }
 
+// Cancelled consults can't be edited, so to the form they no longer exist
+pub async fn consult_by_slug(db: &Pool<Postgres>, slug: &str) -> Result<ConsultFormRequest, sqlx::Error> {
+    sqlx::query_as::<_, ConsultFormRequest>(
+        "SELECT consultant_id, slug, consult_purpose_id, location_id, client_id, consult_result_id, consult_start, consult_end, notes, updated_at
+            FROM consults 
+            WHERE slug = $1 AND cancelled_at IS NULL",
+    )
+    .bind(slug)
+    .fetch_one(db)
+    .await
+}
+
+impl From<ConsultFormRequest> for ConsultWithDates {
+    fn from(consult: ConsultFormRequest) -> Self {
+        ConsultWithDates {
+            notes: consult.notes,
+            slug: consult.slug,
+            consult_purpose_id: consult.consult_purpose_id,
+            location_id: consult.location_id,
+            consultant_id: consult.consultant_id,
+            client_id: consult.client_id,
+            consult_result_id: consult.consult_result_id,
+            consult_start_date: get_consult_date(consult.consult_start),
+            consult_start_time: get_consult_time(consult.consult_start),
+            consult_end_date: get_consult_date(consult.consult_end),
+            consult_end_time: get_consult_time(consult.consult_end),
+            updated_at: consult.updated_at,
+        }
+    }
+}
+
 #[get("/form/{slug}")]
 async fn consult_edit_form(
     hb: web::Data<Handlebars<'_>>,
@@ -542,16 +673,7 @@ async fn consult_edit_form(
     println!("consults_form firing");
     let consult_slug = path.into_inner();
 
-    let query_result = sqlx::query_as!(
-        ConsultFormRequest,
-        "SELECT consultant_id, slug, consult_purpose_id, location_id, client_id, consult_result_id, consult_start, consult_end, notes 
-            FROM consults 
-            WHERE slug = $1
-            ORDER by consult_start",
-        consult_slug
-    )
-    .fetch_one(&state.db)
-    .await;
+    let query_result = consult_by_slug(&state.db, &consult_slug).await;
 
     dbg!(&query_result);
 
@@ -562,21 +684,7 @@ async fn consult_edit_form(
         return HttpResponse::Ok().body(body);
     }
 
-    let consult = query_result.unwrap();
-
-    let consult_with_dates = ConsultWithDates {
-        notes: consult.notes,
-        slug: consult.slug,
-        consult_purpose_id: consult.consult_purpose_id,
-        location_id: consult.location_id,
-        consultant_id: consult.consultant_id,
-        client_id: consult.client_id,
-        consult_result_id: consult.consult_result_id,
-        consult_start_date: get_consult_date(consult.consult_start),
-        consult_start_time: get_consult_time(consult.consult_start),
-        consult_end_date: get_consult_date(consult.consult_end),
-        consult_end_time: get_consult_time(consult.consult_end),
-    };
+    let consult_with_dates = ConsultWithDates::from(query_result.unwrap());
 
     let location_options = location_options(&state, &r_state).await;
     let consultant_options = consultant_options(&state, &r_state).await;
@@ -603,6 +711,16 @@ pub struct OwnedQuery {
     str_args: Vec<String>,
 }
 
+impl From<&SimpleQuery> for OwnedQuery {
+    fn from(query: &SimpleQuery) -> Self {
+        OwnedQuery {
+            query_str: query.query_str.to_owned(),
+            int_args: query.int_args.clone().unwrap_or_default(),
+            str_args: query.str_args.clone().unwrap_or_default(),
+        }
+    }
+}
+
 impl std::hash::Hash for OwnedQuery {
     fn hash<H: std::hash::Hasher>(&self, state: &mut H) {
         self.query_str.hash(state);
@@ -639,7 +757,7 @@ fn get_str_args(opts: &FilterOptions) -> Vec<String> {
 async fn sort_query(
     opts: &FilterOptions,
     pool: &Pool<Postgres>,
-    r_pool: &RedisPool,
+    r_state: &RedisState,
 ) -> Result<ConsultListVec, Error> {
     let limit = opts.limit.unwrap_or(10);
     let offset = (opts.page.unwrap_or(1) - 1) * limit;
@@ -693,49 +811,19 @@ async fn sort_query(
     dbg!(&owned_query.int_args);
     dbg!(&owned_query.str_args);
 
-    let query_hash = hash_owned_query(&owned_query);
-    println!("query hash = {}", query_hash);
-    // Search for key in Redis before reaching for DB
-    let mut con = r_pool.get().await.unwrap();
-    let prefix = "query";
-    let key = format!("{}:{}", prefix, query_hash);
-
-    let exists: Result<ConsultListVec, RedisError> = con.get(&key).await;
-    dbg!(&exists);
-
-    if exists.is_ok() {
-        println!("Getting consultant_options from Redis");
-        let result = exists.unwrap();
-        return Ok(result);
-    } else {
-        println!("Getting consultant_options from DB");
-
-        let q_build = query.build();
-        let res = q_build.fetch_all(pool).await;
-
-        // This almost got me there. Error on .as_str() for consult_start column
-        // let consults = res.unwrap().iter().map(|row| row_to_consult_list(row)).collect::<Vec<ConsultList>>();
-
-        let consults = res
-            .unwrap()
+    // The list joins clients, locations and consultants, a write to any of them changes it
+    let tags = [CacheTag::Consult, CacheTag::Client, CacheTag::Location, CacheTag::Consultant];
+    cached(r_state, &owned_query, &tags, 120, || async move {
+        let consults = query
+            .build()
+            .fetch_all(pool)
+            .await?
             .iter()
-            .map(|row| ConsultList::from_row(row).unwrap())
-            .collect::<Vec<ConsultList>>();
-
-        let clv = ConsultListVec {
-            vec: consults,
-        };
-        // Cache the query in Redis -- `query:hash_value => result_hash`
-        let val = serde_json::to_string(&clv).unwrap();
-        let _: RedisResult<bool> = con.set_ex(key, &val, 120).await;
-
-        return Ok(clv);
-    }
-
-    // let query_str = query.build().sql().into();
-    // dbg!(&query_str);
-    // query_str
-    // res
+            .map(|row| ConsultList::from_row(row))
+            .collect::<Result<Vec<ConsultList>, Error>>()?;
+        Ok::<ConsultListVec, Error>(ConsultListVec { vec: consults })
+    })
+    .await
 }
 
 // Had to remove conflicting FromRow in the derive list
@@ -783,7 +871,7 @@ pub async fn get_consults_handler(
                 let offset = (opts.page.unwrap_or(1) - 1) * limit;
 
                 // QueryBuilder gets the query correct but end up w/ Vec<PgRow>. Need to get to Vec<Consult> or impl Serialize for PgRow?
-                let query_result = sort_query(&opts, &state.db, &r_state.r_pool).await;
+                let query_result = sort_query(&opts, &state.db, &r_state).await;
 
                 dbg!(&query_result);
 
@@ -888,6 +976,219 @@ async fn get_attachments(
     return HttpResponse::Ok().body(body);
 }
 
+#[derive(Debug, Serialize, FromRow, Deserialize)]
+pub struct ConsultDetail {
+    pub slug: String,
+    pub consult_start: DateTime<Utc>,
+    pub consult_end: Option<DateTime<Utc>>,
+    pub notes: Option<String>,
+    pub texfile: Option<String>,
+    pub cancelled_at: Option<DateTime<Utc>>,
+    pub consultant_name: Option<String>,
+    pub client_name: Option<String>,
+    pub location_name: String,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct ConsultDetailData {
+    pub consult: ConsultDetail,
+    pub consult_start_date: Option<String>,
+    pub consult_start_time: Option<String>,
+    pub consult_end_time: Option<String>,
+}
+
+async fn render_consult_detail(hb: &Handlebars<'_>, db: &Pool<Postgres>, consult_slug: &str) -> HttpResponse {
+    let query_result = sqlx::query_as::<_, ConsultDetail>(
+        "SELECT consults.slug, consult_start, consult_end, notes, texfile, cancelled_at,
+                consultant_f_name || ' ' || consultant_l_name AS consultant_name,
+                COALESCE(client_company_name, client_f_name || ' ' || client_l_name) AS client_name,
+                location_name
+            FROM consults
+            LEFT JOIN consultants ON consultants.id = consults.consultant_id
+            INNER JOIN clients ON clients.id = consults.client_id
+            INNER JOIN locations ON locations.id = consults.location_id
+            WHERE consults.slug = $1",
+    )
+    .bind(consult_slug)
+    .fetch_one(db)
+    .await;
+
+    if query_result.is_err() {
+        let error_msg = "Error occurred while fetching consult";
+        let validation_response = ValidationResponse::from((error_msg, "validation_error"));
+        let body = hb.render("validation", &validation_response).unwrap();
+        return HttpResponse::Ok().body(body);
+    }
+
+    let consult = query_result.unwrap();
+    let view_data = ConsultDetailData {
+        consult_start_date: get_consult_date(Some(consult.consult_start)),
+        consult_start_time: get_consult_time(Some(consult.consult_start)),
+        consult_end_time: get_consult_time(consult.consult_end),
+        consult: consult,
+    };
+
+    let body = hb.render("consult-detail", &view_data).unwrap();
+    return HttpResponse::Ok().body(body);
+}
+
+#[get("/{slug}")]
+async fn consult_detail(
+    hb: web::Data<Handlebars<'_>>,
+    state: web::Data<AppState>,
+    path: web::Path<String>,
+) -> impl Responder {
+    render_consult_detail(&hb, &state.db, &path.into_inner()).await
+}
+
+// Refuses an edit the consultant can't take, as an error on the consult form.
+// A failed lookup doesn't block the edit.
+async fn conflict_response(
+    hb: &Handlebars<'_>,
+    db: &Pool<Postgres>,
+    consultant_id: i32,
+    start: DateTime<Utc>,
+    end: DateTime<Utc>,
+    ignore_slug: Option<&str>,
+) -> Option<HttpResponse> {
+    let error_msg = conflict_message(db, consultant_id, start, end, ignore_slug).await?;
+    let validation_response = ValidationResponse::from((error_msg.as_str(), "validation_error"));
+    let body = hb.render("validation", &validation_response).unwrap();
+    Some(
+        HttpResponse::BadRequest()
+            .header("HX-Retarget", "#consult_errors")
+            .body(body),
+    )
+}
+
+// Why the consultant can't take [start, end): off, outside their hours, or already booked
+pub async fn conflict_message(
+    db: &Pool<Postgres>,
+    consultant_id: i32,
+    start: DateTime<Utc>,
+    end: DateTime<Utc>,
+    ignore_slug: Option<&str>,
+) -> Option<String> {
+    if consultant_id < 1 {
+        return None;
+    }
+    if let Some(reason) = unavailable_reason(db, consultant_id, start, end).await {
+        return Some(reason);
+    }
+    let conflicts = match consultant_conflicts(db, consultant_id, start, end, ignore_slug).await {
+        Ok(conflicts) => conflicts,
+        Err(err) => {
+            println!("Error checking conflicts for consultant {}: {}", consultant_id, err);
+            return None;
+        }
+    };
+    let first = conflicts.first()?;
+    Some(format!(
+        "Consultant is busy {} - {}{}",
+        first.busy_start.with_timezone(&consult_offset()).format("%Y-%m-%d %H:%M"),
+        first.busy_end.with_timezone(&consult_offset()).format("%H:%M"),
+        if conflicts.len() > 1 { format!(" and {} more time(s)", conflicts.len() - 1) } else { String::new() },
+    ))
+}
+
+// Working hours and approved time off. Like conflicts, a failed lookup doesn't block the save.
+pub async fn unavailable_reason(
+    db: &Pool<Postgres>,
+    consultant_id: i32,
+    start: DateTime<Utc>,
+    end: DateTime<Utc>,
+) -> Option<String> {
+    match load_availability(db, start, end).await {
+        Ok(availability) => availability.unavailable_reason(consultant_id, start, end),
+        Err(err) => {
+            println!("Error loading availability for consultant {}: {}", consultant_id, err);
+            None
+        }
+    }
+}
+
+// Keeps the written .ics in step with the row. The feeds and downloads read the row directly.
+pub async fn regenerate_ics(r_state: &RedisState, consult_id: i32) {
+    if let Err(err) = enqueue(&r_state.r_pool, Job::GenerateIcs { consult_id }).await {
+        println!("Error queueing ICS for consult {}: {}", consult_id, err);
+    }
+}
+
+// Emails the client and consultant an invite (or its cancellation) with the consult's current SEQUENCE
+pub async fn send_invites(r_state: &RedisState, consult_id: i32, method: ItipMethod) {
+    if let Err(err) = enqueue(&r_state.r_pool, Job::SendInvites { consult_id, method }).await {
+        println!("Error queueing invites for consult {}: {}", consult_id, err);
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize, FromRow, Clone)]
+pub struct CancelledConsult {
+    id: i32,
+    client_id: i32,
+    location_id: i32,
+    consultant_id: Option<i32>,
+    pending: bool,
+}
+
+// The row stays so calendar feeds can tell subscribers it's cancelled.
+#[post("/form/{slug}/cancel")]
+async fn cancel_consult(
+    hb: web::Data<Handlebars<'_>>,
+    req: HttpRequest,
+    state: web::Data<AppState>,
+    path: web::Path<String>,
+    r_state: web::Data<RedisState>,
+    broadcaster: web::Data<Broadcaster>,
+) -> impl Responder {
+    let cookie = match req.headers().get(actix_web::http::header::COOKIE) {
+        Some(cookie) => cookie,
+        None => return HttpResponse::Unauthorized().finish(),
+    };
+    if let Err(err) = redis_validate_and_get_user(cookie, &r_state).await {
+        dbg!(&err);
+        return HttpResponse::Unauthorized().finish();
+    }
+    let consult_slug = path.into_inner();
+    let cancelled = sqlx::query_as::<_, CancelledConsult>(
+        "UPDATE consults
+            SET cancelled_at = NOW(),
+                updated_at = NOW(),
+                ics_sequence = ics_sequence + 1
+            WHERE slug = $1 AND cancelled_at IS NULL
+            RETURNING id, client_id, location_id, consultant_id, booking_status = 'pending' AS pending",
+    )
+    .bind(&consult_slug)
+    .fetch_optional(&state.db)
+    .await;
+
+    match cancelled {
+        Ok(Some(consult)) => {
+            invalidate(&r_state, &[CacheTag::Consult]).await;
+            refresh_for_consult(&r_state, consult.id).await;
+            let summary = format!("Consult #{} cancelled", consult.id);
+            let mut targets = vec![(7, consult.client_id), (5, consult.location_id), (6, consult.id)];
+            if let Some(consultant_id) = consult.consultant_id {
+                targets.push((4, consultant_id));
+            }
+            let notified = notify_subscribers(&state.db, &targets, &summary).await;
+            publish_live_event(&r_state, &broadcaster, LiveEvent::new(LiveEntity::Consult, consult.id, "cancelled", summary).notify(notified)).await;
+            regenerate_ics(&r_state, consult.id).await;
+            if !consult.pending {
+                send_invites(&r_state, consult.id, ItipMethod::Cancel).await;
+            }
+        }
+        // Already cancelled, the detail below shows it
+        Ok(None) => {}
+        Err(err) => {
+            dbg!(&err);
+            let validation_response = ValidationResponse::from(("Error cancelling the consult", "validation_error"));
+            let body = hb.render("validation", &validation_response).unwrap();
+            return HttpResponse::Ok().body(body);
+        }
+    }
+    render_consult_detail(&hb, &state.db, &consult_slug).await
+}
+
 #[derive(Debug, Serialize, FromRow, Deserialize)]
 pub struct ConsultAvailability {
     scheduled: String,
@@ -906,6 +1207,89 @@ async fn availability(hb: web::Data<Handlebars<'_>>, state: web::Data<AppState>)
     return HttpResponse::Ok().body(body);
 }
 
+#[derive(Debug, Serialize, Deserialize)]
+pub struct SuggestedSlotsQuery {
+    pub client_id: Option<i32>,
+    pub consult_purpose_id: Option<i32>,
+    pub location_id: Option<i32>,
+    pub consultant_id: Option<i32>,
+    pub num_attendees: Option<i32>,
+    pub consult_start_date: Option<String>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct SuggestedSlotsData {
+    pub message: Option<String>,
+    pub slots: Vec<SuggestedSlot>,
+}
+
+// Suggested start times on /consult/form. Fires whenever client, purpose, location, consultant or date change.
+#[get("/suggested-slots")]
+async fn suggested_slots_handler(
+    query: web::Query<SuggestedSlotsQuery>,
+    hb: web::Data<Handlebars<'_>>,
+    state: web::Data<AppState>,
+    r_state: web::Data<RedisState>,
+) -> impl Responder {
+    let date = query
+        .consult_start_date
+        .as_ref()
+        .and_then(|d| chrono::NaiveDate::parse_from_str(d, "%Y-%m-%d").ok());
+    let (client_id, date) = match (query.client_id, date) {
+        (Some(client_id), Some(date)) if client_id > 0 => (client_id, date),
+        _ => {
+            let data = SuggestedSlotsData {
+                message: Some("Select a client and a date to see suggested times.".to_string()),
+                slots: vec![],
+            };
+            let body = hb.render("consult-suggested-slots", &data).unwrap();
+            return HttpResponse::Ok().body(body);
+        }
+    };
+
+    let slots = match (
+        get_client_details(client_id, &state.db, &r_state).await,
+        load_assignment_data(&state.db).await,
+        load_busy_blocks(&state.db, date).await,
+    ) {
+        (Ok(cd), Ok(assignment_data), Ok(busy)) => {
+            let input = MeetingTimeInput {
+                consult_purpose_id: query.consult_purpose_id.unwrap_or(1),
+                client_type: cd.0,
+                specialty_id: cd.1,
+                territory_id: cd.2,
+                location_id: query.location_id.unwrap_or(1),
+                num_attendees: query.num_attendees.unwrap_or(1),
+            };
+            // A chosen consultant must be free. Otherwise (Linfa assign) any consultant will do.
+            let consultant_ids = match query.consultant_id {
+                Some(id) if id > 0 => vec![id],
+                _ => assignment_data.consultants.iter().map(|c| c.id).collect(),
+            };
+            suggest_meeting_time(&assignment_data.history, &input, Utc::now())
+                .map(|suggestion| suggested_slots(date, &suggestion, &busy, &consultant_ids, 5))
+        }
+        (cd, assignment_data, busy) => {
+            dbg!(cd.err(), assignment_data.err(), busy.err());
+            None
+        }
+    };
+
+    let data = match slots {
+        Some(slots) if !slots.is_empty() => SuggestedSlotsData { message: None, slots },
+        Some(_) => SuggestedSlotsData {
+            message: Some("No open slots on that date.".to_string()),
+            slots: vec![],
+        },
+        None => SuggestedSlotsData {
+            message: Some("Not enough history to suggest a time yet.".to_string()),
+            slots: vec![],
+        },
+    };
+    let body = hb.render("consult-suggested-slots", &data).unwrap();
+    HttpResponse::Ok().body(body)
+}
+
 fn read_file_buffer(filepath: &str, new_filepath: &str) -> Result<(), Box<dyn std::error::Error>> {
     const BUFFER_LEN: usize = 512;
     let mut buffer = [0u8; BUFFER_LEN];
@@ -927,7 +1311,12 @@ async fn upload(
     mut payload: Multipart,
     hb: web::Data<Handlebars<'_>>,
     req: HttpRequest,
+    r_state: web::Data<RedisState>,
 ) -> HttpResponse {
+    let user = session_key(&req, &r_state).await;
+    if let Some(resp) = rate_limited(&req, &r_state, &hb, &UPLOAD, user.as_deref()).await {
+        return resp;
+    }
     let max_file_size: usize = 20_000;
     let max_file_count: usize = 3;
     let legal_file_types: [Mime; 6] = [
@@ -1049,9 +1438,10 @@ Tests
 mod tests {
     use super::*;
     use crate::{
-        hbs_helpers::{concat_str_args, int_eq},
+        hbs_helpers::{concat_str_args, int_eq, to_title_case},
         test_common::{self, *},
     };
+    use chrono::TimeZone;
     use test_context::test_context;
 
     fn mock_locations() -> Vec<SelectOption> {
@@ -1123,6 +1513,7 @@ mod tests {
             consult_start_time: Some("14:30".to_string()),
             consult_end_date: Some("2023-09-10".to_string()),
             consult_end_time: Some("15:30".to_string()),
+            updated_at: None,
         };
         let template_data = ConsultFormTemplate {
             entity: Some(mock_consult_with_dates),
@@ -1151,4 +1542,48 @@ mod tests {
         assert_eq!(element.inner_text(parser), "Edit Consult");
         // assert_eq!(1, 1);
     }
+
+    #[test]
+    fn booking_problems_go_to_the_consult_form() {
+        let mut hb = Handlebars::new();
+        hb.register_templates_directory(".hbs", "./templates")
+            .unwrap();
+        hb.register_helper("to_title_case", Box::new(to_title_case));
+        let post = ConsultPost {
+            consult_purpose_id: 9,
+            client_id: 1,
+            consultant_id: 1,
+            location_id: 1,
+            attachment_path: None,
+            linfa_assign: None,
+            num_attendees: 1,
+            consult_result_id: 1,
+            consult_start_date: "2023-11-20".to_string(),
+            consult_start_time: "09:00".to_string(),
+            consult_end_date: "2023-11-20".to_string(),
+            consult_end_time: "10:00".to_string(),
+            notes: "".to_string(),
+            updated_at: None,
+        };
+        let invalid = booking_response(&hb, Err(BookingError::Invalid(post.validate().unwrap_err())));
+        assert_eq!(invalid.status(), 400);
+        assert_eq!(invalid.headers().get("HX-Retarget").unwrap(), "#consult_errors");
+
+        let busy = booking_response(&hb, Err(BookingError::Unavailable("Consultant doesn't work Sundays".to_string())));
+        assert_eq!(busy.status(), 400);
+        assert_eq!(busy.headers().get("HX-Retarget").unwrap(), "#consult_errors");
+
+        let booked = booking_response(&hb, Ok(BookedConsult { id: 7, consultant_id: 1 }));
+        assert_eq!(booked.status(), 200);
+        assert!(booked.headers().get("HX-Retarget").is_none());
+    }
+
+    #[test]
+    fn edit_form_prefills_the_local_time_it_was_booked_in() {
+        // 23:30 local is already the next day in UTC
+        let start = consult_offset().with_ymd_and_hms(2023, 11, 20, 23, 30, 0).unwrap().with_timezone(&Utc);
+        assert_eq!(get_consult_date(Some(start)), Some("2023-11-20".to_string()));
+        assert_eq!(get_consult_time(Some(start)), Some("23:30".to_string()));
+        assert_eq!(get_consult_date(None), None);
+    }
 }
@@ -1,4 +1,4 @@
-use std::{fs, ops::Deref};
+use std::{collections::HashMap, fs, ops::Deref};
 
 use actix_multipart::{
     form::{tempfile::TempFile, MultipartForm},
@@ -6,16 +6,18 @@ use actix_multipart::{
 };
 use actix_web::{
     get,
-    http::{header::CONTENT_LENGTH, Error},
+    http::{
+        header::{CONTENT_DISPOSITION, CONTENT_LENGTH, CONTENT_TYPE},
+        Error,
+    },
     post,
     web::{self, Data},
     HttpRequest, HttpResponse, Responder, Scope,
 };
+use chrono::{Datelike, Duration, NaiveDate, Utc};
 use futures_util::TryStreamExt;
 use handlebars::Handlebars;
-use image::{imageops::FilterType, DynamicImage};
-use mime::{Mime, IMAGE_GIF, IMAGE_JPEG, IMAGE_PNG};
-use redis::{RedisResult, AsyncCommands};
+use mime::{Mime, APPLICATION_PDF, IMAGE_GIF, IMAGE_JPEG, IMAGE_PNG};
 use serde::{Deserialize, Serialize};
 use sqlx::{postgres::PgRow, FromRow, Pool, Postgres, QueryBuilder, Row};
 use validator::Validate;
@@ -23,6 +25,13 @@ use std::io::Write;
 use uuid::Uuid;
 
 use crate::{
+    calendar::{
+        agenda::{agenda_consultant, agenda_consults, agenda_days, agenda_pdf, agenda_range, agenda_title, AgendaDay},
+        availability::{
+            request_time_off, set_working_hours, time_off_for, week_from_form, weekday_name, working_hours_for, TimeOff,
+        },
+        import::{busy_intervals, busy_sources, clear_busy_source, replace_busy_blocks, BusySource},
+    },
     config::{
         specialty_options, territory_options, test_subs, FilterOptions, ResponsiveTableData,
         SelectOption, UserAlert, ValidationResponse, redis_validate_and_get_user, ValidationErrorMap, FormErrorResponse,
@@ -31,6 +40,13 @@ use crate::{
         ConsultantFormRequest, ConsultantFormTemplate, ConsultantPostRequest,
         ConsultantPostResponse, ResponseConsultant,
     },
+    jobs::{queue::enqueue, Job},
+    linfa::consult_offset,
+    redis_mod::{
+        query_cache::{invalidate, CacheTag},
+        rate_limit::{rate_limited, session_key, UPLOAD},
+    },
+    scopes::notification::{current_user, current_user_id, is_admin},
     AppState, RedisState,
 };
 
@@ -42,6 +58,15 @@ pub fn consultant_scope() -> Scope {
         .service(get_consultants_handler)
         .service(create_consultant)
         .service(upload)
+        .service(busy_calendars)
+        .service(import_busy_calendar)
+        .service(clear_busy_calendar)
+        .service(availability)
+        .service(save_working_hours)
+        .service(create_time_off)
+        // Before the page so the extension isn't read as part of the slug
+        .service(agenda_pdf_download)
+        .service(agenda_page)
 }
 #[derive(Serialize, Deserialize, Debug, Default, Clone)]
 pub struct ResponsiveConsultantData {
@@ -418,19 +443,7 @@ async fn create_consultant(
                     {
                         Ok(consultant_response) => {
                             dbg!(&consultant_response.user_id);
-                            // Del / Invalidate Redis Key to force a DB fetch
-                            let mut con = r_state.r_pool.get().await.unwrap();
-                            let key = format!("{}:{}", "query", "consultant_options");
-                            let deleted: RedisResult<bool> = con.del(&key).await;
-                            match deleted {
-                                Ok(true) => {
-                                    println!("Key deleted");
-                                },
-                                Ok(false) => {
-                                    println!("Key not found {}", &key);
-                                },
-                                Err(err) => println!("Error: {}", err)
-                            }
+                            invalidate(&r_state, &[CacheTag::Consultant]).await;
                             match sqlx::query_as::<_, ConsultantPostResponse>(
                                 "UPDATE users SET user_type_id = 2, updated_at = now() WHERE id = $1 RETURNING id AS user_id",
                             )
@@ -502,7 +515,12 @@ async fn upload(
     mut payload: Multipart,
     hb: web::Data<Handlebars<'_>>,
     req: HttpRequest,
+    r_state: web::Data<RedisState>,
 ) -> HttpResponse {
+    let user = session_key(&req, &r_state).await;
+    if let Some(resp) = rate_limited(&req, &r_state, &hb, &UPLOAD, user.as_deref()).await {
+        return resp;
+    }
     let max_file_size: usize = 20_000;
     let max_file_count: usize = 3;
     let legal_file_types: [Mime; 3] = [IMAGE_GIF, IMAGE_JPEG, IMAGE_PNG];
@@ -575,19 +593,22 @@ async fn upload(
 
             filenames.push(to_save);
 
-            web::block(move || async move {
-                let updated_img: DynamicImage = image::open(&destination).unwrap();
-                let _ = fs::remove_file(&destination).unwrap();
-                let filename = format!("{}{}.png", dir, const_uuid);
-
-                updated_img
-                    .resize_exact(200, 200, FilterType::Nearest)
-                    .save(filename)
-                    .unwrap();
-            })
-            .await
-            .unwrap()
-            .await;
+            // The worker writes the 200x200 png to `filename` and removes the original upload
+            let job = Job::ResizeImage {
+                source: destination,
+                destination: filename,
+                width: 200,
+                height: 200,
+            };
+            if let Err(err) = enqueue(&r_state.r_pool, job).await {
+                println!("Error queueing image resize: {}", err);
+                let error_msg = "Unable to process upload. Please try again shortly.";
+                let validation_response = ValidationResponse::from((error_msg, "validation_error"));
+                let body = hb.render("validation", &validation_response).unwrap();
+                return HttpResponse::BadRequest()
+                    .header("HX-Retarget", "#validation_response")
+                    .body(body);
+            }
         } else {
             break;
         }
@@ -601,6 +622,450 @@ async fn upload(
     return HttpResponse::Ok().body(body);
 }
 
+// Outside calendars are imported for a year ahead, plus a day back for events in progress.
+const BUSY_PAST_DAYS: i64 = 1;
+const BUSY_FUTURE_DAYS: i64 = 365;
+const MAX_ICS_SIZE: usize = 2_000_000;
+
+#[derive(Serialize, Deserialize, Debug, Default, Clone)]
+pub struct BusyCalendarsTemplate {
+    slug: String,
+    sources: Vec<BusySource>,
+    message: Option<String>,
+    skipped: Vec<String>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct BusySourcePost {
+    source: String,
+}
+
+async fn consultant_id_by_slug(db: &Pool<Postgres>, slug: &str) -> Result<i32, sqlx::Error> {
+    sqlx::query("SELECT id FROM consultants WHERE slug = $1")
+        .bind(slug)
+        .fetch_one(db)
+        .await
+        .and_then(|row| row.try_get("id"))
+}
+
+// Admins can manage any consultant's calendars and hours, everyone else only their own
+async fn can_manage_consultant(
+    req: &HttpRequest,
+    state: &web::Data<AppState>,
+    r_state: &web::Data<RedisState>,
+    consultant_id: i32,
+) -> bool {
+    match current_user(req, state, r_state).await {
+        Some((_, user_type_id)) if is_admin(user_type_id) => true,
+        Some((user_id, _)) => sqlx::query_scalar::<_, i32>("SELECT user_id FROM consultants WHERE id = $1")
+            .bind(consultant_id)
+            .fetch_optional(&state.db)
+            .await
+            .ok()
+            .flatten()
+            .is_some_and(|owner_id| owner_id == user_id),
+        None => false,
+    }
+}
+
+fn busy_error(hb: &Handlebars<'_>, error_msg: &str) -> HttpResponse {
+    let validation_response = ValidationResponse::from((error_msg, "validation_error"));
+    let body = hb.render("validation", &validation_response).unwrap();
+    HttpResponse::BadRequest()
+        .header("HX-Retarget", "#consultant_errors")
+        .body(body)
+}
+
+async fn render_busy_calendars(
+    hb: &Handlebars<'_>,
+    db: &Pool<Postgres>,
+    slug: String,
+    consultant_id: i32,
+    message: Option<String>,
+    skipped: Vec<String>,
+) -> HttpResponse {
+    let sources = busy_sources(db, consultant_id).await.unwrap_or_else(|err| {
+        println!("Error loading busy calendars: {}", err);
+        vec![]
+    });
+    let template_data = BusyCalendarsTemplate { slug, sources, message, skipped };
+    let body = hb.render("calendar/busy", &template_data).unwrap();
+    HttpResponse::Ok().body(body)
+}
+
+#[get("/busy/{slug}")]
+async fn busy_calendars(
+    hb: web::Data<Handlebars<'_>>,
+    req: HttpRequest,
+    state: web::Data<AppState>,
+    r_state: web::Data<RedisState>,
+    path: web::Path<String>,
+) -> impl Responder {
+    if current_user_id(&req, &state, &r_state).await.is_none() {
+        return HttpResponse::Unauthorized().finish();
+    }
+    let slug = path.into_inner();
+    match consultant_id_by_slug(&state.db, &slug).await {
+        Ok(consultant_id) => render_busy_calendars(&hb, &state.db, slug, consultant_id, None, vec![]).await,
+        Err(_) => busy_error(&hb, "Consultant not found"),
+    }
+}
+
+// Takes an .ics upload in the `ics` field. Blocks are kept per file name, so re-importing a refreshed
+// export replaces the old blocks rather than adding to them.
+#[post("/busy/{slug}")]
+async fn import_busy_calendar(
+    mut payload: Multipart,
+    hb: web::Data<Handlebars<'_>>,
+    req: HttpRequest,
+    state: web::Data<AppState>,
+    r_state: web::Data<RedisState>,
+    path: web::Path<String>,
+) -> impl Responder {
+    if current_user_id(&req, &state, &r_state).await.is_none() {
+        return HttpResponse::Unauthorized().finish();
+    }
+    let user = session_key(&req, &r_state).await;
+    if let Some(resp) = rate_limited(&req, &r_state, &hb, &UPLOAD, user.as_deref()).await {
+        return resp;
+    }
+    let slug = path.into_inner();
+    let consultant_id = match consultant_id_by_slug(&state.db, &slug).await {
+        Ok(consultant_id) => consultant_id,
+        Err(_) => return busy_error(&hb, "Consultant not found"),
+    };
+    if !can_manage_consultant(&req, &state, &r_state, consultant_id).await {
+        return HttpResponse::Forbidden().finish();
+    }
+
+    let mut upload: Option<(String, Vec<u8>)> = None;
+    while let Ok(Some(mut field)) = payload.try_next().await {
+        if field.name() != "ics" {
+            continue;
+        }
+        let source = field
+            .content_disposition()
+            .get_filename()
+            .map(|name| name.trim().to_owned())
+            .filter(|name| !name.is_empty())
+            .unwrap_or_else(|| "calendar.ics".to_owned());
+        let mut bytes = vec![];
+        while let Ok(Some(chunk)) = field.try_next().await {
+            if bytes.len() + chunk.len() > MAX_ICS_SIZE {
+                return busy_error(&hb, "Calendar file is too large");
+            }
+            bytes.extend_from_slice(&chunk);
+        }
+        upload = Some((source, bytes));
+        break;
+    }
+    let (source, bytes) = match upload {
+        Some(upload) => upload,
+        None => return busy_error(&hb, "Choose an .ics file to import"),
+    };
+    let text = match String::from_utf8(bytes) {
+        Ok(text) => text,
+        Err(_) => return busy_error(&hb, "Calendar file isn't UTF-8 text"),
+    };
+
+    let now = Utc::now();
+    // Expanding a big calendar's recurrences is CPU work, so it runs off the request thread
+    let parsed = match web::block(move || {
+        busy_intervals(&text, now - Duration::days(BUSY_PAST_DAYS), now + Duration::days(BUSY_FUTURE_DAYS))
+    })
+    .await
+    {
+        Ok(Ok(parsed)) => parsed,
+        Ok(Err(err)) => return busy_error(&hb, &format!("Unable to read calendar: {}", err)),
+        Err(err) => {
+            dbg!(&err);
+            return busy_error(&hb, "Error reading calendar");
+        }
+    };
+    match replace_busy_blocks(&state.db, consultant_id, &source, &parsed.intervals).await {
+        Ok(count) => {
+            let message = format!("Imported {} busy block(s) from {} event(s) in {}", count, parsed.events, source);
+            render_busy_calendars(&hb, &state.db, slug, consultant_id, Some(message), parsed.skipped).await
+        }
+        Err(err) => {
+            dbg!(&err);
+            busy_error(&hb, "Error saving busy blocks")
+        }
+    }
+}
+
+#[post("/busy/{slug}/clear")]
+async fn clear_busy_calendar(
+    body: web::Form<BusySourcePost>,
+    hb: web::Data<Handlebars<'_>>,
+    req: HttpRequest,
+    state: web::Data<AppState>,
+    r_state: web::Data<RedisState>,
+    path: web::Path<String>,
+) -> impl Responder {
+    if current_user_id(&req, &state, &r_state).await.is_none() {
+        return HttpResponse::Unauthorized().finish();
+    }
+    let slug = path.into_inner();
+    let consultant_id = match consultant_id_by_slug(&state.db, &slug).await {
+        Ok(consultant_id) => consultant_id,
+        Err(_) => return busy_error(&hb, "Consultant not found"),
+    };
+    if !can_manage_consultant(&req, &state, &r_state, consultant_id).await {
+        return HttpResponse::Forbidden().finish();
+    }
+    let message = match clear_busy_source(&state.db, consultant_id, &body.source).await {
+        Ok(removed) => format!("Removed {} busy block(s) from {}", removed, body.source),
+        Err(err) => {
+            dbg!(&err);
+            "Error removing busy blocks".to_owned()
+        }
+    };
+    render_busy_calendars(&hb, &state.db, slug, consultant_id, Some(message), vec![]).await
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct WorkingDay {
+    weekday: i16,
+    name: String,
+    start_time: Option<String>,
+    end_time: Option<String>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct AvailabilityTemplate {
+    slug: String,
+    days: Vec<WorkingDay>,
+    time_off: Vec<TimeOff>,
+    message: Option<String>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct TimeOffPost {
+    first_day: String,
+    last_day: String,
+    reason: String,
+}
+
+async fn render_availability(
+    hb: &Handlebars<'_>,
+    db: &Pool<Postgres>,
+    slug: String,
+    consultant_id: i32,
+    message: Option<String>,
+) -> HttpResponse {
+    let hours = working_hours_for(db, consultant_id).await.unwrap_or_else(|err| {
+        println!("Error loading working hours: {}", err);
+        vec![]
+    });
+    let time_off = time_off_for(db, consultant_id, Utc::now().date_naive()).await.unwrap_or_else(|err| {
+        println!("Error loading time off: {}", err);
+        vec![]
+    });
+    // Monday first, the way the week reads on the form
+    let days = [1, 2, 3, 4, 5, 6, 0]
+        .into_iter()
+        .map(|weekday| {
+            let day = hours.iter().find(|hours| hours.weekday == weekday);
+            WorkingDay {
+                weekday,
+                name: weekday_name(weekday).to_owned(),
+                start_time: day.map(|day| day.start_time.format("%H:%M").to_string()),
+                end_time: day.map(|day| day.end_time.format("%H:%M").to_string()),
+            }
+        })
+        .collect();
+    let template_data = AvailabilityTemplate { slug, days, time_off, message };
+    let body = hb.render("calendar/availability", &template_data).unwrap();
+    HttpResponse::Ok().body(body)
+}
+
+#[get("/availability/{slug}")]
+async fn availability(
+    hb: web::Data<Handlebars<'_>>,
+    req: HttpRequest,
+    state: web::Data<AppState>,
+    r_state: web::Data<RedisState>,
+    path: web::Path<String>,
+) -> impl Responder {
+    if current_user_id(&req, &state, &r_state).await.is_none() {
+        return HttpResponse::Unauthorized().finish();
+    }
+    let slug = path.into_inner();
+    match consultant_id_by_slug(&state.db, &slug).await {
+        Ok(consultant_id) => render_availability(&hb, &state.db, slug, consultant_id, None).await,
+        Err(_) => busy_error(&hb, "Consultant not found"),
+    }
+}
+
+// Saves the whole week at once. Leaving every day blank removes the restriction.
+#[post("/availability/{slug}/hours")]
+async fn save_working_hours(
+    body: web::Form<HashMap<String, String>>,
+    hb: web::Data<Handlebars<'_>>,
+    req: HttpRequest,
+    state: web::Data<AppState>,
+    r_state: web::Data<RedisState>,
+    path: web::Path<String>,
+) -> impl Responder {
+    if current_user_id(&req, &state, &r_state).await.is_none() {
+        return HttpResponse::Unauthorized().finish();
+    }
+    let slug = path.into_inner();
+    let consultant_id = match consultant_id_by_slug(&state.db, &slug).await {
+        Ok(consultant_id) => consultant_id,
+        Err(_) => return busy_error(&hb, "Consultant not found"),
+    };
+    if !can_manage_consultant(&req, &state, &r_state, consultant_id).await {
+        return HttpResponse::Forbidden().finish();
+    }
+    let week = match week_from_form(&body) {
+        Ok(week) => week,
+        Err(err) => return busy_error(&hb, &err),
+    };
+    match set_working_hours(&state.db, consultant_id, &week).await {
+        Ok(()) => render_availability(&hb, &state.db, slug, consultant_id, Some("Working hours saved".to_owned())).await,
+        Err(err) => {
+            dbg!(&err);
+            busy_error(&hb, "Error saving working hours")
+        }
+    }
+}
+
+// Requests start out pending and only block bookings once an admin approves them
+#[post("/availability/{slug}/time-off")]
+async fn create_time_off(
+    body: web::Form<TimeOffPost>,
+    hb: web::Data<Handlebars<'_>>,
+    req: HttpRequest,
+    state: web::Data<AppState>,
+    r_state: web::Data<RedisState>,
+    path: web::Path<String>,
+) -> impl Responder {
+    let user_id = match current_user_id(&req, &state, &r_state).await {
+        Some(user_id) => user_id,
+        None => return HttpResponse::Unauthorized().finish(),
+    };
+    let slug = path.into_inner();
+    let consultant_id = match consultant_id_by_slug(&state.db, &slug).await {
+        Ok(consultant_id) => consultant_id,
+        Err(_) => return busy_error(&hb, "Consultant not found"),
+    };
+    if !can_manage_consultant(&req, &state, &r_state, consultant_id).await {
+        return HttpResponse::Forbidden().finish();
+    }
+    let first_day = NaiveDate::parse_from_str(&body.first_day, "%Y-%m-%d");
+    let last_day = NaiveDate::parse_from_str(&body.last_day, "%Y-%m-%d");
+    let (first_day, last_day) = match (first_day, last_day) {
+        (Ok(first_day), Ok(last_day)) if last_day >= first_day => (first_day, last_day),
+        (Ok(_), Ok(_)) => return busy_error(&hb, "Time off has to end on or after its first day"),
+        _ => return busy_error(&hb, "Time off needs valid dates"),
+    };
+    match request_time_off(&state.db, consultant_id, first_day, last_day, body.reason.trim(), Some(user_id)).await {
+        Ok(()) => {
+            let message = "Time off requested. It blocks bookings once an admin approves it.".to_owned();
+            render_availability(&hb, &state.db, slug, consultant_id, Some(message)).await
+        }
+        Err(err) => {
+            dbg!(&err);
+            busy_error(&hb, "Error saving the time off request")
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct AgendaQuery {
+    start: Option<String>,
+    end: Option<String>,
+}
+
+#[derive(Serialize, Debug, Clone)]
+pub struct AgendaTemplate {
+    slug: String,
+    title: String,
+    start: String,
+    end: String,
+    today: String,
+    week_start: String,
+    week_end: String,
+    days: Vec<AgendaDay>,
+}
+
+// Either the agenda or the response explaining why there isn't one
+async fn load_agenda(db: &Pool<Postgres>, slug: &str, query: &AgendaQuery) -> Result<AgendaTemplate, HttpResponse> {
+    let today = Utc::now().with_timezone(&consult_offset()).date_naive();
+    let (first_day, last_day) = agenda_range(query.start.as_deref(), query.end.as_deref(), today)
+        .map_err(|err| HttpResponse::BadRequest().body(err))?;
+    let consultant = match agenda_consultant(db, slug).await {
+        Ok(Some(consultant)) => consultant,
+        Ok(None) => return Err(HttpResponse::NotFound().body("Consultant not found")),
+        Err(err) => {
+            dbg!(&err);
+            return Err(HttpResponse::InternalServerError().body("Error loading the agenda"));
+        }
+    };
+    let consults = agenda_consults(db, consultant.id, first_day, last_day).await.map_err(|err| {
+        dbg!(&err);
+        HttpResponse::InternalServerError().body("Error loading the agenda")
+    })?;
+    let week_start = today - Duration::days(today.weekday().num_days_from_monday() as i64);
+    Ok(AgendaTemplate {
+        title: agenda_title(&consultant.consultant_name, first_day, last_day),
+        slug: consultant.slug,
+        start: first_day.to_string(),
+        end: last_day.to_string(),
+        today: today.to_string(),
+        week_start: week_start.to_string(),
+        week_end: (week_start + Duration::days(6)).to_string(),
+        days: agenda_days(consults, first_day, last_day),
+    })
+}
+
+// A standalone page meant to be opened in its own tab and printed. ?start= and ?end= pick the days.
+#[get("/agenda/{slug}")]
+async fn agenda_page(
+    query: web::Query<AgendaQuery>,
+    hb: web::Data<Handlebars<'_>>,
+    req: HttpRequest,
+    state: web::Data<AppState>,
+    r_state: web::Data<RedisState>,
+    path: web::Path<String>,
+) -> impl Responder {
+    if current_user_id(&req, &state, &r_state).await.is_none() {
+        return HttpResponse::Unauthorized().finish();
+    }
+    match load_agenda(&state.db, &path.into_inner(), &query).await {
+        Ok(template_data) => {
+            let body = hb.render("calendar/agenda", &template_data).unwrap();
+            HttpResponse::Ok().body(body)
+        }
+        Err(resp) => resp,
+    }
+}
+
+#[get("/agenda/{slug}.pdf")]
+async fn agenda_pdf_download(
+    query: web::Query<AgendaQuery>,
+    req: HttpRequest,
+    state: web::Data<AppState>,
+    r_state: web::Data<RedisState>,
+    path: web::Path<String>,
+) -> impl Responder {
+    if current_user_id(&req, &state, &r_state).await.is_none() {
+        return HttpResponse::Unauthorized().finish();
+    }
+    match load_agenda(&state.db, &path.into_inner(), &query).await {
+        Ok(agenda) => {
+            let filename = format!("agenda-{}.pdf", agenda.start);
+            HttpResponse::Ok()
+                .insert_header((CONTENT_TYPE, APPLICATION_PDF.to_string()))
+                .insert_header((CONTENT_DISPOSITION, format!("inline; filename=\"{}\"", filename)))
+                .body(agenda_pdf(&agenda.title, &agenda.days))
+        }
+        Err(resp) => resp,
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
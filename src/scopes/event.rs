@@ -1,18 +1,37 @@
 use actix_web::web::{Data, Form};
+use actix_web::http::header::{CONTENT_DISPOSITION, CONTENT_TYPE};
 use actix_web::{get, post, web, HttpRequest, HttpResponse, Responder, Scope};
-use chrono::{DateTime, Datelike, NaiveDate, Utc};
-use ics::properties::{Categories, Description, DtEnd, DtStart, Organizer, Status, Summary};
-use ics::{escape_text, Event, ICalendar};
+use chrono::{DateTime, Duration, NaiveDate, Utc};
 use serde_json::json;
 use sqlx::FromRow;
-use uuid::Uuid;
 
 use crate::RedisState;
+use crate::calendar::export::{
+    consult_calendar, create_feed, feed_by_token, feed_consults, ics_consult_by_slug, is_feed_type, revoke_feed,
+    user_feeds, CalendarFeed, ItipMethod, USER_FEED,
+};
+use crate::calendar::availability::availability_in_range;
+use crate::calendar::booking::BookingStatus;
+use crate::calendar::holidays::holidays_in_range;
+use crate::calendar::view::{
+    calendar_title, calendar_url, consults_in_range, lay_out, local_date, moved_window, parse_time, resized_end, step,
+    visible_range, CalendarDay, CalendarFilters, CalendarPage, CalendarQuery, CalendarView,
+};
+use crate::linfa::{consult_offset, meeting_time::{consultant_conflicts, within_business_hours}};
+use crate::redis_mod::live_updates::{publish_live_event, Broadcaster, LiveEntity, LiveEvent};
+use crate::redis_mod::query_cache::{invalidate, CacheTag};
+use crate::scopes::consult::{
+    book_consult, booking_response, client_options, consultant_options, location_options, regenerate_ics,
+    send_invites, unavailable_reason,
+};
+use crate::scopes::notification::notify_subscribers;
+use crate::scopes::notification::current_user_id;
+use crate::redis_mod::materialized::{refresh_for_refs, ConsultRefs};
 use crate::config::redis_validate_and_get_user;
 use crate::models::model_consult::ConsultPost;
 use crate::{
     config::{
-        self, get_validation_response, subs_from_user, test_subs, validate_and_get_user,
+        self, subs_from_user, test_subs, validate_and_get_user,
         FilterOptions, ResponsiveTableData, SelectOption, UserAlert, ValidationResponse,
         ACCEPTED_SECONDARIES,
     },
@@ -34,8 +53,14 @@ pub fn event_scope() -> Scope {
         .service(get_locations_handler)
         .service(search_location)
         .service(home)
-        .service(next_month)
-    //.service(prev_month)
+        .service(calendar)
+        .service(move_consult)
+        .service(resize_consult)
+        .service(consult_ics)
+        .service(feed_ics)
+        .service(feeds)
+        .service(create_feed_handler)
+        .service(revoke_feed_handler)
 }
 
 #[derive(Debug, Serialize, Deserialize)]
@@ -47,82 +72,6 @@ pub struct CalError {
     err: String,
 }
 
-fn date_to_cal_date(date: &DateTime<Utc>) -> String {
-    date.format("%Y%m%dT%H%M%SZ").to_string()
-}
-
-fn category_from_purpose(id: i32) -> &'static str {
-    match id {
-        1 => "INTRODUCTION",
-        2 => "WALKTHROUGH/INIT",
-        3 => "CONTINUED",
-        4 => "FINAL SERVICE",
-        5 => "AUDIT",
-        _ => "Oops",
-    }
-}
-
-fn create_calendar_event(
-    start: &DateTime<Utc>,
-    end: &DateTime<Utc>,
-    purpose_id: i32,
-) -> ICalendar<'static> {
-    // fn create_calendar_event() -> Result<(), CalError> {
-    // create new iCalendar object
-    let dt = NaiveDate::from_ymd_opt(2023, 12, 1)
-        .unwrap()
-        .and_hms_milli_opt(9, 10, 11, 12)
-        .unwrap()
-        .and_local_timezone(Utc)
-        .unwrap(); // `2014-07-08T09:10:11.012Z`
-    let weekday = dt.weekday();
-    // Sunday is 1, Saturday is 7
-    let weekday_int = weekday.number_from_sunday();
-    dbg!(weekday);
-
-    let cal_now = date_to_cal_date(&Utc::now());
-    let dt_start = date_to_cal_date(start);
-    let dt_end = date_to_cal_date(end);
-
-    let mut calendar = ICalendar::new("2.0", "-//xyz Corp//NONSGML PDA Calendar Version 1.0//EN");
-    // create event which contains the information regarding the conference
-    let uuid_str = Uuid::new_v4().to_string();
-    let mut event = Event::new(uuid_str, cal_now);
-    // add properties
-    let organizer = "mailto:jsmith@example.com";
-    // let dt_start = "19960918T143000Z";
-    // let dt_end = "19960920T220000Z";
-    let status = if true {
-        Status::confirmed()
-    } else {
-        Status::tentative()
-    };
-    let categories = category_from_purpose(purpose_id);
-    let summary = "Networld+Interop Conference";
-    // Values that are "TEXT" must be escaped (only if the text contains a comma,
-    // semicolon, backslash or newline).
-    let description = escape_text(
-        "Networld+Interop Conference and Exhibit\n\
-         Atlanta World Congress Center\n\
-         Atlanta, Georgia",
-    );
-    event.push(Organizer::new(organizer));
-    event.push(DtStart::new(dt_start));
-    event.push(DtEnd::new(dt_end));
-    event.push(status);
-    event.push(Categories::new(categories));
-    event.push(Summary::new(summary));
-    event.push(Description::new(description));
-    // add event to calendar
-    calendar.add_event(event);
-
-    // write calendar to file
-    // calendar.save_file("event.ics")?;
-    // let _ = calendar.save_file("event.ics");
-    calendar
-    // Ok::<(), CalError>(())
-}
-
 #[post("/search")]
 async fn search_location(
     opts: web::Query<FilterOptions>,
@@ -186,121 +135,62 @@ async fn search_location(
     return HttpResponse::Ok().body(body);
 }
 
-#[derive(Debug, Serialize, Deserialize)]
-pub struct CalendarData {
-    pub month: u32,
-    pub year: u32,
-    pub first_day_of_month: u32,
-    pub num_days: u32,
-    pub weekday_range: Vec<u32>,
-    pub holidays: Vec<(u32, String)>,
-}
-
-fn get_month_holidays(month: u32) -> Vec<(u32, String)> {
-    match month {
-        1 => vec![
-            (1, "New Year's Day".to_string()),
-            (11, "Veteran's Day".to_string()),
-        ],
-        2 => vec![
-            (2, "Groundhog's Day".to_string()),
-            (14, "Valentine's Day".to_string()),
-        ],
-        3 => vec![(21, "Spring".to_string())],
-        4 => vec![
-            (1, "April Fools".to_string()),
-            (14, "Valentine's Day".to_string()),
-        ],
-        5 => vec![
-            (1, "New Year's Day".to_string()),
-            (11, "Veteran's Day".to_string()),
-        ],
-        6 => vec![
-            (2, "Groundhog's Day".to_string()),
-            (14, "Valentine's Day".to_string()),
-        ],
-        7 => vec![
-            (4, "Independence Day".to_string()),
-            (11, "Veteran's Day".to_string()),
-        ],
-        8 => vec![
-            (2, "Groundhog's Day".to_string()),
-            (14, "Valentine's Day".to_string()),
-        ],
-        9 => vec![
-            (1, "New Year's Day".to_string()),
-            (11, "Veteran's Day".to_string()),
-        ],
-        10 => vec![
-            (31, "Halloween".to_string()),
-            (14, "Valentine's Day".to_string()),
-        ],
-        11 => vec![
-            (24, "Thanksgiving".to_string()),
-            (11, "Veteran's Day".to_string()),
-        ],
-        12 => vec![
-            (25, "Christmas".to_string()),
-            (31, "New Year's Eve".to_string()),
-        ],
-        _ => vec![
-            (24, "Thanksgiving".to_string()),
-            (11, "Veteran's Day".to_string()),
-        ],
-    }
-}
-
-fn get_num_days(month: u32) -> u32 {
-    match month {
-        1 | 3 | 5 | 7 | 8 | 10 | 12 => 31,
-        4 | 6 | 9 | 11 => 30,
-        // FIXME leap year
-        2 => 28,
-        _ => 0,
-    }
+async fn calendar_page(
+    query: &CalendarQuery,
+    state: &web::Data<AppState>,
+    r_state: &web::Data<RedisState>,
+) -> Result<CalendarPage, sqlx::Error> {
+    let today = local_date(Utc::now());
+    let view = query.view();
+    let date = query.date(today);
+    let filters = query.filters();
+    let (start, end) = visible_range(view, date);
+    let consults = consults_in_range(&state.db, start, end, &filters).await?;
+    let holidays = holidays_in_range(&state.db, start, end).await;
+    let availability = availability_in_range(&state.db, start, end, filters.consultant_id).await;
+    Ok(CalendarPage {
+        view,
+        title: calendar_title(view, date),
+        date,
+        weeks: lay_out(view, date, today, consults, &holidays, &availability),
+        filters,
+        prev_url: calendar_url(view, step(view, date, -1), &filters),
+        next_url: calendar_url(view, step(view, date, 1), &filters),
+        today_url: calendar_url(view, today, &filters),
+        month_url: calendar_url(CalendarView::Month, date, &filters),
+        week_url: calendar_url(CalendarView::Week, date, &filters),
+        day_url: calendar_url(CalendarView::Day, date, &filters),
+        consultant_options: consultant_options(state, r_state).await.vec,
+        location_options: location_options(state, r_state).await.vec,
+        client_options: client_options(state, r_state).await.vec,
+    })
 }
 
 #[get("/")]
 async fn home(
+    query: web::Query<CalendarQuery>,
     hb: web::Data<Handlebars<'_>>,
     req: HttpRequest,
     state: Data<AppState>,
+    r_state: Data<RedisState>,
 ) -> impl Responder {
     if let Some(cookie) = req.headers().get(actix_web::http::header::COOKIE) {
-        match validate_and_get_user(cookie, &state).await {
-            Ok(user) => {
-                if let Some(usr) = user {
-                    // Homepage displays current Mo/Yr
-                    let this_month = Utc::now().month();
-                    let this_year = Utc::now().year();
-
-                    let day_one: NaiveDate =
-                        NaiveDate::from_ymd_opt(this_year, this_month, 1).unwrap();
-                    let day_one_weekday = day_one.weekday();
-                    // Sunday is 1, Saturday is 7
-                    let day_one_int = day_one_weekday.number_from_sunday();
-                    let cal = create_calendar_event(&Utc::now(), &Utc::now(), 1);
-                    dbg!(cal);
-                    let cal_data = CalendarData {
-                        month: this_month,
-                        year: this_year as u32,
-                        first_day_of_month: day_one_int,
-                        num_days: get_num_days(this_month),
-                        weekday_range: vec![1, 2, 3, 4, 5, 6, 7],
-                        // holidays: vec![(24, "Thanksgiving".to_string()),(11, "Veteran's Day".to_string())]
-                        holidays: get_month_holidays(this_month),
-                    };
+        match redis_validate_and_get_user(cookie, &r_state).await {
+            Ok(_user) => match calendar_page(&query, &state, &r_state).await {
+                Ok(calendar) => {
                     let data = json! {{
-                        "cal_data": cal_data,
+                        "calendar": calendar,
                     }};
                     let body = hb.render("event-api", &data).unwrap();
                     HttpResponse::Ok().body(body)
-                } else {
-                    let message = "Cannot find you";
-                    let body = hb.render("index", &message).unwrap();
-                    return HttpResponse::Ok().body(body);
                 }
-            }
+                Err(err) => {
+                    dbg!(&err);
+                    let validation_response = ValidationResponse::from(("Error loading the calendar", "validation_error"));
+                    let body = hb.render("validation", &validation_response).unwrap();
+                    HttpResponse::Ok().body(body)
+                }
+            },
             Err(err) => {
                 dbg!(&err);
                 let body = hb.render("index", &format!("{:?}", err)).unwrap();
@@ -316,9 +206,276 @@ async fn home(
     }
 }
 
-#[get("/calendar/move")]
-async fn next_month(
-    opts: web::Query<FilterOptions>,
+#[derive(Debug, Clone, Deserialize)]
+pub struct MovePost {
+    slug: String,
+    to_date: String,
+    start_time: Option<String>,
+    // The calendar's own view, anchor date and filters, so the returned cells match what's on screen
+    view: Option<String>,
+    date: Option<String>,
+    consultant_id: Option<String>,
+    location_id: Option<String>,
+    client_id: Option<String>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct ResizePost {
+    slug: String,
+    end_time: String,
+    view: Option<String>,
+    date: Option<String>,
+    consultant_id: Option<String>,
+    location_id: Option<String>,
+    client_id: Option<String>,
+}
+
+#[derive(Debug, Clone, FromRow)]
+pub struct ScheduledConsult {
+    id: i32,
+    client_id: i32,
+    location_id: i32,
+    consultant_id: Option<i32>,
+    consult_start: DateTime<Utc>,
+    consult_end: Option<DateTime<Utc>>,
+    updated_at: Option<DateTime<Utc>>,
+    pending: bool,
+}
+
+#[derive(Debug, Serialize)]
+pub struct CalendarCells {
+    view: CalendarView,
+    days: Vec<CalendarDay>,
+    message: Option<String>,
+    // Makes each cell an out-of-band swap
+    oob: bool,
+}
+
+fn calendar_error(hb: &Handlebars<'_>, error_msg: &str) -> HttpResponse {
+    let validation_response = ValidationResponse::from((error_msg, "validation_error"));
+    let body = hb.render("validation", &validation_response).unwrap();
+    HttpResponse::BadRequest()
+        .insert_header(("HX-Retarget", "#calendar_message"))
+        .insert_header(("HX-Reswap", "innerHTML"))
+        .body(body)
+}
+
+// Re-renders the given days, skipping any the calendar isn't showing
+async fn calendar_cells(
+    hb: &Handlebars<'_>,
+    db: &sqlx::Pool<sqlx::Postgres>,
+    query: &CalendarQuery,
+    dates: &[NaiveDate],
+    message: String,
+) -> HttpResponse {
+    let today = local_date(Utc::now());
+    let view = query.view();
+    let (first, last) = visible_range(view, query.date(today));
+    let filters: CalendarFilters = query.filters();
+    let mut days = vec![];
+    for date in dates.iter().filter(|date| **date >= first && **date < last) {
+        let next = *date + Duration::days(1);
+        let consults = match consults_in_range(db, *date, next, &filters).await {
+            Ok(consults) => consults,
+            Err(err) => {
+                dbg!(&err);
+                return calendar_error(hb, "Rescheduled, but the calendar couldn't be refreshed. Reload to see it.");
+            }
+        };
+        let holidays = holidays_in_range(db, *date, next).await;
+        let availability = availability_in_range(db, *date, next, filters.consultant_id).await;
+        days.extend(
+            lay_out(CalendarView::Day, *date, today, consults, &holidays, &availability)
+                .into_iter()
+                .flatten(),
+        );
+    }
+    let cells = CalendarCells {
+        view,
+        days,
+        message: Some(message),
+        oob: true,
+    };
+    let body = hb.render("calendar/cells", &cells).unwrap();
+    HttpResponse::Ok().body(body)
+}
+
+// Checks the new time the same way bookings are checked, then saves it as a new revision of the
+// consult: attendees get an updated invite and the .ics is rewritten.
+async fn reschedule(
+    hb: &Handlebars<'_>,
+    state: &web::Data<AppState>,
+    r_state: &web::Data<RedisState>,
+    broadcaster: &Broadcaster,
+    query: &CalendarQuery,
+    slug: &str,
+    window: impl FnOnce(&ScheduledConsult) -> Result<(DateTime<Utc>, DateTime<Utc>), String>,
+) -> HttpResponse {
+    let consult = match sqlx::query_as::<_, ScheduledConsult>(
+        "SELECT id, client_id, location_id, consultant_id, consult_start, consult_end, updated_at,
+                booking_status = 'pending' AS pending
+            FROM consults
+            WHERE slug = $1 AND cancelled_at IS NULL",
+    )
+    .bind(slug)
+    .fetch_optional(&state.db)
+    .await
+    {
+        Ok(Some(consult)) => consult,
+        Ok(None) => return calendar_error(hb, "That consult was cancelled or no longer exists"),
+        Err(err) => {
+            dbg!(&err);
+            return calendar_error(hb, "Error loading the consult");
+        }
+    };
+    let (start, end) = match window(&consult) {
+        Ok(window) => window,
+        Err(error_msg) => return calendar_error(hb, &error_msg),
+    };
+    if !within_business_hours(start, end) {
+        return calendar_error(hb, "Consults have to be within business hours");
+    }
+    let holidays = holidays_in_range(&state.db, local_date(start), local_date(start) + Duration::days(1)).await;
+    if let Some(holiday) = holidays.values().next() {
+        return calendar_error(hb, &format!("The office is closed that day ({})", holiday));
+    }
+    if let Some(consultant_id) = consult.consultant_id {
+        if let Some(reason) = unavailable_reason(&state.db, consultant_id, start, end).await {
+            return calendar_error(hb, &reason);
+        }
+        match consultant_conflicts(&state.db, consultant_id, start, end, Some(slug)).await {
+            Ok(conflicts) if !conflicts.is_empty() => {
+                return calendar_error(hb, "The consultant is busy then");
+            }
+            Ok(_) => {}
+            Err(err) => println!("Error checking conflicts for consultant {}: {}", consultant_id, err),
+        }
+    }
+
+    // Guarded by updated_at like the edit form, so a save made meanwhile isn't overwritten
+    let updated = sqlx::query(
+        "UPDATE consults
+            SET consult_start = $1, consult_end = $2, updated_at = NOW(), ics_sequence = ics_sequence + 1
+            WHERE id = $3 AND cancelled_at IS NULL AND updated_at IS NOT DISTINCT FROM $4",
+    )
+    .bind(start)
+    .bind(end)
+    .bind(consult.id)
+    .bind(consult.updated_at)
+    .execute(&state.db)
+    .await;
+    match updated {
+        Ok(result) if result.rows_affected() == 1 => {}
+        Ok(_) => return calendar_error(hb, "Someone else just changed this consult. Reload and try again."),
+        Err(err) => {
+            dbg!(&err);
+            return calendar_error(hb, "Error saving the new time");
+        }
+    }
+
+    invalidate(r_state, &[CacheTag::Consult]).await;
+    // A move only changes the time, the updated_at guard means the refs loaded above are still current
+    let refs = ConsultRefs {
+        client_id: consult.client_id,
+        location_id: consult.location_id,
+        consultant_id: consult.consultant_id,
+    };
+    refresh_for_refs(r_state, &[refs]).await;
+    let summary = format!(
+        "Consult #{} moved to {}",
+        consult.id,
+        start.with_timezone(&consult_offset()).format("%Y-%m-%d %H:%M")
+    );
+    let mut targets = vec![(7, consult.client_id), (5, consult.location_id), (6, consult.id)];
+    if let Some(consultant_id) = consult.consultant_id {
+        targets.push((4, consultant_id));
+    }
+    let notified = notify_subscribers(&state.db, &targets, &summary).await;
+    publish_live_event(r_state, broadcaster, LiveEvent::new(LiveEntity::Consult, consult.id, "updated", summary.clone()).notify(notified)).await;
+    regenerate_ics(r_state, consult.id).await;
+    // Pending requests get their invite when they're confirmed
+    if !consult.pending {
+        send_invites(r_state, consult.id, ItipMethod::Request).await;
+    }
+
+    let mut dates = vec![local_date(consult.consult_start), local_date(start)];
+    dates.dedup();
+    calendar_cells(hb, &state.db, query, &dates, summary).await
+}
+
+// A consult dropped on another day of the week or day view, or given a new start time in the day view
+#[post("/calendar/move")]
+async fn move_consult(
+    body: Form<MovePost>,
+    hb: web::Data<Handlebars<'_>>,
+    req: HttpRequest,
+    state: Data<AppState>,
+    r_state: Data<RedisState>,
+    broadcaster: Data<Broadcaster>,
+) -> impl Responder {
+    if current_user_id(&req, &state, &r_state).await.is_none() {
+        return HttpResponse::Unauthorized().finish();
+    }
+    let query = CalendarQuery {
+        view: body.view.clone(),
+        date: body.date.clone(),
+        consultant_id: body.consultant_id.clone(),
+        location_id: body.location_id.clone(),
+        client_id: body.client_id.clone(),
+    };
+    let to_date = match NaiveDate::parse_from_str(&body.to_date, "%Y-%m-%d") {
+        Ok(to_date) => to_date,
+        Err(_) => return calendar_error(&hb, "Invalid date"),
+    };
+    let start_time = match body.start_time.as_deref().filter(|time| !time.is_empty()) {
+        Some(time) => match parse_time(time) {
+            Some(time) => Some(time),
+            None => return calendar_error(&hb, "Invalid start time"),
+        },
+        None => None,
+    };
+    reschedule(&hb, &state, &r_state, &broadcaster, &query, &body.slug, |consult| {
+        Ok(moved_window(consult.consult_start, consult.consult_end, to_date, start_time))
+    })
+    .await
+}
+
+// A new end time from the day view. The start stays put.
+#[post("/calendar/resize")]
+async fn resize_consult(
+    body: Form<ResizePost>,
+    hb: web::Data<Handlebars<'_>>,
+    req: HttpRequest,
+    state: Data<AppState>,
+    r_state: Data<RedisState>,
+    broadcaster: Data<Broadcaster>,
+) -> impl Responder {
+    if current_user_id(&req, &state, &r_state).await.is_none() {
+        return HttpResponse::Unauthorized().finish();
+    }
+    let query = CalendarQuery {
+        view: body.view.clone(),
+        date: body.date.clone(),
+        consultant_id: body.consultant_id.clone(),
+        location_id: body.location_id.clone(),
+        client_id: body.client_id.clone(),
+    };
+    let end_time = match parse_time(&body.end_time) {
+        Some(end_time) => end_time,
+        None => return calendar_error(&hb, "Invalid end time"),
+    };
+    reschedule(&hb, &state, &r_state, &broadcaster, &query, &body.slug, |consult| {
+        resized_end(consult.consult_start, end_time)
+            .map(|end| (consult.consult_start, end))
+            .ok_or_else(|| "The end has to be after the start".to_owned())
+    })
+    .await
+}
+
+// Navigation, view switches and filter changes all re-render just the calendar.
+#[get("/calendar")]
+async fn calendar(
+    query: web::Query<CalendarQuery>,
     hb: web::Data<Handlebars<'_>>,
     req: HttpRequest,
     state: Data<AppState>,
@@ -326,60 +483,24 @@ async fn next_month(
 ) -> impl Responder {
     if let Some(cookie) = req.headers().get(actix_web::http::header::COOKIE) {
         match redis_validate_and_get_user(cookie, &r_state).await {
-            Ok(user) => {
-                // let (input_year, input_month) = path.into_inner();
-
-                dbg!(&opts);
-
-                let input_year = opts.year.unwrap();
-                let input_month = opts.month.unwrap();
-                let input_dir = opts.dir.clone().unwrap();
-
-                let cal_month = 
-                    match (input_month, input_dir.as_str()) {
-                        (12, "next") => 1,
-                        (1, "prev")  => 12,
-                        (_, "prev")  => input_month - 1, 
-                        (_, "next")  => input_month + 1, 
-                        _            => input_month
-                    };
-
-                let cal_year = 
-                    match (input_month, input_dir.as_str()) {
-                        (12, "next") => input_year + 1,
-                        (1, "prev")  => input_year - 1,
-                        _            => input_year
-                    };
-
-                dbg!(&cal_month);
-
-                let day_one: NaiveDate =
-                    NaiveDate::from_ymd_opt(cal_year as i32, cal_month, 1).unwrap();
-                let day_one_weekday = day_one.weekday();
-                // Sunday is 1, Saturday is 7
-                let day_one_int = day_one_weekday.number_from_sunday();
-                let cal_data = CalendarData {
-                    month: cal_month,
-                    year: cal_year,
-                    first_day_of_month: day_one_int,
-                    num_days: get_num_days(cal_month),
-                    weekday_range: vec![1, 2, 3, 4, 5, 6, 7],
-                    holidays: get_month_holidays(cal_month),
-                };
-                let month_data = json! {{
-                    "cal_data": cal_data,
-                }};
-                let body = hb.render("calendar/month", &month_data).unwrap();
-                HttpResponse::Ok().body(body)
-            }
+            Ok(_user) => match calendar_page(&query, &state, &r_state).await {
+                Ok(calendar) => {
+                    let body = hb.render("calendar/calendar", &calendar).unwrap();
+                    HttpResponse::Ok().body(body)
+                }
+                Err(err) => {
+                    dbg!(&err);
+                    let validation_response = ValidationResponse::from(("Error loading the calendar", "validation_error"));
+                    let body = hb.render("validation", &validation_response).unwrap();
+                    HttpResponse::Ok().body(body)
+                }
+            },
             Err(err) => {
                 dbg!(&err);
                 let body = hb.render("index", &format!("{:?}", err)).unwrap();
                 return HttpResponse::Ok().body(body);
-                // HttpResponse::InternalServerError().json(format!("{:?}", err))
             }
         }
-        // FIXME: Is this else right? Redirect?
     } else {
         let message = "Your session seems to have expired. Please login again (3).".to_owned();
         let body = hb.render("index", &message).unwrap();
@@ -387,6 +508,190 @@ async fn next_month(
     }
 }
 
+fn ics_response(body: String, disposition: &str, filename: &str) -> HttpResponse {
+    HttpResponse::Ok()
+        .insert_header((CONTENT_TYPE, "text/calendar; charset=utf-8"))
+        .insert_header((CONTENT_DISPOSITION, format!("{}; filename=\"{}\"", disposition, filename)))
+        .body(body)
+}
+
+#[get("/consult/{slug}.ics")]
+async fn consult_ics(
+    req: HttpRequest,
+    state: Data<AppState>,
+    r_state: Data<RedisState>,
+    path: web::Path<String>,
+) -> impl Responder {
+    if current_user_id(&req, &state, &r_state).await.is_none() {
+        return HttpResponse::Unauthorized().finish();
+    }
+    match ics_consult_by_slug(&state.db, &path.into_inner()).await {
+        Ok(consult) => {
+            let filename = format!("consult-{}.ics", consult.id);
+            let calendar = consult_calendar(&[consult], "Consult", Utc::now());
+            ics_response(calendar.to_string(), "attachment", &filename)
+        }
+        Err(err) => {
+            dbg!(&err);
+            HttpResponse::NotFound().finish()
+        }
+    }
+}
+
+// Polled by calendar apps, so the token is the only credential.
+#[get("/feed/{token}.ics")]
+async fn feed_ics(state: Data<AppState>, path: web::Path<String>) -> impl Responder {
+    let feed = match feed_by_token(&state.db, &path.into_inner()).await {
+        Ok(Some(feed)) => feed,
+        Ok(None) => return HttpResponse::NotFound().finish(),
+        Err(err) => {
+            dbg!(&err);
+            return HttpResponse::InternalServerError().finish();
+        }
+    };
+    let now = Utc::now();
+    match feed_consults(&state.db, &feed, now).await {
+        Ok(consults) => {
+            let calendar = consult_calendar(&consults, &feed.feed_name, now);
+            ics_response(calendar.to_string(), "inline", "consults.ics")
+        }
+        Err(err) => {
+            dbg!(&err);
+            HttpResponse::InternalServerError().finish()
+        }
+    }
+}
+
+#[derive(Debug, Serialize)]
+pub struct FeedRow {
+    pub feed_id: i32,
+    pub feed_name: String,
+    pub url: String,
+    pub webcal_url: String,
+}
+
+#[derive(Debug, Serialize)]
+pub struct FeedsTemplate {
+    pub feeds: Vec<FeedRow>,
+    pub consultant_options: Vec<SelectOption>,
+    pub location_options: Vec<SelectOption>,
+    pub message: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct FeedPost {
+    pub entity_type_id: i32,
+    // String so the select's empty option parses
+    pub entity_id: Option<String>,
+}
+
+fn feed_row(req: &HttpRequest, feed: CalendarFeed) -> FeedRow {
+    let info = req.connection_info();
+    let path = format!("{}/event/feed/{}.ics", info.host(), feed.token);
+    FeedRow {
+        feed_id: feed.feed_id,
+        feed_name: feed.feed_name,
+        url: format!("{}://{}", info.scheme(), path),
+        webcal_url: format!("webcal://{}", path),
+    }
+}
+
+async fn render_feeds(
+    hb: &Handlebars<'_>,
+    req: &HttpRequest,
+    state: &Data<AppState>,
+    r_state: &Data<RedisState>,
+    user_id: i32,
+    message: Option<String>,
+) -> HttpResponse {
+    match user_feeds(&state.db, user_id).await {
+        Ok(feeds) => {
+            let template_data = FeedsTemplate {
+                feeds: feeds.into_iter().map(|feed| feed_row(req, feed)).collect(),
+                consultant_options: consultant_options(state, r_state).await.vec,
+                location_options: location_options(state, r_state).await.vec,
+                message,
+            };
+            let body = hb.render("calendar/feeds", &template_data).unwrap();
+            HttpResponse::Ok().body(body)
+        }
+        Err(err) => {
+            dbg!(&err);
+            let validation_response = ValidationResponse::from(("Error loading calendar feeds", "validation_error"));
+            let body = hb.render("validation", &validation_response).unwrap();
+            HttpResponse::Ok().body(body)
+        }
+    }
+}
+
+#[get("/feeds")]
+async fn feeds(
+    hb: web::Data<Handlebars<'_>>,
+    req: HttpRequest,
+    state: Data<AppState>,
+    r_state: Data<RedisState>,
+) -> impl Responder {
+    match current_user_id(&req, &state, &r_state).await {
+        Some(user_id) => render_feeds(&hb, &req, &state, &r_state, user_id, None).await,
+        None => HttpResponse::Unauthorized().finish(),
+    }
+}
+
+#[post("/feeds")]
+async fn create_feed_handler(
+    body: web::Form<FeedPost>,
+    hb: web::Data<Handlebars<'_>>,
+    req: HttpRequest,
+    state: Data<AppState>,
+    r_state: Data<RedisState>,
+) -> impl Responder {
+    let user_id = match current_user_id(&req, &state, &r_state).await {
+        Some(user_id) => user_id,
+        None => return HttpResponse::Unauthorized().finish(),
+    };
+    // A user feed is always the caller's own
+    let entity_id = match body.entity_type_id {
+        USER_FEED => Some(user_id),
+        _ => body.entity_id.as_deref().and_then(|id| id.parse().ok()),
+    };
+    let message = match entity_id {
+        Some(entity_id) if is_feed_type(body.entity_type_id) => {
+            match create_feed(&state.db, user_id, body.entity_type_id, entity_id).await {
+                Ok(()) => "Feed ready. Paste its link into your calendar app.".to_owned(),
+                Err(err) => {
+                    dbg!(&err);
+                    "Unable to create the feed".to_owned()
+                }
+            }
+        }
+        _ => "Choose what the feed should follow".to_owned(),
+    };
+    render_feeds(&hb, &req, &state, &r_state, user_id, Some(message)).await
+}
+
+#[post("/feeds/{feed_id}/revoke")]
+async fn revoke_feed_handler(
+    hb: web::Data<Handlebars<'_>>,
+    req: HttpRequest,
+    state: Data<AppState>,
+    r_state: Data<RedisState>,
+    path: web::Path<i32>,
+) -> impl Responder {
+    let user_id = match current_user_id(&req, &state, &r_state).await {
+        Some(user_id) => user_id,
+        None => return HttpResponse::Unauthorized().finish(),
+    };
+    let message = match revoke_feed(&state.db, path.into_inner(), user_id).await {
+        Ok(0) => "Feed not found".to_owned(),
+        Ok(_) => "Feed revoked. Calendars using its link stop updating.".to_owned(),
+        Err(err) => {
+            dbg!(&err);
+            "Unable to revoke the feed".to_owned()
+        }
+    };
+    render_feeds(&hb, &req, &state, &r_state, user_id, Some(message)).await
+}
+
 #[get("/list")]
 pub async fn get_locations_handler(
     opts: web::Query<FilterOptions>,
@@ -646,127 +951,30 @@ pub struct EventPostRequest {
     pub message: String,
 }
 
-fn validate_event_input(body: &Form<ConsultPost>) -> bool {
-    true
-}
-#[derive(Debug, Serialize, Deserialize, FromRow, Clone)]
-pub struct ConsultResponse {
-    id: i32,
-}
-
+// Booking from the events page is the same booking as from the consult form
 #[post("/form")]
 async fn create_consult_event(
     body: web::Form<ConsultPost>,
     hb: web::Data<Handlebars<'_>>,
     req: HttpRequest,
     state: web::Data<AppState>,
+    r_state: web::Data<RedisState>,
+    broadcaster: web::Data<Broadcaster>,
 ) -> impl Responder {
-    let headers = req.headers();
-    if let Some(cookie) = headers.get(actix_web::http::header::COOKIE) {
-        dbg!(cookie.clone());
-        match validate_and_get_user(cookie, &state).await {
-            Ok(user_option) => {
-                dbg!(&user_option);
-                let is_valid = body.validate();
-                if is_valid.is_err() {
-                    let validation_response = get_validation_response(is_valid);
-                    let body = hb
-                        .render("forms/form-validation", &validation_response)
-                        .unwrap();
-                    return HttpResponse::BadRequest()
-                        .header("HX-Retarget", "#location_errors")
-                        .body(body);
-                }
-
-                let consult_start_string =
-                    body.consult_start_date.clone() + " " + &body.consult_start_time + ":00 -06:00";
-                dbg!(&consult_start_string);
-                let consult_end_string =
-                    body.consult_end_date.clone() + " " + &body.consult_end_time + ":00 -06:00";
-                dbg!(&consult_end_string);
-                let consult_end_datetime =
-                    DateTime::parse_from_str(&consult_end_string, "%Y-%m-%d %H:%M:%S %z").unwrap();
-                dbg!(&consult_end_datetime);
-                dbg!(&consult_start_string);
-                let consult_start_datetime =
-                    DateTime::parse_from_str(&consult_start_string, "%Y-%m-%d %H:%M:%S %z")
-                        .unwrap();
-                dbg!(&consult_start_datetime);
-                let consult_start_datetime_utc = consult_start_datetime.with_timezone(&Utc);
-                let consult_end_datetime_utc = consult_end_datetime.with_timezone(&Utc);
-
-                // Create ICS calendar to send out
-                let cal = create_calendar_event(
-                    &consult_start_datetime_utc,
-                    &consult_end_datetime_utc,
-                    body.consult_purpose_id,
-                );
-
-                if let Some(user) = user_option {
-                    // let user_body = hb.render("homepage", &user).unwrap();
-                    if validate_event_input(&body) {
-                        match sqlx::query_as::<_, ConsultResponse>(
-                            "INSERT INTO consults (consult_purpose_id, client_id, location_id, consult_start, consult_end, notes) VALUES ($1, $2, $3, $4, $5) RETURNING id",
-                        )
-                        .bind(body.consult_purpose_id)
-                        .bind(body.client_id)
-                        .bind(body.location_id)
-                        .bind(consult_start_datetime)
-                        .bind(consult_end_datetime)
-                        .bind(body.notes.clone())
-                        .fetch_one(&state.db)
-                        .await
-                        {
-                            Ok(consult_response) => {
-                                let user_alert = UserAlert::from((format!("Consult added successfully: ID #{:?}", consult_response.id).as_str(), "alert_success"));
-                                let body = hb.render("crud-api", &user_alert).unwrap();
-                                return HttpResponse::Ok().body(body);
-                            }
-                            Err(err) => {
-                                dbg!(&err);
-                                let user_alert = UserAlert::from((format!("Error Updating User After Adding Them As Consult: {:?}", err).as_str(), "alert_error"));
-                                let body = hb.render("crud-api", &user_alert).unwrap();
-                                return HttpResponse::Ok().body(body);
-                            }
-                        }
-                    } else {
-                        println!("Val error");
-                        let error_msg = "Validation error";
-                        let validation_response =
-                            ValidationResponse::from((error_msg, "validation_error"));
-                        let body = hb.render("validation", &validation_response).unwrap();
-                        return HttpResponse::Ok().body(body);
-                    }
-                } else {
-                    let index_data = IndexData {
-                        message: "Your session seems to have expired. Please login again."
-                            .to_owned(),
-                    };
-                    let body = hb.render("index", &index_data).unwrap();
-
-                    HttpResponse::Ok().body(body)
-                }
-            }
-            Err(_err) => {
-                dbg!(&_err);
-                // User's cookie is invalud or expired. Need to get a new one via logging in.
-                // They had a session. Could give them details about that. Get from DB.
-                let index_data = IndexData {
-                    message: format!("Error in validate and get user: {}", _err.error),
-                };
-                let body = hb.render("index", &index_data).unwrap();
-
-                HttpResponse::Ok().body(body)
-            }
+    let cookie = match req.headers().get(actix_web::http::header::COOKIE) {
+        Some(cookie) => cookie,
+        None => {
+            let message = "Your session seems to have expired. Please login again.".to_owned();
+            let body = hb.render("index", &message).unwrap();
+            return HttpResponse::Ok().header("HX-Redirect", "/").body(body);
         }
-    } else {
-        let data = json!({
-            "header": "Login Form",
-        });
-        let body = hb.render("index", &data).unwrap();
-
-        HttpResponse::Ok().body(body)
+    };
+    if let Err(err) = redis_validate_and_get_user(cookie, &r_state).await {
+        dbg!(&err);
+        let body = hb.render("index", &format!("{:?}", err)).unwrap();
+        return HttpResponse::Ok().header("HX-Redirect", "/").body(body);
     }
+    booking_response(&hb, book_consult(&body, BookingStatus::Confirmed, &state, &r_state, &broadcaster).await)
 }
 
 #[cfg(test)]
@@ -0,0 +1,84 @@
+use std::time::Duration;
+
+use actix_web::{
+    get,
+    http::header::{CACHE_CONTROL, CONTENT_TYPE},
+    rt::time::timeout,
+    web::{self, Bytes},
+    HttpRequest, HttpResponse, Responder, Scope,
+};
+use futures_util::{stream, StreamExt};
+use handlebars::Handlebars;
+use tokio::sync::broadcast::error::RecvError;
+
+use crate::{
+    config::redis_validate_and_get_user,
+    redis_mod::live_updates::{sse_frame, Broadcaster, LiveEvent},
+    scopes::notification::user_id_by_username,
+    AppState, RedisState,
+};
+
+// Comment frames keep proxies from closing an idle stream.
+const KEEP_ALIVE: Duration = Duration::from_secs(15);
+// Browser reconnect delay (ms) after the stream drops.
+const RETRY_MS: u32 = 5000;
+
+pub fn live_scope() -> Scope {
+    web::scope("/live").service(live_events)
+}
+
+// Two frames per write: `<entity>-changed` so open tables re-fetch themselves, and `feed` with a
+// rendered row for the homepage activity feed.
+fn event_frames(event: &LiveEvent, hb: &Handlebars<'_>, user_id: Option<i32>) -> String {
+    let mut frames = sse_frame(&event.entity.changed_event(), Some(&event.id), &event.entity_id.to_string());
+    match hb.render("live-feed-item", event) {
+        Ok(html) => frames.push_str(&sse_frame("feed", None, &html)),
+        Err(err) => println!("Error rendering live feed item: {}", err),
+    }
+    // The navbar badge re-fetches its own count on this
+    if user_id.map_or(false, |id| event.notify_user_ids.contains(&id)) {
+        frames.push_str(&sse_frame("notification", None, &event.summary));
+    }
+    frames
+}
+
+#[get("/events")]
+async fn live_events(
+    hb: web::Data<Handlebars<'static>>,
+    req: HttpRequest,
+    state: web::Data<AppState>,
+    r_state: web::Data<RedisState>,
+    broadcaster: web::Data<Broadcaster>,
+) -> impl Responder {
+    let user_id = if let Some(cookie) = req.headers().get(actix_web::http::header::COOKIE) {
+        match redis_validate_and_get_user(cookie, &r_state).await {
+            Ok(user) => user_id_by_username(&state.db, &user.username).await,
+            Err(err) => {
+                dbg!(&err);
+                return HttpResponse::Unauthorized().finish();
+            }
+        }
+    } else {
+        return HttpResponse::Unauthorized().finish();
+    };
+
+    let rx = broadcaster.subscribe();
+    let retry = stream::once(async { Ok::<Bytes, actix_web::Error>(Bytes::from(format!("retry: {}\n\n", RETRY_MS))) });
+    let events = stream::unfold((rx, hb), |(mut rx, hb)| async move {
+        loop {
+            let chunk = match timeout(KEEP_ALIVE, rx.recv()).await {
+                Err(_elapsed) => ": keep-alive\n\n".to_string(),
+                Ok(Ok(event)) => event_frames(&event, &hb, user_id),
+                // This client fell behind, skip ahead. The next `-changed` re-fetch catches it up.
+                Ok(Err(RecvError::Lagged(_))) => continue,
+                Ok(Err(RecvError::Closed)) => return None,
+            };
+            return Some((Ok::<Bytes, actix_web::Error>(Bytes::from(chunk)), (rx, hb)));
+        }
+    });
+
+    HttpResponse::Ok()
+        .insert_header((CONTENT_TYPE, "text/event-stream"))
+        .insert_header((CACHE_CONTROL, "no-cache"))
+        .streaming(retry.chain(events))
+}
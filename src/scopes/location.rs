@@ -1,5 +1,4 @@
 use actix_web::{get, patch, post, web, HttpRequest, HttpResponse, Responder, Scope};
-use redis::{RedisResult, AsyncCommands};
 use serde_json::json;
 
 use crate::{
@@ -12,6 +11,11 @@ use crate::{
         LocationFormRequest, LocationFormTemplate, LocationList, LocationPatchRequest,
         LocationPostRequest, LocationPostResponse,
     },
+    redis_mod::{
+        live_updates::{publish_live_event, Broadcaster, LiveEntity, LiveEvent},
+        query_cache::{invalidate, CacheTag},
+    },
+    scopes::notification::notify_subscribers,
     AppState, HeaderValueExt, ValidatedUser, RedisState,
 };
 use handlebars::Handlebars;
@@ -348,6 +352,7 @@ async fn create_location(
     req: HttpRequest,
     state: web::Data<AppState>,
     r_state: web::Data<RedisState>,
+    broadcaster: web::Data<Broadcaster>,
 ) -> impl Responder {
     dbg!(&body);
     let headers = req.headers();
@@ -385,16 +390,9 @@ async fn create_location(
                     {
                         Ok(loc) => {
                             dbg!(loc.id);
-                            // Del / Invalidate Redis Key to force a DB fetch
-                            let mut con = r_state.r_pool.get().await.unwrap();
-                            let key = format!("{}:{}", "query", "location_options");
-                            let deleted: RedisResult<bool> = con.del(&key).await;
-                            match deleted {
-                                Ok(bool) => {
-                                    println!("Key:{} -> {}", &key, {if bool {"Found & Deleted"} else {"Not Found"}});
-                                },
-                                Err(err) => println!("Error: {}", err)
-                            }
+                            invalidate(&r_state, &[CacheTag::Location]).await;
+                            let summary = format!("Location #{} added", loc.id);
+                            publish_live_event(&r_state, &broadcaster, LiveEvent::new(LiveEntity::Location, loc.id, "created", summary)).await;
                             let user_alert = UserAlert::from((format!("Location added successfully: ID #{:?}", loc.id).as_str(), "alert_success"));
                             let template_data = json!({
                                 "user_alert": user_alert,
@@ -436,6 +434,7 @@ async fn patch_location(
     req: HttpRequest,
     state: web::Data<AppState>,
     r_state: web::Data<RedisState>,
+    broadcaster: web::Data<Broadcaster>,
     path: web::Path<String>,
 ) -> impl Responder {
     let loc_slug = path.into_inner();
@@ -508,6 +507,10 @@ async fn patch_location(
                     {
                         Ok(loc) => {
                             dbg!(loc.id);
+                            invalidate(&r_state, &[CacheTag::Location]).await;
+                            let summary = format!("Location #{} edited", loc.id);
+                            let notified = notify_subscribers(&state.db, &[(5, loc.id)], &summary).await;
+                            publish_live_event(&r_state, &broadcaster, LiveEvent::new(LiveEntity::Location, loc.id, "updated", summary).notify(notified)).await;
                             let user_alert = UserAlert::from((
                                 format!("Location added successfully: ID #{:?}", loc.id).as_str(),
                                 "alert_success",
@@ -4,6 +4,10 @@ pub mod client;
 pub mod consult;
 pub mod consultant;
 pub mod event;
+pub mod live;
 pub mod location;
+pub mod notification;
+pub mod presence;
 pub mod user;
 pub mod service;
+pub mod booking;
@@ -0,0 +1,230 @@
+use actix_web::{get, post, web, HttpRequest, HttpResponse, Responder, Scope};
+use handlebars::Handlebars;
+use sqlx::{Pool, Postgres};
+
+use crate::{
+    config::{entity_name, redis_validate_and_get_user},
+    models::model_notification::{Notification, NotificationListTemplate, NotificationUserId, UnreadCount},
+    scopes::user::EntityId,
+    AppState, RedisState,
+};
+
+// Per-user notification rows for writes to subscribed entities. Rows are created in the write path
+// (notify_subscribers), pushed over the live SSE stream, and read/cleared here.
+
+const LIST_LIMIT: i32 = 20;
+
+pub fn notification_scope() -> Scope {
+    web::scope("/notification")
+        .service(badge)
+        .service(list)
+        .service(mark_all_read)
+        .service(mark_read)
+}
+
+// The subscription column for an entity, e.g. client_subs. entity_name is a fixed whitelist.
+pub fn subs_column(entity_type_id: i32) -> String {
+    format!("{}_subs", entity_name(entity_type_id))
+}
+
+// Creates one notification per subscriber of any of `targets` (entity_type_id, entity_id) and
+// returns who got one. A user subscribed to several of the targets is only notified once.
+pub async fn notify_subscribers(
+    db: &Pool<Postgres>,
+    targets: &[(i32, i32)],
+    msg: &str,
+) -> Vec<i32> {
+    let mut notified: Vec<i32> = vec![];
+    for (entity_type_id, entity_id) in targets {
+        let sql = format!(
+            "INSERT INTO notifications (user_id, entity_type_id, entity_id, msg)
+                SELECT id, $1, $2, $3 FROM users WHERE $2 = ANY({}) AND NOT (id = ANY($4))
+                RETURNING user_id",
+            subs_column(*entity_type_id)
+        );
+        match sqlx::query_as::<_, NotificationUserId>(&sql)
+            .bind(entity_type_id)
+            .bind(entity_id)
+            .bind(msg)
+            .bind(&notified)
+            .fetch_all(db)
+            .await
+        {
+            Ok(rows) => notified.extend(rows.iter().map(|row| row.user_id)),
+            Err(err) => println!("Error creating notifications: {}", err),
+        }
+    }
+    notified
+}
+
+pub async fn user_id_by_username(db: &Pool<Postgres>, username: &str) -> Option<i32> {
+    sqlx::query_as::<_, EntityId>("SELECT id FROM users WHERE username = $1")
+        .bind(username)
+        .fetch_optional(db)
+        .await
+        .ok()
+        .flatten()
+        .map(|row| row.id)
+}
+
+// Admins and subadmins, who the admin pages are for
+pub const ADMIN_USER_TYPES: [i32; 2] = [1, 2];
+
+pub fn is_admin(user_type_id: i32) -> bool {
+    ADMIN_USER_TYPES.contains(&user_type_id)
+}
+
+// The signed in user's id and user_type_id
+pub async fn current_user(req: &HttpRequest, state: &web::Data<AppState>, r_state: &web::Data<RedisState>) -> Option<(i32, i32)> {
+    let cookie = req.headers().get(actix_web::http::header::COOKIE)?;
+    match redis_validate_and_get_user(cookie, r_state).await {
+        Ok(user) => user_id_by_username(&state.db, &user.username)
+            .await
+            .map(|user_id| (user_id, user.user_type_id)),
+        Err(err) => {
+            dbg!(&err);
+            None
+        }
+    }
+}
+
+pub async fn current_user_id(req: &HttpRequest, state: &web::Data<AppState>, r_state: &web::Data<RedisState>) -> Option<i32> {
+    current_user(req, state, r_state).await.map(|(user_id, _)| user_id)
+}
+
+// Only set when the signed in user is an admin
+pub async fn current_admin_id(req: &HttpRequest, state: &web::Data<AppState>, r_state: &web::Data<RedisState>) -> Option<i32> {
+    match current_user(req, state, r_state).await {
+        Some((user_id, user_type_id)) if is_admin(user_type_id) => Some(user_id),
+        _ => None,
+    }
+}
+
+async fn unread_count(db: &Pool<Postgres>, user_id: i32) -> i64 {
+    sqlx::query_as::<_, UnreadCount>(
+        "SELECT COUNT(*) AS unread FROM notifications WHERE user_id = $1 AND read_at IS NULL",
+    )
+    .bind(user_id)
+    .fetch_one(db)
+    .await
+    .map(|row| row.unread)
+    .unwrap_or(0)
+}
+
+async fn render_list(hb: &Handlebars<'_>, db: &Pool<Postgres>, user_id: i32) -> HttpResponse {
+    let notifications = sqlx::query_as::<_, Notification>(
+        "SELECT notification_id, entity_type_id, entity_id, msg, read_at, created_at
+            FROM notifications
+            WHERE user_id = $1
+            ORDER BY read_at IS NOT NULL, created_at DESC
+            LIMIT $2",
+    )
+    .bind(user_id)
+    .bind(LIST_LIMIT)
+    .fetch_all(db)
+    .await
+    .unwrap_or_default();
+
+    let template_data = NotificationListTemplate {
+        notifications,
+        unread: unread_count(db, user_id).await,
+    };
+    let body = hb.render("notification-list", &template_data).unwrap();
+    // Lets the navbar badge refresh itself
+    HttpResponse::Ok()
+        .insert_header(("HX-Trigger", "notification-read"))
+        .body(body)
+}
+
+#[get("/badge")]
+async fn badge(
+    hb: web::Data<Handlebars<'_>>,
+    req: HttpRequest,
+    state: web::Data<AppState>,
+    r_state: web::Data<RedisState>,
+) -> impl Responder {
+    let unread = match current_user_id(&req, &state, &r_state).await {
+        Some(user_id) => unread_count(&state.db, user_id).await,
+        None => 0,
+    };
+    let body = hb.render("notification-badge", &UnreadCount { unread }).unwrap();
+    HttpResponse::Ok().body(body)
+}
+
+#[get("/list")]
+async fn list(
+    hb: web::Data<Handlebars<'_>>,
+    req: HttpRequest,
+    state: web::Data<AppState>,
+    r_state: web::Data<RedisState>,
+) -> impl Responder {
+    match current_user_id(&req, &state, &r_state).await {
+        Some(user_id) => render_list(&hb, &state.db, user_id).await,
+        None => HttpResponse::Unauthorized().finish(),
+    }
+}
+
+#[post("/read-all")]
+async fn mark_all_read(
+    hb: web::Data<Handlebars<'_>>,
+    req: HttpRequest,
+    state: web::Data<AppState>,
+    r_state: web::Data<RedisState>,
+) -> impl Responder {
+    match current_user_id(&req, &state, &r_state).await {
+        Some(user_id) => {
+            if let Err(err) = sqlx::query("UPDATE notifications SET read_at = NOW() WHERE user_id = $1 AND read_at IS NULL")
+                .bind(user_id)
+                .execute(&state.db)
+                .await
+            {
+                dbg!(&err);
+            }
+            render_list(&hb, &state.db, user_id).await
+        }
+        None => HttpResponse::Unauthorized().finish(),
+    }
+}
+
+// Scoped to the current user, so one user can't clear another's notifications by id.
+#[post("/{notification_id}/read")]
+async fn mark_read(
+    hb: web::Data<Handlebars<'_>>,
+    req: HttpRequest,
+    state: web::Data<AppState>,
+    r_state: web::Data<RedisState>,
+    path: web::Path<i32>,
+) -> impl Responder {
+    let notification_id = path.into_inner();
+    match current_user_id(&req, &state, &r_state).await {
+        Some(user_id) => {
+            if let Err(err) = sqlx::query(
+                "UPDATE notifications SET read_at = NOW() WHERE notification_id = $1 AND user_id = $2 AND read_at IS NULL",
+            )
+            .bind(notification_id)
+            .bind(user_id)
+            .execute(&state.db)
+            .await
+            {
+                dbg!(&err);
+            }
+            render_list(&hb, &state.db, user_id).await
+        }
+        None => HttpResponse::Unauthorized().finish(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn subs_column_matches_user_columns() {
+        assert_eq!(subs_column(4), "consultant_subs");
+        assert_eq!(subs_column(5), "location_subs");
+        assert_eq!(subs_column(6), "consult_subs");
+        assert_eq!(subs_column(7), "client_subs");
+        // Unknown ids fall back to a real column instead of building bad SQL
+        assert_eq!(subs_column(99), "user_subs");
+    }
+}
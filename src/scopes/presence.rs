@@ -0,0 +1,54 @@
+use actix_web::{post, web, HttpRequest, HttpResponse, Responder, Scope};
+use handlebars::Handlebars;
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    config::redis_validate_and_get_user,
+    redis_mod::presence::{heartbeat, presence_entity, HEARTBEAT_SECS},
+    RedisState,
+};
+
+// "Who else is viewing" for the consult and client edit forms. The form loads the fragment once and
+// the fragment then re-posts itself every HEARTBEAT_SECS, which doubles as the viewer's heartbeat.
+
+pub fn presence_scope() -> Scope {
+    web::scope("/presence").service(presence_heartbeat)
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct PresenceTemplate {
+    pub url: String,
+    pub heartbeat_secs: i64,
+    pub viewers: Vec<String>,
+}
+
+#[post("/{entity}/{slug}")]
+async fn presence_heartbeat(
+    hb: web::Data<Handlebars<'_>>,
+    req: HttpRequest,
+    r_state: web::Data<RedisState>,
+    path: web::Path<(String, String)>,
+) -> impl Responder {
+    let (entity_name, slug) = path.into_inner();
+    let entity = match presence_entity(&entity_name) {
+        Some(entity) => entity,
+        None => return HttpResponse::NotFound().finish(),
+    };
+    let user = match req.headers().get(actix_web::http::header::COOKIE) {
+        Some(cookie) => match redis_validate_and_get_user(cookie, &r_state).await {
+            Ok(user) => user,
+            Err(err) => {
+                dbg!(&err);
+                return HttpResponse::Unauthorized().finish();
+            }
+        },
+        None => return HttpResponse::Unauthorized().finish(),
+    };
+    let template_data = PresenceTemplate {
+        url: format!("/presence/{}/{}", entity_name, slug),
+        heartbeat_secs: HEARTBEAT_SECS,
+        viewers: heartbeat(&r_state, entity, &slug, &user.username).await,
+    };
+    let body = hb.render("presence", &template_data).unwrap();
+    HttpResponse::Ok().body(body)
+}
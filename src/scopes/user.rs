@@ -6,8 +6,9 @@ use crate::{
     models::model_user::{
         UserHomeModel, UserHomeQuery, UserSettingsObj, UserSettingsPost, UserSettingsQuery,
     },
+    redis_mod::materialized::drop_user_feed,
     scopes::location::IndexData,
-    AppState, HeaderValueExt, ValidatedUser,
+    AppState, HeaderValueExt, RedisState, ValidatedUser,
 };
 use actix_web::{
     get, put,
@@ -171,7 +172,7 @@ pub fn get_sub_sql(subscribed: bool, entity_id: i32, entity_type_id: i32) -> Str
 
 #[derive(Debug, Serialize, Deserialize, Clone, FromRow)]
 pub struct EntityId {
-    id: i32,
+    pub id: i32,
 }
 
 async fn slug_to_id(entity_type_id: i32, slug: &str, pool: &Pool<Postgres>) -> i32 {
@@ -237,6 +238,7 @@ async fn subscribe(
     hb: web::Data<Handlebars<'_>>,
     req: HttpRequest,
     state: web::Data<AppState>,
+    r_state: web::Data<RedisState>,
     path: web::Path<(i32, String)>,
 ) -> impl Responder {
     let (entity_type_id, slug) = path.into_inner();
@@ -267,6 +269,8 @@ async fn subscribe(
                     .await
                 {
                     Ok(resp) => {
+                        // Feed is built from subscriptions
+                        drop_user_feed(&r_state, &username).await;
                         let msg = format!("Subscription {} successfully", {
                                 if subscribed {
                                     "removed"